@@ -0,0 +1,62 @@
+// Allocations and time per operation on the point read and overwrite paths. Run with
+// `cargo bench --bench hot_path`.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use kvstore::engine::bptree::{BPTree, Options};
+use kvstore::error::Result;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const KEYS: u64 = 100_000;
+const OPS: u64 = 200_000;
+
+fn keys() -> impl Iterator<Item = u64> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    (0..OPS).map(move |_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) % KEYS
+    })
+}
+
+fn measure<F: FnMut(u64) -> Result<()>>(name: &str, mut op: F) -> Result<()> {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for key in keys() {
+        op(key)?;
+    }
+    let elapsed = start.elapsed();
+    println!("{:<24} {:>8.1} allocs/op {:>10.0} ns/op", name,
+             (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64 / OPS as f64,
+             elapsed.as_nanos() as f64 / OPS as f64);
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    for cache_pages in [0, 4096] {
+        let options = Options{ cache_pages, ..Options::default() };
+        let mut tree = BPTree::with_options(Path::new("data").join("bench_hot_path.db"), options)?;
+        tree.bulk_load((0..KEYS).map(|i| (i, i)).collect())?;
+        println!("cache_pages = {}", cache_pages);
+        measure("get", |key| tree.get(&key).map(|_| ()))?;
+        measure("set (overwrite)", |key| tree.set(key, key + 1).map(|_| ()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,33 @@
+/* C interface of the kvstore library, built with `cargo build --release --features ffi`. */
+#ifndef KVSTORE_H
+#define KVSTORE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define KVSTORE_OK 0
+#define KVSTORE_NOT_FOUND 1
+#define KVSTORE_ERROR (-1)
+
+typedef struct KvStore KvStore;
+
+typedef struct {
+    uint8_t *data;
+    size_t len;
+    size_t capacity;
+} KvBuffer;
+
+/* Returning anything but 0 stops the scan. */
+typedef int (*KvScanCallback)(void *context, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len);
+
+/* Opens the store at `path`, creating it when there is no file there yet. Returns NULL on error. */
+KvStore *kvstore_open(const char *path);
+/* Flushes and frees the store; the handle is invalid afterwards even when this fails. */
+int kvstore_close(KvStore *store);
+int kvstore_get(const KvStore *store, const uint8_t *key, size_t key_len, KvBuffer *value);
+int kvstore_set(KvStore *store, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len);
+int kvstore_remove(KvStore *store, const uint8_t *key, size_t key_len);
+int kvstore_scan(const KvStore *store, const uint8_t *start, size_t start_len, const uint8_t *end, size_t end_len, KvScanCallback callback, void *context);
+void kvstore_buffer_free(KvBuffer *buffer);
+
+#endif
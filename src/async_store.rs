@@ -0,0 +1,175 @@
+// An async front to a Shared tree, built with the `tokio` feature. Every call runs on
+// tokio's blocking pool, so page reads, syncs and waiting for the write lock never hold up
+// the executor. Must be used from within a tokio runtime.
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::task;
+use crate::engine::batch::WriteBatch;
+use crate::engine::bptree::{Options, UpsertOutcome};
+use crate::engine::iter::RangeIter;
+use crate::error::{Error, Result};
+use crate::shared::Shared;
+
+pub const SCAN_CHUNK: usize = 256;
+
+// The async counterpart of KVStoreEngine. Keys are taken by value, as they have to move to
+// the thread doing the work.
+#[async_trait]
+pub trait AsyncKVStoreEngine<K, V> {
+    async fn get(&self, key: K) -> Result<V>;
+    async fn set(&self, key: K, value: V) -> Result<()>;
+    async fn remove(&self, key: K) -> Result<()>;
+}
+
+pub struct AsyncStore<K, V> {
+    store: Shared<K, V>,
+}
+
+impl<K, V> Clone for AsyncStore<K, V> {
+    fn clone(&self) -> Self {
+        Self{ store: self.store.clone() }
+    }
+}
+
+// The blocking task only goes away without answering if the call panicked.
+async fn blocking<T, F>(f: F) -> Result<T>
+    where F: FnOnce() -> Result<T> + Send + 'static, T: Send + 'static
+{
+    task::spawn_blocking(f).await.unwrap_or(Err(Error::Poisoned))
+}
+
+impl<K, V> AsyncStore<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    pub async fn create<P: Into<PathBuf>>(path: P, options: Options) -> Result<Self> {
+        let path = path.into();
+        let store = blocking(move || Shared::create(path, options)).await?;
+        Ok(Self{ store })
+    }
+
+    pub async fn open<P: Into<PathBuf>>(path: P, options: Options) -> Result<Self> {
+        let path = path.into();
+        let store = blocking(move || Shared::open(path, options)).await?;
+        Ok(Self{ store })
+    }
+
+    pub fn from_shared(store: Shared<K, V>) -> Self {
+        Self{ store }
+    }
+
+    // The same tree, for code that may block.
+    pub fn shared(&self) -> &Shared<K, V> {
+        &self.store
+    }
+
+    pub async fn get(&self, key: K) -> Result<V> {
+        let store = self.store.clone();
+        blocking(move || store.get(&key)).await
+    }
+
+    pub async fn set(&self, key: K, value: V) -> Result<u64> {
+        let store = self.store.clone();
+        blocking(move || store.set(key, value)).await
+    }
+
+    pub async fn upsert(&self, key: K, value: V) -> Result<UpsertOutcome<V>> {
+        let store = self.store.clone();
+        blocking(move || store.upsert(key, value)).await
+    }
+
+    pub async fn remove(&self, key: K) -> Result<u64> {
+        let store = self.store.clone();
+        blocking(move || store.remove(&key)).await
+    }
+
+    pub async fn write_batch(&self, batch: WriteBatch<K, V>) -> Result<u64> {
+        let store = self.store.clone();
+        blocking(move || store.write_batch(batch)).await
+    }
+
+    pub async fn flush(&self) -> Result<u64> {
+        let store = self.store.clone();
+        blocking(move || store.flush()).await
+    }
+
+    // The entries of a range in key order, read SCAN_CHUNK at a time under a read lock of
+    // their own, so writes between chunks show up in the entries that follow. The scan
+    // ends after the first error.
+    pub fn scan<R: RangeBounds<K>>(&self, range: R) -> impl Stream<Item = Result<(K, V)>> + Send + 'static {
+        let state = Some((self.store.clone(), range.start_bound().cloned(), range.end_bound().cloned()));
+        stream::unfold(state, |state| async move {
+            let (store, next, end) = state?;
+            let (chunk_store, chunk_end) = (store.clone(), end.clone());
+            let read = blocking(move || chunk_store.read(|tree| RangeIter::new(tree, next, chunk_end)?.take(SCAN_CHUNK).collect::<Result<Vec<_>>>())).await;
+            match read {
+                Ok(entries) => {
+                    let state = match entries.last() {
+                        Some((key, _)) if entries.len() == SCAN_CHUNK => Some((store, Bound::Excluded(key.clone()), end)),
+                        _ => None,
+                    };
+                    Some((entries.into_iter().map(Ok).collect::<Vec<_>>(), state))
+                }
+                Err(e) => Some((vec![Err(e)], None)),
+            }
+        }).flat_map(stream::iter)
+    }
+}
+
+#[async_trait]
+impl<K, V> AsyncKVStoreEngine<K, V> for AsyncStore<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    async fn get(&self, key: K) -> Result<V> {
+        AsyncStore::get(self, key).await
+    }
+
+    async fn set(&self, key: K, value: V) -> Result<()> {
+        AsyncStore::set(self, key, value).await.map(|_| ())
+    }
+
+    async fn remove(&self, key: K) -> Result<()> {
+        AsyncStore::remove(self, key).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use futures::TryStreamExt;
+    use tokio::runtime::Builder;
+    use super::*;
+
+    async fn copy<E: AsyncKVStoreEngine<u64, String>>(from: &E, to: &E, keys: std::ops::Range<u64>) -> Result<()> {
+        for key in keys {
+            to.set(key, from.get(key).await?).await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_async_store() -> Result<()> {
+        let runtime = Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let store: AsyncStore<u64, String> = AsyncStore::create(Path::new("data").join("test_async_store.db"), Options::default()).await?;
+            store.write_batch((0..1000).map(|i| (i, format!("v{}", i))).collect()).await?;
+            store.remove(500).await?;
+            assert_eq!(store.get(999).await?, "v999");
+            assert!(matches!(store.get(500).await, Err(Error::KeyNotFound)));
+
+            let keys: Vec<u64> = store.scan(200..800).map_ok(|(key, _)| key).try_collect().await?;
+            assert_eq!(keys, (200..800).filter(|key| *key != 500).collect::<Vec<_>>());
+            assert_eq!(store.scan(990..).try_collect::<Vec<_>>().await?.len(), 10);
+
+            let copied: AsyncStore<u64, String> = AsyncStore::create(Path::new("data").join("test_async_store_copy.db"), Options::default()).await?;
+            copy(&store, &copied, 0..10).await?;
+            assert_eq!(copied.shared().get(&9)?, "v9");
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,64 @@
+// Serves a tree of byte strings to Redis clients.
+//
+//     kvserver [--addr 127.0.0.1:6379] [--path kvserver.db]
+//
+// The tree is created if the file doesn't exist yet. Every write goes through the redo log
+// before it is answered, so the server can be stopped at any time.
+use std::env;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use kvstore::engine::bptree::Options;
+use kvstore::error::{Error, Result};
+use kvstore::resp::{self, ByteStore, ScanCursors};
+use kvstore::shared::Shared;
+
+const USAGE: &str = "usage: kvserver [--addr HOST:PORT] [--path FILE]";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("kvserver: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let (mut addr, mut path) = (String::from("127.0.0.1:6379"), PathBuf::from("kvserver.db"));
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--addr", Some(value)) => addr = value,
+            ("--path", Some(value)) => path = PathBuf::from(value),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+
+    let options = Options{ write_ahead_log: true, ..Options::default() };
+    let store: ByteStore = match Shared::open(&path, options.clone()) {
+        Err(Error::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => Shared::create(&path, options)?,
+        store => store?,
+    };
+    let listener = TcpListener::bind(&addr)?;
+    eprintln!("kvserver: serving {} on {}", path.display(), addr);
+    let cursors = ScanCursors::default();
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("kvserver: {}", e);
+                continue;
+            }
+        };
+        let (store, cursors) = (store.clone(), cursors.clone());
+        thread::spawn(move || {
+            if let Err(e) = resp::serve(store, cursors, stream) {
+                eprintln!("kvserver: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+// Looks into and edits an existing tree file of byte strings (or strings, which are stored
+// the same way) without writing any code.
+//
+//     kvstore FILE get KEY
+//     kvstore FILE set KEY VALUE
+//     kvstore FILE del KEY
+//     kvstore FILE scan [PREFIX] [--limit N]
+//     kvstore FILE stats
+//     kvstore FILE map
+//     kvstore FILE journal [N]
+//     kvstore FILE check
+//     kvstore FILE dump [--dot]
+//     kvstore FILE export [--format json|csv] [--bytes] > DUMP
+//     kvstore FILE import [--format json|csv] [--bytes] < DUMP
+//
+// Keys and values are taken and printed as text, with \xNN for any other byte and \\ for a
+// backslash. Export and import read them as strings, or as arrays of bytes with --bytes for
+// trees that hold anything but UTF-8. Map draws every page of the file as one character,
+// and journal prints the last N (20) records of the change log, with keys only as a hash.
+// Writes are flushed and checkpointed before the tool exits.
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use kvstore::engine::allocation::PageKind;
+use kvstore::engine::bptree::{BPTree, Options};
+use kvstore::engine::dump::DumpFormat;
+use kvstore::engine::export::Format;
+use kvstore::error::{Error, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+// Change log records journal prints when not told how many.
+const JOURNAL_RECORDS: usize = 20;
+
+const USAGE: &str = "usage: kvstore FILE (get KEY | set KEY VALUE | del KEY | scan [PREFIX] [--limit N] | stats | map | journal [N] | check | dump [--dot] | export [--format json|csv] [--bytes] | import [--format json|csv] [--bytes])";
+
+type ByteTree = BPTree<Vec<u8>, Vec<u8>>;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("kvstore: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// Returns the exit code: 1 for a missing key or a failed check.
+fn run(args: &[String]) -> Result<i32> {
+    let (path, command, rest) = match args {
+        [path, command, rest @ ..] => (Path::new(path), command.as_str(), rest),
+        _ => usage(),
+    };
+    match (command, rest) {
+        ("get", [key]) => {
+            let bptree = ByteTree::open(path)?;
+            match bptree.get(&unescape(key)) {
+                Ok(value) => println!("{}", escape(&value)),
+                Err(Error::KeyNotFound) => {
+                    eprintln!("kvstore: key not found");
+                    return Ok(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        ("set", [key, value]) => {
+            let mut bptree = ByteTree::open(path)?;
+            bptree.set(unescape(key), unescape(value))?;
+            bptree.close()?;
+        }
+        ("del", [key]) => {
+            let mut bptree = ByteTree::open(path)?;
+            let removed = bptree.remove(&unescape(key));
+            bptree.close()?;
+            match removed {
+                Ok(_) => (),
+                Err(Error::KeyNotFound) => {
+                    eprintln!("kvstore: key not found");
+                    return Ok(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        ("scan", rest) => {
+            let (prefix, limit) = match rest {
+                [] => (None, None),
+                [prefix] => (Some(prefix), None),
+                [flag, n] if flag == "--limit" => (None, Some(n)),
+                [prefix, flag, n] if flag == "--limit" => (Some(prefix), Some(n)),
+                _ => usage(),
+            };
+            let limit = match limit.map(|n| n.parse::<usize>()) {
+                None => usize::MAX,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => usage(),
+            };
+            let bptree = ByteTree::open(path)?;
+            let entries = match prefix {
+                Some(prefix) => bptree.scan_prefix(&unescape(prefix))?,
+                None => bptree.range(..)?,
+            };
+            for entry in entries.take(limit) {
+                let (key, value) = entry?;
+                println!("{}\t{}", escape(&key), escape(&value));
+            }
+        }
+        ("stats", []) => {
+            let bptree = ByteTree::open(path)?;
+            let map = bptree.allocation_map()?;
+            println!("file size       {}", fs::metadata(path)?.len());
+            println!("keys            {}", bptree.key_count());
+            println!("page size       {}", bptree.page_size());
+            println!("pages           {}", bptree.page_count());
+            for (name, kind) in [("inner", PageKind::Inner), ("leaf", PageKind::Leaf), ("overflow", PageKind::Overflow), ("free", PageKind::Free), ("unreadable", PageKind::Unreadable)] {
+                println!("  {:<13} {}", name, map.count(kind));
+            }
+            println!("max key count   {}", bptree.max_key_count());
+            println!("inline limit    {}", bptree.inline_threshold());
+        }
+        ("map", []) => {
+            print!("{}", ByteTree::open(path)?.allocation_map()?);
+            println!("M meta  I inner  L leaf  O overflow  . free  ? unreadable");
+        }
+        ("journal", rest) if rest.len() <= 1 => {
+            let records = match rest.first().map(|n| n.parse::<usize>()) {
+                None => JOURNAL_RECORDS,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => usage(),
+            };
+            // Opening with a change log would start one where there is none.
+            if !path.with_extension("wal").exists() {
+                return Err(Error::ChangeLogDisabled);
+            }
+            let bptree = ByteTree::open_with_options(path, Options{ change_log: true, ..Options::default() })?;
+            for record in bptree.journal(records)? {
+                println!("{}", record);
+            }
+        }
+        ("check", []) => {
+            let bptree = ByteTree::open(path)?;
+            let report = bptree.verify(|_| ())?;
+            println!("checked {} pages", report.checked);
+            if !report.is_ok() {
+                for ptr in &report.corrupt {
+                    println!("corrupt page {}", ptr);
+                }
+                for violation in &report.violations {
+                    println!("{}", violation);
+                }
+                return Ok(1);
+            }
+            println!("ok");
+        }
+        ("dump", []) | ("dump", [_]) => {
+            let format = match rest {
+                [] => DumpFormat::Text,
+                [flag] if flag == "--dot" => DumpFormat::Dot,
+                _ => usage(),
+            };
+            ByteTree::open(path)?.dump(io::stdout(), format)?;
+        }
+        ("export", rest) | ("import", rest) => {
+            let (mut format, mut bytes) = (Format::Json, false);
+            let mut flags = rest;
+            loop {
+                flags = match flags {
+                    [] => break,
+                    [flag, tail @ ..] if flag == "--bytes" => {
+                        bytes = true;
+                        tail
+                    }
+                    [flag, name, tail @ ..] if flag == "--format" => {
+                        format = match name.as_str() {
+                            "json" => Format::Json,
+                            "csv" => Format::Csv,
+                            _ => usage(),
+                        };
+                        tail
+                    }
+                    _ => usage(),
+                };
+            }
+            let count = match bytes {
+                true => transfer::<Vec<u8>, Vec<u8>>(path, command == "export", format)?,
+                false => transfer::<String, String>(path, command == "export", format)?,
+            };
+            eprintln!("kvstore: {} {} entries", if command == "export" { "exported" } else { "imported" }, count);
+        }
+        _ => usage(),
+    }
+    Ok(0)
+}
+
+// Exports the tree to stdout or imports stdin into it.
+fn transfer<K, V>(path: &Path, export: bool, format: Format) -> Result<u64>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + 'static
+{
+    let mut bptree: BPTree<K, V> = BPTree::open(path)?;
+    if export {
+        return bptree.export(io::stdout().lock(), format);
+    }
+    let count = bptree.import(io::stdin().lock(), format)?;
+    bptree.close()?;
+    Ok(count)
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for &byte in bytes {
+        match byte {
+            b'\\' => text.push_str("\\\\"),
+            b' '..=b'~' => text.push(byte as char),
+            _ => text.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    text
+}
+
+fn unescape(text: &str) -> Vec<u8> {
+    let bad = || -> ! {
+        eprintln!("kvstore: bad escape in {:?}", text);
+        process::exit(2);
+    };
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [b'\\', tail @ ..] => {
+                bytes.push(b'\\');
+                rest = tail;
+            }
+            [b'x', hex @ ..] if hex.len() >= 2 => {
+                let hex = std::str::from_utf8(&hex[..2]).unwrap_or_else(|_| bad());
+                bytes.push(u8::from_str_radix(hex, 16).unwrap_or_else(|_| bad()));
+                rest = &rest[3..];
+            }
+            _ => bad(),
+        }
+    }
+    bytes
+}
@@ -0,0 +1,194 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::iter::FromIterator;
+use roaring::RoaringBitmap;
+use serde::{de::{self, DeserializeOwned}, Deserialize, Deserializer, Serialize, Serializer};
+use crate::engine::bptree::BPTree;
+use crate::error::{Error, Result};
+
+// A roaring bitmap of u32s as a value, stored in the portable roaring format.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bitmap(RoaringBitmap);
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inner(&self) -> &RoaringBitmap {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> RoaringBitmap {
+        self.0
+    }
+}
+
+impl From<RoaringBitmap> for Bitmap {
+    fn from(bitmap: RoaringBitmap) -> Self {
+        Self(bitmap)
+    }
+}
+
+impl FromIterator<u32> for Bitmap {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Eq for Bitmap {}
+
+// Values need an order; bitmaps compare like their sorted members.
+impl Ord for Bitmap {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().cmp(other.0.iter())
+    }
+}
+
+impl PartialOrd for Bitmap {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Serialize for Bitmap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut bytes = Vec::with_capacity(self.0.serialized_size());
+        self.0.serialize_into(&mut bytes).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bitmap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        RoaringBitmap::deserialize_from(bytes.as_slice()).map(Self).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+// Operations on stored bitmaps that run next to the data: only the answer, never the
+// bitmap, goes back to the caller. A missing key reads as an empty bitmap.
+impl<K> BPTree<K, Bitmap>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    // Returns whether the value was new; an unchanged bitmap isn't written back.
+    pub fn bitmap_add(&mut self, key: K, value: u32) -> Result<bool> {
+        let mut bitmap = self.bitmap(&key)?;
+        if !bitmap.0.insert(value) {
+            return Ok(false);
+        }
+        self.set(key, bitmap)?;
+        Ok(true)
+    }
+
+    // Returns whether the value was there. A bitmap left empty is removed.
+    pub fn bitmap_remove(&mut self, key: &K, value: u32) -> Result<bool> {
+        let mut bitmap = self.bitmap(key)?;
+        if !bitmap.0.remove(value) {
+            return Ok(false);
+        }
+        match bitmap.0.is_empty() {
+            true => self.remove(key)?,
+            false => self.set(key.clone(), bitmap)?,
+        };
+        Ok(true)
+    }
+
+    pub fn bitmap_contains<Q>(&self, key: &Q, value: u32) -> Result<bool>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.bitmap_with(key, |bitmap| bitmap.0.contains(value))
+    }
+
+    pub fn bitmap_len<Q>(&self, key: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.bitmap_with(key, |bitmap| bitmap.0.len())
+    }
+
+    // Stores `dest <op> src` under `dest` and returns how many values it holds.
+    pub fn bitmap_apply<Q>(&mut self, dest: K, op: SetOp, src: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        let mut bitmap = self.bitmap::<K>(&dest)?;
+        let before = bitmap.0.len();
+        self.bitmap_with(src, |src| match op {
+            SetOp::Union => bitmap.0 |= &src.0,
+            SetOp::Intersection => bitmap.0 &= &src.0,
+            SetOp::Difference => bitmap.0 -= &src.0,
+        })?;
+        let len = bitmap.0.len();
+        if len != before {
+            match len {
+                0 => self.remove::<K>(&dest)?,
+                _ => self.set(dest, bitmap)?,
+            };
+        }
+        Ok(len)
+    }
+
+    pub fn bitmap_union_into<Q>(&mut self, dest: K, src: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.bitmap_apply(dest, SetOp::Union, src)
+    }
+
+    fn bitmap<Q>(&self, key: &Q) -> Result<Bitmap>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.bitmap_with(key, Bitmap::clone)
+    }
+
+    fn bitmap_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&Bitmap) -> R
+    {
+        let mut f = Some(f);
+        match self.get_with(key, |bitmap| f.take().map(|f| f(bitmap))) {
+            Ok(Some(r)) => Ok(r),
+            Ok(None) => unreachable!(),
+            Err(Error::KeyNotFound) => Ok(f.take().map(|f| f(&Bitmap::default())).unwrap()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_bitmap_operations() -> Result<()> {
+        let mut bptree: BPTree<String, Bitmap> = BPTree::new(Path::new("data").join("test_bitmap.db"), Some(4))?;
+        bptree.set("visited".to_string(), (0..99_000).step_by(3).collect())?;
+        for user in (99_000..100_000).step_by(3) {
+            assert!(bptree.bitmap_add("visited".to_string(), user)?);
+        }
+        assert!(!bptree.bitmap_add("visited".to_string(), 3)?);
+        bptree.set("bought".to_string(), (0..100).chain(99_000..99_010).collect())?;
+        assert!(bptree.bitmap_contains("visited", 99_999)?);
+        assert!(!bptree.bitmap_contains("visited", 1)?);
+        assert!(!bptree.bitmap_contains("nobody", 1)?);
+        assert_eq!(bptree.bitmap_len("visited")?, 33_334);
+
+        assert_eq!(bptree.bitmap_apply("both".to_string(), SetOp::Union, "bought")?, 110);
+        assert_eq!(bptree.bitmap_apply("both".to_string(), SetOp::Intersection, "visited")?, 34 + 4);
+        assert_eq!(bptree.bitmap_union_into("all".to_string(), "visited")?, 33_334);
+        assert_eq!(bptree.bitmap_union_into("all".to_string(), "bought")?, 33_334 + 110 - 38);
+        assert_eq!(bptree.bitmap_apply("both".to_string(), SetOp::Difference, "visited")?, 0);
+        assert!(matches!(bptree.get("both"), Err(Error::KeyNotFound)));
+
+        assert!(bptree.bitmap_remove(&"bought".to_string(), 5)?);
+        assert!(!bptree.bitmap_remove(&"bought".to_string(), 5)?);
+        assert_eq!(bptree.bitmap_len("bought")?, 109);
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::engine::codec::Codec;
+use crate::engine::KVStoreEngine;
+use crate::error::{Error, Result};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+pub type ChunkHash = [u8; 32];
+
+// Every blob is cut into fixed-size chunks that are stored under their SHA-256, so a chunk
+// shared by several blobs (or repeated inside one) is only stored once. Each chunk carries
+// a reference count and the blob itself is just a manifest listing its chunks in order.
+const CHUNK_PREFIX: u8 = b'c';
+const REFCOUNT_PREFIX: u8 = b'r';
+const MANIFEST_PREFIX: u8 = b'm';
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub len: u64,
+    pub chunks: Vec<ChunkHash>,
+}
+
+pub struct Blobs<E> {
+    engine: E,
+    chunk_size: usize,
+    codec: Codec,
+}
+
+impl<E> Blobs<E>
+    where E: KVStoreEngine<Vec<u8>, Vec<u8>>
+{
+    pub fn new(engine: E) -> Self {
+        Self::with_chunk_size(engine, DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(engine: E, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        Self{
+            engine,
+            chunk_size,
+            codec: Codec::default().with_limit(u64::MAX),
+        }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    pub fn put(&mut self, name: &[u8], data: &[u8]) -> Result<Manifest> {
+        let mut chunks = Vec::with_capacity(data.len().div_ceil(self.chunk_size));
+        for chunk in data.chunks(self.chunk_size) {
+            let hash: ChunkHash = Sha256::digest(chunk).into();
+            let refs = self.refcount(&hash)?;
+            if refs == 0 {
+                self.engine.set(key(CHUNK_PREFIX, &hash), chunk.to_vec())?;
+            }
+            self.engine.set(key(REFCOUNT_PREFIX, &hash), (refs + 1).to_be_bytes().to_vec())?;
+            chunks.push(hash);
+        }
+        // Release the previous version only after the new chunks are referenced, so chunks
+        // the two versions share are never dropped in between.
+        let old = self.manifest(name);
+        let manifest = Manifest{
+            len: data.len() as u64,
+            chunks,
+        };
+        self.engine.set(key(MANIFEST_PREFIX, name), self.codec.serialize(&manifest)?)?;
+        match old {
+            Ok(old) => self.release(&old)?,
+            Err(Error::KeyNotFound) => {},
+            Err(e) => return Err(e),
+        }
+        Ok(manifest)
+    }
+
+    pub fn get(&self, name: &[u8]) -> Result<Vec<u8>> {
+        let manifest = self.manifest(name)?;
+        let mut data = Vec::with_capacity(manifest.len as usize);
+        for hash in &manifest.chunks {
+            self.engine.get_with(key(CHUNK_PREFIX, hash).as_slice(), |chunk: &Vec<u8>| data.extend_from_slice(chunk))?;
+        }
+        if data.len() as u64 != manifest.len {
+            return Err(Error::CorruptedPage);
+        }
+        Ok(data)
+    }
+
+    pub fn manifest(&self, name: &[u8]) -> Result<Manifest> {
+        let bytes = self.engine.get(key(MANIFEST_PREFIX, name).as_slice())?;
+        self.codec.deserialize(&bytes)
+    }
+
+    pub fn remove(&mut self, name: &[u8]) -> Result<()> {
+        let manifest = self.manifest(name)?;
+        self.engine.remove(key(MANIFEST_PREFIX, name).as_slice())?;
+        self.release(&manifest)
+    }
+
+    fn release(&mut self, manifest: &Manifest) -> Result<()> {
+        for hash in &manifest.chunks {
+            match self.refcount(hash)? {
+                0 => return Err(Error::KeyNotFound),
+                1 => {
+                    self.engine.remove(key(REFCOUNT_PREFIX, hash).as_slice())?;
+                    self.engine.remove(key(CHUNK_PREFIX, hash).as_slice())?;
+                }
+                refs => self.engine.set(key(REFCOUNT_PREFIX, hash), (refs - 1).to_be_bytes().to_vec())?,
+            }
+        }
+        Ok(())
+    }
+
+    fn refcount(&self, hash: &ChunkHash) -> Result<u64> {
+        let refs = self.engine.get_with(key(REFCOUNT_PREFIX, hash).as_slice(), |bytes: &Vec<u8>| {
+            bytes.as_slice().try_into().map(u64::from_be_bytes)
+        });
+        match refs {
+            Ok(Ok(refs)) => Ok(refs),
+            Ok(Err(_)) => Err(Error::CorruptedPage),
+            Err(Error::KeyNotFound) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn key(prefix: u8, id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(id.len() + 1);
+    key.push(prefix);
+    key.extend_from_slice(id);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use super::*;
+
+    #[test]
+    fn test_blobs_share_chunks() -> Result<()> {
+        let path = Path::new("data").join("test_blobs.db");
+        let bptree: BPTree<Vec<u8>, Vec<u8>> = BPTree::new(path, Some(8))?;
+        let mut blobs = Blobs::with_chunk_size(bptree, 2000);
+        let a: Vec<u8> = (0..20_000u32).map(|i| (i % 7) as u8).collect();
+        let mut b = a.clone();
+        b.extend_from_slice(b"tail");
+
+        let ma = blobs.put(b"a", &a)?;
+        let mb = blobs.put(b"b", &b)?;
+        assert_eq!(ma.chunks[..], mb.chunks[..ma.chunks.len()]);
+        assert_eq!(blobs.get(b"a")?, a);
+        assert_eq!(blobs.get(b"b")?, b);
+
+        blobs.remove(b"a")?;
+        assert!(matches!(blobs.get(b"a"), Err(Error::KeyNotFound)));
+        assert_eq!(blobs.get(b"b")?, b);
+        blobs.put(b"b", b"replaced")?;
+        assert_eq!(blobs.get(b"b")?, b"replaced".to_vec());
+
+        let bptree = blobs.into_inner();
+        for hash in &mb.chunks {
+            assert!(matches!(bptree.get(key(CHUNK_PREFIX, hash).as_slice()), Err(Error::KeyNotFound)));
+        }
+        Ok(())
+    }
+}
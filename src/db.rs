@@ -0,0 +1,474 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::bptree::{BPTree, Options};
+use crate::engine::codec::Codec;
+use crate::engine::verify::VerifyReport;
+use crate::engine::wal::{Change, ChangeStream};
+use crate::error::{Error, Result};
+
+const CATALOG: &str = "CATALOG";
+const CATALOG_TMP: &str = "CATALOG.tmp";
+// Files a tree may have next to its own, removed together with it.
+const SIDECARS: [&str; 3] = ["stats", "wal", "redo"];
+// Ids a sequence reserves with one catalog write.
+pub const SEQUENCE_BATCH: u64 = 1000;
+
+static DRY_RUNS: AtomicU64 = AtomicU64::new(0);
+
+// What rebuilding a tree from its change log would find and produce.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub sets: u64,
+    pub removes: u64,
+    // Removes of keys that weren't there, which make a real rebuild fail.
+    pub missing_removes: u64,
+    pub last_seq: u64,
+    // Bytes of the log that hold complete records, and what follows them: a record that
+    // was being appended when the process died, which recovery leaves out.
+    pub log_bytes: u64,
+    pub torn_bytes: u64,
+    // Offset of a record that couldn't be decoded; nothing from there on was replayed.
+    pub unreadable_at: Option<u64>,
+    pub keys: u64,
+    pub verify: VerifyReport,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_removes == 0 && self.torn_bytes == 0 && self.unreadable_at.is_none() && self.verify.is_ok()
+    }
+}
+
+// Tree files are named after a numeric id that never changes, so renaming a tree only
+// touches the catalog.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Catalog {
+    next_id: u64,
+    trees: BTreeMap<String, u64>,
+    // Ids of dropped trees whose files haven't been deleted yet.
+    dropped: Vec<u64>,
+    // Per named sequence, the first id not yet reserved.
+    sequences: BTreeMap<String, u64>,
+    counters: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterDurability {
+    // Every change is in the catalog on disk before it returns.
+    Sync,
+    // Changes are kept in memory and written with the next catalog write, by
+    // flush_counters() or when the Db is dropped. A crash loses what wasn't written yet.
+    Relaxed,
+}
+
+// A directory of named trees. Every change to the set of names is a single atomic replace
+// of the catalog file, so after a crash a rename or drop either happened completely or not
+// at all. Dropping a tree only schedules its files for deletion; reclaim() deletes them,
+// which open() also does for whatever a previous process left behind.
+//
+// The catalog also keeps named sequences for next_id(). Ids are reserved a batch at a time,
+// so only every SEQUENCE_BATCH-th id costs a catalog write; after a crash a sequence goes on
+// from the end of its last reservation, skipping whatever of it was never handed out.
+pub struct Db {
+    dir: PathBuf,
+    catalog: Catalog,
+    codec: Codec,
+    // Per sequence, the next id to hand out from its current reservation.
+    next_ids: BTreeMap<String, u64>,
+    counter_durability: CounterDurability,
+    // Relaxed counter changes that aren't on disk yet.
+    counters_dirty: bool,
+}
+
+impl Db {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let codec = Codec::default().with_limit(u64::MAX);
+        let catalog = match fs::read(dir.join(CATALOG)) {
+            Ok(bytes) => codec.deserialize(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Catalog::default(),
+            Err(e) => return Err(e.into()),
+        };
+        // A catalog that was being written when we crashed never took effect.
+        match fs::remove_file(dir.join(CATALOG_TMP)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {},
+        }
+        let mut db = Self{ dir, catalog, codec, next_ids: BTreeMap::new(), counter_durability: CounterDurability::Sync, counters_dirty: false };
+        db.reclaim()?;
+        Ok(db)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn tree_names(&self) -> impl Iterator<Item = &str> {
+        self.catalog.trees.keys().map(String::as_str)
+    }
+
+    pub fn contains_tree(&self, name: &str) -> bool {
+        self.catalog.trees.contains_key(name)
+    }
+
+    pub fn tree_path(&self, name: &str) -> Result<PathBuf> {
+        match self.catalog.trees.get(name) {
+            Some(&id) => Ok(self.file(id)),
+            None => Err(Error::TreeNotFound(name.to_string())),
+        }
+    }
+
+    pub fn create_tree<K, V>(&mut self, name: &str, options: Options) -> Result<BPTree<K, V>>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        if self.contains_tree(name) {
+            return Err(Error::TreeExists(name.to_string()));
+        }
+        let mut catalog = self.catalog.clone();
+        let id = catalog.next_id;
+        catalog.next_id += 1;
+        catalog.trees.insert(name.to_string(), id);
+        self.store(catalog)?;
+        BPTree::with_options(self.file(id), options)
+    }
+
+    // Handles to the tree stay valid: its files keep their names.
+    pub fn rename_tree(&mut self, old: &str, new: &str) -> Result<()> {
+        if self.contains_tree(new) {
+            return Err(Error::TreeExists(new.to_string()));
+        }
+        let mut catalog = self.catalog.clone();
+        let id = catalog.trees.remove(old).ok_or_else(|| Error::TreeNotFound(old.to_string()))?;
+        catalog.trees.insert(new.to_string(), id);
+        self.store(catalog)
+    }
+
+    // The name is free again right away; the files go on the next reclaim().
+    pub fn drop_tree(&mut self, name: &str) -> Result<()> {
+        let mut catalog = self.catalog.clone();
+        let id = catalog.trees.remove(name).ok_or_else(|| Error::TreeNotFound(name.to_string()))?;
+        catalog.dropped.push(id);
+        self.store(catalog)
+    }
+
+    pub fn pending_reclaim(&self) -> usize {
+        self.catalog.dropped.len()
+    }
+
+    // Deletes the files of dropped trees and returns how many trees that was.
+    pub fn reclaim(&mut self) -> Result<usize> {
+        let dropped = self.catalog.dropped.len();
+        if dropped == 0 {
+            return Ok(0);
+        }
+        for &id in &self.catalog.dropped {
+            let file = self.file(id);
+            for path in std::iter::once(file.clone()).chain(SIDECARS.iter().map(|ext| file.with_extension(ext))) {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {},
+                }
+            }
+        }
+        let mut catalog = self.catalog.clone();
+        catalog.dropped.clear();
+        self.store(catalog)?;
+        Ok(dropped)
+    }
+
+    // Ids of a sequence start at 1 and only ever grow, also across crashes.
+    pub fn next_id(&mut self, name: &str) -> Result<u64> {
+        let reserved = self.catalog.sequences.get(name).copied().unwrap_or(1);
+        let next = self.next_ids.get(name).copied().unwrap_or(reserved);
+        if next == reserved {
+            let mut catalog = self.catalog.clone();
+            catalog.sequences.insert(name.to_string(), reserved + SEQUENCE_BATCH);
+            self.store(catalog)?;
+        }
+        self.next_ids.insert(name.to_string(), next + 1);
+        Ok(next)
+    }
+
+    pub fn set_counter_durability(&mut self, durability: CounterDurability) -> Result<()> {
+        self.counter_durability = durability;
+        self.flush_counters()
+    }
+
+    // A counter that was never changed, or was reset, is 0.
+    pub fn counter(&self, name: &str) -> i64 {
+        self.catalog.counters.get(name).copied().unwrap_or(0)
+    }
+
+    pub fn counters(&self) -> impl Iterator<Item = (&str, i64)> {
+        self.catalog.counters.iter().map(|(name, value)| (name.as_str(), *value))
+    }
+
+    // Adds `by`, which may be negative, and returns the new value.
+    pub fn increment(&mut self, name: &str, by: i64) -> Result<i64> {
+        let value = self.counter(name).checked_add(by).ok_or(Error::CounterOverflow)?;
+        self.update_counters(|counters| {
+            counters.insert(name.to_string(), value);
+        })?;
+        Ok(value)
+    }
+
+    pub fn reset_counter(&mut self, name: &str) -> Result<()> {
+        if !self.catalog.counters.contains_key(name) {
+            return Ok(());
+        }
+        self.update_counters(|counters| {
+            counters.remove(name);
+        })
+    }
+
+    // Writes relaxed counter changes to disk.
+    pub fn flush_counters(&mut self) -> Result<()> {
+        match self.counters_dirty {
+            true => self.store(self.catalog.clone()),
+            false => Ok(()),
+        }
+    }
+
+    fn update_counters<F: FnOnce(&mut BTreeMap<String, i64>)>(&mut self, f: F) -> Result<()> {
+        match self.counter_durability {
+            CounterDurability::Sync => {
+                let mut catalog = self.catalog.clone();
+                f(&mut catalog.counters);
+                self.store(catalog)
+            }
+            CounterDurability::Relaxed => {
+                f(&mut self.catalog.counters);
+                self.counters_dirty = true;
+                Ok(())
+            }
+        }
+    }
+
+    // Replays the change log of the tree at `path` into a scratch tree in the temp directory
+    // and verifies it, without writing to anything next to `path`. `options` must be the
+    // ones the tree was created with; its change log is read, not rewritten.
+    pub fn recover_dry_run<K, V, P>(path: P, options: Options) -> Result<RecoveryReport>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              P: AsRef<Path>
+    {
+        let log = path.as_ref().with_extension("wal");
+        let log_len = match fs::metadata(&log) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(Error::ChangeLogDisabled),
+            Err(e) => return Err(e.into()),
+        };
+        let scratch = env::temp_dir().join(format!("kvstore-recover-{}-{}.db", process::id(), DRY_RUNS.fetch_add(1, Ordering::Relaxed)));
+        let scratch_options = Options{ change_log: false, analyze_every: None, ..options.clone() };
+        let mut tree: BPTree<K, V> = BPTree::with_options(&scratch, scratch_options)?;
+        let replayed = Self::replay(&mut tree, ChangeStream::open(&log, 0, options.codec)?, log_len);
+        let report = replayed.and_then(|mut report| {
+            report.keys = tree.key_count();
+            report.verify = tree.verify(|_| ())?;
+            Ok(report)
+        });
+        drop(tree);
+        fs::remove_file(&scratch)?;
+        report
+    }
+
+    fn replay<K, V>(tree: &mut BPTree<K, V>, mut changes: ChangeStream<K, V>, log_len: u64) -> Result<RecoveryReport>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        let mut report = RecoveryReport::default();
+        while let Some(event) = changes.next() {
+            let event = match event {
+                Ok(event) => event,
+                Err(Error::SerdeError(_)) => {
+                    report.unreadable_at = Some(changes.offset());
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            report.last_seq = event.seq;
+            match event.change {
+                Change::Set(key, value) => {
+                    tree.set(key, value)?;
+                    report.sets += 1;
+                }
+                Change::SetExpiring(key, value, expires) => {
+                    tree.set_expiring_at(key, value, expires)?;
+                    report.sets += 1;
+                }
+                Change::Remove(key) => match tree.remove(&key) {
+                    Ok(_) => report.removes += 1,
+                    Err(Error::KeyNotFound) => report.missing_removes += 1,
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        report.log_bytes = changes.offset();
+        if report.unreadable_at.is_none() {
+            report.torn_bytes = log_len - changes.offset();
+        }
+        Ok(report)
+    }
+
+    fn file(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("tree-{}.db", id))
+    }
+
+    // Writes the new catalog next to the old one, syncs it and renames it over the old one.
+    // The in-memory catalog only changes once the rename went through.
+    fn store(&mut self, catalog: Catalog) -> Result<()> {
+        let tmp = self.dir.join(CATALOG_TMP);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&self.codec.serialize(&catalog)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(CATALOG))?;
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+        self.catalog = catalog;
+        self.counters_dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        let _ = self.flush_counters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_and_drop_trees() -> Result<()> {
+        let dir = Path::new("data").join("test_db_catalog");
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = Db::open(&dir)?;
+        let mut blue: BPTree<u64, u64> = db.create_tree("blue", Options::default())?;
+        blue.set(1, 1)?;
+        let mut green: BPTree<u64, u64> = db.create_tree("green", Options{ change_log: true, ..Options::default() })?;
+        green.set(1, 2)?;
+        assert!(matches!(db.create_tree::<u64, u64>("blue", Options::default()), Err(Error::TreeExists(_))));
+
+        // Swap green in under the live name.
+        db.rename_tree("blue", "live")?;
+        assert!(matches!(db.rename_tree("green", "live"), Err(Error::TreeExists(_))));
+        let old = db.tree_path("live")?;
+        db.drop_tree("live")?;
+        db.rename_tree("green", "live")?;
+        assert_eq!(green.get(&1)?, 2);
+        assert_eq!(db.tree_path("live")?, green.path());
+        assert!(matches!(db.tree_path("green"), Err(Error::TreeNotFound(_))));
+        assert_eq!(db.pending_reclaim(), 1);
+        assert!(old.exists());
+
+        // A half-written catalog from a crash is ignored, and the drop is finished on open.
+        fs::write(dir.join(CATALOG_TMP), b"garbage")?;
+        let db = Db::open(&dir)?;
+        assert_eq!(db.tree_names().collect::<Vec<_>>(), vec!["live"]);
+        assert_eq!(db.pending_reclaim(), 0);
+        assert!(!old.exists());
+        assert!(db.tree_path("live")?.exists());
+        assert!(db.tree_path("live")?.with_extension("wal").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reclaim_removes_redo_log() -> Result<()> {
+        let dir = Path::new("data").join("test_db_reclaim");
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = Db::open(&dir)?;
+        let mut tree: BPTree<u64, u64> = db.create_tree("logged", Options{ write_ahead_log: true, ..Options::default() })?;
+        tree.set(1, 1)?;
+        tree.close()?;
+        let file = db.tree_path("logged")?;
+        // A checkpoint empties the redo log but leaves the file.
+        assert!(file.with_extension("redo").exists());
+
+        db.drop_tree("logged")?;
+        assert_eq!(db.reclaim()?, 1);
+        assert!(!file.exists());
+        assert!(!file.with_extension("redo").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequences() -> Result<()> {
+        let dir = Path::new("data").join("test_db_sequences");
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = Db::open(&dir)?;
+        let ids = (0..SEQUENCE_BATCH + 5).map(|_| db.next_id("orders")).collect::<Result<Vec<_>>>()?;
+        assert_eq!(ids, (1..SEQUENCE_BATCH + 6).collect::<Vec<_>>());
+        assert_eq!(db.next_id("users")?, 1);
+
+        // Reopening, as after a crash, skips the rest of the reservation.
+        let mut db = Db::open(&dir)?;
+        assert_eq!(db.next_id("orders")?, 2 * SEQUENCE_BATCH + 1);
+        assert_eq!(db.next_id("users")?, SEQUENCE_BATCH + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_counters() -> Result<()> {
+        let dir = Path::new("data").join("test_db_counters");
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = Db::open(&dir)?;
+        assert_eq!(db.increment("hits", 5)?, 5);
+        assert_eq!(db.increment("hits", -2)?, 3);
+        assert_eq!(db.increment("misses", 1)?, 1);
+        assert!(matches!(db.increment("hits", i64::MAX), Err(Error::CounterOverflow)));
+        assert_eq!(Db::open(&dir)?.counter("hits"), 3);
+
+        // Relaxed changes only reach the file when flushed, but a plain drop flushes too.
+        db.set_counter_durability(CounterDurability::Relaxed)?;
+        for _ in 0..100 {
+            db.increment("hits", 1)?;
+        }
+        db.reset_counter("misses")?;
+        assert_eq!(Db::open(&dir)?.counter("hits"), 3);
+        db.flush_counters()?;
+        assert_eq!(Db::open(&dir)?.counters().collect::<Vec<_>>(), vec![("hits", 103)]);
+        db.increment("hits", 1)?;
+        drop(db);
+        assert_eq!(Db::open(&dir)?.counter("hits"), 104);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_dry_run() -> Result<()> {
+        let path = Path::new("data").join("test_recover_dry_run.db");
+        let options = Options{ max_key_count: Some(4), change_log: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(&path, options.clone())?;
+        for i in 0..50 {
+            bptree.set(i, i)?;
+        }
+        for i in 0..10 {
+            bptree.remove(&i)?;
+        }
+        drop(bptree);
+        let log = path.with_extension("wal");
+        let report = Db::recover_dry_run::<u64, u64, _>(&path, options.clone())?;
+        assert!(report.is_clean());
+        assert_eq!((report.sets, report.removes, report.last_seq, report.keys), (50, 10, 60, 40));
+        assert_eq!(report.log_bytes, fs::metadata(&log)?.len());
+
+        // Half a record at the end, as if the process died while appending it.
+        let before = fs::read(&path)?;
+        fs::OpenOptions::new().append(true).open(&log)?.write_all(&[12, 0, 0, 0, 0, 0, 0, 0, 1, 2])?;
+        let report = Db::recover_dry_run::<u64, u64, _>(&path, options)?;
+        assert_eq!((report.torn_bytes, report.keys), (10, 40));
+        assert!(!report.is_clean());
+        assert_eq!(fs::read(&path)?, before);
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+use std::fmt;
+use crate::engine::btnode::{INNER_NODE_TYPE, LEAF_NODE_TYPE, NODE_TYPE_OFFSET};
+use crate::engine::meta::META_NODE_TYPE;
+use crate::engine::overflow::OVERFLOW_NODE_TYPE;
+use crate::engine::page::{PagePtr, Pager};
+use crate::error::{Error, Result};
+
+// Pages per line of the map view.
+const MAP_WIDTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PageKind {
+    Meta,
+    Leaf,
+    Inner,
+    Overflow,
+    // On the free list.
+    Free,
+    // Allocated but missing from the file, failing its checksum or of no known type.
+    Unreadable,
+}
+
+impl PageKind {
+    fn symbol(self) -> char {
+        match self {
+            PageKind::Meta => 'M',
+            PageKind::Leaf => 'L',
+            PageKind::Inner => 'I',
+            PageKind::Overflow => 'O',
+            PageKind::Free => '.',
+            PageKind::Unreadable => '?',
+        }
+    }
+}
+
+// What every page of the file is used for, by page number.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllocationMap {
+    pub pages: Vec<PageKind>,
+    pub page_size: usize,
+}
+
+impl AllocationMap {
+    pub fn count(&self, kind: PageKind) -> u64 {
+        self.pages.iter().filter(|page| **page == kind).count() as u64
+    }
+
+    pub fn bytes(&self, kind: PageKind) -> u64 {
+        self.count(kind) * self.page_size as u64
+    }
+}
+
+// One character per page, MAP_WIDTH pages to a line, each line led by its first page number.
+impl fmt::Display for AllocationMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.pages.chunks(MAP_WIDTH).enumerate() {
+            let symbols: String = line.iter().map(|page| page.symbol()).collect();
+            writeln!(f, "{:>8} {}", i * MAP_WIDTH, symbols)?;
+        }
+        Ok(())
+    }
+}
+
+// Classifies pages by the type byte of their header, as they are in the file; the free
+// list wins over whatever a freed page still holds.
+pub(crate) fn allocation_map(pager: &Pager, page_count: u64, free: &[PagePtr]) -> Result<AllocationMap> {
+    let free: HashSet<PagePtr> = free.iter().copied().collect();
+    let mut pages = Vec::with_capacity(page_count as usize);
+    for ptr in 0..page_count {
+        if free.contains(&ptr) {
+            pages.push(PageKind::Free);
+            continue;
+        }
+        let page = match pager.read_page(ptr) {
+            Ok(page) => page,
+            Err(Error::PageNotFound) => {
+                pages.push(PageKind::Unreadable);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let kind = match page.get_page_byte(NODE_TYPE_OFFSET) {
+            _ if !page.checksum_ok() => PageKind::Unreadable,
+            LEAF_NODE_TYPE => PageKind::Leaf,
+            INNER_NODE_TYPE => PageKind::Inner,
+            OVERFLOW_NODE_TYPE => PageKind::Overflow,
+            META_NODE_TYPE => PageKind::Meta,
+            _ => PageKind::Unreadable,
+        };
+        pages.push(kind);
+    }
+    Ok(AllocationMap{ pages, page_size: pager.page_size() })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use super::*;
+
+    #[test]
+    fn test_allocation_map() -> Result<()> {
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(Path::new("data").join("test_allocation_map.db"), Some(4))?;
+        for i in 0..20 {
+            let len = if i == 3 { 9000 } else { 8 };
+            bptree.set(i, vec![0; len])?;
+        }
+        let map = bptree.allocation_map()?;
+        assert_eq!(map.pages.len() as u64, bptree.page_count());
+        assert_eq!(map.count(PageKind::Overflow), 3);
+        assert_eq!(map.count(PageKind::Free), 0);
+        assert!(map.count(PageKind::Inner) >= 1);
+        assert_eq!(map.count(PageKind::Unreadable), 0);
+
+        bptree.remove(&3)?;
+        let map = bptree.allocation_map()?;
+        assert_eq!(map.count(PageKind::Overflow), 0);
+        // The value's chain, plus whatever the leaves gave up when they were merged.
+        assert!(map.bytes(PageKind::Free) >= 3 * bptree.page_size() as u64);
+        assert_eq!(map.count(PageKind::Meta) + map.count(PageKind::Free) + map.count(PageKind::Leaf) + map.count(PageKind::Inner), bptree.page_count());
+        assert!(map.to_string().starts_with("       0 "));
+        Ok(())
+    }
+}
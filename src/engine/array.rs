@@ -1,4 +1,5 @@
-use crate::engine::KVStoreEngine;
+use std::borrow::Borrow;
+use crate::engine::{ReadEngine, WriteEngine};
 use crate::error::{Error, Result};
 
 pub struct KVPair<K, V>
@@ -32,26 +33,40 @@ impl<K: PartialEq,V> ArrayKVStore<K,V> {
         }
     }
 
-    pub fn contains(&self, key: &K) -> Option<usize> {
-        let pos = self.inner.iter().position(|item| item.key == *key);
+    pub fn contains<Q>(&self, key: &Q) -> Option<usize>
+        where K: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let pos = self.inner.iter().position(|item| item.key.borrow() == key);
         pos
     }
 }
 
-impl<K, V> KVStoreEngine<K, V> for ArrayKVStore<K,V>  where V: Clone, K: PartialEq{
+impl<K: PartialEq, V> Default for ArrayKVStore<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> ReadEngine<K, V> for ArrayKVStore<K,V>  where K: PartialEq{
 
-    fn get(&self, key: &K) -> Result<V> {
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
         if let Some(pos) = self.contains(key) {
-            Ok(self.inner[pos].value.clone())
+            Ok(f(&self.inner[pos].value))
         }
         else{
             Err(Error::KeyNotFound)
         }
     }
+}
+
+impl<K, V> WriteEngine<K, V> for ArrayKVStore<K,V>  where K: PartialEq{
+
     fn set(&mut self, key: K, value: V) -> Result<()> {
 
         if let Some(pos) = self.contains(&key) {
-            self.inner[pos].setvalue(value);
+            self.inner[pos].setvalue(value)?;
         }
         else{
             let kv = KVPair::new(key, value);
@@ -60,7 +75,9 @@ impl<K, V> KVStoreEngine<K, V> for ArrayKVStore<K,V>  where V: Clone, K: Partial
         Ok(())
     }
 
-    fn remove(&mut self, key: &K) -> Result<()> {
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
         if let Some(pos) = self.contains(key){
             self.inner.remove(pos);
             Ok(())
@@ -75,21 +92,33 @@ impl<K, V> KVStoreEngine<K, V> for ArrayKVStore<K,V>  where V: Clone, K: Partial
 #[cfg(test)]
 mod tests{
     use crate::engine::array::ArrayKVStore;
-    use std::collections::HashMap;
-    use crate::engine::KVStoreEngine;
+    use crate::engine::{KVStoreEngine, ReadEngine, ReadOnly, WriteEngine};
+    use crate::error::Error;
+
+    fn fill<E: KVStoreEngine<i32, i32>>(engine: &mut E) {
+        engine.set(1, 10).unwrap();
+        engine.set(2, 20).unwrap();
+    }
 
     #[test]
     fn test_kv() {
         let mut kvengine:ArrayKVStore<i32, i32> = ArrayKVStore::new();
-        kvengine.set(1,2);
-        kvengine.remove(&1).unwrap();
+        kvengine.set(1,2).unwrap();
         assert_eq!(kvengine.get(&1).unwrap(),2);
+        kvengine.remove(&1).unwrap();
+        assert!(matches!(kvengine.get(&1), Err(Error::KeyNotFound)));
 
 
     }
     #[test]
     fn test_kv2() {
-
+        let mut kvengine:ArrayKVStore<i32, i32> = ArrayKVStore::new();
+        fill(&mut kvengine);
+        let readonly = ReadOnly::new(kvengine);
+        assert_eq!(readonly.get(&2).unwrap(), 20);
+        let mut kvengine = readonly.into_inner();
+        kvengine.remove(&2).unwrap();
+        assert!(kvengine.get(&2).is_err());
     }
 
 }
@@ -0,0 +1,131 @@
+use std::iter::FromIterator;
+use crate::engine::wal::Change;
+
+// Writes collected up front and applied together by BPTree::write_batch, in the order they
+// were added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteBatch<K, V> {
+    changes: Vec<Change<K, V>>,
+}
+
+impl<K, V> Default for WriteBatch<K, V> {
+    fn default() -> Self {
+        Self{ changes: Vec::new() }
+    }
+}
+
+impl<K, V> WriteBatch<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self{ changes: Vec::with_capacity(capacity) }
+    }
+
+    pub fn put(&mut self, key: K, value: V) -> &mut Self {
+        self.changes.push(Change::Set(key, value));
+        self
+    }
+
+    // Removing a key that isn't there is not an error inside a batch.
+    pub fn delete(&mut self, key: K) -> &mut Self {
+        self.changes.push(Change::Remove(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.changes.clear();
+    }
+
+    pub fn into_changes(self) -> Vec<Change<K, V>> {
+        self.changes
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for WriteBatch<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut batch = Self::new();
+        batch.extend(iter);
+        batch
+    }
+}
+
+impl<K, V> Extend<(K, V)> for WriteBatch<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.changes.extend(iter.into_iter().map(|(key, value)| Change::Set(key, value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::engine::bptree::{BPTree, Options};
+    use crate::engine::clock::ManualClock;
+    use crate::engine::quota::Quota;
+    use crate::error::{Error, Result};
+    use super::*;
+
+    #[test]
+    fn test_write_batch_in_order() -> Result<()> {
+        let mut bptree: BPTree<u32, u32> = BPTree::new(Path::new("data").join("test_write_batch.db"), Some(4))?;
+        bptree.set(1, 1)?;
+        let mut batch = WriteBatch::new();
+        batch.extend((2..20).map(|i| (i, i * 10)));
+        batch.delete(1).delete(5).delete(100).put(5, 55);
+        assert_eq!(batch.len(), 22);
+        let seq = bptree.write_batch(batch)?;
+        assert_eq!(seq, bptree.last_seq());
+        assert!(matches!(bptree.get(&1), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.get(&5)?, 55);
+        assert_eq!(bptree.get(&19)?, 190);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_write_batch_is_undone() -> Result<()> {
+        let path = Path::new("data").join("test_write_batch_undo.db");
+        let options = Options{ max_key_count: Some(4), write_once: true, write_ahead_log: true, ..Options::default() };
+        let mut bptree: BPTree<u32, u32> = BPTree::with_options(&path, options.clone())?;
+        bptree.write_batch((0..10).map(|i| (i, i)).collect())?;
+        let mut batch: WriteBatch<u32, u32> = (10..40).map(|i| (i, i)).collect();
+        // Write-once: the last put fails, after the deletes.
+        batch.delete(3).delete(4).delete(100).put(5, 55);
+        assert!(matches!(bptree.write_batch(batch), Err(Error::AlreadyExists)));
+        assert_eq!(bptree.key_count(), 10);
+        assert_eq!(bptree.get(&3)?, 3);
+        assert!(matches!(bptree.get(&10), Err(Error::KeyNotFound)));
+        bptree.close()?;
+
+        let bptree: BPTree<u32, u32> = BPTree::open_with_options(&path, options)?;
+        let keys: Vec<u32> = bptree.scan_all()?.map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, (0..10).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undone_write_keeps_expiry_and_version() -> Result<()> {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let options = Options{ max_key_count: Some(4), clock: Some(clock.clone()), ..Options::default() };
+        let mut bptree: BPTree<String, u32> = BPTree::with_options(Path::new("data").join("test_write_batch_expiry.db"), options)?;
+        let version = bptree.set_with_ttl(String::from("a"), 1, Duration::from_millis(200))?;
+        bptree.set_quota(String::from("b"), Quota{ max_keys: Some(0), max_bytes: None })?;
+        let mut batch = WriteBatch::new();
+        batch.put(String::from("a"), 2).put(String::from("b"), 3);
+        assert!(matches!(bptree.write_batch(batch), Err(Error::QuotaExceeded)));
+        assert_eq!(bptree.get_versioned("a")?, (1, version));
+        clock.advance(Duration::from_millis(300));
+        assert!(matches!(bptree.get("a"), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+}
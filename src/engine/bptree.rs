@@ -1,14 +1,132 @@
-#[allow(dead_code)]
-use std::borrow::BorrowMut;
+use std::borrow::{Borrow, BorrowMut};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
-use crate::engine::page::{Pager, PagePtr, split_at, max_key_count};
+use crate::engine::allocation::{self, AllocationMap};
+use crate::engine::batch::WriteBatch;
+use crate::engine::cache::{Admission, CacheStats};
+use crate::engine::clock::{self, Clock};
+use crate::engine::cipher::{PageCipher, Passphrase};
+use crate::engine::codec::Codec;
+use crate::engine::comparator::Comparator;
+use crate::engine::compression::Compression;
+use crate::engine::compaction::{self, CompactionFilter, CompactionStats, Decision};
+use crate::engine::cursor::Cursor;
+use crate::engine::dump::{self, DumpFormat};
+use crate::engine::export::{self, Format};
+use crate::engine::family::{ColumnFamily, Family, DEFAULT_FAMILY};
+use crate::engine::heat::{Heat, LeafHeat};
+use crate::engine::page::{self, Pager, PagePtr, split_at, max_key_count, DEFAULT_PAGE_SIZE};
 use crate::error::{Error, Result};
 use serde::{de::DeserializeOwned, Serialize};
+use std::iter::{once, Rev};
 use std::marker::PhantomData;
 use std::mem;
-use crate::engine::btnode::{Node, InnerNode, LeafNode};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::engine::btnode::{self, Entry, InnerNode, LeafNode, Node, Slot, PAGE_PTR_LEN};
+use crate::engine::overflow::{self, OverflowReader, SharedValues, ValueReader, ValueWriter};
+use crate::engine::iter::{self, Iter, RangeIter};
+use crate::engine::journal::{self, JournalRecord};
+use crate::engine::limits::{LimitWatch, SoftLimits, Warning};
+use crate::engine::merge::{self, MergeOperator};
+use crate::engine::metrics::{self, Metrics};
+use crate::engine::meta::{self, Meta, META_PAGE};
+use crate::engine::prefix::KeyPrefix;
+use crate::engine::quarantine::{self, KeyRange, Quarantine};
+use crate::engine::pressure::MemoryPressure;
+use crate::engine::quota::{Quota, Quotas, Usage};
+use crate::engine::stats::{self, Stats};
+use crate::engine::vacuum;
+use crate::engine::verify::{Verifier, VerifyProgress, VerifyReport, VERIFY_BATCH};
+use crate::engine::wal::{self, Change, ChangeLog, ChangeStream, RedoLog};
+use crate::engine::zone::{ValueScan, ZoneMap};
+use crate::engine::{ReadEngine, WriteEngine};
 
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub max_key_count: Option<u64>,
+    // Bytes per page, a power of two from 4096 to 65536; DEFAULT_PAGE_SIZE if not set. Bigger
+    // pages keep bigger values inline and more keys per node. open() takes the size the file
+    // was created with, and fails with PageSizeMismatch if this is another.
+    pub page_size: Option<usize>,
+    pub codec: Codec,
+    // Keep the (empty) root leaf around when the last key is removed instead of freeing it.
+    pub keep_empty_root: bool,
+    // Store identical overflowed values once and share them between keys.
+    pub dedup_values: bool,
+    // Re-run analyze() after this many writes.
+    pub analyze_every: Option<u64>,
+    // Append every write to a change log next to the tree file, readable through changes().
+    pub change_log: bool,
+    // Count reads and writes per leaf for heat_map().
+    pub track_heat: bool,
+    // Pages kept in the page cache; 0 reads every page from the file.
+    pub cache_pages: usize,
+    pub cache_admission: Admission,
+    // Keep written pages in the page cache and write them to the file only when they are
+    // evicted or on flush(), so a page written over and over costs one write. Needs
+    // cache_pages; writes not flushed are lost with the tree.
+    pub write_back: bool,
+    // Shrink the page cache while the system or container is short on memory.
+    pub memory_pressure: Option<MemoryPressure>,
+    // Read pages from a memory map of the tree file rather than with a syscall each.
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+    // Largest encoded value kept inline in its leaf; bigger ones go to overflow pages. Higher
+    // saves a page read per lookup of mid-sized values, lower keeps more keys per leaf.
+    // Defaults to a quarter of the page size.
+    pub inline_threshold: Option<usize>,
+    // Compress the entries of every leaf, so that more of them fit a page. Needs the lz4 or
+    // zstd feature.
+    pub compression: Compression,
+    // Order keys with this instead of their Ord, e.g. CaseInsensitive. The tree keeps its
+    // name and has to be opened with the same one. Prefix scans and quarantined ranges still
+    // go by Ord.
+    pub comparator: Option<Arc<dyn Comparator>>,
+    // Entries older than this are no longer returned and compact() removes them. Every entry
+    // then carries its write time, which costs 8 bytes of leaf space per key.
+    pub retention: Option<Duration>,
+    // Remember the smallest and largest value of each leaf scanned by values_between(), so
+    // later scans skip the leaves that can't match until they are written again.
+    pub zone_maps: bool,
+    // Keys can be written once: setting a key that is still live fails with AlreadyExists.
+    // Removes are still allowed, and so a compaction filter can drop entries but not
+    // replace them.
+    pub write_once: bool,
+    // Where write times and expiry come from; the system clock if not set.
+    pub clock: Option<Arc<dyn Clock>>,
+    // Check every page on open() and fail with CorruptedPage if one is bad.
+    pub verify_on_open: bool,
+    // Log the pages of every write to a redo log next to the tree file and sync it before
+    // they are written in place, so open() can finish or redo a write a crash cut short.
+    // Every write is durable once it returns, at the cost of a sync per write.
+    pub write_ahead_log: bool,
+    // Encrypt every page of the tree file with a key derived from this passphrase, which
+    // open() then needs as well. Other files next to the tree, like the change log and the
+    // stats, are not encrypted.
+    pub passphrase: Option<Passphrase>,
+}
+
+// Past this size the redo log is cut back after the tree file is synced.
+pub const REDO_LOG_CHECKPOINT: u64 = 64 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpsertOutcome<V> {
+    Inserted,
+    Updated(V),
+}
+
+// Undoes one write of a failed batch: removes the key it added, or writes back the whole
+// entry it replaced.
+enum Undo<K, V> {
+    Remove(K),
+    Restore{ key: K, value: V, version: u64, written: u64, expires: u64 },
+}
 
 pub struct BPTree<K,V> {
     root_ptr: Option<PagePtr>,
@@ -21,24 +139,222 @@ pub struct BPTree<K,V> {
     max_key_count: u64,
     split_at: usize,
     emtpy_pages: Vec<PagePtr>,
+    // Pages holding the part of the free list that didn't fit the meta page.
+    free_chain: Vec<PagePtr>,
+    // Smallest and largest key ever inserted. Removals don't shrink it, so it may be wider
+    // than the live keys, but a key outside of it is certainly absent.
+    key_bounds: Option<(K, K)>,
+    // The column family the root, key count and bounds above belong to, and the others,
+    // DEFAULT_FAMILY among them when it isn't that one.
+    family: String,
+    families: BTreeMap<String, Family<K>>,
+    keep_empty_root: bool,
+    shared_values: Option<SharedValues>,
+    // Sequence number of the last write; every stored entry carries the one it was written with.
+    seq: u64,
+    // Every write up to this sequence number has been synced to disk.
+    durable_seq: u64,
+    // Kept on the meta page for envelope::Encrypted, see store_rotation().
+    rotation: Option<Vec<u8>>,
+    path: PathBuf,
+    stats_path: PathBuf,
+    stats: Option<Stats<K>>,
+    analyze_every: Option<u64>,
+    writes_since_analyze: u64,
+    quotas: Quotas<K>,
+    change_log_path: PathBuf,
+    change_log: Option<ChangeLog>,
+    redo_log: Option<RedoLog>,
+    compaction_filter: Option<CompactionFilter<K, V>>,
+    merge_operator: Option<MergeOperator<K, V>>,
+    // Behind a lock because reads take `&self` and the tree is shared between threads.
+    heat: Option<Mutex<Heat>>,
+    inline_threshold: usize,
+    retention: Option<Duration>,
+    zones: Option<Mutex<ZoneMap<V>>>,
+    quarantine: Quarantine<K>,
+    // Stored entries, expired ones included until compact() drops them.
+    key_count: u64,
+    // Nodes split and merged since the tree was opened.
+    splits: u64,
+    merges: u64,
+    limits: LimitWatch,
+    clock: Arc<dyn Clock>,
+    // Inside write_batch(), which commits its pages to the redo log once, at the end.
+    batching: bool,
+    // What the tree was created with, so repair() can create it again.
+    options: Options,
+}
+
+impl<K, V> ReadEngine<K, V> for BPTree<K, V>
+    where  K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+           V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        BPTree::get_with(self, key, f)
+    }
+}
+
+impl<K, V> WriteEngine<K, V> for BPTree<K, V>
+    where  K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+           V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+{
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        BPTree::set(self, key, value).map(|_| ())
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        BPTree::remove(self, key).map(|_| ())
+    }
+}
+
+impl<K, V> BPTree<K, V> {
+    // Parks the family the tree has and puts `name` in its place.
+    pub(crate) fn switch_family(&mut self, name: &str) {
+        let family = self.families.remove(name).unwrap();
+        let parked = Family{ root_ptr: self.root_ptr, key_count: self.key_count, key_bounds: self.key_bounds.take() };
+        self.families.insert(mem::replace(&mut self.family, name.to_string()), parked);
+        self.root_ptr = family.root_ptr;
+        self.key_count = family.key_count;
+        self.key_bounds = family.key_bounds;
+    }
 }
 
 impl<K, V> BPTree<K,V>
-    where  K: Debug + Clone + Ord + Serialize + DeserializeOwned,
-           V: Debug + Clone + Ord + Serialize + DeserializeOwned,
+    where  K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+           V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
 {
     pub fn new<P: AsRef<Path>>(path: P, override_max_key_count: Option<u64>) -> Result<Self>{
-        let pager = Pager::open(path)?;
+        Self::with_options(path, Options{ max_key_count: override_max_key_count, ..Options::default() })
+    }
+
+    // Creates the tree, replacing whatever file is at `path`.
+    pub fn with_options<P: AsRef<Path>>(path: P, options: Options) -> Result<Self>{
+        let page_size = options.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        page::check_page_size(page_size)?;
+        if options.inline_threshold.unwrap_or(overflow::max_inline_value(page_size)) > overflow::inline_threshold_limit(page_size) {
+            return Err(Error::PageSizeNotEnough);
+        }
+        if !options.compression.is_available() {
+            return Err(Error::UnsupportedCompression(options.compression.tag()));
+        }
+        let change_log = match options.change_log {
+            true => Some(ChangeLog::create(path.as_ref().with_extension("wal"), options.codec)?),
+            false => None,
+        };
+        let redo_log_path = path.as_ref().with_extension("redo");
+        let redo_log = match options.write_ahead_log {
+            true => Some(RedoLog::create(&redo_log_path)?),
+            false => {
+                remove_if_exists(&redo_log_path)?;
+                None
+            }
+        };
+        let cipher = options.passphrase.as_ref().map(PageCipher::create).transpose()?;
+        let pager = Pager::open(&path, options.codec)?.with_cipher(cipher);
+        let mut bptree = Self::assemble(path.as_ref(), options, pager, change_log, redo_log)?;
+        // Page 0 is the meta page.
+        bptree.page_count = 1;
+        bptree.write_meta()?;
+        bptree.commit_pages()?;
+        Ok(bptree)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    // Opens the tree at `path` as of its last flush. Pages are written in place as they
+    // change, so writes since then may show up in part. The settings that shape the file
+    // (codec, compression, node size, inline threshold, retention, keep_empty_root,
+    // dedup_values and write_once) are the ones it was created with; the rest come from `options`.
+    // Quotas, the compaction filter and soft limits are not kept in the file and have to be
+    // set again.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, mut options: Options) -> Result<Self> {
+        let redo_log_path = path.as_ref().with_extension("redo");
+        let pager = Pager::open_existing(&path, Codec::default())?;
+        wal::recover(&redo_log_path, &pager)?;
+        let pager = pager.with_stored_page_size()?;
+        let cipher = match &options.passphrase {
+            Some(passphrase) => Some(PageCipher::open(passphrase, &pager.read_page(META_PAGE)?)?),
+            None => None,
+        };
+        let pager = pager.with_cipher(cipher.clone());
+        let mut meta = meta::load(&pager)?;
+        if meta.key_size != mem::size_of::<K>() as u64 || meta.value_size != mem::size_of::<V>() as u64 {
+            return Err(Error::IncompatibleFormat);
+        }
+        if meta.comparator.as_deref() != options.comparator.as_ref().map(|comparator| comparator.name()) {
+            return Err(Error::ComparatorMismatch(meta.comparator));
+        }
+        if options.page_size.is_some_and(|page_size| page_size as u64 != meta.page_size) {
+            return Err(Error::PageSizeMismatch(meta.page_size as usize));
+        }
+        options.page_size = Some(meta.page_size as usize);
+        options.codec = meta.codec;
+        options.compression = meta.compression;
+        options.max_key_count = Some(meta.max_key_count);
+        options.inline_threshold = Some(meta.inline_threshold as usize);
+        options.retention = meta.retention_ms.map(Duration::from_millis);
+        options.keep_empty_root = meta.keep_empty_root;
+        options.dedup_values = meta.dedup_values;
+        options.write_once = meta.write_once;
+        let change_log = match options.change_log {
+            true => Some(ChangeLog::open(path.as_ref().with_extension("wal"), options.codec)?),
+            false => None,
+        };
+        let redo_log = match options.write_ahead_log {
+            true => Some(RedoLog::create(&redo_log_path)?),
+            false => None,
+        };
+        let pager = Pager::open_existing(&path, options.codec)?.with_cipher(cipher);
+        let verify_on_open = options.verify_on_open;
+        let mut bptree = Self::assemble(path.as_ref(), options, pager, change_log, redo_log)?;
+        bptree.free_chain = meta::chain_pages(&meta, &bptree.pager)?;
+        bptree.restore_families(&meta)?;
+        bptree.page_count = meta.page_count;
+        bptree.emtpy_pages = mem::take(&mut meta.free_pages);
+        bptree.seq = meta.seq;
+        bptree.durable_seq = meta.seq;
+        bptree.rotation = meta.rotation.take();
+        if bptree.shared_values.is_some() {
+            bptree.count_shared_values()?;
+        }
+        bptree.stats = stats::load(&bptree.stats_path, bptree.pager.codec())?;
+        if verify_on_open && !bptree.verify(|_| ())?.corrupt.is_empty() {
+            return Err(Error::CorruptedPage);
+        }
+        Ok(bptree)
+    }
+
+    // Opens the tree at `path` with open_with_options(), or creates it with `options` when
+    // there is no file there yet.
+    pub fn open_or_create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        match path.as_ref().exists() {
+            true => Self::open_with_options(path, options),
+            false => Self::with_options(path, options),
+        }
+    }
+
+    fn assemble(path: &Path, options: Options, pager: Pager, change_log: Option<ChangeLog>, redo_log: Option<RedoLog>) -> Result<Self> {
+        let created_with = options.clone();
+        let pager = configure_pager(pager, &options, redo_log.is_some());
+        let inline_threshold = options.inline_threshold.unwrap_or(overflow::max_inline_value(pager.page_size()));
         let key_size = mem::size_of::<K>() as u64;
         let value_size = mem::size_of::<V>() as u64;
-        let max_key_count = match override_max_key_count {
-            None => max_key_count(key_size, value_size),
+        let max_key_count = match options.max_key_count {
+            None if options.retention.is_some() => max_key_count(pager.page_size(), key_size, value_size + 8),
+            None => max_key_count(pager.page_size(), key_size, value_size),
             Some(n) => n,
         };
         let split_at = split_at(max_key_count);
         Ok(Self{
             root_ptr: None,
-            pager: pager,
+            pager,
             page_count: 0,
             key_size,
             value_size,
@@ -47,101 +363,2682 @@ impl<K, V> BPTree<K,V>
             max_key_count,
             split_at,
             emtpy_pages: vec![],
+            free_chain: vec![],
+            key_bounds: None,
+            family: DEFAULT_FAMILY.to_string(),
+            families: BTreeMap::new(),
+            keep_empty_root: options.keep_empty_root,
+            shared_values: if options.dedup_values { Some(SharedValues::default()) } else { None },
+            seq: 0,
+            durable_seq: 0,
+            rotation: None,
+            path: path.to_path_buf(),
+            stats_path: path.with_extension("stats"),
+            stats: None,
+            analyze_every: options.analyze_every,
+            writes_since_analyze: 0,
+            quotas: Quotas::default(),
+            change_log_path: path.with_extension("wal"),
+            change_log,
+            redo_log,
+            compaction_filter: None,
+            merge_operator: None,
+            heat: if options.track_heat { Some(Mutex::default()) } else { None },
+            inline_threshold,
+            retention: options.retention,
+            zones: if options.zone_maps { Some(Mutex::default()) } else { None },
+            quarantine: Quarantine::default(),
+            key_count: 0,
+            splits: 0,
+            merges: 0,
+            limits: LimitWatch::default(),
+            clock: clock::or_system(options.clock.as_ref()),
+            batching: false,
+            options: created_with,
         })
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        todo!()
+    // Returns the version assigned to the entry, which grows with every write to the tree.
+    pub fn set(&mut self, key: K, value: V) -> Result<u64> {
+        let slot = Slot::new(value, self)?;
+        self.set_slot(key, slot)
+    }
+
+    // Optimistic write: only stores the value if the key is still at `expected` (0 meaning
+    // the key must not exist yet), so concurrent writers can't silently overwrite each other.
+    pub fn set_if_version(&mut self, key: K, expected: u64, value: V) -> Result<u64> {
+        let actual = match self.version(&key) {
+            Ok(version) => version,
+            Err(Error::KeyNotFound) => 0,
+            Err(e) => return Err(e),
+        };
+        if actual != expected {
+            return Err(Error::VersionMismatch{ expected, actual });
+        }
+        self.set(key, value)
+    }
+
+    // Compare-and-swap: stores `value` only if the key holds `expected`, or holds nothing
+    // when that is None, and returns whether it did. This is two descents, one to compare
+    // the value in its leaf and one for the set; writes need `&mut self`, which is all that
+    // keeps another write out from between them.
+    pub fn set_if(&mut self, key: K, expected: Option<&V>, value: V) -> Result<bool> {
+        let matches = match self.get_with(&key, |current| Some(current) == expected) {
+            Ok(matches) => matches,
+            Err(Error::KeyNotFound) => expected.is_none(),
+            Err(e) => return Err(e),
+        };
+        if matches {
+            self.set(key, value)?;
+        }
+        Ok(matches)
+    }
+
+    pub(crate) fn set_slot(&mut self, key: K, slot: Slot<V>) -> Result<u64> {
+        self.write_slot(key, slot, 0, None, None)
+    }
+
+    // Like set(), but the entry reads as missing once `ttl` has passed and purge_expired()
+    // removes it. Setting the key again drops the TTL unless it is given again.
+    pub fn set_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> Result<u64> {
+        let expires = self.clock.now().saturating_add(ttl.as_millis() as u64);
+        self.set_expiring_at(key, value, expires)
+    }
+
+    // set_with_ttl() with the expiry as unix millis, e.g. to replay a logged write.
+    pub fn set_expiring_at(&mut self, key: K, value: V, expires: u64) -> Result<u64> {
+        let slot = Slot::new(value, self)?;
+        self.write_slot(key, slot, expires.max(1), None, None)
+    }
+
+    // Like set(), but says whether the key was new or what it held before, found on the same
+    // descent that stores the value.
+    pub fn upsert(&mut self, key: K, value: V) -> Result<UpsertOutcome<V>> {
+        let slot = Slot::new(value, self)?;
+        let mut replaced = None;
+        self.write_slot(key, slot, 0, None, Some(&mut replaced))?;
+        Ok(match replaced {
+            Some(old) => UpsertOutcome::Updated(old),
+            None => UpsertOutcome::Inserted,
+        })
     }
 
-    pub fn set(&mut self, key: K, value: V) -> Result<()> {
-        let mut root_node;
-        if self.root_ptr.is_none() {
-            root_node = self.create_root_node();
+    // Stores the slot under a new sequence number. `restored` keeps the version and write
+    // time of an entry being written back instead.
+    fn write_slot(&mut self, key: K, slot: Slot<V>, expires: u64, restored: Option<(u64, u64)>, replaced: Option<&mut Option<V>>) -> Result<u64> {
+        if let Err(e) = self.check_key_size(&key).and_then(|_| self.quarantine.check(&key)) {
+            slot.free(self)?;
+            return Err(e);
         }
-        else{
-            root_node = Node::load_node(self.root_ptr.unwrap(), self.get_pager())?;
+        if self.quotas.covers(&key) {
+            if let Err(e) = self.charge_quota(&key, &slot) {
+                slot.free(self)?;
+                return Err(e);
+            }
         }
-        if let Some((split_key, new_page_ptr)) = root_node.set(key,value, self)? {
+        let logged = match self.change_log {
+            Some(_) => Some((key.clone(), slot.with_value(self.pager(), V::clone)?)),
+            None => None,
+        };
+        self.seq += 1;
+        let (version, written) = restored.unwrap_or((self.seq, self.write_time()));
+        let value = Entry{ version, written, expires, slot };
+        let root_node = match self.root_ptr {
+            None => self.create_root_node(),
+            Some(ptr) => Node::load_node(ptr, self.get_pager())?,
+        };
+        let order = self.pager.order();
+        self.key_bounds = match self.key_bounds.take() {
+            None => Some((key.clone(), key.clone())),
+            Some((low, high)) if order.cmp::<K, K>(&key, &low).is_lt() => Some((key.clone(), high)),
+            Some((low, high)) if order.cmp::<K, K>(&key, &high).is_gt() => Some((low, key.clone())),
+            bounds => bounds,
+        };
+        let split = match root_node.set(key, value, replaced, self) {
+            Err(Error::AlreadyExists) => {
+                self.seq -= 1;
+                return Err(Error::AlreadyExists);
+            }
+            split => split?,
+        };
+        if let Some((split_key, new_page_ptr)) = split {
+            self.count_split();
             self.create_new_root(split_key, new_page_ptr)?;
         }
-        Ok(())
+        match logged {
+            Some((key, value)) if expires != 0 => self.log_change(&Change::SetExpiring(key, value, expires))?,
+            Some((key, value)) => self.log_change(&Change::Set(key, value))?,
+            None => (),
+        }
+        self.count_write()?;
+        Ok(version)
+    }
+
+    fn charge_quota(&mut self, key: &K, slot: &Slot<V>) -> Result<()> {
+        let old = match self.leaf_for(key) {
+            Ok(leaf) => leaf.entry(key, self.pager()).filter(|entry| !self.is_expired(entry)).map(|entry| self.entry_bytes(key, &entry.slot)).transpose()?,
+            Err(Error::KeyNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        // Checked here too so a refused write isn't charged.
+        if old.is_some() && self.options.write_once {
+            return Err(Error::AlreadyExists);
+        }
+        let new = self.entry_bytes(key, slot)?;
+        self.quotas.charge(key, old, new)
+    }
+
+    fn check_key_size(&self, key: &K) -> Result<()> {
+        let max_key_size = btnode::max_key_size(self.pager.page_size());
+        match self.pager.codec().with_limit(u64::MAX).serialized_size(key)? {
+            size if size > max_key_size => Err(Error::KeyTooLarge(max_key_size)),
+            _ => Ok(()),
+        }
+    }
+
+    // What an entry counts against a quota: its encoded key plus its encoded value.
+    fn entry_bytes(&self, key: &K, slot: &Slot<V>) -> Result<u64> {
+        let codec = self.pager.codec().with_limit(u64::MAX);
+        Ok(codec.serialized_size(key)? + slot.value_len(&codec)?)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Result<V>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.get_with(key, V::clone)
+    }
+
+    // Runs `f` on the value while it still sits in the decoded leaf, so callers that only
+    // need part of a large value don't pay for a full copy.
+    pub fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        match self.leaf_for(key)?.entry(key, self.pager()) {
+            Some(entry) if !self.is_expired(entry) => entry.slot.with_value(self.pager(), f),
+            _ => Err(Error::KeyNotFound),
+        }
+    }
+
+    pub fn get_versioned<Q>(&self, key: &Q) -> Result<(V, u64)>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match self.leaf_for(key)?.entry(key, self.pager()) {
+            Some(entry) if !self.is_expired(entry) => Ok((entry.slot.with_value(self.pager(), V::clone)?, entry.version)),
+            _ => Err(Error::KeyNotFound),
+        }
+    }
+
+    // Version of the stored entry, without reading the value.
+    pub fn version<Q>(&self, key: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match self.leaf_for(key)?.entry(key, self.pager()) {
+            Some(entry) if !self.is_expired(entry) => Ok(entry.version),
+            _ => Err(Error::KeyNotFound),
+        }
+    }
+
+    // Leaf that would hold `key`, or KeyNotFound when the key can't be in the tree at all.
+    pub(crate) fn leaf_for<Q>(&self, key: &Q) -> Result<LeafNode<K, V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.pager.order().check::<K, Q>()?;
+        self.quarantine.check(key)?;
+        if self.root_ptr.is_none() || !self.in_bounds(key) {
+            return Err(Error::KeyNotFound);
+        }
+        let leaf = self.load_root()?.find_leaf(key, self.pager())?;
+        if let Some(heat) = &self.heat {
+            heat.lock().unwrap().record_read(leaf.ptr());
+        }
+        Ok(leaf)
     }
 
-    pub fn get(&mut self, key: K) -> Result<V> {
-        if self.root_ptr.is_none() {
-            Err(Error::RootPageIsNull)
+    // Like set(), returns the sequence number of the write.
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.pager.order().check::<K, Q>()?;
+        self.quarantine.check(key)?;
+        if self.root_ptr.is_none() || !self.in_bounds(key) {
+            return Err(Error::KeyNotFound);
         }
-        else{
-            let root_node: Node<K,V> = Node::load_node(self.root_ptr.unwrap(), self.get_pager())?;
-            let  res = root_node.get(&key, self.get_pager())?;
-            if res.is_some(){
-                Ok(res.unwrap())
+        let root_node = match self.load_root()? {
+            Node::Leaf(leaf) if !self.keep_empty_root && leaf.len() == 1 && leaf.contains(key, self.pager()) => {
+                let ptr = leaf.ptr();
+                let (mut keys, entries) = leaf.into_parts();
+                self.release(&keys[0], &entries[0].slot)?;
+                self.delete_page(ptr);
+                self.root_ptr = None;
+                self.key_bounds = None;
+                return self.finish_remove(keys.swap_remove(0));
             }
-            else{
-                Err(Error::KeyNotFound)
+            node => node,
+        };
+        match root_node.remove(key, self)? {
+            (Some((key, entry)), _) => {
+                self.release(&key, &entry.slot)?;
+                self.finish_remove(key)
             }
+            (None, _) => Err(Error::KeyNotFound),
         }
     }
 
-    pub fn remove(&mut self, key: &K) -> Result<()> {
-        if self.root_ptr.is_none() {
-            Err(Error::RootPageIsNull)
+    fn finish_remove(&mut self, key: K) -> Result<u64> {
+        self.seq += 1;
+        self.key_count -= 1;
+        self.log_change(&Change::Remove(key))?;
+        self.count_write()?;
+        Ok(self.seq)
+    }
+
+    fn log_change(&mut self, change: &Change<K, V>) -> Result<()> {
+        if let Some(change_log) = &mut self.change_log {
+            change_log.append(self.seq, self.clock.now(), change)?;
         }
-        else{
-            let root_node: Node<K,V> = Node::load_node(self.root_ptr.unwrap(), self.get_pager())?;
-            root_node.remove(key, self)?;
-            Ok(())
+        Ok(())
+    }
+
+    // Drops a removed entry from the quotas covering it and frees its overflow pages.
+    fn release(&mut self, key: &K, slot: &Slot<V>) -> Result<()> {
+        if self.quotas.covers(key) {
+            let bytes = self.entry_bytes(key, slot)?;
+            self.quotas.release(key, bytes);
         }
+        slot.free(self)
     }
 
-    pub fn max_key_count(&self) -> u64 {
-        self.max_key_count
+    // Applies the batch in order and returns the sequence number of its last write. Either
+    // all of it is applied or none: when a write fails, the ones before it are undone by
+    // writing back the entries they replaced, with their versions, write times and expiries,
+    // and its error is returned. Undone writes still take sequence numbers and show up in the
+    // change log. With a write-ahead log the batch goes to the redo log as one record, so
+    // after a crash the file holds all of it or none; without one, a crash partway through
+    // can leave part of the batch in the file.
+    pub fn write_batch(&mut self, batch: WriteBatch<K, V>) -> Result<u64> {
+        self.batching = true;
+        let mut undo = Vec::new();
+        let applied = batch.into_changes().into_iter().try_for_each(|change| self.apply_change(change, &mut undo));
+        let undone = match applied {
+            Ok(()) => Ok(()),
+            Err(_) => undo.into_iter().rev().try_for_each(|undo| self.undo(undo)),
+        };
+        self.batching = false;
+        undone?;
+        let committed = self.commit_pages();
+        applied?;
+        committed?;
+        Ok(self.seq)
     }
 
-    pub fn split_at(&self) -> usize {
-        self.split_at
+    // What writes the key back as it is now: its live entry, or its absence.
+    fn undo_for(&self, key: &K) -> Result<Undo<K, V>> {
+        let leaf = match self.leaf_for(key) {
+            Ok(leaf) => leaf,
+            Err(Error::KeyNotFound) => return Ok(Undo::Remove(key.clone())),
+            Err(e) => return Err(e),
+        };
+        match leaf.entry(key, self.pager()).filter(|entry| !self.is_expired(entry)) {
+            Some(entry) => Ok(Undo::Restore{
+                key: key.clone(),
+                value: entry.slot.with_value(self.pager(), V::clone)?,
+                version: entry.version,
+                written: entry.written,
+                expires: entry.expires,
+            }),
+            None => Ok(Undo::Remove(key.clone())),
+        }
     }
 
-    pub fn next_page_ptr(&mut self) -> PagePtr {
-        let next_ptr = self.page_count;
-        self.page_count += 1;
-        next_ptr
+    // Applies one change of a batch and records what would undo it.
+    fn apply_change(&mut self, change: Change<K, V>, undo: &mut Vec<Undo<K, V>>) -> Result<()> {
+        let before = match &change {
+            Change::Set(key, _) | Change::SetExpiring(key, _, _) | Change::Remove(key) => self.undo_for(key)?,
+        };
+        let removal = matches!(change, Change::Remove(_));
+        match change {
+            Change::Set(key, value) => self.set(key, value).map(|_| ())?,
+            Change::SetExpiring(key, value, expires) => self.set_expiring_at(key, value, expires).map(|_| ())?,
+            Change::Remove(key) => match self.remove(&key) {
+                Ok(_) | Err(Error::KeyNotFound) => (),
+                Err(e) => return Err(e),
+            },
+        }
+        // Removing a key that isn't there, or has expired, leaves nothing to undo.
+        if !(removal && matches!(before, Undo::Remove(_))) {
+            undo.push(before);
+        }
+        Ok(())
     }
 
-    fn create_root_node(&mut self) -> Node<K,V> {
-        self.root_ptr = Some(self.next_page_ptr());
-        Node::new_leaf(self.root_ptr.unwrap(), &[], &[], None)
+    fn undo(&mut self, undo: Undo<K, V>) -> Result<()> {
+        match undo {
+            Undo::Remove(key) => self.remove(&key).map(|_| ()),
+            Undo::Restore{ key, value, version, written, expires } => {
+                let slot = Slot::new(value, self)?;
+                self.write_slot(key, slot, expires, Some((version, written)), None).map(|_| ())
+            }
+        }
     }
 
-    fn create_new_root(&mut self, key: K, new_page_ptr: PagePtr) -> Result<()> {
-        let old_root_ptr = self.root_ptr.unwrap();
-        self.root_ptr = Some(self.next_page_ptr());
-        let mut new_root: Node<K,V> = Node::new_inner(self.root_ptr.unwrap(), &[key], &[old_root_ptr, new_page_ptr]);
-        new_root.store_node(self.get_pager())?;
+    // Removes every key in the range and returns how many were removed. Subtrees the range
+    // covers whole are cut out and freed without descending to each key; only the keys in
+    // the leaves at either end of the range are removed one by one. The nodes along both
+    // edges of the cut are merged with their neighbours where it left them too small.
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> Result<u64> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        if self.quarantine.overlaps(&start, &end) {
+            return Err(Error::RangeUnavailable);
+        }
+        let mut removed = 0;
+        if let Some(root) = self.root_ptr {
+            let mut cut = Vec::new();
+            match (&start, &end) {
+                (Bound::Unbounded, Bound::Unbounded) => cut.push(root),
+                _ => if let Node::Inner(mut inner) = self.load_root()? {
+                    inner.prune(start.as_ref(), end.as_ref(), self.pager(), &mut cut)?;
+                    inner.store_node_to_page(self.pager())?;
+                },
+            }
+            if !cut.is_empty() {
+                removed = self.free_subtrees(cut)?;
+                self.count_write()?;
+            }
+        }
+        // Settled first so the removals below find neighbours to merge with, and again for
+        // what they leave.
+        self.settle_edges(&start, &end)?;
+        // Expired entries included, they are still stored.
+        let keys = RangeIter::new(self, start.clone(), end.clone())?
+            .entries()
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<K>>>()?;
+        for key in &keys {
+            self.remove(key)?;
+        }
+        self.settle_edges(&start, &end)?;
+        Ok(removed + keys.len() as u64)
+    }
+
+    fn settle_edges(&mut self, start: &Bound<K>, end: &Bound<K>) -> Result<()> {
+        for bound in [start, end] {
+            if let Bound::Included(key) | Bound::Excluded(key) = bound {
+                self.settle(key)?;
+            }
+        }
         Ok(())
     }
 
-    fn load_root(&mut self) -> Result<Node<K, V>> {
-        match self.root_ptr {
-            None => Err(Error::RootPageIsNull),
-            Some(ptr) => Node::load_node(ptr, self.get_pager())
+    // Merges away the empty leaves and single-child inner nodes on the path to `key`, from
+    // the root down. A merge can leave its parent with a single child in turn, so the path
+    // is walked again from the root until nothing changes.
+    fn settle(&mut self, key: &K) -> Result<()> {
+        loop {
+            self.collapse_root()?;
+            let mut next = self.root_ptr;
+            let mut changed = false;
+            while let Some(ptr) = next {
+                let mut inner = match InnerNode::load_inner(ptr, self.pager())? {
+                    Some(inner) => inner,
+                    None => break,
+                };
+                if inner.settle_child(key, self)? {
+                    inner.store_node_to_page(self.pager())?;
+                    changed = true;
+                    break;
+                }
+                next = Some(inner.get(key, self.pager()));
+            }
+            if !changed {
+                return Ok(());
+            }
         }
     }
 
-    pub fn get_pager(&mut self) -> &mut Pager {
-        self.pager.borrow_mut()
+    // Makes the only child of a root without keys the root, and drops a root leaf left
+    // empty unless keep_empty_root.
+    fn collapse_root(&mut self) -> Result<()> {
+        while self.root_ptr.is_some() {
+            match self.load_root()? {
+                Node::Inner(root) if root.keys().is_empty() => {
+                    self.delete_page(root.ptr());
+                    self.root_ptr = Some(root.childptrs()[0]);
+                }
+                Node::Leaf(root) if root.is_empty() && !self.keep_empty_root => {
+                    self.delete_page(root.ptr());
+                    self.root_ptr = None;
+                    self.key_bounds = None;
+                }
+                _ => break,
+            }
+        }
+        Ok(())
     }
 
-    pub fn set_root(&mut self, new_root_ptr: Option<PagePtr>) {
-        self.root_ptr = new_root_ptr;
+    // Frees subtrees cut out of the tree, dropping their entries as remove() would, and
+    // links the leaves on either side of them.
+    fn free_subtrees(&mut self, cut: Vec<PagePtr>) -> Result<u64> {
+        let prev = self.edge_leaf(cut.first().copied(), false)?.and_then(|leaf| leaf.prev());
+        let next = self.edge_leaf(cut.last().copied(), true)?.and_then(|leaf| leaf.next());
+        let whole = cut.first() == self.root_ptr.as_ref();
+        let removed = self.drop_pages(cut)?;
+        self.key_count -= removed;
+        if whole {
+            self.root_ptr = None;
+            self.key_bounds = None;
+            if self.keep_empty_root {
+                self.create_root_node().store_node(self.get_pager())?;
+            }
+            return Ok(removed);
+        }
+        btnode::link_leaves::<K, V>(prev, next, self.pager())?;
+        Ok(removed)
     }
 
-    pub fn delete_page(&mut self, ptr: PagePtr){
-        self.emtpy_pages.push(ptr);
+    // Frees the pages of the subtrees, and their entries as remove() would, except that the
+    // key count is the caller's to settle. Returns how many entries there were.
+    fn drop_pages(&mut self, mut pending: Vec<PagePtr>) -> Result<u64> {
+        let mut dropped = 0;
+        while let Some(ptr) = pending.pop() {
+            match Node::<K, V>::load_node(ptr, self.pager())? {
+                Node::Inner(inner) => pending.extend(inner.childptrs()),
+                Node::Leaf(leaf) => {
+                    let (keys, entries) = leaf.into_parts();
+                    dropped += self.drop_entries(keys, entries)?;
+                }
+            }
+            self.delete_page(ptr);
+        }
+        Ok(dropped)
     }
 
-    pub fn print_deleted(&self) {
-        println!("{:?}", self.emtpy_pages);
+    // Drops entries as remove() would, leaving the key count to the caller.
+    fn drop_entries(&mut self, keys: Vec<K>, entries: Vec<Entry<V>>) -> Result<u64> {
+        let dropped = keys.len() as u64;
+        for (key, entry) in keys.into_iter().zip(entries) {
+            self.release(&key, &entry.slot)?;
+            self.seq += 1;
+            self.log_change(&Change::Remove(key))?;
+        }
+        Ok(dropped)
+    }
+
+    pub fn remove_prefix(&mut self, prefix: &K) -> Result<u64>
+        where K: KeyPrefix
+    {
+        self.remove_range(prefix.prefix_range())
+    }
+
+    pub fn bulk_load(&mut self, items: Vec<(K, V)>) -> Result<()>
+        where K: Send, V: Send
+    {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        self.bulk_load_with_threads(items, threads)
+    }
+
+    // Builds the tree bottom-up from sorted input instead of inserting key by key. Leaves
+    // are filled evenly and handed out in contiguous runs to `threads` workers that write
+    // them concurrently; the inner levels are stitched together afterwards on this thread.
+    pub fn bulk_load_with_threads(&mut self, items: Vec<(K, V)>, threads: usize) -> Result<()>
+        where K: Send, V: Send
+    {
+        if !self.is_empty() {
+            return Err(Error::TreeNotEmpty);
+        }
+        if items.windows(2).any(|pair| !self.pager.order().cmp::<K, K>(&pair[0].0, &pair[1].0).is_lt()) {
+            return Err(Error::UnsortedInput);
+        }
+        items.iter().try_for_each(|(key, _)| self.check_key_size(key))?;
+        let (first_key, last_key) = match (items.first(), items.last()) {
+            (Some(first), Some(last)) => (first.0.clone(), last.0.clone()),
+            _ => return Ok(()),
+        };
+        let mut keys = Vec::with_capacity(items.len());
+        let mut slots = Vec::with_capacity(items.len());
+        for (key, value) in items {
+            match self.loaded_entry(&key, value) {
+                Ok(entry) => slots.push(entry),
+                Err(e) => {
+                    self.drop_entries(keys, slots)?;
+                    self.commit_pages()?;
+                    return Err(e);
+                }
+            }
+            keys.push(key);
+        }
+        if let Some(root) = self.root_ptr.take() {
+            self.delete_page(root);
+        }
+        let count = keys.len() as u64;
+        let codec = self.pager.codec().with_limit(u64::MAX);
+        let bytes = keys.iter().zip(&slots).map(|(key, entry)| btnode::entry_size(key, &entry.slot, &codec)).collect::<Result<Vec<u64>>>()?;
+        let sizes = pack(&bytes, self.max_key_count as usize, btnode::node_capacity(self.pager.page_size()), 1);
+        let ptrs: Vec<PagePtr> = sizes.iter().map(|_| self.next_page_ptr()).collect();
+        let mut level = Vec::with_capacity(sizes.len());
+        let mut leaves = Vec::with_capacity(sizes.len());
+        let (mut keys, mut slots) = (keys.into_iter(), slots.into_iter());
+        for (i, size) in sizes.into_iter().enumerate() {
+            let leaf_keys: Vec<K> = keys.by_ref().take(size).collect();
+            level.push((leaf_keys[0].clone(), ptrs[i]));
+            leaves.push(LeafNode::from_parts(ptrs[i], leaf_keys, slots.by_ref().take(size).collect(), i.checked_sub(1).map(|prev| ptrs[prev]), ptrs.get(i + 1).copied()));
+        }
+
+        let per_worker = leaves.len().div_ceil(threads.max(1));
+        let mut runs = Vec::new();
+        while !leaves.is_empty() {
+            let rest = leaves.split_off(per_worker.min(leaves.len()));
+            runs.push(mem::replace(&mut leaves, rest));
+        }
+        let pager = &self.pager;
+        thread::scope(|scope| {
+            let workers: Vec<_> = runs.into_iter()
+                .map(|run| scope.spawn(move || run.iter().try_for_each(|leaf| leaf.store_node_to_page(pager))))
+                .collect();
+            workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+        })?;
+        self.finish_load(level, (first_key, last_key), count)
+    }
+
+    // Builds the tree bottom-up like bulk_load(), from an iterator of sorted items that is
+    // never held whole: each leaf is written once it is full, and only the first key of
+    // every leaf is kept for the inner levels. Leaves are filled to the brim rather than
+    // evenly. Input found unsorted, a key too large or a key over its quota partway through
+    // fails the load, and what was loaded before it is dropped again as remove() would.
+    pub fn bulk_load_iter<I: IntoIterator<Item = (K, V)>>(&mut self, items: I) -> Result<()> {
+        if !self.is_empty() {
+            return Err(Error::TreeNotEmpty);
+        }
+        let mut level = Vec::new();
+        match self.load_leaves(items.into_iter(), &mut level) {
+            Ok(Some((last_key, count))) => {
+                if let Some(root) = self.root_ptr.take() {
+                    self.delete_page(root);
+                }
+                let first_key = level[0].0.clone();
+                self.finish_load(level, (first_key, last_key), count)
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                self.drop_pages(level.into_iter().map(|(_, ptr)| ptr).collect())?;
+                self.commit_pages()?;
+                Err(e)
+            }
+        }
+    }
+
+    // Writes the items into leaves from left to right and pushes the first key and page of
+    // each onto `level`. Returns the last key and the count, None with no items. Whatever it
+    // took in is in the leaves on `level` when it fails too.
+    fn load_leaves(&mut self, items: impl Iterator<Item = (K, V)>, level: &mut Vec<(K, PagePtr)>) -> Result<Option<(K, u64)>> {
+        let codec = self.pager.codec().with_limit(u64::MAX);
+        let (mut keys, mut entries, mut bytes) = (Vec::new(), Vec::new(), 0);
+        let (mut ptr, mut prev) = (None, None);
+        let mut stored_last: Option<K> = None;
+        let mut count = 0;
+        for (key, value) in items {
+            let checked = match keys.last().or(stored_last.as_ref()) {
+                Some(last) if !self.pager.order().cmp::<K, K>(last, &key).is_lt() => Err(Error::UnsortedInput),
+                _ => self.check_key_size(&key),
+            };
+            let entry = match checked.and_then(|_| self.loaded_entry(&key, value)) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    if let Some(ptr) = ptr {
+                        self.store_loaded_leaf(ptr, keys, entries, prev, None, level)?;
+                    }
+                    return Err(e);
+                }
+            };
+            let size = btnode::entry_size(&key, &entry.slot, &codec)?;
+            if !keys.is_empty() && (keys.len() == self.max_key_count as usize || bytes + size > btnode::node_capacity(self.pager.page_size())) {
+                let next = self.next_page_ptr();
+                stored_last = keys.last().cloned();
+                self.store_loaded_leaf(ptr.unwrap(), mem::take(&mut keys), mem::take(&mut entries), prev, Some(next), level)?;
+                (prev, ptr, bytes) = (ptr, Some(next), 0);
+            }
+            if ptr.is_none() {
+                ptr = Some(self.next_page_ptr());
+            }
+            keys.push(key);
+            entries.push(entry);
+            bytes += size;
+            count += 1;
+        }
+        match (ptr, keys.last().cloned()) {
+            (Some(ptr), Some(last)) => {
+                self.store_loaded_leaf(ptr, keys, entries, prev, None, level)?;
+                Ok(Some((last, count)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn store_loaded_leaf(&self, ptr: PagePtr, keys: Vec<K>, entries: Vec<Entry<V>>, prev: Option<PagePtr>, next: Option<PagePtr>, level: &mut Vec<(K, PagePtr)>) -> Result<()> {
+        level.push((keys[0].clone(), ptr));
+        LeafNode::from_parts(ptr, keys, entries, prev, next).store_node_to_page(self.pager())
+    }
+
+    // The entry a bulk load writes for `key`, with its sequence number, change-log record
+    // and quota charge. A key its quotas have no room for fails with QuotaExceeded.
+    fn loaded_entry(&mut self, key: &K, value: V) -> Result<Entry<V>> {
+        let logged = self.change_log.as_ref().map(|_| value.clone());
+        let slot = Slot::new(value, self)?;
+        if self.quotas.covers(key) {
+            let charged = self.entry_bytes(key, &slot).and_then(|bytes| self.quotas.charge(key, None, bytes));
+            if let Err(e) = charged {
+                slot.free(self)?;
+                return Err(e);
+            }
+        }
+        self.seq += 1;
+        if let Some(value) = logged {
+            self.log_change(&Change::Set(key.clone(), value))?;
+        }
+        Ok(Entry{ version: self.seq, written: self.write_time(), expires: 0, slot })
+    }
+
+    // Stacks the inner levels on the leaves of a bulk load, given by their first keys, and
+    // makes the top one the root.
+    fn finish_load(&mut self, mut level: Vec<(K, PagePtr)>, key_bounds: (K, K), count: u64) -> Result<()> {
+        let codec = self.pager.codec().with_limit(u64::MAX);
+        while level.len() > 1 {
+            // Every child brings its first key, as a separator or the low fence, and the
+            // high fence is at most one more key.
+            let bytes = level.iter().map(|(key, _)| Ok(codec.serialized_size(key)? + PAGE_PTR_LEN as u64)).collect::<Result<Vec<u64>>>()?;
+            let page_size = self.pager.page_size();
+            let sizes = pack(&bytes, self.max_key_count as usize + 1, btnode::node_capacity(page_size) - btnode::max_key_size(page_size), 2);
+            let mut groups = Vec::with_capacity(sizes.len());
+            let mut children = level.into_iter();
+            for size in sizes {
+                groups.push(children.by_ref().take(size).collect::<Vec<_>>());
+            }
+            level = Vec::with_capacity(groups.len());
+            for i in 0..groups.len() {
+                let low = if i == 0 { None } else { Some(groups[i][0].0.clone()) };
+                let high = groups.get(i + 1).map(|group| group[0].0.clone());
+                let separators: Vec<K> = groups[i][1..].iter().map(|(key, _)| key.clone()).collect();
+                let childptrs: Vec<PagePtr> = groups[i].iter().map(|(_, ptr)| *ptr).collect();
+                let ptr = self.next_page_ptr();
+                InnerNode::from(ptr, &separators, &childptrs).with_fences(low, high).store_node_to_page(self.pager())?;
+                level.push((groups[i][0].0.clone(), ptr));
+            }
+        }
+        self.root_ptr = Some(level[0].1);
+        self.key_bounds = Some(key_bounds);
+        self.key_count = count;
+        self.commit_pages()?;
+        self.check_limits()
+    }
+
+    pub fn par_set_batch(&mut self, items: Vec<(K, V)>) -> Result<u64>
+        where K: Send, V: Send
+    {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        self.par_set_batch_with_threads(items, threads)
+    }
+
+    // Sorts the items on `threads` workers, each owning one key range, then writes them in
+    // key order so consecutive writes land in the same leaves. An empty tree is bulk loaded
+    // instead. When a key repeats, its last value wins. Returns the sequence number of the
+    // last write.
+    pub fn par_set_batch_with_threads(&mut self, items: Vec<(K, V)>, threads: usize) -> Result<u64>
+        where K: Send, V: Send
+    {
+        let order = self.pager.order();
+        let mut items = match order.is_ord() {
+            true => par_sort_by_key(items, threads, |(key, _)| key),
+            // The workers split the keys by Ord, so another order is sorted on one thread.
+            false => order.sorted(items),
+        };
+        items.reverse();
+        items.dedup_by(|(later, _), (earlier, _)| order.cmp::<K, K>(later, earlier).is_eq());
+        items.reverse();
+        if self.is_empty() {
+            self.bulk_load_with_threads(items, threads)?;
+            return Ok(self.seq);
+        }
+        for (key, value) in items {
+            self.set(key, value)?;
+        }
+        Ok(self.seq)
+    }
+
+    // Looks the keys up in key order, staying in the leaf of the last key while the next one
+    // falls between its first and last key, so keys close together share one descent and
+    // one leaf read. Results come back in the order of `keys`.
+    pub fn multi_get(&self, keys: &[K]) -> Vec<Result<V>> {
+        let order = self.pager.order();
+        let mut sorted: Vec<usize> = (0..keys.len()).collect();
+        sorted.sort_by(|a, b| order.cmp::<K, K>(&keys[*a], &keys[*b]));
+        let mut results: Vec<Result<V>> = keys.iter().map(|_| Err(Error::KeyNotFound)).collect();
+        let mut leaf: Option<LeafNode<K, V>> = None;
+        for i in sorted {
+            let key = &keys[i];
+            let inside = leaf.as_ref().is_some_and(|leaf| match (leaf.keys().first(), leaf.keys().last()) {
+                (Some(first), Some(last)) => order.cmp::<K, K>(first, key).is_le() && order.cmp::<K, K>(key, last).is_le(),
+                _ => false,
+            });
+            if !inside {
+                match self.leaf_for(key) {
+                    Ok(found) => leaf = Some(found),
+                    Err(e) => {
+                        results[i] = Err(e);
+                        continue;
+                    }
+                }
+            }
+            results[i] = match leaf.as_ref().and_then(|leaf| leaf.entry(key, self.pager())) {
+                Some(entry) if !self.is_expired(entry) => entry.slot.with_value(self.pager(), V::clone),
+                _ => Err(Error::KeyNotFound),
+            };
+        }
+        results
+    }
+
+    pub fn par_get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>>
+        where K: Sync, V: Send, Self: Sync
+    {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        self.par_get_many_with_threads(keys, threads)
+    }
+
+    // Looks the keys up on `threads` workers, each taking the keys of one key range in
+    // order. Values come back in the order of `keys`, None for missing ones.
+    pub fn par_get_many_with_threads(&self, keys: &[K], threads: usize) -> Result<Vec<Option<V>>>
+        where K: Sync, V: Send, Self: Sync
+    {
+        let lookups: Vec<(&K, usize)> = keys.iter().zip(0..).collect();
+        let runs = partition_by_range(lookups, threads, |(key, _)| *key);
+        let found = thread::scope(|scope| {
+            let workers: Vec<_> = runs.into_iter()
+                .map(|mut run| scope.spawn(move || {
+                    run.sort_by(|a, b| a.0.cmp(b.0));
+                    run.into_iter().map(|(key, i)| match self.get(key) {
+                        Ok(value) => Ok((i, Some(value))),
+                        Err(Error::KeyNotFound) => Ok((i, None)),
+                        Err(e) => Err(e),
+                    }).collect::<Result<Vec<_>>>()
+                }))
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect::<Result<Vec<_>>>()
+        })?;
+        let mut values: Vec<Option<V>> = (0..keys.len()).map(|_| None).collect();
+        for (i, value) in found.into_iter().flatten() {
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    // Splits the range into up to `shards` disjoint, independent cursors that together
+    // cover it in order. Split points are separator keys from the highest inner level that
+    // has enough of them inside the range, so shards are roughly balanced by subtree.
+    pub fn par_range<R: RangeBounds<K>>(&self, range: R, shards: usize) -> Result<Vec<RangeIter<'_, K, V>>> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let cmp = |a: &K, b: &K| self.pager.order().cmp::<K, K>(a, b);
+        let inside = |key: &K| {
+            let after_start = match &start {
+                Bound::Included(start) | Bound::Excluded(start) => cmp(key, start).is_gt(),
+                Bound::Unbounded => true,
+            };
+            let before_end = match &end {
+                Bound::Included(end) => cmp(key, end).is_le(),
+                Bound::Excluded(end) => cmp(key, end).is_lt(),
+                Bound::Unbounded => true,
+            };
+            after_start && before_end
+        };
+
+        let mut separators = Vec::new();
+        let mut level = match self.root_ptr {
+            Some(root) if shards > 1 => vec![Node::<K, V>::load_node(root, self.pager())?],
+            _ => vec![],
+        };
+        loop {
+            let inners: Vec<InnerNode<K>> = level.into_iter().filter_map(|node| match node {
+                Node::Inner(inner) => Some(inner),
+                Node::Leaf(_) => None,
+            }).collect();
+            if inners.is_empty() {
+                break;
+            }
+            separators = inners.iter().flat_map(|inner| inner.keys().iter().filter(|key| inside(key)).cloned()).collect();
+            if separators.len() + 1 >= shards {
+                break;
+            }
+            level = Vec::new();
+            for inner in &inners {
+                for (i, ptr) in inner.childptrs().iter().enumerate() {
+                    let (low, high) = inner.child_bounds(i);
+                    let below_end = match (low, &end) {
+                        (Some(low), Bound::Included(end)) => cmp(low, end).is_le(),
+                        (Some(low), Bound::Excluded(end)) => cmp(low, end).is_lt(),
+                        _ => true,
+                    };
+                    let above_start = match (high, &start) {
+                        (Some(high), Bound::Included(start) | Bound::Excluded(start)) => cmp(high, start).is_gt(),
+                        _ => true,
+                    };
+                    if below_end && above_start {
+                        level.push(Node::load_node(*ptr, self.pager())?);
+                    }
+                }
+            }
+        }
+
+        let count = separators.len().min(shards.saturating_sub(1));
+        let splits: Vec<K> = (1..=count).map(|i| separators[i * separators.len() / (count + 1)].clone()).collect();
+        let mut cursors = Vec::with_capacity(splits.len() + 1);
+        let mut shard_start = start;
+        for split in splits {
+            cursors.push(RangeIter::new(self, shard_start, Bound::Excluded(split.clone()))?);
+            shard_start = Bound::Included(split);
+        }
+        cursors.push(RangeIter::new(self, shard_start, end)?);
+        Ok(cursors)
+    }
+
+    // Samples the tree, keeps the result for estimate_range() and writes it next to the
+    // tree file so it can be inspected without rescanning.
+    pub fn analyze(&mut self) -> Result<&Stats<K>> {
+        let stats = stats::analyze(self)?;
+        stats::store(&stats, &self.stats_path, self.pager.codec())?;
+        self.writes_since_analyze = 0;
+        Ok(self.stats.insert(stats))
+    }
+
+    pub fn stats(&self) -> Option<&Stats<K>> {
+        self.stats.as_ref()
+    }
+
+    // Approximate number of keys in the range, from the last analyze() or a fresh sample.
+    pub fn estimate_range<R: RangeBounds<K>>(&self, range: R) -> Result<u64> {
+        match &self.stats {
+            Some(stats) => Ok(stats.estimate_range(&range)),
+            None => Ok(stats::analyze(self)?.estimate_range(&range)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Sequence number of the last write.
+    pub fn last_seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn durable_seq(&self) -> u64 {
+        self.durable_seq
+    }
+
+    // Makes sure every write up to `seq` is on disk and returns the new durable sequence
+    // number. Pages are written through as they change, so this is at most one sync, and
+    // none when `seq` is already durable.
+    pub fn flush_until(&mut self, seq: u64) -> Result<u64> {
+        self.commit_pages()?;
+        if seq > self.durable_seq {
+            self.sync_meta()?;
+        }
+        Ok(self.durable_seq)
+    }
+
+    fn sync_meta(&mut self) -> Result<()> {
+        if let Some(change_log) = &self.change_log {
+            change_log.sync()?;
+        }
+        self.write_meta()?;
+        self.pager.sync()?;
+        self.durable_seq = self.seq;
+        Ok(())
+    }
+
+    pub(crate) fn rotation(&self) -> Option<&[u8]> {
+        self.rotation.as_deref()
+    }
+
+    // Records how far a master key rotation got and flushes the tree along with it, so
+    // the progress open() finds never runs ahead of the values it describes.
+    pub(crate) fn store_rotation(&mut self, rotation: Option<Vec<u8>>) -> Result<()> {
+        self.rotation = rotation;
+        self.commit_pages()?;
+        self.sync_meta()
+    }
+
+    // Flushes and closes the tree, so open() finds it as it is now.
+    pub fn close(mut self) -> Result<()> {
+        self.flush()?;
+        self.checkpoint()
+    }
+
+    // Writes a copy of the tree as it is now to `path`, replacing whatever file is there,
+    // and returns the number of pages copied. Writes need `&mut self`, so none can happen
+    // while the pages are read, and pages not yet in the file are read from where they wait.
+    // The copy gets a meta page of its own and opens like a closed tree; the change log and
+    // stats are not part of it.
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        remove_if_exists(&path.as_ref().with_extension("redo"))?;
+        let target = Pager::open(&path, *self.pager.codec())?.with_page_size(self.pager.page_size()).with_cipher(self.pager.cipher().cloned());
+        let mut copied = 0;
+        for ptr in 1..self.page_count {
+            match self.pager.read_page(ptr) {
+                Ok(page) => target.write_page(ptr, &page)?,
+                // Allocated but never written, so nothing refers to it yet.
+                Err(Error::PageNotFound) => continue,
+                Err(e) => return Err(e),
+            }
+            copied += 1;
+        }
+        // The pages holding this tree's spilled free list are free in the copy, which
+        // spills its own.
+        let mut free = self.emtpy_pages.clone();
+        free.extend(&self.free_chain);
+        meta::store(&mut self.meta(), &mut free, &target)?;
+        target.sync()?;
+        Ok(copied + 1)
+    }
+
+    // Writes every entry to `writer` in key order and returns how many there were.
+    pub fn export<W: Write>(&self, writer: W, format: Format) -> Result<u64> {
+        export::export(self, writer, format)
+    }
+
+    // Writes the nodes of the current family level by level, for debugging.
+    pub fn dump<W: Write>(&self, writer: W, format: DumpFormat) -> Result<()> {
+        dump::dump(self, writer, format)
+    }
+
+    // Reads what export() wrote into the tree, bulk-loading it when the tree is empty.
+    // Returns how many keys were read.
+    pub fn import<R: Read>(&mut self, reader: R, format: Format) -> Result<u64>
+        where K: Send, V: Send
+    {
+        export::import(self, reader, format)
     }
-}
\ No newline at end of file
+
+    // Rewrites the tree into a new file that holds only its live pages, in order and without
+    // gaps, and renames it over the tree file, so the file shrinks by every free page. Returns
+    // how many pages it lost. Needs room for the copy next to the tree file, with extension
+    // "vacuum"; until the rename the tree file stays as it was.
+    pub fn vacuum(&mut self) -> Result<u64> {
+        if !self.quarantine.is_empty() {
+            return Err(Error::RangeUnavailable);
+        }
+        self.flush()?;
+        self.checkpoint()?;
+        let copy_path = self.path.with_extension("vacuum");
+        let target = Pager::open(&copy_path, *self.pager.codec())?.with_page_size(self.pager.page_size()).with_cipher(self.pager.cipher().cloned());
+        let mut meta = self.meta();
+        let roots: Vec<Option<PagePtr>> = once(meta.root_ptr).chain(meta.families.iter().map(|family| family.1)).collect();
+        let rewritten = vacuum::rewrite(self, &roots, &target).and_then(|(roots, page_count)| {
+            meta.root_ptr = roots[0];
+            meta.families.iter_mut().zip(&roots[1..]).for_each(|(family, root)| family.1 = *root);
+            meta.page_count = page_count;
+            meta::store(&mut meta, &mut Vec::new(), &target)?;
+            target.sync()?;
+            Ok(page_count)
+        });
+        drop(target);
+        let page_count = match rewritten {
+            Ok(rewritten) => rewritten,
+            Err(e) => {
+                remove_if_exists(&copy_path)?;
+                return Err(e);
+            }
+        };
+        fs::rename(&copy_path, &self.path)?;
+        let pager = Pager::open_existing(&self.path, *self.pager.codec())?.with_cipher(self.pager.cipher().cloned());
+        self.pager = configure_pager(pager, &self.options, self.redo_log.is_some());
+
+        let reclaimed = self.page_count - page_count;
+        self.restore_families(&meta)?;
+        self.page_count = page_count;
+        self.emtpy_pages.clear();
+        self.free_chain.clear();
+        // Heat and zones are kept by page number.
+        if let Some(heat) = &mut self.heat {
+            *heat = Mutex::default();
+        }
+        if let Some(zones) = &mut self.zones {
+            *zones = Mutex::default();
+        }
+        if self.shared_values.is_some() {
+            self.count_shared_values()?;
+        }
+        Ok(reclaimed)
+    }
+
+    // Replaces the tree at `path` with a copy of the backup at `backup` and opens it. The
+    // change log and stats of the tree that was there are removed, as they no longer
+    // describe it.
+    pub fn restore_from<P: AsRef<Path>, Q: AsRef<Path>>(backup: P, path: Q, options: Options) -> Result<Self> {
+        meta::load(&Pager::open_existing(&backup, Codec::default())?.with_stored_page_size()?)?;
+        let path = path.as_ref();
+        for extension in ["redo", "wal", "stats"] {
+            remove_if_exists(&path.with_extension(extension))?;
+        }
+        fs::copy(backup, path)?;
+        Self::open_with_options(path, options)
+    }
+
+    // Adds an empty column family: another tree of the same key and value types in this file,
+    // sharing its pages, free list and meta page. Not possible with dedup_values, whose
+    // reference counts are kept for one tree.
+    pub fn create_cf(&mut self, name: &str) -> Result<()> {
+        if name == self.family || self.families.contains_key(name) {
+            return Err(Error::TreeExists(name.to_string()));
+        }
+        if self.shared_values.is_some() {
+            return Err(Error::HasColumnFamilies);
+        }
+        self.families.insert(name.to_string(), Family{ root_ptr: None, key_count: 0, key_bounds: None });
+        self.write_meta()?;
+        self.commit_pages()
+    }
+
+    // The tree with the column family `name` in place of its own until the handle is dropped.
+    pub fn open_cf(&mut self, name: &str) -> Result<ColumnFamily<'_, K, V>> {
+        if name == DEFAULT_FAMILY || !self.families.contains_key(name) {
+            return Err(Error::TreeNotFound(name.to_string()));
+        }
+        let previous = self.family.clone();
+        self.switch_family(name);
+        Ok(ColumnFamily::new(self, previous))
+    }
+
+    // Removes every entry of the column family `name` and the family itself.
+    pub fn drop_cf(&mut self, name: &str) -> Result<()> {
+        if name == self.family {
+            return Err(Error::ColumnFamilyInUse(name.to_string()));
+        }
+        let mut family = self.open_cf(name)?;
+        family.remove_range(..)?;
+        if let Some(root) = family.root_ptr.take() {
+            family.delete_page(root);
+        }
+        drop(family);
+        self.families.remove(name);
+        self.write_meta()?;
+        self.commit_pages()
+    }
+
+    pub fn cf_names(&self) -> impl Iterator<Item = &str> {
+        once(self.family.as_str()).chain(self.families.keys().map(String::as_str))
+            .filter(|name| *name != DEFAULT_FAMILY)
+    }
+
+    // The roots of every column family.
+    pub(crate) fn family_roots(&self) -> Vec<PagePtr> {
+        once(self.root_ptr).chain(self.families.values().map(|family| family.root_ptr)).flatten().collect()
+    }
+
+    // With a redo log, hands the pages staged since the last write to it and then writes
+    // them in place. The meta page goes with them, so the log always replays to a whole tree.
+    fn commit_pages(&mut self) -> Result<()> {
+        if self.redo_log.is_none() || (!self.pager.has_staged() && self.durable_seq == self.seq) {
+            return Ok(());
+        }
+        self.write_meta()?;
+        let pages = self.pager.take_staged();
+        let images = pages.iter().map(|(ptr, page)| Ok((*ptr, self.pager.image(*ptr, page)?))).collect::<Result<Vec<_>>>()?;
+        let redo_log = self.redo_log.as_mut().unwrap();
+        redo_log.append(self.seq, &images)?;
+        let full = redo_log.len() > REDO_LOG_CHECKPOINT;
+        pages.iter().try_for_each(|(ptr, page)| self.pager.write_through(*ptr, page))?;
+        if let Some(change_log) = &self.change_log {
+            change_log.sync()?;
+        }
+        self.durable_seq = self.seq;
+        if full {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // Syncs the tree file, after which the redo log has nothing left to redo.
+    fn checkpoint(&mut self) -> Result<()> {
+        self.pager.sync()?;
+        match &mut self.redo_log {
+            Some(redo_log) => redo_log.truncate(),
+            None => Ok(()),
+        }
+    }
+
+    fn write_meta(&mut self) -> Result<()> {
+        let mut meta = self.meta();
+        let chain = meta::store(&mut meta, &mut self.emtpy_pages, &self.pager)?;
+        let replaced = mem::replace(&mut self.free_chain, chain);
+        self.emtpy_pages.extend(replaced);
+        Ok(())
+    }
+
+    // The tree's state as of now, with the free list left for meta::store() to fill in.
+    fn meta(&self) -> Meta {
+        let mut families: Vec<(String, Option<PagePtr>, u64)> = self.families.iter()
+            .map(|(name, family)| (name.clone(), family.root_ptr, family.key_count))
+            .collect();
+        families.push((self.family.clone(), self.root_ptr, self.key_count));
+        families.sort();
+        let (_, root_ptr, key_count) = families.remove(0);
+        Meta{
+            version: meta::FORMAT_VERSION,
+            page_size: self.pager.page_size() as u64,
+            codec: *self.pager.codec(),
+            compression: self.pager.compression(),
+            comparator: self.pager.order().name(),
+            key_size: self.key_size,
+            value_size: self.value_size,
+            max_key_count: self.max_key_count,
+            inline_threshold: self.inline_threshold as u64,
+            retention_ms: self.retention.map(|retention| retention.as_millis() as u64),
+            keep_empty_root: self.keep_empty_root,
+            dedup_values: self.shared_values.is_some(),
+            write_once: self.options.write_once,
+            root_ptr,
+            page_count: self.page_count,
+            seq: self.seq,
+            key_count,
+            families,
+            rotation: self.rotation.clone(),
+            free_pages: Vec::new(),
+            free_chain: None,
+        }
+    }
+
+    // Takes the root and key count of every family from `meta`, finding their key bounds.
+    fn restore_families(&mut self, meta: &Meta) -> Result<()> {
+        let mut families = BTreeMap::new();
+        let default = (DEFAULT_FAMILY.to_string(), meta.root_ptr, meta.key_count);
+        for (name, root_ptr, key_count) in once(default).chain(meta.families.iter().cloned()) {
+            let key_bounds = match (self.edge_key(root_ptr, false)?, self.edge_key(root_ptr, true)?) {
+                (Some(low), Some(high)) => Some((low, high)),
+                _ => None,
+            };
+            families.insert(name, Family{ root_ptr, key_count, key_bounds });
+        }
+        let family = families.remove(&self.family).unwrap();
+        self.root_ptr = family.root_ptr;
+        self.key_count = family.key_count;
+        self.key_bounds = family.key_bounds;
+        self.families = families;
+        Ok(())
+    }
+
+    // The smallest or the largest key under `root`, found along the leftmost or rightmost
+    // edge and then along the leaf chain past any leaves left empty.
+    fn edge_key(&self, root: Option<PagePtr>, last: bool) -> Result<Option<K>> {
+        let mut leaf = self.edge_leaf(root, last)?;
+        while let Some(node) = leaf {
+            let next = if last { node.prev() } else { node.next() };
+            let (mut keys, _) = node.into_parts();
+            let key = if last { keys.pop() } else { keys.into_iter().next() };
+            if key.is_some() {
+                return Ok(key);
+            }
+            leaf = match next {
+                Some(ptr) => Some(LeafNode::load(ptr, self.pager())?),
+                None => None,
+            };
+        }
+        Ok(None)
+    }
+
+    // The first leaf under `root`, or the last.
+    fn edge_leaf(&self, root: Option<PagePtr>, last: bool) -> Result<Option<LeafNode<K, V>>> {
+        let mut next = root;
+        while let Some(ptr) = next {
+            match Node::<K, V>::load_node(ptr, self.pager())? {
+                Node::Inner(inner) => next = if last { inner.childptrs().last() } else { inner.childptrs().first() }.copied(),
+                Node::Leaf(leaf) => return Ok(Some(leaf)),
+            }
+        }
+        Ok(None)
+    }
+
+
+    // Reference counts of shared overflow chains only live in memory; they are counted
+    // again from the leaves.
+    fn count_shared_values(&mut self) -> Result<()> {
+        let mut shared = SharedValues::default();
+        let mut next = iter::first_leaf(self)?;
+        while let Some(ptr) = next {
+            let leaf = LeafNode::<K, V>::load(ptr, self.pager())?;
+            next = leaf.next();
+            for entry in leaf.into_parts().1 {
+                if let Slot::Overflow(value) = entry.slot {
+                    shared.add_ref(value, self.pager())?;
+                }
+            }
+        }
+        self.shared_values = Some(shared);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<u64> {
+        self.flush_until(self.seq)
+    }
+
+    // Changes from `offset` on: 0 for the whole history, or the `next` of the last event a
+    // consumer handled to resume after it.
+    pub fn changes(&self, offset: u64) -> Result<ChangeStream<K, V>> {
+        if self.change_log.is_none() {
+            return Err(Error::ChangeLogDisabled);
+        }
+        ChangeStream::open(&self.change_log_path, offset, *self.pager.codec())
+    }
+
+    // The last `records` changes of the log, decoded for a bug report. Keys only appear as
+    // a hash.
+    pub fn journal(&self, records: usize) -> Result<Vec<JournalRecord>> {
+        journal::tail(self.changes(0)?, records, self.pager.codec())
+    }
+
+    // Offset just past the last logged change, for consumers that only want new changes.
+    pub fn change_log_end(&self) -> Option<u64> {
+        self.change_log.as_ref().map(ChangeLog::len)
+    }
+
+    pub fn change_log_path(&self) -> Option<&Path> {
+        self.change_log.as_ref().map(|_| self.change_log_path.as_path())
+    }
+
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.pager.cache_stats()
+    }
+
+    // Recent reads and writes per leaf, in key order, with the key range each leaf holds.
+    // Counts are approximate and decay over time; point lookups and writes are counted,
+    // scans are not. Empty when the tree was opened without `track_heat`.
+    pub fn heat_map(&self) -> Result<Vec<LeafHeat<K>>> {
+        let leaves = match &self.heat {
+            Some(heat) => heat.lock().unwrap().leaves(),
+            None => return Ok(Vec::new()),
+        };
+        let mut map = Vec::with_capacity(leaves.len());
+        for (ptr, counts) in leaves {
+            if let Node::Leaf(leaf) = Node::<K, V>::load_node(ptr, self.pager())? {
+                let (mut keys, _) = leaf.into_parts();
+                if let (Some(first_key), Some(last_key)) = (keys.first().cloned(), keys.pop()) {
+                    map.push(LeafHeat{ leaf: ptr, first_key, last_key, reads: counts.reads, writes: counts.writes });
+                }
+            }
+        }
+        map.sort_by(|a, b| self.pager.order().cmp::<K, K>(&a.first_key, &b.first_key));
+        Ok(map)
+    }
+
+    // Lets the application expire or rewrite records lazily: the filter is consulted for
+    // every record on each compact().
+    pub fn set_compaction_filter<F>(&mut self, filter: F)
+        where F: Fn(&K, &V) -> Decision<V> + Send + Sync + 'static
+    {
+        self.compaction_filter = Some(Box::new(filter));
+    }
+
+    pub fn clear_compaction_filter(&mut self) {
+        self.compaction_filter = None;
+    }
+
+    // What merge() combines values and operands with.
+    pub fn set_merge_operator<F>(&mut self, operator: F)
+        where F: Fn(&K, Option<&V>, V) -> V + Send + Sync + 'static
+    {
+        self.merge_operator = Some(Box::new(operator));
+    }
+
+    pub fn clear_merge_operator(&mut self) {
+        self.merge_operator = None;
+    }
+
+    pub(crate) fn merge_operator(&self) -> Option<&MergeOperator<K, V>> {
+        self.merge_operator.as_ref()
+    }
+
+    // Sets `key` to what the merge operator makes of its value and `operand`, without the
+    // copy of the old value get() would hand out. Returns the version written.
+    pub fn merge(&mut self, key: K, operand: V) -> Result<u64> {
+        merge::merge(self, key, operand)
+    }
+
+    pub fn compact(&mut self) -> Result<CompactionStats> {
+        if self.compaction_filter.is_none() && self.retention.is_none() {
+            return Ok(CompactionStats::default());
+        }
+        let filter = self.compaction_filter.take();
+        let stats = compaction::compact(self, filter.as_ref());
+        self.compaction_filter = filter;
+        stats
+    }
+
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention
+    }
+
+    pub(crate) fn is_expired(&self, entry: &Entry<V>) -> bool {
+        if entry.expires != 0 && self.clock.now() >= entry.expires {
+            return true;
+        }
+        match self.retention {
+            Some(retention) if entry.written != 0 => self.clock.now().saturating_sub(entry.written) > retention.as_millis() as u64,
+            _ => false,
+        }
+    }
+
+    // Removes every entry whose TTL or retention has run out and returns how many.
+    pub fn purge_expired(&mut self) -> Result<u64> {
+        Ok(compaction::compact(self, None)?.expired)
+    }
+
+    fn write_time(&self) -> u64 {
+        match self.retention {
+            Some(_) => self.clock.now(),
+            None => 0,
+        }
+    }
+
+    // Bounds the keys under `prefix`. Usage starts from what is already stored there and is
+    // kept up to date by every write; a set() that would pass a limit fails with QuotaExceeded.
+    pub fn set_quota(&mut self, prefix: K, quota: Quota) -> Result<Usage>
+        where K: KeyPrefix
+    {
+        let range = prefix.prefix_range();
+        let mut usage = Usage::default();
+        for entry in RangeIter::new(self, range.0.clone(), range.1.clone())?.entries() {
+            let (key, entry) = entry?;
+            usage.keys += 1;
+            usage.bytes += self.entry_bytes(&key, &entry.slot)?;
+        }
+        self.quotas.insert(prefix, range, quota, usage);
+        Ok(usage)
+    }
+
+    pub fn remove_quota(&mut self, prefix: &K) -> Option<Quota> {
+        self.quotas.remove(prefix)
+    }
+
+    pub fn quota(&self, prefix: &K) -> Option<(Quota, Usage)> {
+        self.quotas.get(prefix)
+    }
+
+    fn count_write(&mut self) -> Result<()> {
+        if !self.batching {
+            self.commit_pages()?;
+        }
+        self.writes_since_analyze += 1;
+        if let Some(every) = self.analyze_every {
+            if self.writes_since_analyze >= every {
+                self.analyze()?;
+            }
+        }
+        self.check_limits()
+    }
+
+    fn check_limits(&mut self) -> Result<()> {
+        let mut limits = mem::take(&mut self.limits);
+        let checked = limits.check(self);
+        self.limits = limits;
+        checked
+    }
+
+    // Thresholds to warn about before they turn into trouble; see on_soft_limit().
+    pub fn set_soft_limits(&mut self, limits: SoftLimits) {
+        self.limits.set_limits(limits);
+    }
+
+    pub fn soft_limits(&self) -> SoftLimits {
+        self.limits.limits()
+    }
+
+    // Called from the write that takes a value over its soft limit.
+    pub fn on_soft_limit<F>(&mut self, callback: F)
+        where F: Fn(&Warning) + Send + Sync + 'static
+    {
+        self.limits.add_callback(Box::new(callback));
+    }
+
+    pub fn key_count(&self) -> u64 {
+        self.key_count
+    }
+
+    // Entries stored, kept in the meta page; expired ones count until they are swept.
+    pub fn len(&self) -> u64 {
+        self.key_count
+    }
+
+    // Entries with keys in the range, counted as len() counts them, from the leaves the
+    // range spans.
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> Result<u64> {
+        RangeIter::new(self, range.start_bound().cloned(), range.end_bound().cloned())?
+            .entries()
+            .try_fold(0, |count, entry| entry.map(|_| count + 1))
+    }
+
+    pub(crate) fn count_key(&mut self) {
+        self.key_count += 1;
+    }
+
+    pub(crate) fn count_split(&mut self) {
+        self.splits += 1;
+    }
+
+    pub(crate) fn count_merge(&mut self) {
+        self.merges += 1;
+    }
+
+    pub(crate) fn splits_and_merges(&self) -> (u64, u64) {
+        (self.splits, self.merges)
+    }
+
+    // How the tree sits on disk and how much work it has done since it was opened.
+    pub fn metrics(&self) -> Result<Metrics> {
+        metrics::collect(self)
+    }
+
+    // Entries whose value lies in `range`, in key order.
+    pub fn values_between<R: RangeBounds<V>>(&self, range: R) -> Result<ValueScan<'_, K, V, R>> {
+        ValueScan::new(self, range)
+    }
+
+    pub(crate) fn quarantine(&self) -> &Quarantine<K> {
+        &self.quarantine
+    }
+
+    pub(crate) fn zones(&self) -> Option<&Mutex<ZoneMap<V>>> {
+        self.zones.as_ref()
+    }
+
+    // Walks the tree and quarantines the key ranges whose pages can't be read any more, so
+    // the rest of the keyspace stays available. Returns the ranges found this time.
+    pub fn quarantine_corrupt(&mut self) -> Result<Vec<KeyRange<K>>> {
+        let lost = quarantine::salvage(self, false)?.lost;
+        for range in &lost {
+            self.quarantine.add(range.clone());
+        }
+        Ok(lost)
+    }
+
+    pub fn quarantined(&self) -> &[KeyRange<K>] {
+        self.quarantine.ranges()
+    }
+
+    // Rewrites the tree from everything still readable plus, for the quarantined ranges and
+    // any other unreadable ones, the entries `source` has there, e.g. from a replica or a
+    // backup, and lifts the quarantine. Returns how many entries came from `source`.
+    // The surviving entries are held in memory while the file is rewritten. Not possible
+    // in a file with column families, which the rewrite would lose.
+    pub fn repair<I: IntoIterator<Item = (K, V)>>(&mut self, source: I) -> Result<u64>
+        where K: Send, V: Send
+    {
+        if !self.families.is_empty() {
+            return Err(Error::HasColumnFamilies);
+        }
+        let quarantine::Salvaged{ mut entries, lost } = quarantine::salvage(self, true)?;
+        let mut damaged = mem::take(&mut self.quarantine);
+        lost.into_iter().for_each(|range| damaged.add(range));
+        entries.retain(|(key, _)| !damaged.covers(key));
+        let mut restored: Vec<(K, V)> = source.into_iter().filter(|(key, _)| damaged.covers(key)).collect();
+        restored.sort_by(|a, b| a.0.cmp(&b.0));
+        restored.dedup_by(|later, earlier| later.0 == earlier.0);
+        let count = restored.len() as u64;
+        entries.extend(restored);
+        let entries = self.pager.order().sorted(entries);
+
+        let mut rebuilt = BPTree::with_options(&self.path, self.options.clone())?;
+        rebuilt.seq = self.seq;
+        rebuilt.rotation = self.rotation.take();
+        rebuilt.quotas = mem::take(&mut self.quotas);
+        rebuilt.quotas.reset_usage();
+        rebuilt.compaction_filter = self.compaction_filter.take();
+        rebuilt.merge_operator = self.merge_operator.take();
+        rebuilt.limits = mem::take(&mut self.limits);
+        rebuilt.bulk_load(entries)?;
+        *self = rebuilt;
+        Ok(count)
+    }
+
+    // Checks the checksum of every reachable page, e.g. after an unclean shutdown, and how the
+    // pages fit together, and calls `progress` after every VERIFY_BATCH pages. Corrupt pages
+    // and violations are reported, not repaired.
+    pub fn verify<F: FnMut(&VerifyProgress)>(&self, mut progress: F) -> Result<VerifyReport> {
+        let mut verifier = Verifier::structural();
+        loop {
+            let done = verifier.step(self, VERIFY_BATCH)?;
+            progress(&verifier.progress(self));
+            if done {
+                return Ok(verifier.into_report());
+            }
+        }
+    }
+
+    // Ordered scan over the whole tree, pinned to the current root. See Iter for why the
+    // scan can never observe a half-applied split or merge.
+    pub fn iter_snapshot(&self) -> Result<Iter<'_, K, V>> {
+        Iter::new(self)
+    }
+
+    // Entries with keys in `range`, in key order, read leaf by leaf along the leaf chain.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<RangeIter<'_, K, V>> {
+        RangeIter::new(self, range.start_bound().cloned(), range.end_bound().cloned())
+    }
+
+    // Entries whose keys start with `prefix`, in key order. The scan starts at the leaf
+    // holding the prefix itself and stops at the first key past the prefix range.
+    pub fn scan_prefix(&self, prefix: &K) -> Result<RangeIter<'_, K, V>>
+        where K: KeyPrefix
+    {
+        self.range(prefix.prefix_range())
+    }
+
+    // Every entry in key order.
+    pub fn scan_all(&self) -> Result<Iter<'_, K, V>> {
+        self.iter_snapshot()
+    }
+
+    // A cursor off the tree, for seek(), next() and prev() to move.
+    pub fn cursor(&self) -> Result<Cursor<'_, K, V>> {
+        Cursor::new(self)
+    }
+
+    // Every entry, largest key first, following the leaves' prev pointers.
+    pub fn iter_rev(&self) -> Result<Rev<RangeIter<'_, K, V>>> {
+        Ok(self.range(..)?.rev())
+    }
+
+    // The entry with the smallest key, found down the leftmost child pointers; leaves left
+    // empty, or holding only expired entries, are passed along the leaf chain.
+    pub fn first(&self) -> Result<Option<(K, V)>> {
+        self.range(..)?.next().transpose()
+    }
+
+    // The entry with the largest key, down the rightmost child pointers.
+    pub fn last(&self) -> Result<Option<(K, V)>> {
+        self.iter_rev()?.next().transpose()
+    }
+
+    // Removes the entry with the smallest key and returns it, the way a priority queue pops.
+    pub fn pop_first(&mut self) -> Result<Option<(K, V)>> {
+        let first = self.first()?;
+        if let Some((key, _)) = &first {
+            self.remove(key)?;
+        }
+        Ok(first)
+    }
+
+    pub fn pop_last(&mut self) -> Result<Option<(K, V)>> {
+        let last = self.last()?;
+        if let Some((key, _)) = &last {
+            self.remove(key)?;
+        }
+        Ok(last)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key_count == 0
+    }
+
+    fn in_bounds<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match &self.key_bounds {
+            Some((low, high)) => {
+                let order = self.pager.order();
+                order.cmp::<K, Q>(low.borrow(), key).is_le() && order.cmp::<K, Q>(key, high.borrow()).is_le()
+            }
+            None => true,
+        }
+    }
+
+    pub fn max_key_count(&self) -> u64 {
+        self.max_key_count
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    // The options of the tree, with the settings the file keeps as it was created with them.
+    pub(crate) fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn write_once(&self) -> bool {
+        self.options.write_once
+    }
+
+    pub fn inline_threshold(&self) -> usize {
+        self.inline_threshold
+    }
+
+    pub fn split_at(&self) -> usize {
+        self.split_at
+    }
+
+    // Pages handed out so far, live or not.
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.pager.page_size()
+    }
+
+    // Pages on the free list, waiting to be handed out again.
+    pub fn free_page_count(&self) -> u64 {
+        (self.emtpy_pages.len() + self.free_chain.len()) as u64
+    }
+
+    // Pages the tree must not reference: the free list and the pages it spilled onto.
+    pub(crate) fn free_pages(&self) -> impl Iterator<Item = PagePtr> + '_ {
+        self.emtpy_pages.iter().chain(&self.free_chain).copied()
+    }
+
+    // The kind of every page in the file, read from the file itself, e.g. for space accounting.
+    pub fn allocation_map(&self) -> Result<AllocationMap> {
+        allocation::allocation_map(self.pager(), self.page_count, &self.emtpy_pages)
+    }
+
+    // Hands out freed pages first and only grows the file when there are none.
+    pub fn next_page_ptr(&mut self) -> PagePtr {
+        if let Some(ptr) = self.emtpy_pages.pop() {
+            return ptr;
+        }
+        let next_ptr = self.page_count;
+        self.page_count += 1;
+        next_ptr
+    }
+
+    fn create_root_node(&mut self) -> Node<K,V> {
+        self.root_ptr = Some(self.next_page_ptr());
+        Node::new_leaf(self.root_ptr.unwrap(), &[], &[], None)
+    }
+
+    fn create_new_root(&mut self, key: K, new_page_ptr: PagePtr) -> Result<()> {
+        let old_root_ptr = self.root_ptr.unwrap();
+        self.root_ptr = Some(self.next_page_ptr());
+        let new_root: Node<K,V> = Node::new_inner(self.root_ptr.unwrap(), &[key], &[old_root_ptr, new_page_ptr]);
+        new_root.store_node(self.get_pager())?;
+        Ok(())
+    }
+
+    fn load_root(&self) -> Result<Node<K, V>> {
+        match self.root_ptr {
+            None => Err(Error::RootPageIsNull),
+            Some(ptr) => Node::load_node(ptr, self.pager())
+        }
+    }
+
+    pub fn pager(&self) -> &Pager {
+        &self.pager
+    }
+
+    pub fn get_pager(&mut self) -> &mut Pager {
+        self.pager.borrow_mut()
+    }
+
+    pub fn root_ptr(&self) -> Option<PagePtr> {
+        self.root_ptr
+    }
+
+    pub fn set_root(&mut self, new_root_ptr: Option<PagePtr>) {
+        self.root_ptr = new_root_ptr;
+    }
+
+    pub(crate) fn shared_values(&mut self) -> Option<&mut SharedValues> {
+        self.shared_values.as_mut()
+    }
+
+    pub fn delete_page(&mut self, ptr: PagePtr){
+        if let Some(heat) = &mut self.heat {
+            heat.get_mut().unwrap().forget(ptr);
+        }
+        self.forget_zone(ptr);
+        self.emtpy_pages.push(ptr);
+    }
+
+    pub(crate) fn record_write(&mut self, leaf: PagePtr) {
+        if let Some(heat) = &mut self.heat {
+            heat.get_mut().unwrap().record_write(leaf);
+        }
+        self.forget_zone(leaf);
+    }
+
+    // For leaves that change without being the target of the write, like siblings in a merge.
+    pub(crate) fn forget_zone(&mut self, leaf: PagePtr) {
+        if let Some(zones) = &mut self.zones {
+            zones.get_mut().unwrap().forget(leaf);
+        }
+    }
+
+    pub fn print_deleted(&self) {
+        println!("{:?}", self.emtpy_pages);
+    }
+}
+
+fn configure_pager(pager: Pager, options: &Options, staging: bool) -> Pager {
+    let pager = pager
+        .with_page_size(options.page_size.unwrap_or(DEFAULT_PAGE_SIZE))
+        .with_staging(staging)
+        .with_compression(options.compression)
+        .with_comparator(options.comparator.clone())
+        .with_cache(options.cache_pages, options.cache_admission)
+        .with_write_back(options.write_back)
+        .with_memory_pressure(options.memory_pressure.clone());
+    #[cfg(feature = "mmap")]
+    let pager = pager.with_mmap(options.mmap);
+    pager
+}
+
+pub(crate) fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Sizes of the fewest chunks of at most `max` items covering `len` items, as equal as possible.
+fn even_split(len: usize, max: usize) -> Vec<usize> {
+    let count = len.div_ceil(max);
+    (0..count).map(|i| len / count + usize::from(i < len % count)).collect()
+}
+
+// Groups items of the given byte sizes into nodes: evenly when every group fits `capacity`,
+// otherwise greedily, each group taking items until the next would not fit. Inner nodes
+// need two children, so the last group then takes one from the one before if it must.
+fn pack(bytes: &[u64], max: usize, capacity: u64, min: usize) -> Vec<usize> {
+    let even = even_split(bytes.len(), max);
+    let mut start = 0;
+    let fits = even.iter().all(|size| {
+        start += size;
+        bytes[start - size..start].iter().sum::<u64>() <= capacity
+    });
+    if fits {
+        return even;
+    }
+    let mut sizes = Vec::new();
+    let (mut count, mut total) = (0, 0);
+    for size in bytes {
+        if count > 0 && (count == max || total + size > capacity) {
+            sizes.push(count);
+            count = 0;
+            total = 0;
+        }
+        count += 1;
+        total += size;
+    }
+    if let (true, Some(last)) = (count < min, sizes.last_mut()) {
+        *last -= min - count;
+        count = min;
+    }
+    sizes.push(count);
+    sizes
+}
+
+// Splits the items into up to `parts` runs covering disjoint, increasing key ranges, with
+// split keys picked from a sample. Items with the same key end up in the same run, in
+// their original order.
+fn partition_by_range<K: Ord + Clone, T, F: Fn(&T) -> &K>(items: Vec<T>, parts: usize, key: F) -> Vec<Vec<T>> {
+    if parts <= 1 || items.len() < 2 * parts {
+        return vec![items];
+    }
+    let mut sample: Vec<K> = items.iter().step_by((items.len() / (parts * 16)).max(1)).map(|item| key(item).clone()).collect();
+    sample.sort();
+    let mut splits: Vec<K> = (1..parts).map(|i| sample[i * sample.len() / parts].clone()).collect();
+    splits.dedup();
+    let mut runs: Vec<Vec<T>> = (0..=splits.len()).map(|_| Vec::with_capacity(items.len() / parts)).collect();
+    for item in items {
+        runs[splits.partition_point(|split| split <= key(&item))].push(item);
+    }
+    runs
+}
+
+// Stable sort by key, with each key range sorted on its own scoped thread.
+fn par_sort_by_key<K, T, F>(items: Vec<T>, threads: usize, key: F) -> Vec<T>
+    where K: Ord + Clone, T: Send, F: Fn(&T) -> &K + Sync
+{
+    let key = &key;
+    let runs = partition_by_range(items, threads, key);
+    thread::scope(|scope| {
+        let workers: Vec<_> = runs.into_iter()
+            .map(|mut run| scope.spawn(move || {
+                run.sort_by(|a, b| key(a).cmp(key(b)));
+                run
+            }))
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    })
+}
+
+impl<K> BPTree<K, Vec<u8>>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    // Streams a byte value instead of materializing it. Values kept in overflow pages are
+    // read one page at a time, so this works for values far larger than memory allows.
+    pub fn get_reader<Q>(&self, key: &Q) -> Result<ValueReader<'_>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        let leaf = self.leaf_for(key)?;
+        match leaf.slot(key, self.pager()) {
+            None => Err(Error::KeyNotFound),
+            Some(Slot::Inline(value)) => Ok(ValueReader::Inline(io::Cursor::new(value.clone()))),
+            Some(Slot::Overflow(overflow)) => {
+                let mut reader = OverflowReader::new(overflow, self.pager());
+                // Skip the length prefix the codec writes in front of the bytes.
+                reader.read_exact(&mut [0u8; 8])?;
+                Ok(ValueReader::Overflow(reader))
+            }
+        }
+    }
+
+    // Counterpart of get_reader: the value is written straight to overflow pages as it
+    // arrives and replaces any existing value for `key` once the writer is committed.
+    // `len_hint` only decides whether to start spilling right away.
+    pub fn put_writer(&mut self, key: K, len_hint: u64) -> Result<ValueWriter<'_, K>> {
+        ValueWriter::new(self, key, len_hint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::engine::clock::ManualClock;
+    use super::*;
+
+    #[test]
+    fn test_shrink_to_empty_and_regrow() -> Result<()> {
+        let path = Path::new("data").join("test_shrink.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        assert!(bptree.is_empty());
+        for round in 0..2 {
+            for i in 0..100 {
+                bptree.set(i, i + round)?;
+            }
+            assert!(!bptree.is_empty());
+            for i in (0..100).rev() {
+                bptree.remove(&i)?;
+            }
+            assert!(bptree.is_empty());
+            assert_eq!(bptree.root_ptr(), None);
+            let mut freed = bptree.emtpy_pages.clone();
+            freed.sort_unstable();
+            freed.dedup();
+            // Everything but the meta page.
+            assert_eq!(freed.len() as u64, bptree.page_count - 1);
+        }
+        bptree.set(7, 7)?;
+        assert_eq!(bptree.get(&7)?, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_borrowed_key_lookup() -> Result<()> {
+        let path = Path::new("data").join("test_borrowed.db");
+        let mut bptree: BPTree<String, u64> = BPTree::new(path, Some(4))?;
+        for i in 0..50u64 {
+            bptree.set(format!("key{:02}", i), i)?;
+        }
+        assert_eq!(bptree.get("key07")?, 7);
+        bptree.remove("key07")?;
+        assert!(matches!(bptree.get("key07"), Err(Error::KeyNotFound)));
+        assert!(matches!(bptree.get("zzz"), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_with_borrows_value() -> Result<()> {
+        let path = Path::new("data").join("test_get_with.db");
+        let mut bptree: BPTree<u64, String> = BPTree::new(path, Some(4))?;
+        for i in 0..20u64 {
+            bptree.set(i, "x".repeat(i as usize))?;
+        }
+        assert_eq!(bptree.get_with(&12, |v| v.len())?, 12);
+        assert!(matches!(bptree.get_with(&99, |v| v.len()), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_empty_root() -> Result<()> {
+        let path = Path::new("data").join("test_keep_root.db");
+        let options = Options{ max_key_count: Some(4), keep_empty_root: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(path, options)?;
+        bptree.set(1, 1)?;
+        bptree.remove(&1)?;
+        assert!(bptree.is_empty());
+        assert!(bptree.root_ptr().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_reader_streams_overflow_values() -> Result<()> {
+        let path = Path::new("data").join("test_get_reader.db");
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(path, Some(4))?;
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        for i in 0..10u64 {
+            bptree.set(i, vec![i as u8; 16])?;
+        }
+        bptree.set(5, big.clone())?;
+
+        let mut streamed = Vec::new();
+        let mut reader = bptree.get_reader(&5)?;
+        let mut chunk = [0u8; 1000];
+        loop {
+            match reader.read(&mut chunk)? {
+                0 => break,
+                n => streamed.extend_from_slice(&chunk[..n]),
+            }
+        }
+        assert_eq!(streamed, big);
+        assert_eq!(bptree.get(&5)?, big);
+        let mut small = Vec::new();
+        bptree.get_reader(&3)?.read_to_end(&mut small)?;
+        assert_eq!(small, vec![3u8; 16]);
+        assert_eq!(bptree.iter_snapshot()?.count(), 10);
+
+        for i in 0..10u64 {
+            bptree.remove(&i)?;
+        }
+        let mut freed = bptree.emtpy_pages.clone();
+        freed.sort_unstable();
+        freed.dedup();
+        // Everything but the meta page.
+        assert_eq!(freed.len() as u64, bptree.page_count - 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_writer_round_trip() -> Result<()> {
+        let path = Path::new("data").join("test_put_writer.db");
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(path, Some(4))?;
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        for (key, hint) in [(1u64, 0u64), (2, big.len() as u64)] {
+            let mut writer = bptree.put_writer(key, hint)?;
+            for chunk in big.chunks(777) {
+                writer.write_all(chunk)?;
+            }
+            writer.commit()?;
+        }
+        let mut writer = bptree.put_writer(3, 0)?;
+        writer.write_all(b"small")?;
+        writer.commit()?;
+        assert_eq!(bptree.get(&1)?, big);
+        assert_eq!(bptree.get(&3)?, b"small".to_vec());
+        let mut streamed = Vec::new();
+        bptree.get_reader(&2)?.read_to_end(&mut streamed)?;
+        assert_eq!(streamed, big);
+
+        let pages = bptree.page_count;
+        let mut writer = bptree.put_writer(4, 0)?;
+        writer.write_all(&big)?;
+        drop(writer);
+        assert!(matches!(bptree.get(&4), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.emtpy_pages.len() as u64, bptree.page_count - pages);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_shares_identical_values() -> Result<()> {
+        let path = Path::new("data").join("test_dedup.db");
+        let options = Options{ max_key_count: Some(4), dedup_values: true, ..Options::default() };
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(path, options)?;
+        let payload = vec![7u8; 20_000];
+        for i in 0..8u64 {
+            bptree.set(i, payload.clone())?;
+        }
+        let mut writer = bptree.put_writer(8, 0)?;
+        writer.write_all(&payload)?;
+        writer.commit()?;
+        let pages = bptree.page_count;
+        let mut reader = bptree.get_reader(&8)?;
+        let mut streamed = Vec::new();
+        reader.read_to_end(&mut streamed)?;
+        assert_eq!(streamed, payload);
+        // One chain for all nine keys, plus the leaves and inner nodes.
+        assert!(pages - bptree.emtpy_pages.len() as u64 <= 5 + 6);
+
+        bptree.set(3, vec![1u8; 20_000])?;
+        assert_eq!(bptree.get(&3)?, vec![1u8; 20_000]);
+        assert_eq!(bptree.get(&4)?, payload);
+        for i in 0..9u64 {
+            bptree.remove(&i)?;
+        }
+        let mut freed = bptree.emtpy_pages.clone();
+        freed.sort_unstable();
+        freed.dedup();
+        // Everything but the meta page.
+        assert_eq!(freed.len() as u64, bptree.page_count - 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_load() -> Result<()> {
+        let path = Path::new("data").join("test_bulk_load.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        assert!(matches!(bptree.bulk_load(vec![(2, 2), (1, 1)]), Err(Error::UnsortedInput)));
+        let items: Vec<(u64, u64)> = (0..5000).map(|i| (i * 2, i)).collect();
+        bptree.bulk_load_with_threads(items.clone(), 4)?;
+        let scanned = bptree.iter_snapshot()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(scanned, items);
+        assert_eq!(bptree.get(&1234)?, 617);
+        assert!(matches!(bptree.get(&1235), Err(Error::KeyNotFound)));
+        assert!(matches!(bptree.bulk_load(vec![(1, 1)]), Err(Error::TreeNotEmpty)));
+
+        for i in 0..5000 {
+            bptree.set(i * 2 + 1, i)?;
+        }
+        for i in 0..5000 {
+            bptree.remove(&(i * 2))?;
+        }
+        let keys = bptree.iter_snapshot()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (0..5000).map(|i| i * 2 + 1).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_par_range_covers_range_once() -> Result<()> {
+        let path = Path::new("data").join("test_par_range.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        bptree.bulk_load((0..2000).map(|i| (i, i * 3)).collect())?;
+        for shards in [1, 3, 8, 64] {
+            let cursors = bptree.par_range(100..1900, shards)?;
+            assert!(cursors.len() <= shards);
+            let parts = thread::scope(|scope| {
+                let workers: Vec<_> = cursors.into_iter()
+                    .map(|cursor| scope.spawn(move || cursor.collect::<Result<Vec<_>>>()))
+                    .collect();
+                workers.into_iter().map(|worker| worker.join().unwrap()).collect::<Result<Vec<_>>>()
+            })?;
+            let keys: Vec<u64> = parts.concat().into_iter().map(|(k, _)| k).collect();
+            assert_eq!(keys, (100..1900).collect::<Vec<_>>());
+        }
+        assert!(bptree.par_range(.., 16)?.len() > 1);
+        assert_eq!(bptree.par_range(5000.., 4)?.into_iter().flatten().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_get() -> Result<()> {
+        let options = Options{ max_key_count: Some(8), track_heat: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(Path::new("data").join("test_multi_get.db"), options)?;
+        bptree.bulk_load((0..1000u64).map(|i| (i * 2, i)).collect())?;
+        let keys: Vec<u64> = (0..400u64).map(|i| (i * 7919) % 400).chain([10, 5000]).collect();
+        let results = bptree.multi_get(&keys);
+        for (key, result) in keys.iter().zip(&results) {
+            match key % 2 {
+                0 if *key < 2000 => assert_eq!(result.as_ref().ok(), Some(&(key / 2))),
+                _ => assert!(matches!(result, Err(Error::KeyNotFound)), "key {}", key),
+            }
+        }
+        let descents: u64 = bptree.heat_map()?.iter().map(|leaf| leaf.reads).sum();
+        assert!(descents * 4 < keys.len() as u64, "{}", descents);
+        Ok(())
+    }
+
+    #[test]
+    fn test_par_batches() -> Result<()> {
+        let mut bptree: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_par_batches.db"), Some(8))?;
+        let shuffled: Vec<(u64, u64)> = (0..3000u64).map(|i| ((i * 7919) % 3000, i)).collect();
+        bptree.par_set_batch_with_threads(shuffled, 4)?;
+        let mut updates: Vec<(u64, u64)> = (0..1000u64).rev().map(|i| (i * 5, 1)).collect();
+        updates.push((0, 2));
+        bptree.par_set_batch_with_threads(updates, 3)?;
+
+        let keys: Vec<u64> = (0..60).rev().map(|i| i * 101).collect();
+        let values = bptree.par_get_many_with_threads(&keys, 4)?;
+        assert_eq!(values.len(), 60);
+        for (key, value) in keys.iter().zip(values) {
+            let expected = match *key {
+                0 => Some(2),
+                key if key % 5 == 0 && key < 5000 => Some(1),
+                key if key < 3000 => Some((0..3000u64).find(|i| (i * 7919) % 3000 == key).unwrap()),
+                _ => None,
+            };
+            assert_eq!(value, expected, "key {}", key);
+        }
+        assert_eq!(bptree.iter_snapshot()?.count(), 3000 + 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyze_estimates_ranges() -> Result<()> {
+        let path = Path::new("data").join("test_analyze.db");
+        let options = Options{ max_key_count: Some(64), analyze_every: Some(1000), ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(&path, options)?;
+        assert!(bptree.stats().is_none());
+        for i in 0..999 {
+            bptree.set(i, i)?;
+        }
+        assert!(bptree.stats().is_none());
+        bptree.set(999, 999)?;
+        let stats = bptree.stats().unwrap().clone();
+        assert_eq!(stats.row_count, 1000);
+        assert!((stats.avg_value_size - 8.0).abs() < f64::EPSILON);
+        assert_eq!(stats.histogram.iter().map(|bucket| bucket.rows).sum::<u64>(), 1000);
+        assert_eq!(bptree.estimate_range(..)?, 1000);
+        let half = bptree.estimate_range(0..500)?;
+        assert!((450..=550).contains(&half), "{}", half);
+        assert!(bptree.estimate_range(2000..)? <= 32);
+        assert!(path.with_extension("stats").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions_follow_writes() -> Result<()> {
+        let path = Path::new("data").join("test_versions.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        let mut expected = std::collections::BTreeMap::new();
+        let mut last = 0;
+        for i in 0..600u64 {
+            let key = (i * 37) % 200;
+            let version = bptree.set(key, i)?;
+            assert!(version > last);
+            last = version;
+            expected.insert(key, (i, version));
+            if i % 3 == 0 {
+                let gone = (i * 11) % 200;
+                if bptree.remove(&gone).is_ok() {
+                    expected.remove(&gone);
+                }
+            }
+        }
+        for (key, (value, version)) in &expected {
+            assert_eq!(bptree.get_versioned(key)?, (*value, *version));
+            assert_eq!(bptree.version(key)?, *version);
+        }
+        assert!(matches!(bptree.version(&1000), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_if_version() -> Result<()> {
+        let path = Path::new("data").join("test_set_if_version.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        let v1 = bptree.set_if_version(1, 0, 10)?;
+        assert!(matches!(bptree.set_if_version(1, 0, 11), Err(Error::VersionMismatch{ expected: 0, actual }) if actual == v1));
+        let v2 = bptree.set_if_version(1, v1, 12)?;
+        assert!(matches!(bptree.set_if_version(1, v1, 13), Err(Error::VersionMismatch{ .. })));
+        assert_eq!(bptree.get_versioned(&1)?, (12, v2));
+        bptree.remove(&1)?;
+        bptree.set_if_version(1, 0, 14)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_if() -> Result<()> {
+        let path = Path::new("data").join("test_set_if.db");
+        let mut bptree: BPTree<u64, String> = BPTree::new(path, Some(4))?;
+        assert!(bptree.set_if(1, None, "a".to_string())?);
+        assert!(!bptree.set_if(1, None, "b".to_string())?);
+        assert!(!bptree.set_if(1, Some(&"b".to_string()), "c".to_string())?);
+        assert!(bptree.set_if(1, Some(&"a".to_string()), "c".to_string())?);
+        assert!(!bptree.set_if(2, Some(&"c".to_string()), "d".to_string())?);
+        assert_eq!(bptree.get(&1)?, "c");
+        assert!(matches!(bptree.get(&2), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_len_and_count_range() -> Result<()> {
+        let path = Path::new("data").join("test_len.db");
+        let mut bptree: BPTree<u64, String> = BPTree::new(&path, Some(4))?;
+        assert!(bptree.is_empty());
+        for i in 0..500u64 {
+            bptree.set(i, format!("value-{}", i))?;
+        }
+        bptree.set(7, "again".to_string())?;
+        bptree.remove(&8)?;
+        bptree.remove_range(100..200)?;
+        assert_eq!(bptree.len(), 399);
+        assert_eq!(bptree.count_range(..)?, 399);
+        assert_eq!(bptree.count_range(0..10)?, 9);
+        assert_eq!(bptree.count_range(150..=250)?, 51);
+        assert_eq!(bptree.count_range(600..)?, 0);
+        bptree.close()?;
+
+        let bptree: BPTree<u64, String> = BPTree::open(&path)?;
+        assert_eq!(bptree.len(), 399);
+        assert_eq!(bptree.count_range(490..)?, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_last_and_pop() -> Result<()> {
+        let path = Path::new("data").join("test_pop.db");
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let options = Options{ max_key_count: Some(4), clock: Some(clock.clone()), ..Options::default() };
+        let mut queue: BPTree<u64, String> = BPTree::with_options(&path, options)?;
+        assert_eq!(queue.first()?, None);
+        assert_eq!(queue.pop_last()?, None);
+        for i in 0..100u64 {
+            queue.set((i * 37) % 100, format!("job-{}", i))?;
+        }
+        for i in 0..5u64 {
+            queue.set_with_ttl(i, "soon gone".to_string(), Duration::from_secs(1))?;
+        }
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(queue.first()?.map(|(key, _)| key), Some(5));
+        assert_eq!(queue.last()?.map(|(key, _)| key), Some(99));
+        let popped = (0..10).map(|_| queue.pop_first().map(|entry| entry.unwrap().0)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(popped, (5..15).collect::<Vec<_>>());
+        assert_eq!(queue.pop_last()?.map(|(key, _)| key), Some(99));
+        assert_eq!(queue.last()?.map(|(key, _)| key), Some(98));
+        assert_eq!(queue.len(), 89);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_load_iter() -> Result<()> {
+        let path = Path::new("data").join("test_bulk_load_iter.db");
+        let mut bptree: BPTree<u64, String> = BPTree::new(path, Some(8))?;
+        let unsorted = (0..1000u64).map(|i| (if i == 700 { 3 } else { i }, i.to_string()));
+        assert!(matches!(bptree.bulk_load_iter(unsorted), Err(Error::UnsortedInput)));
+        assert!(bptree.is_empty() && bptree.root_ptr().is_none());
+        assert!(bptree.free_page_count() >= 700 / 8);
+
+        bptree.bulk_load_iter((0..20_000u64).map(|i| (i * 3, i.to_string())))?;
+        assert_eq!(bptree.len(), 20_000);
+        assert_eq!(bptree.get(&2997)?, "999");
+        assert!(matches!(bptree.get(&2998), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.last()?, Some((59_997, "19999".to_string())));
+        assert_eq!(bptree.iter_rev()?.count(), 20_000);
+        assert!(bptree.verify(|_| ())?.is_ok());
+        for i in 0..100u64 {
+            bptree.set(i * 3 + 1, "between".to_string())?;
+        }
+        assert_eq!(bptree.count_range(..300)?, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_prefix() -> Result<()> {
+        let path = Path::new("data").join("test_remove_prefix.db");
+        let mut bptree: BPTree<String, u64> = BPTree::new(path, Some(4))?;
+        for tenant in ["a", "b", "bb", "c"] {
+            for i in 0..40 {
+                bptree.set(format!("{}/{:03}", tenant, i), i)?;
+            }
+        }
+        assert_eq!(bptree.remove_prefix(&String::from("b/"))?, 40);
+        assert_eq!(bptree.remove_prefix(&String::from("b/"))?, 0);
+        let keys = bptree.iter_snapshot()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys.len(), 120);
+        assert!(keys.iter().all(|key| !key.starts_with("b/")));
+        assert_eq!(bptree.remove_range(String::from("c")..)?, 40);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> Result<()> {
+        let path = Path::new("data").join("test_scan_prefix.db");
+        let mut bptree: BPTree<Vec<u8>, u64> = BPTree::new(path, Some(4))?;
+        for first in [0x00u8, 0x61, 0x62, 0xff] {
+            for i in 0..30u8 {
+                bptree.set(vec![first, i], i as u64)?;
+            }
+        }
+        bptree.set(vec![0x61], 100)?;
+        let keys = bptree.scan_prefix(&vec![0x61])?.map(|entry| entry.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys.len(), 31);
+        assert_eq!((keys[0].clone(), keys[30].clone()), (vec![0x61], vec![0x61, 29]));
+        // A prefix of only 0xff bytes has no successor and runs to the end of the tree.
+        assert_eq!(bptree.scan_prefix(&vec![0xff])?.count(), 30);
+        assert_eq!(bptree.scan_prefix(&vec![0x62, 7])?.next().transpose()?, Some((vec![0x62, 7], 7)));
+        assert_eq!(bptree.scan_prefix(&vec![0x63])?.count(), 0);
+        assert_eq!(bptree.scan_prefix(&Vec::new())?.count(), 121);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quotas() -> Result<()> {
+        let path = Path::new("data").join("test_quotas.db");
+        let mut bptree: BPTree<String, u64> = BPTree::new(path, Some(4))?;
+        for i in 0..5 {
+            bptree.set(format!("a/{:03}", i), i)?;
+        }
+        // "a/000" encodes to 13 bytes and its value to 8.
+        let usage = bptree.set_quota(String::from("a/"), Quota{ max_keys: Some(8), max_bytes: None })?;
+        assert_eq!(usage, Usage{ keys: 5, bytes: 5 * 21 });
+        for i in 5..8 {
+            bptree.set(format!("a/{:03}", i), i)?;
+        }
+        assert!(matches!(bptree.set(String::from("a/008"), 8), Err(Error::QuotaExceeded)));
+        assert!(matches!(bptree.get("a/008"), Err(Error::KeyNotFound)));
+        bptree.set(String::from("a/000"), 100)?;
+        bptree.remove("a/001")?;
+        bptree.set(String::from("a/008"), 8)?;
+        assert_eq!(bptree.quota(&String::from("a/")).unwrap().1, Usage{ keys: 8, bytes: 8 * 21 });
+
+        bptree.set_quota(String::from("b/"), Quota{ max_keys: None, max_bytes: Some(2 * 21) })?;
+        bptree.set(String::from("b/000"), 0)?;
+        bptree.set(String::from("b/001"), 1)?;
+        assert!(matches!(bptree.set(String::from("b/002"), 2), Err(Error::QuotaExceeded)));
+        bptree.set(String::from("c/000"), 0)?;
+        assert_eq!(bptree.remove_prefix(&String::from("b/"))?, 2);
+        assert_eq!(bptree.quota(&String::from("b/")).unwrap().1, Usage::default());
+
+        assert!(bptree.remove_quota(&String::from("a/")).is_some());
+        bptree.set(String::from("a/009"), 9)?;
+        assert_eq!(bptree.quota(&String::from("a/")), None);
+
+        let mut loaded: BPTree<String, u64> = BPTree::new(Path::new("data").join("test_quotas_bulk.db"), Some(4))?;
+        loaded.set_quota(String::from("a/"), Quota{ max_keys: Some(8), max_bytes: None })?;
+        let items: Vec<(String, u64)> = (0..10).map(|i| (format!("a/{:03}", i), i)).collect();
+        assert!(matches!(loaded.bulk_load_iter(items.clone()), Err(Error::QuotaExceeded)));
+        assert!(matches!(loaded.bulk_load(items.clone()), Err(Error::QuotaExceeded)));
+        assert!(loaded.is_empty());
+        assert_eq!(loaded.quota(&String::from("a/")).unwrap().1, Usage::default());
+        loaded.bulk_load(items[..8].to_vec())?;
+        assert_eq!(loaded.quota(&String::from("a/")).unwrap().1, Usage{ keys: 8, bytes: 8 * 21 });
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_until() -> Result<()> {
+        let path = Path::new("data").join("test_flush_until.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        let first = bptree.set(1, 1)?;
+        let second = bptree.set(2, 2)?;
+        let removed = bptree.remove(&1)?;
+        assert!(first < second && second < removed);
+        assert_eq!(bptree.last_seq(), removed);
+        assert_eq!(bptree.durable_seq(), 0);
+
+        assert_eq!(bptree.flush_until(second)?, removed);
+        let third = bptree.set(3, 3)?;
+        assert_eq!(bptree.flush_until(second)?, removed);
+        assert!(bptree.durable_seq() < third);
+        assert_eq!(bptree.flush()?, third);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_filter() -> Result<()> {
+        let path = Path::new("data").join("test_compaction_filter.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        for i in 0..100 {
+            bptree.set(i, i)?;
+        }
+        assert_eq!(bptree.compact()?, CompactionStats{ scanned: 0, dropped: 0, replaced: 0, expired: 0 });
+        bptree.set_compaction_filter(|key, value| match key % 10 {
+            0 => Decision::Drop,
+            5 => Decision::Replace(value * 2),
+            _ => Decision::Keep,
+        });
+        assert_eq!(bptree.compact()?, CompactionStats{ scanned: 100, dropped: 10, replaced: 10, expired: 0 });
+        assert!(matches!(bptree.get(&20), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.get(&25)?, 50);
+        assert_eq!(bptree.get(&26)?, 26);
+        assert_eq!(bptree.compact()?.dropped, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_heat_map() -> Result<()> {
+        let path = Path::new("data").join("test_heat_map.db");
+        let options = Options{ max_key_count: Some(8), track_heat: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(path, options)?;
+        for i in 0..200 {
+            bptree.set(i, i)?;
+        }
+        for _ in 0..50 {
+            for key in 150..154 {
+                bptree.get(&key)?;
+            }
+        }
+        let map = bptree.heat_map()?;
+        assert!(map.windows(2).all(|pair| pair[0].last_key < pair[1].first_key));
+        let hot = map.iter().find(|leaf| leaf.first_key <= 150 && 150 <= leaf.last_key).unwrap();
+        assert!(hot.reads >= 50);
+        assert_eq!(map.iter().map(|leaf| leaf.reads).max(), Some(hot.reads));
+        assert_eq!(map.iter().map(|leaf| leaf.writes).sum::<u64>(), 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention() -> Result<()> {
+        let options = Options{ max_key_count: Some(4), retention: Some(Duration::from_millis(200)), ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(Path::new("data").join("test_retention.db"), options)?;
+        for i in 0..10u64 {
+            bptree.set(i, i)?;
+        }
+        thread::sleep(Duration::from_millis(300));
+        for i in 5..15u64 {
+            bptree.set(i, i * 10)?;
+        }
+        assert!(matches!(bptree.get(&0), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.get(&7)?, 70);
+        let keys = bptree.iter_snapshot()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (5..15).collect::<Vec<_>>());
+        assert_eq!(bptree.par_range(..8, 2)?.into_iter().flatten().count(), 3);
+
+        let stats = bptree.compact()?;
+        assert_eq!((stats.scanned, stats.expired), (15, 5));
+        assert_eq!(bptree.remove_range(..)?, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_finds_bounds_past_empty_edge_leaves() -> Result<()> {
+        let path = Path::new("data").join("test_open_bounds.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(&path, Some(4))?;
+        for i in 0..40u64 {
+            bptree.set(i, i)?;
+        }
+        // Leaves emptied in place, the way a cut at either end of the tree can leave them.
+        for last in [false, true] {
+            let leaf = bptree.edge_leaf(bptree.root_ptr(), last)?.unwrap();
+            let (ptr, prev, next) = (leaf.ptr(), leaf.prev(), leaf.next());
+            LeafNode::<u64, u64>::from_parts(ptr, vec![], vec![], prev, next).store_node_to_page(bptree.pager())?;
+        }
+        bptree.close()?;
+
+        let mut bptree: BPTree<u64, u64> = BPTree::open(&path)?;
+        bptree.set(20, 200)?;
+        assert_eq!(bptree.get(&10)?, 10);
+        assert_eq!(bptree.get(&30)?, 30);
+        bptree.remove(&31)?;
+        assert!(matches!(bptree.get(&31), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_and_restore() -> Result<()> {
+        let path = Path::new("data").join("test_backup.db");
+        let backup = Path::new("data").join("test_backup.bak");
+        // Written pages wait in the cache, so the backup has to find them there.
+        let options = Options{ max_key_count: Some(4), cache_pages: 64, write_back: true, ..Options::default() };
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(&path, options.clone())?;
+        for i in 0..1500u64 {
+            bptree.set(i, vec![i as u8; if i % 100 == 0 { 5000 } else { 8 }])?;
+        }
+        for i in (0..1500u64).filter(|i| i % 4 != 0) {
+            bptree.remove(&i)?;
+        }
+        assert_eq!(bptree.backup_to(&backup)?, bptree.page_count());
+        bptree.set(1, vec![1])?;
+        bptree.remove(&0)?;
+
+        let restored_path = Path::new("data").join("test_backup_restored.db");
+        let restored: BPTree<u64, Vec<u8>> = BPTree::restore_from(&backup, &restored_path, options.clone())?;
+        assert_eq!(restored.key_count(), 375);
+        assert_eq!(restored.get(&0)?, vec![0; 5000]);
+        assert!(matches!(restored.get(&1), Err(Error::KeyNotFound)));
+        assert_eq!(restored.iter_snapshot()?.count(), 375);
+        assert!(restored.verify(|_| ())?.corrupt.is_empty());
+        // Enough free pages to spill the list off the meta page.
+        assert!(restored.free_page_count() > 256);
+        drop(restored);
+        assert!(matches!(BPTree::<u64, Vec<u8>>::restore_from(restored_path.with_extension("missing"), &restored_path, options), Err(Error::IOError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttl() -> Result<()> {
+        let path = Path::new("data").join("test_ttl.db");
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let options = Options{ max_key_count: Some(4), change_log: true, clock: Some(clock.clone()), ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(&path, options.clone())?;
+        for i in 0..20u64 {
+            match i % 2 {
+                0 => bptree.set_with_ttl(i, i, Duration::from_secs(60))?,
+                _ => bptree.set(i, i)?,
+            };
+        }
+        bptree.set_with_ttl(1, 10, Duration::from_secs(120))?;
+        // Setting without a TTL drops the one the key had.
+        bptree.set(2, 20)?;
+        clock.advance(Duration::from_secs(61));
+        assert!(matches!(bptree.get(&0), Err(Error::KeyNotFound)));
+        assert_eq!((bptree.get(&1)?, bptree.get(&2)?), (10, 20));
+        let keys = bptree.range(..10)?.map(|entry| entry.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![1, 2, 3, 5, 7, 9]);
+        assert_eq!(bptree.purge_expired()?, 9);
+        bptree.close()?;
+
+        // The expiry is stored with the entry and logged with the write.
+        let mut bptree: BPTree<u64, u64> = BPTree::open_with_options(&path, options)?;
+        assert!(matches!(bptree.changes(0)?.nth(1).transpose()?.map(|event| event.change), Some(Change::Set(1, 1))));
+        assert_eq!(bptree.key_count(), 11);
+        clock.advance(Duration::from_secs(60));
+        assert!(matches!(bptree.get(&1), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.purge_expired()?, 1);
+        let expiring = bptree.changes(0)?.filter_map(|event| match event.map(|event| event.change) {
+            Ok(Change::SetExpiring(key, _, expires)) => Some(Ok((key, expires))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }).collect::<Result<Vec<_>>>()?;
+        assert_eq!(expiring.len(), 11);
+        assert_eq!(expiring[10], (1, 1_120_000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_threshold() -> Result<()> {
+        let path = Path::new("data").join("test_inline_threshold.db");
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(&path, Some(8))?;
+        for i in 0..4u64 {
+            bptree.set(i, vec![i as u8; 100])?;
+        }
+        // The meta page and the leaf.
+        assert_eq!(bptree.page_count, 2);
+
+        let options = Options{ max_key_count: Some(8), inline_threshold: Some(64), ..Options::default() };
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(&path, options)?;
+        for i in 0..4u64 {
+            bptree.set(i, vec![i as u8; 100])?;
+        }
+        bptree.set(4, vec![4; 32])?;
+        assert_eq!(bptree.page_count, 2 + 4);
+        assert_eq!(bptree.get(&3)?, vec![3; 100]);
+        let mut writer = bptree.put_writer(5, 0)?;
+        writer.write_all(&[5; 80])?;
+        writer.commit()?;
+        assert_eq!(bptree.page_count, 2 + 5);
+        assert_eq!(bptree.get(&5)?, vec![5; 80]);
+
+        let options = Options{ inline_threshold: Some(overflow::inline_threshold_limit(DEFAULT_PAGE_SIZE) + 1), ..Options::default() };
+        assert!(matches!(BPTree::<u64, Vec<u8>>::with_options(&path, options), Err(Error::PageSizeNotEnough)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_node_capacity() -> Result<()> {
+        let mut bptree: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_default_capacity.db"), None)?;
+        for i in 0..2000u64 {
+            bptree.set(i, i)?;
+        }
+        assert_eq!(bptree.get(&1999)?, 1999);
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_cache() -> Result<()> {
+        let path = Path::new("data").join("test_page_cache.db");
+        let options = Options{ max_key_count: Some(8), cache_pages: 16, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(path, options)?;
+        for i in 0..2000 {
+            bptree.set(i, i)?;
+        }
+        for _ in 0..20 {
+            bptree.get(&7)?;
+        }
+        // The scan reads every leaf once; the lookups in between never have to go to disk.
+        let mut lookup_misses = 0;
+        for (i, entry) in bptree.iter_snapshot()?.enumerate() {
+            entry?;
+            if i % 64 == 0 {
+                let misses = bptree.cache_stats().unwrap().misses;
+                bptree.get(&7)?;
+                lookup_misses += bptree.cache_stats().unwrap().misses - misses;
+            }
+        }
+        assert_eq!(lookup_misses, 0);
+        assert!(bptree.cache_stats().unwrap().evictions > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_once() -> Result<()> {
+        let path = Path::new("data").join("test_write_once.db");
+        let options = Options{ max_key_count: Some(4), write_once: true, ..Options::default() };
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(path, options)?;
+        for i in 0..20 {
+            bptree.set(i, vec![i as u8; 8])?;
+        }
+        let version = bptree.version(&7)?;
+        assert!(matches!(bptree.set(7, vec![0; 9000]), Err(Error::AlreadyExists)));
+        assert_eq!(bptree.get(&7)?, vec![7; 8]);
+        assert_eq!(bptree.version(&7)?, version);
+        // The refused write didn't use up a sequence number.
+        assert_eq!(bptree.set(20, vec![20])?, version + 13);
+
+        bptree.remove(&7)?;
+        bptree.set(7, vec![1])?;
+        assert_eq!(bptree.get(&7)?, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upsert() -> Result<()> {
+        let path = Path::new("data").join("test_upsert.db");
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(path, Some(4))?;
+        for i in 0..20 {
+            assert_eq!(bptree.upsert(i, vec![i as u8])?, UpsertOutcome::Inserted);
+        }
+        assert_eq!(bptree.upsert(7, vec![1; 9000])?, UpsertOutcome::Updated(vec![7]));
+        assert_eq!(bptree.upsert(7, vec![2])?, UpsertOutcome::Updated(vec![1; 9000]));
+        assert_eq!(bptree.get(&7)?, vec![2]);
+        bptree.remove(&7)?;
+        assert_eq!(bptree.upsert(7, vec![3])?, UpsertOutcome::Inserted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_freed_pages_are_reused() -> Result<()> {
+        let path = Path::new("data").join("test_page_reuse.db");
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(&path, Some(4))?;
+        for i in 0..200 {
+            bptree.set(i, vec![i as u8; 16])?;
+        }
+        bptree.set(1000, vec![1; 20_000])?;
+        let pages = bptree.page_count();
+        bptree.remove(&1000)?;
+        for i in 0..100 {
+            bptree.remove(&i)?;
+        }
+        let free = bptree.free_page_count();
+        assert!(free > 5);
+        bptree.close()?;
+
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::open(&path)?;
+        assert_eq!(bptree.free_page_count(), free);
+        bptree.set(2000, vec![2; 20_000])?;
+        for i in 0..100 {
+            bptree.set(i, vec![i as u8; 16])?;
+        }
+        assert_eq!(bptree.page_count(), pages);
+        assert!(bptree.free_page_count() < free);
+        assert_eq!(bptree.get(&2000)?, vec![2; 20_000]);
+        assert_eq!(bptree.iter_snapshot()?.count(), 201);
+        assert!(bptree.verify(|_| ())?.corrupt.is_empty());
+        Ok(())
+    }
+}
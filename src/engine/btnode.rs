@@ -1,96 +1,386 @@
-#[allow(dead_code)]
+use std::borrow::Borrow;
 use std::fmt::Debug;
-use bincode::{Serializer, Deserializer};
-use serde::{de::DeserializeOwned, Serialize};
-use crate::engine::page::{Page, Pager, PagePtr, PAGE_SIZE};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::codec::Codec;
+use crate::engine::compression::{self, UNCOMPRESSED};
+use crate::engine::overflow::{self, OverflowRef, ValueDigest};
+use sha2::{Digest, Sha256};
+use crate::engine::page::{self, Page, Pager, PagePtr};
 use crate::error::{Error, Result};
 use crate::engine::bptree::BPTree;
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryInto;
+use std::mem;
+use std::ops::Bound;
 
-const LEAF_NODE_TYPE: u8 = 0;
-const INNER_NODE_TYPE: u8 = 1;
+pub(crate) const LEAF_NODE_TYPE: u8 = 0;
+pub(crate) const INNER_NODE_TYPE: u8 = 1;
 
-const PAGE_PTR_LEN: usize = 8;
+pub(crate) const PAGE_PTR_LEN: usize = 8;
 const KEYS_LEN: usize = 8;
 const VALUES_LEN: usize = 8;
 const CHILD_PTRS_LEN: usize = 8;
+const FENCES_LEN: usize = 8;
 
-const PAGE_PTR_OFFSET: usize = 0;
-const NODE_TYPE_OFFSET: usize = PAGE_PTR_LEN; //8
-const HAS_NEXT_OFFSET: usize = PAGE_PTR_LEN + 1; //9
-const NEXT_PAGE_PTR_OFFSET: usize = HAS_NEXT_OFFSET + 1;//10
+pub(crate) const PAGE_PTR_OFFSET: usize = 0;
+pub(crate) const NODE_TYPE_OFFSET: usize = PAGE_PTR_LEN; //8
+pub(crate) const HAS_NEXT_OFFSET: usize = PAGE_PTR_LEN + 1; //9
+pub(crate) const NEXT_PAGE_PTR_OFFSET: usize = HAS_NEXT_OFFSET + 1;//10
 const KEYS_LEN_OFFSET: usize = NEXT_PAGE_PTR_OFFSET + PAGE_PTR_LEN;//18
 const VALUES_LEN_OFFSET: usize = KEYS_LEN_OFFSET + KEYS_LEN;//26
 const CHILD_PTRS_LEN_OFFSET: usize =  KEYS_LEN_OFFSET + KEYS_LEN;//26
+const FENCES_LEN_OFFSET: usize = CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN;//34
+const INNER_DATA_OFFSET: usize = FENCES_LEN_OFFSET + FENCES_LEN;//42
+const VERSIONS_LEN_OFFSET: usize = VALUES_LEN_OFFSET + VALUES_LEN;//34
+const VERSIONS_LEN: usize = 8;
+const VALUE_FORMAT_OFFSET: usize = VERSIONS_LEN_OFFSET + VERSIONS_LEN;//42
+const TIMES_LEN_OFFSET: usize = VALUE_FORMAT_OFFSET + 1;//43
+const TIMES_LEN: usize = 8;
+const EXPIRIES_LEN_OFFSET: usize = TIMES_LEN_OFFSET + TIMES_LEN;//51
+const EXPIRIES_LEN: usize = 8;
+const HAS_PREV_OFFSET: usize = EXPIRIES_LEN_OFFSET + EXPIRIES_LEN;//59
+const PREV_PAGE_PTR_OFFSET: usize = HAS_PREV_OFFSET + 1;//60
+const LEAF_DATA_OFFSET: usize = PREV_PAGE_PTR_OFFSET + PAGE_PTR_LEN;//68
 
+// Leaves whose values are all inline keep the plain Vec<V> encoding so fixed-width
+// values stay on the codec fast path; otherwise every slot is tagged.
+const VALUES_INLINE: u8 = 0;
+const VALUES_SLOTTED: u8 = 1;
+// The high bits of the same byte hold the codec a leaf's entries were compressed with. The
+// compressed entries follow their length, and decompress to what an uncompressed leaf holds.
+const COMPRESSION_SHIFT: u8 = 4;
+const COMPRESSED_LEN: usize = 8;
 
+// Entry bytes a compressed leaf may hold before compression.
+fn max_leaf_bytes(page_size: usize) -> usize {
+    16 * page_size
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Slot<V> {
+    Inline(V),
+    Overflow(OverflowRef),
+}
+
+impl<V> Slot<V>
+    where V: Serialize + DeserializeOwned
+{
+    pub(crate) fn new<K>(value: V, bptree: &mut BPTree<K, V>) -> Result<Self>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + 'static
+    {
+        let codec = bptree.pager().codec().with_limit(u64::MAX);
+        if codec.serialized_size(&value)? <= bptree.inline_threshold() as u64 {
+            return Ok(Slot::Inline(value));
+        }
+        let bytes = codec.serialize(&value)?;
+        let digest = match bptree.shared_values() {
+            Some(shared) => {
+                let digest: ValueDigest = Sha256::digest(&bytes).into();
+                if let Some(overflow) = shared.acquire(&digest) {
+                    return Ok(Slot::Overflow(overflow));
+                }
+                Some(digest)
+            }
+            None => None,
+        };
+        let ptrs: Vec<PagePtr> = (0..overflow::pages_needed(bytes.len(), bptree.pager())).map(|_| bptree.next_page_ptr()).collect();
+        let overflow = overflow::write_chain(&bytes, &ptrs, bptree.get_pager())?;
+        if let (Some(digest), Some(shared)) = (digest, bptree.shared_values()) {
+            shared.insert(digest, overflow);
+        }
+        Ok(Slot::Overflow(overflow))
+    }
+
+    pub fn with_value<R, F: FnOnce(&V) -> R>(&self, pager: &Pager, f: F) -> Result<R> {
+        match self {
+            Slot::Inline(value) => Ok(f(value)),
+            Slot::Overflow(overflow) => Ok(f(&Self::load_overflow(overflow, pager)?)),
+        }
+    }
+
+    pub fn into_value(self, pager: &Pager) -> Result<V> {
+        match self {
+            Slot::Inline(value) => Ok(value),
+            Slot::Overflow(overflow) => Self::load_overflow(&overflow, pager),
+        }
+    }
+
+    fn load_overflow(overflow: &OverflowRef, pager: &Pager) -> Result<V> {
+        let bytes = overflow::read_chain(overflow, pager)?;
+        pager.codec().with_limit(overflow.len()).deserialize(&bytes)
+    }
+
+    // Encoded size of the value, without loading an overflowed one.
+    pub(crate) fn value_len(&self, codec: &Codec) -> Result<u64>
+        where V: Serialize
+    {
+        match self {
+            Slot::Inline(value) => codec.serialized_size(value),
+            Slot::Overflow(overflow) => Ok(overflow.len()),
+        }
+    }
+
+    // Hands the pages of an overflowed value back to the tree.
+    pub(crate) fn free<K>(&self, bptree: &mut BPTree<K, V>) -> Result<()>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + 'static
+    {
+        if let Slot::Overflow(overflow) = self {
+            if let Some(shared) = bptree.shared_values() {
+                if !shared.release(overflow.head()) {
+                    return Ok(());
+                }
+            }
+            for ptr in overflow::chain_pages(overflow, bptree.pager())? {
+                bptree.delete_page(ptr);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+
+// The removed key and entry, if any, and the page freed by merging on the way back up.
+pub type Removed<K, V> = (Option<(K, Entry<V>)>, Option<PagePtr>);
+
+// A leaf value together with the sequence number of the write that stored it, in trees
+// with a retention policy when that write happened, and when it expires if it was set with
+// a TTL (unix millis, 0 otherwise).
+#[derive(Debug, Clone)]
+pub struct Entry<V> {
+    pub version: u64,
+    pub written: u64,
+    pub expires: u64,
+    pub slot: Slot<V>,
+}
+
+// Version, write time and expiry, on top of an entry's key and slot.
+const ENTRY_OVERHEAD: u64 = 24;
+
+fn fits(page: Result<Page>) -> Result<bool> {
+    match page {
+        Ok(_) => Ok(true),
+        Err(Error::PageSizeNotEnough) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// The index within [min, max] that splits `sizes` into the two most even halves.
+fn midpoint(sizes: &[u64], min: usize, max: usize) -> usize {
+    let total: u64 = sizes.iter().sum();
+    let mut left: u64 = sizes[..min].iter().sum();
+    let mut best = (left.max(total - left), min);
+    for (i, size) in sizes.iter().enumerate().take(max).skip(min) {
+        left += size;
+        best = best.min((left.max(total - left), i + 1));
+    }
+    best.1
+}
+
+// Entry and separator bytes an encoded node has room for, after its header and column lengths.
+pub(crate) fn node_capacity(page_size: usize) -> u64 {
+    (page::data_size(page_size) - LEAF_DATA_OFFSET - 5 * 8) as u64
+}
+
+// Encoded keys may take up to this many bytes, so that any inner node that no longer fits
+// a page has enough keys to be split.
+pub fn max_key_size(page_size: usize) -> u64 {
+    page::data_size(page_size) as u64 / 8
+}
+
+// Near enough the bytes an entry takes up on a leaf page to split leaves by.
+pub(crate) fn entry_size<K: Serialize, V: Serialize>(key: &K, slot: &Slot<V>, codec: &Codec) -> Result<u64> {
+    Ok(codec.serialized_size(key)? + codec.serialized_size(slot)? + ENTRY_OVERHEAD)
+}
+
+// Pairs decoded values with their versions, write times and expiries, which must be exactly
+// as many. Leaves without a times or expiries column get 0 for every entry.
+fn entries<V, T, I>(versions: I, times: Option<I>, expiries: Option<I>, values: Vec<T>, slot: fn(T) -> Slot<V>) -> Result<Vec<Entry<V>>>
+    where I: ExactSizeIterator<Item = u64>
+{
+    let mismatched = |column: &Option<I>| column.as_ref().is_some_and(|column| column.len() != values.len());
+    if versions.len() != values.len() || mismatched(&times) || mismatched(&expiries) {
+        return Err(Error::CorruptedPage);
+    }
+    let times = times.into_iter().flatten().chain(std::iter::repeat(0));
+    let expiries = expiries.into_iter().flatten().chain(std::iter::repeat(0));
+    Ok(versions.zip(times).zip(expiries).zip(values)
+        .map(|(((version, written), expires), value)| Entry{ version, written, expires, slot: slot(value) })
+        .collect())
+}
 
 #[derive(Debug)]
 pub struct LeafNode<K, V>
 {
     ptr: PagePtr,
     keys: Vec<K>,
-    values: Vec<V>,
+    values: Vec<Entry<V>>,
+    prev: Option<PagePtr>,
     next: Option<PagePtr>,
 }
 
 impl<K, V> LeafNode<K, V>
-    where K: Debug + Clone + Ord + Serialize + DeserializeOwned,
-          V: Debug + Clone + Ord + Serialize + DeserializeOwned
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
 {
     pub fn new(page_ptr: PagePtr) -> Self{
         Self{
             ptr: page_ptr,
             keys: Vec::new(),
             values: Vec::new(),
+            prev: None,
             next: None,
         }
     }
 
-    pub fn from(page_ptr: PagePtr, keys: &[K], entries: &[V], next: Option<PagePtr>) -> Self {
+    pub fn from(page_ptr: PagePtr, keys: &[K], entries: &[Entry<V>], next: Option<PagePtr>) -> Self {
         Self{
             ptr: page_ptr,
             keys: keys.to_vec(),
             values: entries.to_vec(),
+            prev: None,
             next,
         }
     }
 
-    pub fn store_node_to_page(&self, pager: &mut Pager) -> Result<()> {
-        let mut bytes = [0u8; PAGE_SIZE];
-        let keys_bytes = bincode::serialize(&self.keys)?;
-        let values_bytes = bincode::serialize(&self.values)?;
-        let keys_bytes_len = keys_bytes.len();
-        let values_bytes_len = values_bytes.len() ;
+    pub(crate) fn from_parts(page_ptr: PagePtr, keys: Vec<K>, values: Vec<Entry<V>>, prev: Option<PagePtr>, next: Option<PagePtr>) -> Self {
+        Self{
+            ptr: page_ptr,
+            keys,
+            values,
+            prev,
+            next,
+        }
+    }
 
-        bytes[PAGE_PTR_OFFSET..PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&(self.ptr as u64).to_be_bytes());
+    pub fn store_node_to_page(&self, pager: &Pager) -> Result<()> {
+        pager.write_page(self.ptr, &self.encode(pager)?)
+    }
+
+    // Encodes straight into a pooled page buffer, so storing an uncompressed node doesn't
+    // allocate. A node that doesn't fit is PageSizeNotEnough.
+    fn encode(&self, pager: &Pager) -> Result<Page> {
+        let mut page = pager.new_page();
+        let (data_size, max_leaf_bytes) = (page.data_size(), max_leaf_bytes(page.size()));
+        let bytes = page.bytes_mut();
+        let (lens, inline, tag) = match pager.compression().tag() {
+            UNCOMPRESSED => {
+                let (lens, inline) = self.encode_entries(pager.codec(), &mut bytes[LEAF_DATA_OFFSET..data_size])?;
+                (lens, inline, UNCOMPRESSED)
+            }
+            tag => {
+                let mut entries = vec![0u8; max_leaf_bytes];
+                let (lens, inline) = self.encode_entries(&pager.codec().with_limit(max_leaf_bytes as u64), &mut entries)?;
+                let entries = &entries[..lens.iter().sum()];
+                let compressed = pager.compression().compress(entries)?;
+                // Entries that don't get any smaller are stored as they are.
+                if compressed.len() + COMPRESSED_LEN >= entries.len() && LEAF_DATA_OFFSET + entries.len() <= data_size {
+                    bytes[LEAF_DATA_OFFSET..LEAF_DATA_OFFSET + entries.len()].copy_from_slice(entries);
+                    (lens, inline, UNCOMPRESSED)
+                } else if LEAF_DATA_OFFSET + COMPRESSED_LEN + compressed.len() <= data_size {
+                    bytes[LEAF_DATA_OFFSET..LEAF_DATA_OFFSET + COMPRESSED_LEN].copy_from_slice(&(compressed.len() as u64).to_be_bytes());
+                    bytes[LEAF_DATA_OFFSET + COMPRESSED_LEN..LEAF_DATA_OFFSET + COMPRESSED_LEN + compressed.len()].copy_from_slice(&compressed);
+                    (lens, inline, tag)
+                } else {
+                    return Err(Error::PageSizeNotEnough);
+                }
+            }
+        };
+        let [keys_bytes_len, values_bytes_len, versions_bytes_len, times_bytes_len, expiries_bytes_len] = lens;
+
+        bytes[PAGE_PTR_OFFSET..PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&self.ptr.to_be_bytes());
         bytes[NODE_TYPE_OFFSET] =  LEAF_NODE_TYPE;
-        if self.next.is_some(){
+        if let Some(next) = self.next {
             bytes[HAS_NEXT_OFFSET] = 1;
-            bytes[NEXT_PAGE_PTR_OFFSET..NEXT_PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&(self.next.unwrap() as u64).to_be_bytes());
+            bytes[NEXT_PAGE_PTR_OFFSET..NEXT_PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&next.to_be_bytes());
+        }
+        if let Some(prev) = self.prev {
+            bytes[HAS_PREV_OFFSET] = 1;
+            bytes[PREV_PAGE_PTR_OFFSET..PREV_PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&prev.to_be_bytes());
         }
         bytes[KEYS_LEN_OFFSET..KEYS_LEN_OFFSET + KEYS_LEN].clone_from_slice(&(keys_bytes_len as u64).to_be_bytes());
         bytes[VALUES_LEN_OFFSET..VALUES_LEN_OFFSET + VALUES_LEN].clone_from_slice(&(values_bytes_len as u64).to_be_bytes());
-        if keys_bytes_len > 0 {
-            bytes[VALUES_LEN_OFFSET + VALUES_LEN..VALUES_LEN_OFFSET + VALUES_LEN + keys_bytes_len]
-                .clone_from_slice(keys_bytes.as_slice());
-        }
-        if values_bytes_len > 0 {
-            bytes[VALUES_LEN_OFFSET + VALUES_LEN + keys_bytes_len..
-                VALUES_LEN_OFFSET + VALUES_LEN + keys_bytes_len + values_bytes_len]
-                .clone_from_slice(values_bytes.as_slice());
-        }
+        bytes[VERSIONS_LEN_OFFSET..VERSIONS_LEN_OFFSET + VERSIONS_LEN].clone_from_slice(&(versions_bytes_len as u64).to_be_bytes());
+        bytes[VALUE_FORMAT_OFFSET] = if inline { VALUES_INLINE } else { VALUES_SLOTTED } | tag << COMPRESSION_SHIFT;
+        bytes[TIMES_LEN_OFFSET..TIMES_LEN_OFFSET + TIMES_LEN].clone_from_slice(&(times_bytes_len as u64).to_be_bytes());
+        bytes[EXPIRIES_LEN_OFFSET..EXPIRIES_LEN_OFFSET + EXPIRIES_LEN].clone_from_slice(&(expiries_bytes_len as u64).to_be_bytes());
+        Ok(page)
+    }
+
+    // Writes the keys, values, versions, write times and expiries one after the other,
+    // returning the length of each and whether the values are inline.
+    fn encode_entries(&self, codec: &Codec, bytes: &mut [u8]) -> Result<([usize; 5], bool)> {
+        let keys_bytes_len = codec.serialize_iter_into(self.keys.iter(), bytes)?;
+        let values_offset = keys_bytes_len;
+        let inline = self.values.iter().all(|entry| matches!(entry.slot, Slot::Inline(_)));
+        let values_bytes_len = match inline {
+            true => codec.serialize_iter_into(self.values.iter().map(|entry| match &entry.slot {
+                Slot::Inline(value) => value,
+                Slot::Overflow(_) => unreachable!(),
+            }), &mut bytes[values_offset..])?,
+            false => codec.serialize_iter_into(self.values.iter().map(|entry| &entry.slot), &mut bytes[values_offset..])?,
+        };
+        let versions_offset = values_offset + values_bytes_len;
+        let versions_bytes_len = codec.serialize_iter_into(self.values.iter().map(|entry| &entry.version), &mut bytes[versions_offset..])?;
+        let times_offset = versions_offset + versions_bytes_len;
+        let times_bytes_len = match self.values.iter().any(|entry| entry.written != 0) {
+            true => codec.serialize_iter_into(self.values.iter().map(|entry| &entry.written), &mut bytes[times_offset..])?,
+            false => 0,
+        };
+        let expiries_offset = times_offset + times_bytes_len;
+        let expiries_bytes_len = match self.values.iter().any(|entry| entry.expires != 0) {
+            true => codec.serialize_iter_into(self.values.iter().map(|entry| &entry.expires), &mut bytes[expiries_offset..])?,
+            false => 0,
+        };
+        Ok(([keys_bytes_len, values_bytes_len, versions_bytes_len, times_bytes_len, expiries_bytes_len], inline))
+    }
+
+    fn fits(&self, pager: &Pager) -> Result<bool> {
+        fits(self.encode(pager))
+    }
+
+    // Whether this leaf and `right` would fit one page together.
+    fn fits_with(&self, right: &Self, pager: &Pager) -> Result<bool> {
+        let keys = self.keys.iter().chain(&right.keys).cloned().collect();
+        let values = self.values.iter().chain(&right.values).cloned().collect();
+        Self::from_parts(self.ptr, keys, values, self.prev, right.next).fits(pager)
+    }
 
-        let page = Page::from_bytes(bytes);
-        match pager.insert_page(self.ptr, &page) {
-            Ok(()) => {Ok(())},
-            Err(Error::PageNotFound) => {pager.append_page(&page)}
-            Err(e) => {Err(e)}
+    // Stores the leaf, or, when its entries no longer fit a page, e.g. because of large
+    // inline values, splits it and stores both halves.
+    fn store_or_split(&mut self, bptree: &mut BPTree<K, V>) -> Result<Option<(K, PagePtr)>> {
+        match self.encode(bptree.pager()) {
+            Ok(page) => {
+                bptree.pager().write_page(self.ptr, &page)?;
+                Ok(None)
+            }
+            Err(Error::PageSizeNotEnough) if self.keys.len() > 1 => {
+                let split_at = self.byte_midpoint(bptree.pager().codec())?;
+                let (split_key, new_leaf) = self.split(bptree.next_page_ptr(), split_at)?;
+                self.store_node_to_page(bptree.pager())?;
+                new_leaf.store_node_to_page(bptree.pager())?;
+                new_leaf.relink_next(bptree.pager())?;
+                Ok(Some((split_key, new_leaf.ptr)))
+            }
+            Err(e) => Err(e),
         }
     }
 
-    pub fn load_node_from_page(mut self, page: Page) -> Result<Self> {
-        let bytes = page.get_page_data();
+    // Where the entries' bytes are halved, leaving at least one entry on either side.
+    fn byte_midpoint(&self, codec: &Codec) -> Result<usize> {
+        let codec = codec.with_limit(u64::MAX);
+        let sizes = self.keys.iter().zip(&self.values).map(|(key, entry)| entry_size(key, &entry.slot, &codec)).collect::<Result<Vec<u64>>>()?;
+        Ok(midpoint(&sizes, 1, sizes.len() - 1))
+    }
+
+    pub fn load(page_ptr: PagePtr, pager: &Pager) -> Result<Self> {
+        let page = pager.load_page(page_ptr)?;
+        Self::new(page_ptr).load_node_from_page(page, pager.codec())
+    }
+
+    pub fn load_node_from_page(mut self, page: Page, codec: &Codec) -> Result<Self> {
+        let bytes = page.bytes();
         self.ptr = u64::from_be_bytes(bytes[PAGE_PTR_OFFSET..PAGE_PTR_OFFSET + PAGE_PTR_LEN].try_into().unwrap());
         if bytes[HAS_NEXT_OFFSET] == 0 {
             self.next = Option::None;
@@ -98,114 +388,281 @@ impl<K, V> LeafNode<K, V>
         else{
             self.next = Some(u64::from_be_bytes(bytes[NEXT_PAGE_PTR_OFFSET..NEXT_PAGE_PTR_OFFSET + PAGE_PTR_LEN].try_into().unwrap()));
         }
+        self.prev = match bytes[HAS_PREV_OFFSET] {
+            0 => None,
+            _ => Some(u64::from_be_bytes(bytes[PREV_PAGE_PTR_OFFSET..PREV_PAGE_PTR_OFFSET + PAGE_PTR_LEN].try_into().unwrap())),
+        };
         let keys_bytes_len = usize::from_be_bytes(bytes[KEYS_LEN_OFFSET..KEYS_LEN_OFFSET + KEYS_LEN].try_into().unwrap());
         let values_bytes_len = usize::from_be_bytes(bytes[VALUES_LEN_OFFSET..VALUES_LEN_OFFSET + VALUES_LEN].try_into().unwrap());
+        let versions_bytes_len = usize::from_be_bytes(bytes[VERSIONS_LEN_OFFSET..VERSIONS_LEN_OFFSET + VERSIONS_LEN].try_into().unwrap());
+        let times_bytes_len = usize::from_be_bytes(bytes[TIMES_LEN_OFFSET..TIMES_LEN_OFFSET + TIMES_LEN].try_into().unwrap());
+        let expiries_bytes_len = usize::from_be_bytes(bytes[EXPIRIES_LEN_OFFSET..EXPIRIES_LEN_OFFSET + EXPIRIES_LEN].try_into().unwrap());
+        let entries_len = keys_bytes_len.saturating_add(values_bytes_len).saturating_add(versions_bytes_len)
+            .saturating_add(times_bytes_len).saturating_add(expiries_bytes_len);
+        let decompressed;
+        let (data, codec) = match bytes[VALUE_FORMAT_OFFSET] >> COMPRESSION_SHIFT {
+            UNCOMPRESSED if LEAF_DATA_OFFSET.saturating_add(entries_len) <= page.data_size() =>
+                (&bytes[LEAF_DATA_OFFSET..LEAF_DATA_OFFSET + entries_len], *codec),
+            UNCOMPRESSED => return Err(Error::CorruptedPage),
+            tag => {
+                let compressed_len = usize::from_be_bytes(bytes[LEAF_DATA_OFFSET..LEAF_DATA_OFFSET + COMPRESSED_LEN].try_into().unwrap());
+                let compressed_offset = LEAF_DATA_OFFSET + COMPRESSED_LEN;
+                if entries_len > max_leaf_bytes(page.size()) || compressed_offset.saturating_add(compressed_len) > page.data_size() {
+                    return Err(Error::CorruptedPage);
+                }
+                decompressed = compression::decompress(tag, &bytes[compressed_offset..compressed_offset + compressed_len], entries_len)?;
+                (&decompressed[..], codec.with_limit(max_leaf_bytes(page.size()) as u64))
+            }
+        };
         if keys_bytes_len > 0 {
-            self.keys = bincode::deserialize(&bytes[VALUES_LEN_OFFSET + VALUES_LEN..VALUES_LEN_OFFSET + VALUES_LEN + keys_bytes_len])?;
+            self.keys = codec.deserialize_vec(&data[..keys_bytes_len])?;
         }
         if values_bytes_len > 0 {
-            self.values = bincode::deserialize(&bytes[VALUES_LEN_OFFSET + VALUES_LEN + keys_bytes_len..
-                VALUES_LEN_OFFSET + VALUES_LEN + keys_bytes_len + values_bytes_len])?;
+            let values_bytes = &data[keys_bytes_len..keys_bytes_len + values_bytes_len];
+            let versions_offset = keys_bytes_len + values_bytes_len;
+            let versions = codec.u64s(&data[versions_offset..versions_offset + versions_bytes_len])?;
+            let times_offset = versions_offset + versions_bytes_len;
+            let times = match times_bytes_len {
+                0 => None,
+                len => Some(codec.u64s(&data[times_offset..times_offset + len])?),
+            };
+            let expiries_offset = times_offset + times_bytes_len;
+            let expiries = match expiries_bytes_len {
+                0 => None,
+                len => Some(codec.u64s(&data[expiries_offset..expiries_offset + len])?),
+            };
+            self.values = match bytes[VALUE_FORMAT_OFFSET] & ((1 << COMPRESSION_SHIFT) - 1) {
+                VALUES_INLINE => entries(versions, times, expiries, codec.deserialize_vec::<V>(values_bytes)?, Slot::Inline)?,
+                VALUES_SLOTTED => entries(versions, times, expiries, codec.deserialize::<Vec<Slot<V>>>(values_bytes)?, |slot| slot)?,
+                _ => return Err(Error::CorruptedPage),
+            };
+            if self.values.len() != self.keys.len() {
+                return Err(Error::CorruptedPage);
+            }
         }
         Ok(self)
     }
 
-    pub fn get(&self, key: &K) -> Option<V>{
-        match self.keys.binary_search(key) {
-            Ok(i) => {Some(self.values[i].clone())},
-            Err(_) => Option::None,
+    pub fn ptr(&self) -> PagePtr {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn keys(&self) -> &[K] {
+        &self.keys
+    }
+
+    pub(crate) fn entries(&self) -> &[Entry<V>] {
+        &self.values
+    }
+
+    pub fn next(&self) -> Option<PagePtr> {
+        self.next
+    }
+
+    pub fn prev(&self) -> Option<PagePtr> {
+        self.prev
+    }
+
+    // Gives the leaf, its neighbours and the overflow chains of its values new page numbers.
+    pub(crate) fn renumber<F: Fn(PagePtr) -> PagePtr>(&mut self, new: F) {
+        self.ptr = new(self.ptr);
+        self.prev = self.prev.map(&new);
+        self.next = self.next.map(&new);
+        for entry in &mut self.values {
+            if let Slot::Overflow(value) = &mut entry.slot {
+                *value = value.moved_to(new(value.head()));
+            }
+        }
+    }
+
+    // Points the leaf after this one back at it, once a split or merge has changed which
+    // leaf comes before that one.
+    fn relink_next(&self, pager: &Pager) -> Result<()> {
+        if let Some(next) = self.next {
+            let mut node = Self::load(next, pager)?;
+            node.prev = Some(self.ptr);
+            node.store_node_to_page(pager)?;
         }
+        Ok(())
+    }
+
+    pub fn into_parts(self) -> (Vec<K>, Vec<Entry<V>>) {
+        (self.keys, self.values)
+    }
+
+    pub fn contains<Q>(&self, key: &Q, pager: &Pager) -> bool
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        pager.order().search(&self.keys, key).is_ok()
+    }
+
+    pub fn slot<Q>(&self, key: &Q, pager: &Pager) -> Option<&Slot<V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.entry(key, pager).map(|entry| &entry.slot)
     }
 
-    fn insert(&mut self, i: usize, key: K, value: V) {
+    pub fn entry<Q>(&self, key: &Q, pager: &Pager) -> Option<&Entry<V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match pager.order().search(&self.keys, key) {
+            Ok(i) => Some(&self.values[i]),
+            Err(_) => None,
+        }
+    }
+
+    pub fn get_with<Q, R, F>(&self, key: &Q, pager: &Pager, f: F) -> Result<Option<R>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        match self.slot(key, pager) {
+            Some(slot) => Ok(Some(slot.with_value(pager, f)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q, pager: &Pager) -> Result<Option<V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.get_with(key, pager, V::clone)
+    }
+
+    fn insert(&mut self, i: usize, key: K, value: Entry<V>) {
         self.keys.insert(i, key);
         self.values.insert(i, value);
     }
 
-    pub fn set(&mut self, key: K, value: V, bptree: &mut BPTree<K, V>) -> Result<Option<(K, PagePtr)>> {
-        match self.keys.binary_search(&key) {
+    // `replaced` is given the value the entry held before, unless it had expired.
+    pub fn set(&mut self, key: K, value: Entry<V>, replaced: Option<&mut Option<V>>, bptree: &mut BPTree<K, V>) -> Result<Option<(K, PagePtr)>> {
+        bptree.record_write(self.ptr);
+        match bptree.pager().order().search(&self.keys, &key) {
+            Ok(i) if bptree.write_once() && !bptree.is_expired(&self.values[i]) => {
+                value.slot.free(bptree)?;
+                Err(Error::AlreadyExists)
+            }
             Ok(i) => {
-                self.values[i] = value;
-                Ok(Option::None)
+                let old = mem::replace(&mut self.values[i], value);
+                if let Some(replaced) = replaced.filter(|_| !bptree.is_expired(&old)) {
+                    *replaced = Some(old.slot.with_value(bptree.pager(), V::clone)?);
+                }
+                old.slot.free(bptree)?;
+                self.store_or_split(bptree)
             }
             Err(i) => match self.is_full(bptree.max_key_count()){
                 true => {
-                    let (split_key, mut new_leaf) = self.split(bptree.next_page_ptr(), bptree.split_at())?;
-                    let new_leaf_ptr = new_leaf.ptr;
-                    match i < bptree.split_at() {
-                        true => self.insert(i, key, value),
-                        false => new_leaf.insert(i - bptree.split_at(), key, value),
+                    bptree.count_key();
+                    // The new entry goes into whichever half it would have by count.
+                    let mut split_at = if i <= bptree.split_at() { bptree.split_at() + 1 } else { bptree.split_at() };
+                    self.insert(i, key, value);
+                    let (left, right) = self.keys.split_at(split_at);
+                    let pager = bptree.pager();
+                    if !Self::from(self.ptr, left, &self.values[..split_at], None).fits(pager)?
+                        || !Self::from(self.ptr, right, &self.values[split_at..], None).fits(pager)? {
+                        split_at = self.byte_midpoint(pager.codec())?;
                     }
+                    let (split_key, new_leaf) = self.split(bptree.next_page_ptr(), split_at)?;
                     self.store_node_to_page(bptree.get_pager())?;
                     new_leaf.store_node_to_page(bptree.get_pager())?;
-                    Ok(Some((split_key, new_leaf_ptr)))
+                    new_leaf.relink_next(bptree.get_pager())?;
+                    Ok(Some((split_key, new_leaf.ptr)))
                 },
                 false => {
+                    bptree.count_key();
                     self.insert(i, key, value);
-                    self.store_node_to_page(bptree.get_pager())?;
-                    Ok(None)
+                    self.store_or_split(bptree)
                 }
             }
         }
     }
 
-    pub fn remove(
+    pub(crate) fn remove<Q>(
         mut self,
-        key: &K,
+        key: &Q,
         parent: Option<&mut InnerNode<K>>,
         path_info: Option<&ChildNodeInfo>,
         bptree: &mut BPTree<K, V>,
-    ) -> Result<(Option<V>, Option<PagePtr>)> {
-        match self.keys.binary_search(key) {
+    ) -> Result<Removed<K, V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match bptree.pager().order().search(&self.keys, key) {
             Err(_) => Ok((None, None)),
             Ok(i) => {
-                self.keys.remove(i);
+                bptree.record_write(self.ptr);
+                let original_key = self.keys.remove(i);
                 let original_value = self.values.remove(i);
                 let mut delete_page = None;
-                if self.keys.len() < bptree.split_at() && parent.is_some() {
-                    let parent = parent.unwrap();
+                if let (true, Some(parent)) = (self.keys.len() < bptree.split_at(), parent) {
                     let path_info = path_info.unwrap();
                     let mut done = false;
-                    if path_info.lsibling.is_some() {
-                        let mut node = LeafNode::new(path_info.lsibling.unwrap())
-                            .load_node_from_page(bptree.get_pager().load_page(path_info.lsibling.unwrap())?)?;
+                    if let Some(lsibling) = path_info.lsibling {
+                        let mut node = LeafNode::load(lsibling, bptree.get_pager())?;
                         if node.keys.len() > bptree.split_at() {
-                            let k: K = node.keys.pop().unwrap();
-                            let v = node.values.pop().unwrap();
-                            self.keys.insert(0, k.clone());
-                            self.values.insert(0, v);
-                            parent.keys[path_info.rparent.unwrap()] = k;
-                            node.store_node_to_page(bptree.get_pager())?;
-                            done = true;
+                            self.keys.insert(0, node.keys.pop().unwrap());
+                            self.values.insert(0, node.values.pop().unwrap());
+                            // Entries differ in size, so the borrowed one may not fit.
+                            if self.fits(bptree.pager())? {
+                                parent.keys[path_info.rparent.unwrap()] = self.keys[0].clone();
+                                bptree.forget_zone(node.ptr);
+                                node.store_node_to_page(bptree.get_pager())?;
+                                done = true;
+                            } else {
+                                node.keys.push(self.keys.remove(0));
+                                node.values.push(self.values.remove(0));
+                            }
                         }
                     }
-                    if !done && path_info.rsibling.is_some(){
-                        let mut node = LeafNode::new(path_info.rsibling.unwrap())
-                            .load_node_from_page(bptree.get_pager().load_page(path_info.rsibling.unwrap())?)?;
+                    if let (false, Some(rsibling)) = (done, path_info.rsibling) {
+                        let mut node = LeafNode::load(rsibling, bptree.get_pager())?;
                         if node.keys.len() > bptree.split_at() {
-                            let k = node.keys.remove(0);
-                            let v = node.values.remove(0);
-                            self.keys.push(k);
-                            self.values.push(v);
-                            parent.keys[path_info.lparent.unwrap()] = node.keys[0].clone();
-                            node.store_node_to_page(bptree.get_pager())?;
-                            done = true;
+                            self.keys.push(node.keys.remove(0));
+                            self.values.push(node.values.remove(0));
+                            if self.fits(bptree.pager())? {
+                                parent.keys[path_info.lparent.unwrap()] = node.keys[0].clone();
+                                bptree.forget_zone(node.ptr);
+                                node.store_node_to_page(bptree.get_pager())?;
+                                done = true;
+                            } else {
+                                node.keys.insert(0, self.keys.pop().unwrap());
+                                node.values.insert(0, self.values.pop().unwrap());
+                            }
                         }
                     }
+                    // Leaves too big to merge stay as they are, under half full.
                     if !done {
-                        if path_info.lsibling.is_some() {
-                            let mut node = LeafNode::new(path_info.lsibling.unwrap())
-                                .load_node_from_page(bptree.get_pager().load_page(path_info.lsibling.unwrap())?)?;
+                        let lsibling = match path_info.lsibling {
+                            Some(lsibling) => Some(LeafNode::load(lsibling, bptree.get_pager())?),
+                            None => None,
+                        };
+                        let rsibling = match path_info.rsibling.filter(|ptr| Some(*ptr) == self.next) {
+                            Some(rsibling) if lsibling.is_none() => Some(LeafNode::load(rsibling, bptree.get_pager())?),
+                            _ => None,
+                        };
+                        let pager = bptree.pager();
+                        let lsibling = match lsibling {
+                            Some(node) if node.fits_with(&self, pager)? => Some(node),
+                            _ => None,
+                        };
+                        let rsibling = match rsibling {
+                            Some(node) if self.fits_with(&node, pager)? => Some(node),
+                            _ => None,
+                        };
+                        if let Some(mut node) = lsibling {
                             node.keys.extend(self.keys);
                             node.values.extend(self.values);
                             node.next = self.next;
+                            bptree.forget_zone(node.ptr);
                             delete_page = Some(self.ptr);
                             bptree.delete_page(self.ptr);
                             self = node;
                         }
-                        else if path_info.rsibling.is_some() && path_info.rsibling == self.next{
-                            let mut node = LeafNode::new(path_info.rsibling.unwrap())
-                                .load_node_from_page(bptree.get_pager().load_page(path_info.rsibling.unwrap())?)?;
+                        else if let Some(node) = rsibling {
                             self.keys.extend(node.keys);
                             self.values.extend(node.values);
                             self.next = node.next;
@@ -216,7 +673,10 @@ impl<K, V> LeafNode<K, V>
 
                 }
                 self.store_node_to_page(bptree.get_pager())?;
-                Ok((Some(original_value), delete_page))
+                if delete_page.is_some() {
+                    self.relink_next(bptree.get_pager())?;
+                }
+                Ok((Some((original_key, original_value)), delete_page))
             }
         }
     }
@@ -227,6 +687,7 @@ impl<K, V> LeafNode<K, V>
     pub fn split(&mut self, next_ptr: PagePtr, split_at: usize) -> Result<(K, Self)> {
         let split_key = self.keys[split_at].clone();
         let mut node = Self::from(next_ptr, &self.keys[split_at..], &self.values[split_at..], self.next);
+        node.prev = Some(self.ptr);
         self.next = Some(next_ptr);
         self.keys.drain(split_at..);
         self.values.drain(split_at..);
@@ -236,7 +697,7 @@ impl<K, V> LeafNode<K, V>
 }
 
 #[derive(Debug)]
-struct ChildNodeInfo {
+pub(crate) struct ChildNodeInfo {
     page_nr: PagePtr,
     lparent: Option<usize>, // LeftSubtree(keys[lparent]) == page_nr
     rparent: Option<usize>, // RightSubtree(keys[rparent]) == page_nr
@@ -251,16 +712,21 @@ pub struct InnerNode<K>
     ptr: PagePtr,
     keys: Vec<K>,
     childptrs: Vec<PagePtr>,
+    // Fence keys: every key below this node lies in [low, high); None means unbounded.
+    low: Option<K>,
+    high: Option<K>,
 }
 
 impl<K> InnerNode<K>
-    where K: Debug + Clone + Ord + Serialize + DeserializeOwned
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
 {
     pub fn new(page_ptr: PagePtr) -> Self {
         Self{
             ptr: page_ptr,
             keys: Vec::new(),
             childptrs: Vec::new(),
+            low: None,
+            high: None,
         }
     }
 
@@ -269,77 +735,159 @@ impl<K> InnerNode<K>
             ptr: page_ptr,
             keys: keys.to_vec(),
             childptrs: entries.to_vec(),
+            low: None,
+            high: None,
         }
     }
-    pub fn store_node_to_page(&self, pager: &mut Pager) -> Result<()> {
-        let mut bytes = [0u8; PAGE_SIZE];
-        let keys_bytes = bincode::serialize(&self.keys)?;
-        let childptrs_bytes = bincode::serialize(&self.childptrs)?;
-        let keys_bytes_len = keys_bytes.len();
-        let childptrs_bytes_len = childptrs_bytes.len() ;
 
-        bytes[PAGE_PTR_OFFSET..PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&(self.ptr as u64).to_be_bytes());
+    pub(crate) fn with_fences(mut self, low: Option<K>, high: Option<K>) -> Self {
+        self.low = low;
+        self.high = high;
+        self
+    }
+
+    pub fn ptr(&self) -> PagePtr {
+        self.ptr
+    }
+
+    pub fn keys(&self) -> &[K] {
+        &self.keys
+    }
+
+    pub fn childptrs(&self) -> &[PagePtr] {
+        &self.childptrs
+    }
+
+    pub(crate) fn renumber<F: Fn(PagePtr) -> PagePtr>(&mut self, new: F) {
+        self.ptr = new(self.ptr);
+        self.childptrs.iter_mut().for_each(|child| *child = new(*child));
+    }
+
+    pub fn low_fence(&self) -> Option<&K> {
+        self.low.as_ref()
+    }
+
+    pub fn high_fence(&self) -> Option<&K> {
+        self.high.as_ref()
+    }
+
+    // Key interval [low, high) covered by the i-th child, derived from the fences and separators.
+    pub fn child_bounds(&self, i: usize) -> (Option<&K>, Option<&K>) {
+        let low = if i == 0 { self.low.as_ref() } else { self.keys.get(i - 1) };
+        let high = if i == self.keys.len() { self.high.as_ref() } else { self.keys.get(i) };
+        (low, high)
+    }
+
+    pub fn store_node_to_page(&self, pager: &Pager) -> Result<()> {
+        pager.write_page(self.ptr, &self.encode(pager)?)
+    }
+
+    fn encode(&self, pager: &Pager) -> Result<Page> {
+        let codec = pager.codec();
+        let mut page = pager.new_page();
+        let data_size = page.data_size();
+        let bytes = page.bytes_mut();
+        let keys_bytes_len = codec.serialize_iter_into(self.keys.iter(), &mut bytes[INNER_DATA_OFFSET..data_size])?;
+        let childptrs_offset = INNER_DATA_OFFSET + keys_bytes_len;
+        let childptrs_bytes_len = codec.serialize_iter_into(self.childptrs.iter(), &mut bytes[childptrs_offset..data_size])?;
+        let fences_offset = childptrs_offset + childptrs_bytes_len;
+        let fences_bytes_len = codec.serialize_into(&(&self.low, &self.high), &mut bytes[fences_offset..data_size])?;
+
+        bytes[PAGE_PTR_OFFSET..PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&self.ptr.to_be_bytes());
         bytes[NODE_TYPE_OFFSET] =  INNER_NODE_TYPE;
         bytes[KEYS_LEN_OFFSET..KEYS_LEN_OFFSET + KEYS_LEN].clone_from_slice(&(keys_bytes_len as u64).to_be_bytes());
         bytes[CHILD_PTRS_LEN_OFFSET..CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN].clone_from_slice(&(childptrs_bytes_len as u64).to_be_bytes());
-        if keys_bytes_len > 0 {
-            bytes[CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN..CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN + keys_bytes_len]
-                .clone_from_slice(keys_bytes.as_slice());
-        }
-        if childptrs_bytes_len > 0 {
-            bytes[CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN + keys_bytes_len..
-                CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN + keys_bytes_len + childptrs_bytes_len]
-                .clone_from_slice(childptrs_bytes.as_slice());
-        }
+        bytes[FENCES_LEN_OFFSET..FENCES_LEN_OFFSET + FENCES_LEN].clone_from_slice(&(fences_bytes_len as u64).to_be_bytes());
+        Ok(page)
+    }
 
-        let page = Page::from_bytes(bytes);
-        match pager.insert_page(self.ptr, &page) {
-            Ok(()) => {Ok(())},
-            Err(Error::PageNotFound) => {pager.append_page(&page)}
-            Err(e) => {Err(e)}
+    fn fits(&self, pager: &Pager) -> Result<bool> {
+        fits(self.encode(pager))
+    }
+
+    // Long keys fill a page long before max_key_count does; such a node is split where the
+    // bytes of its keys are halved, keeping at least one key on either side of the separator.
+    fn store_or_split<V>(&mut self, bptree: &mut BPTree<K, V>) -> Result<Option<(K, PagePtr)>>
+    where
+        V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+    {
+        match self.encode(bptree.pager()) {
+            Ok(page) => {
+                bptree.pager().write_page(self.ptr, &page)?;
+                Ok(None)
+            }
+            Err(Error::PageSizeNotEnough) if self.keys.len() > 2 => {
+                let split_at = self.byte_midpoint(bptree.pager().codec())?;
+                let (split_key, new_node) = self.split(bptree.next_page_ptr(), split_at)?;
+                self.store_node_to_page(bptree.pager())?;
+                new_node.store_node_to_page(bptree.pager())?;
+                Ok(Some((split_key, new_node.ptr)))
+            }
+            Err(e) => Err(e),
         }
     }
 
-    pub fn load_node_from_page(mut self, page: Page) -> Result<Self> {
-        let bytes = page.get_page_data();
+    fn byte_midpoint(&self, codec: &Codec) -> Result<usize> {
+        let codec = codec.with_limit(u64::MAX);
+        let sizes = self.keys.iter().map(|key| Ok(codec.serialized_size(key)? + PAGE_PTR_LEN as u64)).collect::<Result<Vec<u64>>>()?;
+        Ok(midpoint(&sizes, 1, sizes.len() - 2))
+    }
+
+    pub fn load(page_ptr: PagePtr, pager: &Pager) -> Result<Self> {
+        let page = pager.load_page(page_ptr)?;
+        Self::new(page_ptr).load_node_from_page(page, pager.codec())
+    }
+
+    pub fn load_node_from_page(mut self, page: Page, codec: &Codec) -> Result<Self> {
+        let bytes = page.bytes();
         self.ptr = u64::from_be_bytes(bytes[PAGE_PTR_OFFSET..PAGE_PTR_OFFSET + PAGE_PTR_LEN].try_into().unwrap());
         let keys_bytes_len = usize::from_be_bytes(bytes[KEYS_LEN_OFFSET..KEYS_LEN_OFFSET + KEYS_LEN].try_into().unwrap());
         let childptrs_bytes_len = usize::from_be_bytes(bytes[CHILD_PTRS_LEN_OFFSET..CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN].try_into().unwrap());
-        if keys_bytes_len > 0 {
-            self.keys = bincode::deserialize(&bytes[CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN..CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN + keys_bytes_len])?;
-        }
-        if childptrs_bytes_len > 0 {
-            self.childptrs = bincode::deserialize(&bytes[CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN + keys_bytes_len..
-                CHILD_PTRS_LEN_OFFSET + CHILD_PTRS_LEN + keys_bytes_len + childptrs_bytes_len])?;
+        let fences_bytes_len = usize::from_be_bytes(bytes[FENCES_LEN_OFFSET..FENCES_LEN_OFFSET + FENCES_LEN].try_into().unwrap());
+        let keys_end = INNER_DATA_OFFSET.checked_add(keys_bytes_len).ok_or(Error::CorruptedPage)?;
+        let childptrs_end = keys_end.checked_add(childptrs_bytes_len).ok_or(Error::CorruptedPage)?;
+        let fences_end = childptrs_end.checked_add(fences_bytes_len).ok_or(Error::CorruptedPage)?;
+        if fences_end > page.data_size() {
+            return Err(Error::CorruptedPage);
         }
+        self.keys = codec.deserialize_vec(&bytes[INNER_DATA_OFFSET..keys_end])?;
+        self.childptrs = codec.deserialize_vec(&bytes[keys_end..childptrs_end])?;
+        let (low, high) = codec.deserialize(&bytes[childptrs_end..fences_end])?;
+        self.low = low;
+        self.high = high;
         Ok(self)
     }
 
-    pub fn get(&self, key: &K) -> PagePtr {
-        match self.keys.binary_search(key) {
+    pub fn get<Q>(&self, key: &Q, pager: &Pager) -> PagePtr
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match pager.order().search(&self.keys, key) {
             Ok(i) => self.childptrs[i+1],
             Err(i) => self.childptrs[i]
         }
     }
 
-    pub fn set<V>(&mut self, key: K, value: V, bptree: &mut BPTree<K, V>) -> Result<(Option<(K, PagePtr)>)>
+    pub fn set<V>(&mut self, key: K, value: Entry<V>, replaced: Option<&mut Option<V>>, bptree: &mut BPTree<K, V>) -> Result<Option<(K, PagePtr)>>
     where
-        V: Debug + Clone + Ord  +  Serialize + DeserializeOwned,
+        V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
     {
-        let child_ptr = self.get(&key);
+        let child_ptr = self.get(&key, bptree.pager());
         let return_value = match Node::load_node(child_ptr, bptree.get_pager())?{
-            Node::Leaf(mut leaf_node) => {leaf_node.set(key, value, bptree)?},
-            Node::Inner(mut inner_node) =>{inner_node.set(key, value,bptree)?}
+            Node::Leaf(mut leaf_node) => {leaf_node.set(key, value, replaced, bptree)?},
+            Node::Inner(mut inner_node) =>{inner_node.set(key, value, replaced, bptree)?}
         };
+        if return_value.is_some() {
+            bptree.count_split();
+        }
         match return_value {
             None => Ok(None),
-            Some((split_key, split_page_ptr)) => match self.keys.binary_search(&split_key) {
+            Some((split_key, split_page_ptr)) => match bptree.pager().order().search(&self.keys, &split_key) {
                 Ok(_) => panic!("Programming error: key should not be present!"),
                 Err(i) => match self.is_full(bptree.max_key_count()) {
                     true => {
                         let (new_split_key, mut new_split_node) = self.split(bptree.next_page_ptr(), bptree.split_at())?;
                         let new_page_ptr = new_split_node.ptr;
-                        match i < bptree.split_at() {
+                        match i <= bptree.split_at() {
                             true => self.insert(i, split_key, split_page_ptr),
                             false => new_split_node.insert(i - bptree.split_at() - 1, split_key, split_page_ptr),
                         }
@@ -349,16 +897,17 @@ impl<K> InnerNode<K>
                     }
                     false => {
                         self.insert(i, split_key, split_page_ptr);
-                        self.store_node_to_page(bptree.get_pager())?;
-                        Ok(None)
+                        self.store_or_split(bptree)
                     }
                 }
             }
         }
     }
 
-    fn get_child_node_info(&self, key: &K) -> ChildNodeInfo {
-        match self.keys.binary_search(key) {
+    fn get_child_node_info<Q>(&self, key: &Q, pager: &Pager) -> ChildNodeInfo
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match pager.order().search(&self.keys, key) {
             Ok(i) => {
                 // exact match -> right subtree
                 ChildNodeInfo {
@@ -382,33 +931,37 @@ impl<K> InnerNode<K>
         }
     }
 
-    pub fn remove<V>(
+    pub(crate) fn remove<V, Q>(
         mut self,
-        key: &K,
+        key: &Q,
         parent: Option<&mut InnerNode<K>>,
         path_info: Option<&ChildNodeInfo>,
         bptree: &mut BPTree<K, V>
-    ) -> Result<(Option<V>, Option<PagePtr>)>
+    ) -> Result<Removed<K, V>>
     where
-        V: Debug + Clone + Ord  +  Serialize + DeserializeOwned,
+        V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+        K: Borrow<Q>,
+        Q: Ord + ?Sized + 'static,
     {
-        let child_info = self.get_child_node_info(&key);
+        let child_info = self.get_child_node_info(key, bptree.pager());
         let (original_value, deleted_page) = match Node::load_node(child_info.page_nr, bptree.get_pager())? {
-            Node::Leaf(mut leaf_node) => leaf_node.remove(key, Some(&mut self), Some(&child_info), bptree)?,
-            Node::Inner(mut inner_node) => inner_node.remove(key,Some(&mut self), Some(&child_info), bptree)?,
+            Node::Leaf(leaf_node) => leaf_node.remove(key, Some(&mut self), Some(&child_info), bptree)?,
+            Node::Inner(inner_node) => inner_node.remove(key,Some(&mut self), Some(&child_info), bptree)?,
         };
-        let result = match deleted_page {
-            None => Ok((original_value, None)),
+        let deleted_page = match deleted_page {
+            None => None,
             Some(page_nr) => {
-                let deleted_page = self.remove_page(page_nr, parent, path_info, bptree)?;
-                Ok((original_value, deleted_page))
+                bptree.count_merge();
+                self.remove_page(page_nr, parent, path_info, bptree)?
             }
         };
-        self.store_node_to_page(bptree.get_pager())?;
-        result
+        if deleted_page != Some(self.ptr) {
+            self.store_node_to_page(bptree.get_pager())?;
+        }
+        Ok((original_value, deleted_page))
     }
 
-    pub fn remove_page<V>(
+    pub(crate) fn remove_page<V>(
         &mut self,
         page_ptr: PagePtr,
         parent: Option<&mut InnerNode<K>>,
@@ -416,16 +969,16 @@ impl<K> InnerNode<K>
         bptree: &mut BPTree<K, V>
     ) -> Result<Option<PagePtr>>
     where
-        V: Debug + Clone + Ord  +  Serialize + DeserializeOwned,
+        V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
     {
-        match self.childptrs.binary_search(&page_ptr) {
-            Err(_) => panic!("Programming error: deleted page should be present!"),
-            Ok(i) => {
+        match self.childptrs.iter().position(|ptr| *ptr == page_ptr) {
+            None => panic!("Programming error: deleted page should be present!"),
+            Some(i) => {
                 self.keys.remove(i-1);
                 self.childptrs.remove(i);
                 let deleted_page_ptr = match parent{
                     None => {
-                        if self.keys.len() == 0 {
+                        if self.keys.is_empty() {
                             let new_root_page_ptr = self.childptrs[0];
                             bptree.set_root(Some(new_root_page_ptr));
                             bptree.delete_page(self.ptr);
@@ -440,50 +993,70 @@ impl<K> InnerNode<K>
                         if self.keys.len() < bptree.split_at() {
                             let mut done = false;
                             let path_info = path_info.unwrap();
-                            if path_info.lsibling.is_some() {
-                                let mut node = InnerNode::new(path_info.lsibling.unwrap())
-                                    .load_node_from_page(bptree.get_pager().load_page(path_info.lsibling.unwrap())?)?;
+                            if let Some(lsibling) = path_info.lsibling {
+                                let mut node = InnerNode::load(lsibling, bptree.get_pager())?;
                                 if node.keys.len() > bptree.split_at() {
                                     let k: K = node.keys.pop().unwrap();
                                     let v = node.childptrs.pop().unwrap();
-                                    self.keys.insert(0, k.clone());
+                                    let separator = mem::replace(&mut parent.keys[path_info.rparent.unwrap()], k.clone());
+                                    self.keys.insert(0, separator);
                                     self.childptrs.insert(0, v);
-                                    parent.keys[path_info.rparent.unwrap()] = k;
-                                    node.store_node_to_page(bptree.get_pager())?;
-                                    done = true;
+                                    // Separators differ in size, so the borrowed one may not fit.
+                                    if self.fits(bptree.pager())? {
+                                        node.high = Some(k.clone());
+                                        self.low = Some(k);
+                                        node.store_node_to_page(bptree.get_pager())?;
+                                        done = true;
+                                    } else {
+                                        node.keys.push(mem::replace(&mut parent.keys[path_info.rparent.unwrap()], self.keys.remove(0)));
+                                        node.childptrs.push(self.childptrs.remove(0));
+                                    }
                                 }
                             }
-                            if !done && path_info.rsibling.is_some(){
-                                let mut node = InnerNode::new(path_info.rsibling.unwrap())
-                                    .load_node_from_page(bptree.get_pager().load_page(path_info.rsibling.unwrap())?)?;
+                            if let (false, Some(rsibling)) = (done, path_info.rsibling) {
+                                let mut node = InnerNode::load(rsibling, bptree.get_pager())?;
                                 if node.keys.len() > bptree.split_at() {
-                                    let k = node.keys.remove(0);
+                                    let k: K = node.keys.remove(0);
                                     let v = node.childptrs.remove(0);
-                                    self.keys.push(k);
+                                    let separator = mem::replace(&mut parent.keys[path_info.lparent.unwrap()], k.clone());
+                                    self.keys.push(separator);
                                     self.childptrs.push(v);
-                                    parent.keys[path_info.lparent.unwrap()] = node.keys[0].clone();
-                                    node.store_node_to_page(bptree.get_pager())?;
-                                    done = true;
+                                    if self.fits(bptree.pager())? {
+                                        self.high = Some(k.clone());
+                                        node.low = Some(k);
+                                        node.store_node_to_page(bptree.get_pager())?;
+                                        done = true;
+                                    } else {
+                                        node.keys.insert(0, mem::replace(&mut parent.keys[path_info.lparent.unwrap()], self.keys.pop().unwrap()));
+                                        node.childptrs.insert(0, self.childptrs.pop().unwrap());
+                                    }
                                 }
                             }
+                            // Nodes too big to merge stay as they are, under half full.
                             if !done {
-                                if path_info.lsibling.is_some() {
-                                    let mut node = InnerNode::new(path_info.lsibling.unwrap())
-                                        .load_node_from_page(bptree.get_pager().load_page(path_info.lsibling.unwrap())?)?;
+                                if let Some(lsibling) = path_info.lsibling {
+                                    let mut node = InnerNode::load(lsibling, bptree.get_pager())?;
                                     node.keys.push(parent.keys[path_info.rparent.unwrap()].clone());
-                                    node.keys.extend(self.keys.iter().map(|k| k.clone()));
+                                    node.keys.extend(self.keys.iter().cloned());
                                     node.childptrs.extend(&self.childptrs);
-                                    deleted_page = Some(self.ptr);
-                                    node.store_node_to_page(bptree.get_pager())?;
+                                    node.high = self.high.clone();
+                                    if node.fits(bptree.pager())? {
+                                        node.store_node_to_page(bptree.get_pager())?;
+                                        deleted_page = Some(self.ptr);
+                                        bptree.delete_page(self.ptr);
+                                    }
                                 }
-                                else if path_info.rsibling.is_some(){
-                                    let node = InnerNode::new(path_info.rsibling.unwrap())
-                                        .load_node_from_page(bptree.get_pager().load_page(path_info.rsibling.unwrap())?)?;
-                                    self.keys.push(parent.keys[path_info.lparent.unwrap()].clone());
-                                    self.keys.extend(node.keys);
-                                    self.childptrs.extend(node.childptrs);
-                                    deleted_page = Some(node.ptr);
-                                    self.store_node_to_page(bptree.get_pager())?;
+                                else if let Some(rsibling) = path_info.rsibling {
+                                    let node = InnerNode::load(rsibling, bptree.get_pager())?;
+                                    let mut merged = InnerNode::from(self.ptr, &self.keys, &self.childptrs).with_fences(self.low.clone(), node.high.clone());
+                                    merged.keys.push(parent.keys[path_info.lparent.unwrap()].clone());
+                                    merged.keys.extend(node.keys);
+                                    merged.childptrs.extend(node.childptrs);
+                                    if merged.fits(bptree.pager())? {
+                                        *self = merged;
+                                        deleted_page = Some(node.ptr);
+                                        bptree.delete_page(node.ptr);
+                                    }
                                 }
                             }
                         }
@@ -502,6 +1075,8 @@ impl<K> InnerNode<K>
     fn split(&mut self, next_ptr: PagePtr, split_at: usize) -> Result<(K, Self)> {
         let split_key = self.keys[split_at].clone();
         let mut node = Self::from(next_ptr, &self.keys[split_at+1..], &self.childptrs[split_at+1..]);
+        node.low = Some(split_key.clone());
+        node.high = self.high.replace(split_key.clone());
         self.keys.drain(split_at..);
         self.childptrs.drain(split_at+1..);
         Ok((split_key, node))
@@ -512,6 +1087,165 @@ impl<K> InnerNode<K>
         self.childptrs.insert(i + 1, value);
     }
 
+    // Takes the children whose keys all lie in [start, end) out of this node, and out of the
+    // children the range only partly covers, and appends them to `cut` in key order. The
+    // children on either side of a cut take over its keys, so their fences widen. Nodes can
+    // be left with a single child; BPTree::settle() merges those away afterwards.
+    pub(crate) fn prune(&mut self, start: Bound<&K>, end: Bound<&K>, pager: &Pager, cut: &mut Vec<PagePtr>) -> Result<()> {
+        let order = pager.order();
+        let mut covered = Vec::new();
+        for i in 0..self.childptrs.len() {
+            let (low, high) = self.child_bounds(i);
+            let starts_inside = match start {
+                Bound::Unbounded => true,
+                Bound::Included(start) => low.is_some_and(|low| order.cmp::<K, K>(low, start).is_ge()),
+                Bound::Excluded(start) => low.is_some_and(|low| order.cmp::<K, K>(low, start).is_gt()),
+            };
+            let ends_inside = match end {
+                Bound::Unbounded => true,
+                Bound::Included(end) | Bound::Excluded(end) => high.is_some_and(|high| order.cmp::<K, K>(high, end).is_le()),
+            };
+            let before = match start {
+                Bound::Unbounded => false,
+                Bound::Included(start) | Bound::Excluded(start) => high.is_some_and(|high| order.cmp::<K, K>(high, start).is_le()),
+            };
+            let after = match end {
+                Bound::Unbounded => false,
+                Bound::Included(end) => low.is_some_and(|low| order.cmp::<K, K>(low, end).is_gt()),
+                Bound::Excluded(end) => low.is_some_and(|low| order.cmp::<K, K>(low, end).is_ge()),
+            };
+            if starts_inside && ends_inside {
+                covered.push(i);
+                cut.push(self.childptrs[i]);
+            } else if !before && !after {
+                if let Some(mut child) = Self::load_inner(self.childptrs[i], pager)? {
+                    child.prune(start, end, pager, cut)?;
+                    child.store_node_to_page(pager)?;
+                }
+            }
+        }
+        // The covered children are one run, and never all of them: then this node would
+        // have been covered itself.
+        match (covered.first(), covered.last()) {
+            (Some(0), Some(&last)) => {
+                self.keys.drain(..=last);
+                self.childptrs.drain(..=last);
+                Self::widen(self.childptrs[0], false, self.low.clone(), pager)
+            }
+            (Some(&first), Some(&last)) => {
+                self.keys.drain(first - 1..last);
+                self.childptrs.drain(first..=last);
+                let high = self.keys.get(first - 1).or(self.high.as_ref()).cloned();
+                Self::widen(self.childptrs[first - 1], true, high, pager)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Merges the child `key` falls in into a neighbour when it is an empty leaf or an inner
+    // node with a single child, or moves a child over to it from the neighbour when the
+    // merged node would not fit. Needs a node with two children at least, and returns
+    // whether it changed it; storing it is the caller's.
+    pub(crate) fn settle_child<V, Q>(&mut self, key: &Q, bptree: &mut BPTree<K, V>) -> Result<bool>
+    where
+        V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+        K: Borrow<Q>,
+        Q: Ord + ?Sized + 'static,
+    {
+        let i = match bptree.pager().order().search(&self.keys, key) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        // The separator between the child and the neighbour it settles with.
+        let (left, right) = if i > 0 { (i - 1, i) } else { (0, 1) };
+        match Node::<K, V>::load_node(self.childptrs[i], bptree.pager())? {
+            Node::Leaf(leaf) if leaf.keys.is_empty() => {
+                link_leaves::<K, V>(leaf.prev, leaf.next, bptree.pager())?;
+                bptree.delete_page(leaf.ptr);
+                self.keys.remove(left);
+                self.childptrs.remove(i);
+                bptree.count_merge();
+            }
+            Node::Inner(inner) if inner.keys.is_empty() => {
+                let (mut lnode, mut rnode) = match i == left {
+                    true => (inner, InnerNode::load(self.childptrs[right], bptree.pager())?),
+                    false => (InnerNode::load(self.childptrs[left], bptree.pager())?, inner),
+                };
+                let mut merged = InnerNode::from(lnode.ptr, &lnode.keys, &lnode.childptrs).with_fences(lnode.low.clone(), rnode.high.clone());
+                merged.keys.push(self.keys[left].clone());
+                merged.keys.extend(rnode.keys.iter().cloned());
+                merged.childptrs.extend(&rnode.childptrs);
+                if merged.fits(bptree.pager())? {
+                    merged.store_node_to_page(bptree.pager())?;
+                    bptree.delete_page(rnode.ptr);
+                    self.keys.remove(left);
+                    self.childptrs.remove(right);
+                    bptree.count_merge();
+                } else {
+                    // The neighbour is too full to merge with, so it has children to spare.
+                    let separator = match i == left {
+                        true => {
+                            lnode.keys.push(self.keys[left].clone());
+                            lnode.childptrs.push(rnode.childptrs.remove(0));
+                            rnode.keys.remove(0)
+                        }
+                        false => {
+                            rnode.keys.insert(0, self.keys[left].clone());
+                            rnode.childptrs.insert(0, lnode.childptrs.pop().unwrap());
+                            lnode.keys.pop().unwrap()
+                        }
+                    };
+                    lnode.high = Some(separator.clone());
+                    rnode.low = Some(separator.clone());
+                    self.keys[left] = separator;
+                    lnode.store_node_to_page(bptree.pager())?;
+                    rnode.store_node_to_page(bptree.pager())?;
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Sets the high fence, or the low one, of the inner nodes down that edge of the subtree.
+    fn widen(mut ptr: PagePtr, high: bool, fence: Option<K>, pager: &Pager) -> Result<()> {
+        while let Some(mut node) = Self::load_inner(ptr, pager)? {
+            match high {
+                true => node.high = fence.clone(),
+                false => node.low = fence.clone(),
+            }
+            node.store_node_to_page(pager)?;
+            ptr = if high { *node.childptrs.last().unwrap() } else { node.childptrs[0] };
+        }
+        Ok(())
+    }
+
+    // The node at `ptr`, None if it is a leaf.
+    pub(crate) fn load_inner(ptr: PagePtr, pager: &Pager) -> Result<Option<Self>> {
+        let page = pager.load_page(ptr)?;
+        match page.get_page_byte(NODE_TYPE_OFFSET) {
+            INNER_NODE_TYPE => Ok(Some(Self::new(ptr).load_node_from_page(page, pager.codec())?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+// Makes the leaves at `prev` and `next` neighbours, once the ones between them are gone.
+pub(crate) fn link_leaves<K, V>(prev: Option<PagePtr>, next: Option<PagePtr>, pager: &Pager) -> Result<()>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    if let Some(ptr) = prev {
+        let mut leaf = LeafNode::<K, V>::load(ptr, pager)?;
+        leaf.next = next;
+        leaf.store_node_to_page(pager)?;
+    }
+    if let Some(ptr) = next {
+        let mut leaf = LeafNode::<K, V>::load(ptr, pager)?;
+        leaf.prev = prev;
+        leaf.store_node_to_page(pager)?;
+    }
+    Ok(())
 }
 
 pub enum Node<K, V> {
@@ -520,8 +1254,8 @@ pub enum Node<K, V> {
 }
 
 impl<K, V> Node<K, V>
-    where K: Debug + Clone + Ord + Serialize + DeserializeOwned,
-          V: Debug + Clone + Ord + Serialize + DeserializeOwned
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
 {
     pub fn store_node(self, pager: &mut Pager) -> Result<()>{
         match self {
@@ -531,47 +1265,61 @@ impl<K, V> Node<K, V>
         Ok(())
     }
 
-    pub fn load_node(page_ptr: PagePtr, pager: &mut Pager) ->Result<Self> {
+    pub fn load_node(page_ptr: PagePtr, pager: &Pager) ->Result<Self> {
         let page = pager.load_page(page_ptr)?;
+        Self::from_page(page_ptr, page, pager.codec())
+    }
+
+    pub fn from_page(page_ptr: PagePtr, page: Page, codec: &Codec) -> Result<Self> {
         match page.get_page_byte(NODE_TYPE_OFFSET) {
-            LEAF_NODE_TYPE => { Ok(Node::Leaf(LeafNode::new(page_ptr).load_node_from_page(page)?))},
-            INNER_NODE_TYPE => {Ok(Node::Inner(InnerNode::new(page_ptr).load_node_from_page(page)?))},
+            LEAF_NODE_TYPE => { Ok(Node::Leaf(LeafNode::new(page_ptr).load_node_from_page(page, codec)?))},
+            INNER_NODE_TYPE => {Ok(Node::Inner(InnerNode::new(page_ptr).load_node_from_page(page, codec)?))},
             _ =>{Err(Error::UnkonwNodeType)}
         }
     }
 
-    pub fn get(self, key: &K, pager: &mut Pager) -> Result<Option<V>> {
+    pub fn get<Q>(self, key: &Q, pager: &Pager) -> Result<Option<V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.find_leaf(key, pager)?.get(key, pager)
+    }
+
+    pub fn find_leaf<Q>(self, key: &Q, pager: &Pager) -> Result<LeafNode<K, V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
         match self {
             Self::Leaf(leaf_node) =>{
-                Ok(leaf_node.get(key))
+                Ok(leaf_node)
             }
             Self::Inner(inner_node) => {
-                let mut child_ptr = inner_node.get(key);
+                let mut child_ptr = inner_node.get(key, pager);
                 loop {
                     match Self::load_node(child_ptr, pager)? {
-                        Self::Leaf(leaf_node) => { return Ok(leaf_node.get(key)) },
-                        Self::Inner(inner_node) => { child_ptr = inner_node.get(key);}
+                        Self::Leaf(leaf_node) => { return Ok(leaf_node) },
+                        Self::Inner(inner_node) => { child_ptr = inner_node.get(key, pager);}
                     }
                 }
             }
         }
     }
 
-    pub fn set(self, key: K, value: V, bptree: &mut BPTree<K, V>) -> Result<Option<(K,PagePtr)>> {
+    pub fn set(self, key: K, value: Entry<V>, replaced: Option<&mut Option<V>>, bptree: &mut BPTree<K, V>) -> Result<Option<(K,PagePtr)>> {
         match self {
-            Self::Leaf(mut leaf_node) => leaf_node.set(key, value, bptree),
-            Self::Inner(mut inner_node) => inner_node.set(key, value, bptree),
+            Self::Leaf(mut leaf_node) => leaf_node.set(key, value, replaced, bptree),
+            Self::Inner(mut inner_node) => inner_node.set(key, value, replaced, bptree),
         }
     }
 
-    pub fn remove(self, key: &K, bptree: &mut BPTree<K, V>) -> Result<(Option<V>, Option<PagePtr>)> {
+    pub fn remove<Q>(self, key: &Q, bptree: &mut BPTree<K, V>) -> Result<Removed<K, V>>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
         match self {
-            Self::Leaf(mut leaf_node) => leaf_node.remove(key, None, None, bptree),
-            Self::Inner(mut inner_node) => inner_node.remove(key, None, None, bptree),
+            Self::Leaf(leaf_node) => leaf_node.remove(key, None, None, bptree),
+            Self::Inner(inner_node) => inner_node.remove(key, None, None, bptree),
         }
     }
 
-    pub fn new_leaf(ptr: PagePtr, keys: &[K], entries: &[V], next: Option<PagePtr>) -> Self{
+    pub fn new_leaf(ptr: PagePtr, keys: &[K], entries: &[Entry<V>], next: Option<PagePtr>) -> Self{
         Self::Leaf(LeafNode::from(ptr, keys, entries, next))
     }
 
@@ -583,42 +1331,207 @@ impl<K, V> Node<K, V>
 
 #[cfg(test)]
 mod test{
+    use std::ops::RangeBounds;
     use std::path::Path;
+    use crate::engine::dump::DumpFormat;
     use super::*;
     #[test]
     fn test_node() -> Result<()> {
-        let path = Path::new("data\\t4.txt");
+        let path = Path::new("data").join("test_node.db");
         let mut bptree: BPTree<u128, u128> = BPTree::new(path, Some(5))?;
         for i in 1..=60 {
-            bptree.set(i, i*10);
+            bptree.set(i, i*10)?;
         }
         for i in 1..=60{
-            println!("{}", bptree.get(i)?);
-            assert_eq!(i*10, bptree.get(i)?);
+            println!("{}", bptree.get(&i)?);
+            assert_eq!(i*10, bptree.get(&i)?);
         }
         for i in 1..=14 {
             let key = i*3;
             bptree.remove(&key)?;
         }
-        let p1 = 15;
-        let p2 = 17;
-        for p in 0..26{
-            let n:Node<u128, u128> = Node::load_node(p,bptree.get_pager())?;
-            match n{
-                Node::Leaf(leaf) => println!("{:?}", leaf),
-                Node::Inner(inner) => println!("{:?}", inner),
-            }
-        }
+        bptree.dump(std::io::stdout(), DumpFormat::Text)?;
         bptree.print_deleted();
         for i in 1..=60{
-            match bptree.get(i){
+            match bptree.get(&i){
                 Ok(j) => println!("{}", j),
-                Err(e) => {println!("{} is removed", i)}
+                Err(_) => {println!("{} is removed", i)}
             }
 
         }
         Ok(())
     }
 
-}
+    fn check_fences(ptr: PagePtr, low: Option<u64>, high: Option<u64>, pager: &Pager) -> Result<()> {
+        match Node::<u64, u64>::load_node(ptr, pager)? {
+            Node::Leaf(leaf) => {
+                for key in &leaf.keys {
+                    assert!(low.is_none_or(|low| low <= *key) && high.is_none_or(|high| *key < high));
+                }
+            }
+            Node::Inner(inner) => {
+                assert_eq!((inner.low, inner.high), (low, high));
+                for (i, child) in inner.childptrs.iter().enumerate() {
+                    let (child_low, child_high) = inner.child_bounds(i);
+                    check_fences(*child, child_low.cloned(), child_high.cloned(), pager)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fences_follow_splits_and_merges() -> Result<()> {
+        const STEPS: u64 = 300;
+        let path = Path::new("data").join("test_fences.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        for i in 0..400 {
+            bptree.set((i * 7919) % 400, i)?;
+        }
+        let root = bptree.root_ptr().unwrap();
+        check_fences(root, None, None, bptree.pager())?;
+        for i in 0..STEPS {
+            let key = (i * 131) % 400;
+            bptree.remove(&key)?;
+            let root = bptree.root_ptr().unwrap();
+            check_fences(root, None, None, bptree.pager())?;
+        }
+        let removed: Vec<u64> = (0..STEPS).map(|i| (i * 131) % 400).collect();
+        for key in 0..400 {
+            assert_eq!(bptree.get(&key).is_ok(), !removed.contains(&key));
+        }
+        assert!(matches!(bptree.get(&1000), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_range_cuts_subtrees() -> Result<()> {
+        let path = Path::new("data").join("test_remove_range_cuts.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        let mut model = std::collections::BTreeMap::new();
+        for i in 0..2000u64 {
+            bptree.set(i, i)?;
+            model.insert(i, i);
+        }
+        let free = bptree.free_page_count();
+        assert_eq!(bptree.remove_range(300..1700)?, 1400);
+        assert!(bptree.free_page_count() > free + 400);
+        model.retain(|key, _| !(300..1700).contains(key));
+        let ranges = [(Bound::Excluded(10), Bound::Included(250)), (Bound::Included(1750), Bound::Unbounded), (Bound::Unbounded, Bound::Excluded(5))];
+        for range in ranges {
+            let expected = model.range(range).count() as u64;
+            assert_eq!(bptree.remove_range(range)?, expected);
+            model.retain(|key, _| !range.contains(key));
+            check_fences(bptree.root_ptr().unwrap(), None, None, bptree.pager())?;
+        }
+        assert_eq!(bptree.key_count(), model.len() as u64);
+        let forward = bptree.scan_all()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(forward, model.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+        let backward = bptree.iter_rev()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(backward, model.keys().rev().copied().collect::<Vec<_>>());
+        assert!(bptree.verify(|_| ())?.is_ok());
+        for i in 0..2000u64 {
+            bptree.set(i, i)?;
+        }
+        check_fences(bptree.root_ptr().unwrap(), None, None, bptree.pager())?;
+        assert_eq!(bptree.remove_range(..)?, 2000);
+        assert!(bptree.root_ptr().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_range_rebalances_the_cut_edges() -> Result<()> {
+        let path = Path::new("data").join("test_remove_range_edges.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(&path, Some(4))?;
+        for i in 0..200u64 {
+            bptree.set(i, i)?;
+        }
+        bptree.remove_range(100..130)?;
+        bptree.remove_range(130..)?;
+        check_node_sizes::<u64, u64>(bptree.root_ptr().unwrap(), bptree.pager())?;
+        bptree.close()?;
+        let mut bptree: BPTree<u64, u64> = BPTree::open(&path)?;
+        bptree.set(50, 500)?;
+        assert_eq!(bptree.len(), 100);
+        for i in 0..100u64 {
+            assert_eq!(bptree.get(&i)?, if i == 50 { 500 } else { i });
+        }
 
+        // Writes, removals and range removals against a BTreeMap, reopening now and then.
+        let mut model = std::collections::BTreeMap::new();
+        let mut bptree: BPTree<u64, u64> = BPTree::new(&path, Some(4))?;
+        let mut state = 7u64;
+        let mut next = |n: u64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % n
+        };
+        for step in 0..3000 {
+            let key = next(500);
+            match next(10) {
+                0..=5 => {
+                    bptree.set(key, step)?;
+                    model.insert(key, step);
+                }
+                6 | 7 => {
+                    assert_eq!(bptree.remove(&key).is_ok(), model.remove(&key).is_some());
+                }
+                8 => {
+                    let end = key + next(120);
+                    assert_eq!(bptree.remove_range(key..end)?, model.range(key..end).count() as u64);
+                    model.retain(|k, _| !(key..end).contains(k));
+                }
+                _ => {
+                    bptree.close()?;
+                    bptree = BPTree::open(&path)?;
+                }
+            }
+            if let Some(root) = bptree.root_ptr() {
+                check_node_sizes::<u64, u64>(root, bptree.pager())?;
+            }
+        }
+        assert_eq!(bptree.scan_all()?.collect::<Result<Vec<_>>>()?, model.into_iter().collect::<Vec<_>>());
+        assert!(bptree.verify(|_| ())?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_entries_split_by_size() -> Result<()> {
+        let key = |i: u64| format!("{:0>300}", i);
+        let mut bptree: BPTree<String, Vec<u8>> = BPTree::new(Path::new("data").join("test_large_entries.db"), None)?;
+        for i in 0..300 {
+            bptree.set(key(i), vec![i as u8; 1000])?;
+        }
+        bptree.set(key(7), vec![1; 900])?;
+        for i in (0..300).filter(|i| i % 3 != 0) {
+            bptree.remove(&key(i))?;
+        }
+        for i in 0..300 {
+            match i % 3 {
+                0 => assert_eq!(bptree.get(&key(i))?, vec![i as u8; 1000]),
+                _ => assert!(matches!(bptree.get(&key(i)), Err(Error::KeyNotFound))),
+            }
+        }
+        assert_eq!(bptree.scan_all()?.count(), 100);
+        assert!(bptree.verify(|_| ())?.corrupt.is_empty());
+        assert!(matches!(bptree.set("k".repeat(max_key_size(bptree.page_size()) as usize), Vec::new()), Err(Error::KeyTooLarge(_))));
+
+        let mut loaded: BPTree<String, Vec<u8>> = BPTree::new(Path::new("data").join("test_large_entries_bulk.db"), None)?;
+        loaded.bulk_load((0..300).map(|i| (key(i), vec![i as u8; 1000])).collect())?;
+        assert_eq!(loaded.get(&key(299))?, vec![43u8; 1000]);
+        check_node_sizes::<String, Vec<u8>>(loaded.root_ptr().unwrap(), loaded.pager())
+    }
+
+    fn check_node_sizes<K, V>(ptr: PagePtr, pager: &Pager) -> Result<()>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        match Node::<K, V>::load_node(ptr, pager)? {
+            Node::Leaf(leaf) => assert!(!leaf.is_empty()),
+            Node::Inner(inner) => {
+                assert!(!inner.keys().is_empty());
+                inner.childptrs().iter().try_for_each(|child| check_node_sizes::<K, V>(*child, pager))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::engine::heat::Heat;
+use crate::engine::page::{Page, PagePtr};
+use crate::engine::pressure::{self, MemoryPressure, PressureWatch};
+
+// Share of the cache reserved for pages that were hit at least once after being loaded.
+const PROTECTED_PERCENT: usize = 80;
+// How many of the oldest protected pages compete on heat when one has to be demoted.
+const DEMOTION_WINDOW: usize = 4;
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_MAX: u8 = 15;
+
+// Whether a page read from disk may take the place of the eviction victim.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Admission {
+    #[default]
+    Always,
+    // Only when the page has been asked for more often lately than the victim (TinyLFU).
+    TinyLfu,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub rejections: u64,
+    // Pages the cache may hold right now, below the configured size under memory pressure.
+    pub capacity: usize,
+    // With write-back: pages changed in the cache but not yet in the file, and how many
+    // were written out because they were evicted.
+    pub dirty: usize,
+    pub write_backs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Probation,
+    Protected,
+}
+
+// Count-min sketch of recent page requests with small saturating counters. All counters
+// are halved every `sample` requests, so the estimates follow the current workload.
+struct FrequencySketch {
+    counters: Vec<[u8; SKETCH_DEPTH]>,
+    mask: u64,
+    additions: usize,
+    sample: usize,
+}
+
+impl FrequencySketch {
+    fn new(capacity: usize) -> Self {
+        // One counter per row for each request of a sample keeps collisions rare.
+        let sample = capacity.max(1) * 10;
+        let width = sample.next_power_of_two();
+        Self{
+            counters: vec![[0; SKETCH_DEPTH]; width],
+            mask: width as u64 - 1,
+            additions: 0,
+            sample,
+        }
+    }
+
+    fn slot(&self, ptr: PagePtr, row: usize) -> usize {
+        // splitmix64 finalizer, seeded differently per row.
+        let mut hash = ptr.wrapping_add((row as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ((hash ^ (hash >> 31)) & self.mask) as usize
+    }
+
+    fn record(&mut self, ptr: PagePtr) {
+        for row in 0..SKETCH_DEPTH {
+            let slot = self.slot(ptr, row);
+            let counter = &mut self.counters[slot][row];
+            *counter = (*counter + 1).min(SKETCH_MAX);
+        }
+        self.additions += 1;
+        if self.additions >= self.sample {
+            self.additions /= 2;
+            for counters in &mut self.counters {
+                counters.iter_mut().for_each(|counter| *counter /= 2);
+            }
+        }
+    }
+
+    fn estimate(&self, ptr: PagePtr) -> u8 {
+        (0..SKETCH_DEPTH).map(|row| self.counters[self.slot(ptr, row)][row]).min().unwrap_or(0)
+    }
+}
+
+struct Cached {
+    tick: u64,
+    segment: Segment,
+    page: Page,
+    dirty: bool,
+}
+
+// Cache of pages, split in two LRU segments. Pages come in on probation and
+// move to the protected segment when they are hit there, so a scan that reads every page
+// once only ever churns the probation segment. When the protected segment is full, the
+// coldest of its oldest pages by recent access count goes back on probation. With TinyLFU
+// admission a full cache also turns away pages requested less often than the victim.
+// When it watches memory pressure, the capacity follows it between the configured size and
+// a small floor. Dirty pages are the only copy of a write: whatever pushes one out of the
+// cache hands it back to the caller to be written to the file.
+pub(crate) struct PageCache {
+    capacity: usize,
+    max_capacity: usize,
+    protected_capacity: usize,
+    pages: HashMap<PagePtr, Cached>,
+    probation: BTreeMap<u64, PagePtr>,
+    protected: BTreeMap<u64, PagePtr>,
+    tick: u64,
+    heat: Heat,
+    sketch: Option<FrequencySketch>,
+    stats: CacheStats,
+    pressure: Option<PressureWatch>,
+    dirty: usize,
+}
+
+impl PageCache {
+    pub(crate) fn new(capacity: usize, admission: Admission) -> Self {
+        Self{
+            capacity,
+            max_capacity: capacity,
+            protected_capacity: capacity * PROTECTED_PERCENT / 100,
+            pages: HashMap::with_capacity(capacity),
+            probation: BTreeMap::new(),
+            protected: BTreeMap::new(),
+            tick: 0,
+            heat: Heat::default(),
+            sketch: match admission {
+                Admission::Always => None,
+                Admission::TinyLfu => Some(FrequencySketch::new(capacity)),
+            },
+            stats: CacheStats::default(),
+            pressure: None,
+            dirty: 0,
+        }
+    }
+
+    pub(crate) fn watch(&mut self, source: MemoryPressure) {
+        self.pressure = Some(PressureWatch::new(source));
+    }
+
+    pub(crate) fn get(&mut self, ptr: PagePtr) -> Option<Page> {
+        self.heat.record_read(ptr);
+        if let Some(sketch) = &mut self.sketch {
+            sketch.record(ptr);
+        }
+        let segment = match self.pages.get(&ptr) {
+            Some(cached) => cached.segment,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        self.stats.hits += 1;
+        self.unlink(ptr);
+        if segment == Segment::Probation && self.protected.len() >= self.protected_capacity {
+            self.demote();
+        }
+        self.link(ptr, Segment::Protected);
+        self.pages.get(&ptr).map(|cached| cached.page.clone())
+    }
+
+    // Returns the dirty pages that had to make room, and the page itself if it is dirty and
+    // was turned away; the caller writes them to the file.
+    pub(crate) fn insert(&mut self, ptr: PagePtr, page: Page, dirty: bool) -> Vec<(PagePtr, Page)> {
+        if let Some(cached) = self.pages.get_mut(&ptr) {
+            cached.page = page;
+            match (cached.dirty, dirty) {
+                (false, true) => self.dirty += 1,
+                (true, false) => self.dirty -= 1,
+                _ => (),
+            }
+            cached.dirty = dirty;
+            return Vec::new();
+        }
+        let mut evicted = Vec::new();
+        if let Some(pressure) = self.pressure.as_mut().and_then(PressureWatch::poll) {
+            evicted = self.resize(pressure::target_capacity(self.max_capacity, pressure));
+        }
+        if self.pages.len() >= self.capacity {
+            let victim = match self.victim() {
+                Some(victim) => victim,
+                None if dirty => {
+                    evicted.push((ptr, page));
+                    return evicted;
+                }
+                None => return evicted,
+            };
+            if let Some(sketch) = &self.sketch {
+                if sketch.estimate(ptr) <= sketch.estimate(victim) {
+                    self.stats.rejections += 1;
+                    if dirty {
+                        evicted.push((ptr, page));
+                    }
+                    return evicted;
+                }
+            }
+            evicted.extend(self.evict(victim));
+        }
+        self.dirty += usize::from(dirty);
+        self.pages.insert(ptr, Cached{ tick: 0, segment: Segment::Probation, page, dirty });
+        self.link(ptr, Segment::Probation);
+        evicted
+    }
+
+    // The page if it is newer than the file.
+    pub(crate) fn dirty_page(&self, ptr: PagePtr) -> Option<Page> {
+        self.pages.get(&ptr).filter(|cached| cached.dirty).map(|cached| cached.page.clone())
+    }
+
+    // Every dirty page, in page order; they count as clean from here on.
+    pub(crate) fn take_dirty(&mut self) -> Vec<(PagePtr, Page)> {
+        let mut dirty: Vec<(PagePtr, Page)> = self.pages.iter_mut()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(ptr, cached)| {
+                cached.dirty = false;
+                (*ptr, cached.page.clone())
+            })
+            .collect();
+        dirty.sort_unstable_by_key(|(ptr, _)| *ptr);
+        self.dirty = 0;
+        dirty
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats{ capacity: self.capacity, dirty: self.dirty, ..self.stats }
+    }
+
+    fn evict(&mut self, victim: PagePtr) -> Option<(PagePtr, Page)> {
+        self.unlink(victim);
+        let cached = self.pages.remove(&victim)?;
+        self.stats.evictions += 1;
+        if !cached.dirty {
+            return None;
+        }
+        self.dirty -= 1;
+        self.stats.write_backs += 1;
+        Some((victim, cached.page))
+    }
+
+    // Evicts pages, coldest first, until no more than `capacity` are left.
+    fn resize(&mut self, capacity: usize) -> Vec<(PagePtr, Page)> {
+        self.capacity = capacity;
+        self.protected_capacity = capacity * PROTECTED_PERCENT / 100;
+        let mut evicted = Vec::new();
+        while self.pages.len() > capacity {
+            let victim = match self.victim() {
+                Some(victim) => victim,
+                None => break,
+            };
+            evicted.extend(self.evict(victim));
+        }
+        while self.protected.len() > self.protected_capacity {
+            self.demote();
+        }
+        evicted
+    }
+
+    fn link(&mut self, ptr: PagePtr, segment: Segment) {
+        self.tick += 1;
+        if let Some(cached) = self.pages.get_mut(&ptr) {
+            cached.tick = self.tick;
+            cached.segment = segment;
+        }
+        match segment {
+            Segment::Probation => self.probation.insert(self.tick, ptr),
+            Segment::Protected => self.protected.insert(self.tick, ptr),
+        };
+    }
+
+    fn unlink(&mut self, ptr: PagePtr) {
+        if let Some(cached) = self.pages.get(&ptr) {
+            match cached.segment {
+                Segment::Probation => self.probation.remove(&cached.tick),
+                Segment::Protected => self.protected.remove(&cached.tick),
+            };
+        }
+    }
+
+    fn demote(&mut self) {
+        let coldest = self.protected.values()
+            .take(DEMOTION_WINDOW)
+            .min_by_key(|ptr| self.heat.get(**ptr).reads)
+            .copied();
+        if let Some(ptr) = coldest {
+            self.unlink(ptr);
+            self.link(ptr, Segment::Probation);
+        }
+    }
+
+    fn victim(&self) -> Option<PagePtr> {
+        self.probation.values().next().or_else(|| self.protected.values().next()).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::allocation::PageKind;
+    use crate::engine::bptree::{BPTree, Options};
+    use crate::engine::page::DEFAULT_PAGE_SIZE;
+    use crate::error::Result;
+    use super::*;
+
+    #[test]
+    fn test_hot_pages_survive_scan() {
+        let mut cache = PageCache::new(16, Admission::Always);
+        let read = |cache: &mut PageCache, ptr| {
+            if cache.get(ptr).is_none() {
+                cache.insert(ptr, Page::new(DEFAULT_PAGE_SIZE), false);
+            }
+        };
+        for _ in 0..10 {
+            for ptr in 0..4 {
+                read(&mut cache, ptr);
+            }
+        }
+        // The working set is touched once per 32 scanned pages, which a plain LRU of this
+        // size would evict every time.
+        for ptr in 100..1060 {
+            read(&mut cache, ptr);
+            if ptr % 32 == 0 {
+                (0..4).for_each(|hot| read(&mut cache, hot));
+            }
+        }
+        assert_eq!(cache.stats().misses, 4 + 960);
+    }
+
+    // Working set misses over rounds of touching 14 hot pages and then scanning 50 pages
+    // that are never read again.
+    fn working_set_misses(admission: Admission) -> u64 {
+        let mut cache = PageCache::new(16, admission);
+        let mut misses = 0;
+        for round in 0..20u64 {
+            for ptr in 0..14 {
+                if cache.get(ptr).is_none() {
+                    misses += 1;
+                    cache.insert(ptr, Page::new(DEFAULT_PAGE_SIZE), false);
+                }
+            }
+            for ptr in 0..50 {
+                let ptr = 1000 + round * 50 + ptr;
+                if cache.get(ptr).is_none() {
+                    cache.insert(ptr, Page::new(DEFAULT_PAGE_SIZE), false);
+                }
+            }
+        }
+        misses
+    }
+
+    #[test]
+    fn test_tiny_lfu_keeps_working_set() {
+        assert_eq!(working_set_misses(Admission::TinyLfu), 14);
+        assert!(working_set_misses(Admission::Always) > 14);
+    }
+
+    #[test]
+    fn test_write_back_defers_page_writes() -> Result<()> {
+        let path = Path::new("data").join("test_write_back.db");
+        let options = Options{ max_key_count: Some(8), cache_pages: 32, write_back: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(&path, options)?;
+        for i in 0..20 {
+            bptree.set(i, i)?;
+        }
+        // Everything still fits the cache, so nothing reached the file yet, but reads of the
+        // file's pages see them.
+        let stats = bptree.cache_stats().unwrap();
+        assert_eq!(stats.write_backs, 0);
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+        assert_eq!(stats.dirty as u64, bptree.page_count());
+        assert_eq!(bptree.allocation_map()?.count(PageKind::Meta), 1);
+
+        for i in 20..2000 {
+            bptree.set(i, i)?;
+        }
+        assert!(bptree.cache_stats().unwrap().write_backs > 0);
+        bptree.close()?;
+        let bptree: BPTree<u64, u64> = BPTree::open(&path)?;
+        assert_eq!(bptree.range(..)?.count(), 2000);
+        assert!(bptree.verify(|_| ())?.corrupt.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{AeadInPlace, KeyInit, OsRng};
+use aes_gcm::{AeadCore, Aes256Gcm, Key, Nonce, Tag};
+use argon2::Argon2;
+use crate::engine::meta::META_PAGE;
+use crate::engine::page::{Page, PagePtr, CHECKSUM_LEN};
+use crate::error::{Error, Result};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// Every page keeps room for the nonce and tag it is sealed with, which go in front of the
+// ciphertext in the file.
+pub const CIPHER_HEADER_LEN: usize = NONCE_LEN + TAG_LEN;
+// Page 0 also holds the salt the key is derived with, which has to be read before anything
+// can be decrypted; so the meta page has this much less room.
+pub const SALT_LEN: usize = 16;
+
+// A passphrase, kept out of Debug output.
+#[derive(Clone)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    pub fn new<S: Into<String>>(passphrase: S) -> Self {
+        Self(passphrase.into())
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+impl From<&str> for Passphrase {
+    fn from(passphrase: &str) -> Self {
+        Self::new(passphrase)
+    }
+}
+
+// Seals pages with AES-256-GCM on their way to the file and opens them on the way back.
+// The key comes from a passphrase through Argon2id. Every write takes a fresh random nonce,
+// and the page number is authenticated with the page, so a page copied to another place in
+// the file doesn't open there either.
+#[derive(Clone)]
+pub(crate) struct PageCipher {
+    cipher: Aes256Gcm,
+    salt: [u8; SALT_LEN],
+}
+
+impl PageCipher {
+    // The key for a new file, with a salt of its own.
+    pub(crate) fn create(passphrase: &Passphrase) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(passphrase, salt)
+    }
+
+    // The key for the file whose page 0, as it is in the file, is `meta`.
+    pub(crate) fn open(passphrase: &Passphrase, meta: &Page) -> Result<Self> {
+        let salt = meta.bytes()[CIPHER_HEADER_LEN..CIPHER_HEADER_LEN + SALT_LEN].try_into().unwrap();
+        Self::derive(passphrase, salt)
+    }
+
+    fn derive(passphrase: &Passphrase, salt: [u8; SALT_LEN]) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default().hash_password_into(passphrase.0.as_bytes(), &salt, &mut key)
+            .map_err(|_| Error::DecryptionFailed)?;
+        Ok(Self{ cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)), salt })
+    }
+
+    // Where the ciphertext goes on page `ptr`, of `size` bytes; the plaintext is as long,
+    // from the start of the page.
+    fn body(ptr: PagePtr, size: usize) -> Range<usize> {
+        let salt = if ptr == META_PAGE { SALT_LEN } else { 0 };
+        CIPHER_HEADER_LEN + salt..size - CHECKSUM_LEN
+    }
+
+    // The page as it goes into the file: nonce, tag, the salt on page 0, the ciphertext and
+    // a checksum of all of it.
+    pub(crate) fn seal(&self, ptr: PagePtr, page: &Page) -> Result<Page> {
+        let body = Self::body(ptr, page.size());
+        let mut sealed = Page::new(page.size());
+        let bytes = sealed.bytes_mut();
+        bytes[body.clone()].copy_from_slice(&page.bytes()[..body.len()]);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let tag = self.cipher.encrypt_in_place_detached(&nonce, &ptr.to_be_bytes(), &mut bytes[body])
+            .map_err(|_| Error::DecryptionFailed)?;
+        bytes[..NONCE_LEN].copy_from_slice(&nonce);
+        bytes[NONCE_LEN..CIPHER_HEADER_LEN].copy_from_slice(&tag);
+        if ptr == META_PAGE {
+            bytes[CIPHER_HEADER_LEN..CIPHER_HEADER_LEN + SALT_LEN].copy_from_slice(&self.salt);
+        }
+        sealed.stamp();
+        Ok(sealed)
+    }
+
+    // The page seal() was given. A page that fails its checksum is handed back as it is, for
+    // the caller to find it corrupt; one that doesn't decrypt, with a checksum that holds, was
+    // sealed with another key or tampered with.
+    pub(crate) fn open_page(&self, ptr: PagePtr, sealed: Page) -> Result<Page> {
+        if !sealed.checksum_ok() {
+            return Ok(sealed);
+        }
+        let body = Self::body(ptr, sealed.size());
+        let len = body.len();
+        let mut page = Page::new(sealed.size());
+        let bytes = page.bytes_mut();
+        bytes[..len].copy_from_slice(&sealed.bytes()[body]);
+        let (nonce, tag) = (&sealed.bytes()[..NONCE_LEN], &sealed.bytes()[NONCE_LEN..CIPHER_HEADER_LEN]);
+        self.cipher.decrypt_in_place_detached(Nonce::from_slice(nonce), &ptr.to_be_bytes(), &mut bytes[..len], Tag::from_slice(tag))
+            .map_err(|_| Error::DecryptionFailed)?;
+        page.stamp();
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use super::*;
+
+    #[test]
+    fn test_encrypted_tree() -> Result<()> {
+        let path = Path::new("data").join("test_cipher.db");
+        let encrypted = || Options{ passphrase: Some("correct horse".into()), write_ahead_log: true, ..Options::default() };
+        let mut bptree: BPTree<u64, String> = BPTree::with_options(&path, encrypted())?;
+        for i in 0..600u64 {
+            bptree.set(i, format!("secret-{}", i))?;
+        }
+        for i in (0..600u64).step_by(4) {
+            bptree.remove(&i)?;
+        }
+        let backup = Path::new("data").join("test_cipher_backup.db");
+        bptree.backup_to(&backup)?;
+        bptree.vacuum()?;
+        bptree.close()?;
+        for file in [&path, &backup] {
+            let bytes = fs::read(file)?;
+            assert!(!bytes.windows(7).any(|window| window == b"secret-"));
+        }
+
+        for file in [&path, &backup] {
+            let bptree: BPTree<u64, String> = BPTree::open_with_options(file, encrypted())?;
+            assert_eq!(bptree.key_count(), 450);
+            assert_eq!(bptree.get(&599)?, "secret-599");
+            assert!(matches!(bptree.get(&400), Err(Error::KeyNotFound)));
+            assert!(bptree.verify(|_| ())?.is_ok());
+        }
+        let wrong = Options{ passphrase: Some("wrong horse".into()), ..Options::default() };
+        assert!(matches!(BPTree::<u64, String>::open_with_options(&path, wrong), Err(Error::DecryptionFailed)));
+        assert!(matches!(BPTree::<u64, String>::open(&path), Err(Error::NotADatabase)));
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Where trees and the wrappers around them get the time from: write times for retention
+// and the change log, partition expiry, standby delays. Hosts that need reproducible runs
+// hand in a ManualClock instead of the system's.
+pub trait Clock: Debug + Send + Sync {
+    // Wall time, in milliseconds since the Unix epoch.
+    fn now(&self) -> u64;
+    // Time since some fixed point; never goes backwards.
+    fn monotonic(&self) -> Duration;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+    }
+
+    fn monotonic(&self) -> Duration {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed()
+    }
+}
+
+// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+    elapsed_nanos: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self{ now: AtomicU64::new(now), elapsed_nanos: AtomicU64::new(0) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+        self.elapsed_nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    // Moves wall time only, e.g. to play a clock being adjusted.
+    pub fn set_now(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    fn monotonic(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+}
+
+pub(crate) fn or_system(clock: Option<&Arc<dyn Clock>>) -> Arc<dyn Clock> {
+    clock.cloned().unwrap_or_else(|| Arc::new(SystemClock))
+}
+
+// Where ids come from that would otherwise be random, e.g. session ids.
+pub trait IdSource: Debug + Send + Sync {
+    fn next_id(&self) -> u64;
+}
+
+#[derive(Debug, Default)]
+pub struct SequentialIds {
+    next: AtomicU64,
+}
+
+impl SequentialIds {
+    pub fn starting_at(first: u64) -> Self {
+        Self{ next: AtomicU64::new(first) }
+    }
+}
+
+impl IdSource for SequentialIds {
+    fn next_id(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use crate::error::{Error, Result};
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_drives_retention() -> Result<()> {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let options = Options{
+            max_key_count: Some(4),
+            retention: Some(Duration::from_secs(60)),
+            change_log: true,
+            clock: Some(clock.clone()),
+            ..Options::default()
+        };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(Path::new("data").join("test_manual_clock.db"), options)?;
+        bptree.set(1, 1)?;
+        clock.advance(Duration::from_secs(30));
+        bptree.set(2, 2)?;
+        clock.advance(Duration::from_secs(31));
+        assert!(matches!(bptree.get(&1), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.get(&2)?, 2);
+        assert_eq!(bptree.compact()?.expired, 1);
+        let times: Vec<u64> = bptree.changes(0)?.map(|event| event.map(|event| event.time)).collect::<Result<_>>()?;
+        assert_eq!(times[..2], [1_000_000, 1_030_000]);
+        assert_eq!(clock.monotonic(), Duration::from_secs(61));
+        Ok(())
+    }
+}
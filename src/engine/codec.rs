@@ -0,0 +1,293 @@
+use std::any::{Any, TypeId};
+use std::convert::TryInto;
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::page::DEFAULT_PAGE_SIZE;
+use crate::error::{Error, Result};
+
+const SEQ_LEN: usize = 8;
+
+macro_rules! encode_int {
+    ($value:expr, $endian:expr) => {
+        match $endian {
+            Endian::Big => $value.to_be_bytes(),
+            Endian::Little => $value.to_le_bytes(),
+        }
+    };
+}
+
+macro_rules! decode_int {
+    ($ty:ty, $bytes:expr, $endian:expr) => {
+        match $endian {
+            Endian::Big => <$ty>::from_be_bytes($bytes),
+            Endian::Little => <$ty>::from_le_bytes($bytes),
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+// Explicit bincode configuration used for every node payload. Integers are always
+// fixed width so the on-disk layout does not depend on the values stored, and the
+// size limit bounds how much a corrupted length field can make us allocate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Codec {
+    endian: Endian,
+    limit: u64,
+}
+
+impl Codec {
+    pub fn new(endian: Endian, limit: u64) -> Self {
+        Self{
+            endian,
+            limit,
+        }
+    }
+
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn with_limit(self, limit: u64) -> Self {
+        Self::new(self.endian, limit)
+    }
+
+    pub fn serialized_size<T: ?Sized + Serialize>(&self, value: &T) -> Result<u64> {
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(self.limit);
+        let size = match self.endian {
+            Endian::Big => options.with_big_endian().serialized_size(value)?,
+            Endian::Little => options.with_little_endian().serialized_size(value)?,
+        };
+        Ok(size)
+    }
+
+    pub fn serialize<T: ?Sized + Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(self.limit);
+        let bytes = match self.endian {
+            Endian::Big => options.with_big_endian().serialize(value)?,
+            Endian::Little => options.with_little_endian().serialize(value)?,
+        };
+        Ok(bytes)
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(self.limit);
+        let value = match self.endian {
+            Endian::Big => options.with_big_endian().deserialize(bytes)?,
+            Endian::Little => options.with_little_endian().deserialize(bytes)?,
+        };
+        Ok(value)
+    }
+
+    // Sequences of fixed-width integers skip serde entirely. The layout written here is
+    // byte-for-byte what bincode produces with fixint encoding (u64 length followed by
+    // the elements), so either path can read what the other one wrote.
+    pub fn serialize_vec<T: Serialize + 'static>(&self, items: &[T]) -> Result<Vec<u8>> {
+        self.serialize_iter(items.iter())
+    }
+
+    // Same as serialize_vec for callers that only hold references to the elements.
+    pub fn serialize_iter<'a, T, I>(&self, items: I) -> Result<Vec<u8>>
+        where T: Serialize + 'static, I: ExactSizeIterator<Item = &'a T> + Clone
+    {
+        if TypeId::of::<T>() == TypeId::of::<u32>() {
+            return self.encode_fixed(items, |item: &u32, endian| encode_int!(item, endian));
+        }
+        if TypeId::of::<T>() == TypeId::of::<u64>() {
+            return self.encode_fixed(items, |item: &u64, endian| encode_int!(item, endian));
+        }
+        if TypeId::of::<T>() == TypeId::of::<u128>() {
+            return self.encode_fixed(items, |item: &u128, endian| encode_int!(item, endian));
+        }
+        self.serialize(&Seq(items))
+    }
+
+    // serialize_iter into a caller's buffer, see serialize_into.
+    pub fn serialize_iter_into<'a, T, I>(&self, items: I, out: &mut [u8]) -> Result<usize>
+        where T: Serialize + 'static, I: ExactSizeIterator<Item = &'a T> + Clone
+    {
+        if TypeId::of::<T>() == TypeId::of::<u32>() {
+            return self.encode_fixed_into(items, |item: &u32, endian| encode_int!(item, endian), out);
+        }
+        if TypeId::of::<T>() == TypeId::of::<u64>() {
+            return self.encode_fixed_into(items, |item: &u64, endian| encode_int!(item, endian), out);
+        }
+        if TypeId::of::<T>() == TypeId::of::<u128>() {
+            return self.encode_fixed_into(items, |item: &u128, endian| encode_int!(item, endian), out);
+        }
+        self.serialize_into(&Seq(items), out)
+    }
+
+    // Like serialize, but writes straight into `out` and returns how many bytes that took.
+    // Running out of room, or going over the limit, is PageSizeNotEnough.
+    pub fn serialize_into<T: ?Sized + Serialize>(&self, value: &T, out: &mut [u8]) -> Result<usize> {
+        let capacity = out.len();
+        let mut writer = out;
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(self.limit);
+        let written = match self.endian {
+            Endian::Big => options.with_big_endian().serialize_into(&mut writer, value),
+            Endian::Little => options.with_little_endian().serialize_into(&mut writer, value),
+        };
+        match written {
+            Ok(()) => Ok(capacity - writer.len()),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(_) | bincode::ErrorKind::SizeLimit => Err(Error::PageSizeNotEnough),
+                e => Err(Box::new(e).into()),
+            },
+        }
+    }
+
+    pub fn deserialize_vec<T: DeserializeOwned + 'static>(&self, bytes: &[u8]) -> Result<Vec<T>> {
+        // Filled through a typed slot instead of a Box<dyn Any>, which would allocate.
+        let mut items: Option<Vec<T>> = None;
+        let slot = &mut items as &mut dyn Any;
+        if let Some(slot) = slot.downcast_mut::<Option<Vec<u32>>>() {
+            *slot = Some(self.decode_fixed(bytes, |chunk, endian| decode_int!(u32, chunk, endian))?);
+        }
+        else if let Some(slot) = slot.downcast_mut::<Option<Vec<u64>>>() {
+            *slot = Some(self.decode_fixed(bytes, |chunk, endian| decode_int!(u64, chunk, endian))?);
+        }
+        else if let Some(slot) = slot.downcast_mut::<Option<Vec<u128>>>() {
+            *slot = Some(self.decode_fixed(bytes, |chunk, endian| decode_int!(u128, chunk, endian))?);
+        }
+        else {
+            return self.deserialize(bytes);
+        }
+        Ok(items.unwrap())
+    }
+
+    fn encode_fixed<'a, T, U, I, F, const N: usize>(&self, items: I, encode: F) -> Result<Vec<u8>>
+    where T: 'static, U: 'static, I: ExactSizeIterator<Item = &'a T>, F: Fn(&U, Endian) -> [u8; N]
+    {
+        let mut bytes = vec![0u8; SEQ_LEN + items.len() * N];
+        self.encode_fixed_into(items, encode, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn encode_fixed_into<'a, T, U, I, F, const N: usize>(&self, items: I, encode: F, out: &mut [u8]) -> Result<usize>
+    where T: 'static, U: 'static, I: ExactSizeIterator<Item = &'a T>, F: Fn(&U, Endian) -> [u8; N]
+    {
+        let len = SEQ_LEN + items.len() * N;
+        if len as u64 > self.limit || len > out.len() {
+            return Err(Error::PageSizeNotEnough);
+        }
+        out[..SEQ_LEN].copy_from_slice(&encode_int!(&(items.len() as u64), self.endian));
+        for (item, chunk) in items.zip(out[SEQ_LEN..len].chunks_exact_mut(N)) {
+            let item = (item as &dyn Any).downcast_ref::<U>().unwrap();
+            chunk.copy_from_slice(&encode(item, self.endian));
+        }
+        Ok(len)
+    }
+
+    // Reads a sequence of u64 written by serialize_vec without collecting it.
+    pub(crate) fn u64s<'a>(&self, bytes: &'a [u8]) -> Result<impl ExactSizeIterator<Item = u64> + 'a> {
+        self.decode_fixed_iter(bytes, |chunk, endian| decode_int!(u64, chunk, endian))
+    }
+
+    fn decode_fixed<T, F, const N: usize>(&self, bytes: &[u8], decode: F) -> Result<Vec<T>>
+    where F: Fn([u8; N], Endian) -> T
+    {
+        Ok(self.decode_fixed_iter(bytes, decode)?.collect())
+    }
+
+    fn decode_fixed_iter<'a, T, F, const N: usize>(&self, bytes: &'a [u8], decode: F) -> Result<impl ExactSizeIterator<Item = T> + 'a>
+    where F: Fn([u8; N], Endian) -> T + 'a
+    {
+        if bytes.len() < SEQ_LEN || bytes.len() as u64 > self.limit {
+            return Err(Error::CorruptedPage);
+        }
+        let count = decode_int!(u64, bytes[..SEQ_LEN].try_into().unwrap(), self.endian);
+        let body = &bytes[SEQ_LEN..];
+        if body.len() as u64 != count.saturating_mul(N as u64) {
+            return Err(Error::CorruptedPage);
+        }
+        let endian = self.endian;
+        Ok(body.chunks_exact(N).map(move |chunk| decode(chunk.try_into().unwrap(), endian)))
+    }
+}
+
+struct Seq<I>(I);
+
+impl<'a, T, I> Serialize for Seq<I>
+    where T: Serialize + 'a, I: ExactSizeIterator<Item = &'a T> + Clone
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.clone())
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(Endian::Big, DEFAULT_PAGE_SIZE as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_width_layout() -> Result<()> {
+        let big = Codec::new(Endian::Big, DEFAULT_PAGE_SIZE as u64);
+        let little = Codec::new(Endian::Little, DEFAULT_PAGE_SIZE as u64);
+        assert_eq!(big.serialize(&vec![1u32])?, vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(little.serialize(&vec![1u32])?, vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        let keys: Vec<u64> = big.deserialize(&big.serialize(&vec![7u64, 9])?)?;
+        assert_eq!(keys, vec![7, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_width_fast_path_matches_bincode() -> Result<()> {
+        for codec in [Codec::new(Endian::Big, DEFAULT_PAGE_SIZE as u64), Codec::new(Endian::Little, DEFAULT_PAGE_SIZE as u64)] {
+            let small = vec![1u32, 70000, u32::MAX];
+            let wide = vec![3u64, 1 << 40];
+            let huge = vec![9u128, u128::MAX];
+            assert_eq!(codec.serialize_vec(&small)?, codec.serialize(&small)?);
+            assert_eq!(codec.serialize_vec(&wide)?, codec.serialize(&wide)?);
+            assert_eq!(codec.serialize_vec(&huge)?, codec.serialize(&huge)?);
+            assert_eq!(codec.deserialize_vec::<u32>(&codec.serialize(&small)?)?, small);
+            assert_eq!(codec.deserialize_vec::<u64>(&codec.serialize(&wide)?)?, wide);
+            assert_eq!(codec.deserialize_vec::<u128>(&codec.serialize(&huge)?)?, huge);
+            let strings = vec![String::from("a")];
+            assert_eq!(codec.deserialize_vec::<String>(&codec.serialize_vec(&strings)?)?, strings);
+        }
+        let codec = Codec::default();
+        let mut out = [0u8; 32];
+        assert_eq!(codec.serialize_iter_into([1u64, 2].iter(), &mut out)?, 24);
+        assert_eq!(out[..24], codec.serialize_vec(&[1u64, 2])?[..]);
+        let strings = [String::from("abc")];
+        assert_eq!(codec.serialize_iter_into(strings.iter(), &mut out)?, 19);
+        assert!(matches!(codec.serialize_iter_into([0u64; 4].iter(), &mut out), Err(Error::PageSizeNotEnough)));
+        assert!(matches!(codec.serialize_iter_into(vec![String::from("a"); 4].iter(), &mut out), Err(Error::PageSizeNotEnough)));
+        let mut truncated = codec.serialize_vec(&[1u64, 2])?;
+        truncated.pop();
+        assert!(codec.deserialize_vec::<u64>(&truncated).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_limit_rejects_bogus_length() {
+        let codec = Codec::new(Endian::Big, 64);
+        let mut bytes = vec![0xffu8; 8];
+        bytes.extend_from_slice(&[0u8; 16]);
+        assert!(codec.deserialize::<Vec<u64>>(&bytes).is_err());
+        assert!(codec.serialize(&vec![0u64; 16]).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt::Debug;
+use serde::{de::DeserializeOwned, Serialize};
+use std::ops::Bound;
+use crate::engine::bptree::BPTree;
+use crate::engine::iter::RangeIter;
+use crate::error::Result;
+
+// What a compaction filter wants done with a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision<V> {
+    Keep,
+    Drop,
+    Replace(V),
+}
+
+pub type CompactionFilter<K, V> = Box<dyn Fn(&K, &V) -> Decision<V> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub scanned: u64,
+    pub dropped: u64,
+    pub replaced: u64,
+    // Dropped because they outlived the tree's retention, without asking the filter.
+    pub expired: u64,
+}
+
+// Runs the filter over every record first and only then applies its decisions, so the
+// scan never sees its own writes. Dropped records go through remove() and rewritten ones
+// through set(), which keeps versions, quotas and the change log in step.
+pub(crate) fn compact<K, V>(bptree: &mut BPTree<K, V>, filter: Option<&CompactionFilter<K, V>>) -> Result<CompactionStats>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let mut stats = CompactionStats::default();
+    let mut expired = Vec::new();
+    let mut decisions = Vec::new();
+    for record in RangeIter::new(bptree, Bound::Unbounded, Bound::Unbounded)?.entries() {
+        let (key, entry) = record?;
+        stats.scanned += 1;
+        if bptree.is_expired(&entry) {
+            expired.push(key);
+            continue;
+        }
+        let filter = match filter {
+            Some(filter) => filter,
+            None => continue,
+        };
+        match entry.slot.with_value(bptree.pager(), |value| filter(&key, value))? {
+            Decision::Keep => {},
+            decision => decisions.push((key, decision)),
+        }
+    }
+    for key in expired {
+        bptree.remove(&key)?;
+        stats.expired += 1;
+    }
+    for (key, decision) in decisions {
+        match decision {
+            Decision::Drop => {
+                bptree.remove(&key)?;
+                stats.dropped += 1;
+            }
+            Decision::Replace(value) => {
+                bptree.set(key, value)?;
+                stats.replaced += 1;
+            }
+            Decision::Keep => {},
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,186 @@
+use std::any::TypeId;
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::sync::Arc;
+use serde::Serialize;
+use crate::engine::codec::Codec;
+use crate::engine::search::search;
+use crate::error::{Error, Result};
+
+// Orders the keys of a tree in place of their Ord. It is shown keys as bytes: the contents
+// of strings and byte strings, and for other key types the key as the tree's codec encodes
+// it. Keys it finds Equal are the same key. The tree file keeps the comparator's name and
+// open() refuses one with another name, since the pages are sorted by it.
+pub trait Comparator: Debug + Send + Sync {
+    fn name(&self) -> &str;
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+// Raw-bytes mode: keys in the order of their encoded bytes, as memcmp has it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+// ASCII letters compare regardless of case, so "Apple" and "apple" are one key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaseInsensitive;
+
+impl Comparator for CaseInsensitive {
+    fn name(&self) -> &str {
+        "case-insensitive"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.iter().map(u8::to_ascii_lowercase).cmp(b.iter().map(u8::to_ascii_lowercase))
+    }
+}
+
+// How the pages of a tree are sorted: by the comparator when there is one, by Ord if not.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct KeyOrder {
+    comparator: Option<Arc<dyn Comparator>>,
+    codec: Codec,
+}
+
+impl KeyOrder {
+    pub(crate) fn new(comparator: Option<Arc<dyn Comparator>>, codec: Codec) -> Self {
+        Self{ comparator, codec: codec.with_limit(u64::MAX) }
+    }
+
+    pub(crate) fn name(&self) -> Option<String> {
+        self.comparator.as_ref().map(|comparator| comparator.name().to_string())
+    }
+
+    // Fails when a comparator would have to be shown a key borrowed as a type it can't turn
+    // into bytes: lookups then take the key type, str, String, [u8] or Vec<u8>. Entry points
+    // taking a borrowed key call this before searching.
+    pub(crate) fn check<K, Q>(&self) -> Result<()>
+        where K: 'static, Q: ?Sized + 'static
+    {
+        let shown = is::<Q, K>() || is::<Q, str>() || is::<Q, String>() || is::<Q, [u8]>() || is::<Q, Vec<u8>>();
+        match self.comparator.is_none() || shown {
+            true => Ok(()),
+            false => Err(Error::UnsupportedLookupType),
+        }
+    }
+
+    pub(crate) fn search<K, Q>(&self, keys: &[K], key: &Q) -> std::result::Result<usize, usize>
+        where K: Borrow<Q> + Serialize + 'static, Q: Ord + ?Sized + 'static
+    {
+        match &self.comparator {
+            None => search(keys, key),
+            Some(comparator) => {
+                let key = self.bytes::<K, Q>(key);
+                keys.binary_search_by(|k| comparator.compare(&self.bytes::<K, Q>(k.borrow()), &key))
+            }
+        }
+    }
+
+    pub(crate) fn cmp<K, Q>(&self, a: &Q, b: &Q) -> Ordering
+        where K: Borrow<Q> + Serialize + 'static, Q: Ord + ?Sized + 'static
+    {
+        match &self.comparator {
+            None => a.cmp(b),
+            Some(comparator) => comparator.compare(&self.bytes::<K, Q>(a), &self.bytes::<K, Q>(b)),
+        }
+    }
+
+    pub(crate) fn is_ord(&self) -> bool {
+        self.comparator.is_none()
+    }
+
+    // `items` sorted by key, with items whose keys are equal in the order they came.
+    pub(crate) fn sorted<K, V>(&self, mut items: Vec<(K, V)>) -> Vec<(K, V)>
+        where K: Ord + Serialize + 'static
+    {
+        items.sort_by(|(a, _), (b, _)| self.cmp::<K, K>(a, b));
+        items
+    }
+
+    fn bytes<'a, K, Q>(&self, key: &'a Q) -> Cow<'a, [u8]>
+        where K: Serialize + 'static, Q: ?Sized + 'static
+    {
+        // SAFETY: each cast is to the type TypeId has just shown Q to be, so the reference
+        // is copied as it is.
+        unsafe {
+            if is::<Q, str>() {
+                return Cow::Borrowed(cast::<Q, &str>(&key).as_bytes());
+            }
+            if is::<Q, String>() {
+                return Cow::Borrowed(cast::<Q, &String>(&key).as_bytes());
+            }
+            if is::<Q, [u8]>() {
+                return Cow::Borrowed(cast::<Q, &[u8]>(&key));
+            }
+            if is::<Q, Vec<u8>>() {
+                return Cow::Borrowed(cast::<Q, &Vec<u8>>(&key));
+            }
+            if is::<Q, K>() {
+                return Cow::Owned(self.codec.serialize(cast::<Q, &K>(&key)).expect("keys are encoded when stored"));
+            }
+        }
+        unreachable!("lookups with other key types fail check()")
+    }
+}
+
+fn is<A: ?Sized + 'static, B: ?Sized + 'static>() -> bool {
+    TypeId::of::<A>() == TypeId::of::<B>()
+}
+
+unsafe fn cast<Q: ?Sized, T: Copy>(key: &&Q) -> T {
+    std::mem::transmute_copy(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use crate::error::{Error, Result};
+    use super::*;
+
+    #[test]
+    fn test_case_insensitive_tree() -> Result<()> {
+        let path = Path::new("data").join("test_comparator.db");
+        let options = || Options{ comparator: Some(Arc::new(CaseInsensitive)), max_key_count: Some(4), ..Options::default() };
+        let mut bptree: BPTree<String, u64> = BPTree::with_options(&path, options())?;
+        for (i, word) in ["banana", "Apple", "cherry", "apricot", "Blueberry", "date", "Cranberry", "avocado", "Elderberry"].iter().enumerate() {
+            bptree.set(word.to_string(), i as u64)?;
+        }
+        bptree.set("APPLE".to_string(), 100)?;
+        assert_eq!(bptree.key_count(), 9);
+        assert_eq!(bptree.get("apple")?, 100);
+        assert_eq!(bptree.get("BANANA")?, 0);
+        let keys: Vec<String> = bptree.scan_all()?.map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, ["Apple", "apricot", "avocado", "banana", "Blueberry", "cherry", "Cranberry", "date", "Elderberry"]);
+        let keys: Vec<String> = bptree.range("b".to_string().."D".to_string())?.map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, ["banana", "Blueberry", "cherry", "Cranberry"]);
+        bptree.remove("CHERRY")?;
+        assert!(matches!(bptree.get("cherry"), Err(Error::KeyNotFound)));
+        assert!(bptree.verify(|_| ())?.is_ok());
+        bptree.close()?;
+
+        let bptree: BPTree<String, u64> = BPTree::open_with_options(&path, options())?;
+        assert_eq!(bptree.get("elderBERRY")?, 8);
+        drop(bptree);
+        assert!(matches!(BPTree::<String, u64>::open(&path), Err(Error::ComparatorMismatch(_))));
+        let bytewise = || Options{ comparator: Some(Arc::new(Bytewise)), ..Options::default() };
+        assert!(matches!(BPTree::<String, u64>::open_with_options(&path, bytewise()), Err(Error::ComparatorMismatch(_))));
+
+        let mut bptree: BPTree<Vec<u16>, u64> = BPTree::with_options(Path::new("data").join("test_comparator_lookup.db"), bytewise())?;
+        bptree.set(vec![1, 2], 12)?;
+        assert_eq!(bptree.get(&vec![1, 2])?, 12);
+        assert!(matches!(bptree.get(&[1u16, 2][..]), Err(Error::UnsupportedLookupType)));
+        assert!(matches!(bptree.remove(&[1u16, 2][..]), Err(Error::UnsupportedLookupType)));
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+
+// How the entries of a leaf are compressed when it is stored. The tree keeps the setting it
+// was created with, and every page records its own codec besides. The codecs need the lz4
+// and zstd features; without them the tree can't be created, nor its pages read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    // The zstd level; 0 is zstd's default.
+    Zstd(i32),
+}
+
+pub(crate) const UNCOMPRESSED: u8 = 0;
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+impl Compression {
+    // What a page's header records for pages compressed with this.
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            Compression::None => UNCOMPRESSED,
+            Compression::Lz4 => LZ4,
+            Compression::Zstd(_) => ZSTD,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd(_) => cfg!(feature = "zstd"),
+        }
+    }
+
+    pub(crate) fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::block::compress(bytes)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Ok(zstd::bulk::compress(bytes, *level)?),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::UnsupportedCompression(self.tag())),
+        }
+    }
+}
+
+// Decompresses what was written with the codec `tag` stands for, which has to come out as
+// exactly `len` bytes.
+pub(crate) fn decompress(tag: u8, bytes: &[u8], len: usize) -> Result<Vec<u8>> {
+    let decompressed = match tag {
+        UNCOMPRESSED => bytes.to_vec(),
+        #[cfg(feature = "lz4")]
+        LZ4 => lz4_flex::block::decompress(bytes, len).map_err(|_| Error::CorruptedPage)?,
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd::bulk::decompress(bytes, len).map_err(|_| Error::CorruptedPage)?,
+        #[cfg(not(feature = "lz4"))]
+        LZ4 => return Err(Error::UnsupportedCompression(tag)),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD => return Err(Error::UnsupportedCompression(tag)),
+        _ => return Err(Error::CorruptedPage),
+    };
+    match decompressed.len() == len {
+        true => Ok(decompressed),
+        false => Err(Error::CorruptedPage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use super::*;
+
+    fn json(i: u64) -> String {
+        format!(r#"{{"id":{},"name":"user-{}","roles":["reader","writer"],"settings":{{"theme":"dark","language":"en","notifications":true}},"tags":["alpha","beta","gamma","delta"]}}"#, i, i)
+    }
+
+    // Fills a tree written with `compression`, then reopens it and reads every key back.
+    fn check(name: &str, compression: Compression) -> Result<u64> {
+        let path = Path::new("data").join(name);
+        let mut bptree: BPTree<String, String> = BPTree::with_options(&path, Options{ compression, ..Options::default() })?;
+        for i in 0..2000u64 {
+            bptree.set(format!("key-{:05}", i), json(i))?;
+        }
+        for i in (0..2000u64).step_by(3) {
+            bptree.remove(&format!("key-{:05}", i))?;
+        }
+        let page_count = bptree.page_count();
+        assert!(bptree.verify(|_| ())?.is_ok());
+        bptree.close()?;
+
+        let mut bptree: BPTree<String, String> = BPTree::open(&path)?;
+        for i in 0..2000u64 {
+            let expected = if i % 3 == 0 { None } else { Some(json(i)) };
+            assert_eq!(bptree.get(&format!("key-{:05}", i)).ok(), expected);
+        }
+        bptree.set("key-00000".to_string(), json(0))?;
+        assert_eq!(bptree.get("key-00000")?, json(0));
+        Ok(page_count)
+    }
+
+    #[test]
+    fn test_compressed_leaves() -> Result<()> {
+        let uncompressed = check("test_compression_none.db", Compression::None)?;
+        for (name, compression) in [("test_compression_lz4.db", Compression::Lz4), ("test_compression_zstd.db", Compression::Zstd(0))] {
+            match compression.is_available() {
+                true => assert!(check(name, compression)? * 2 < uncompressed),
+                false => assert!(matches!(check(name, compression), Err(Error::UnsupportedCompression(_)))),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::{LeafNode, Node};
+use crate::engine::iter::{first_leaf, last_leaf};
+use crate::error::{Error, Result};
+
+// A position in the tree, moved by hand: the leaf it is in, decoded, and the index of its
+// entry there. Past the last entry and before the first is one position, off the tree,
+// which is where a new cursor starts; next() from there goes to the first entry and prev()
+// to the last. Like Iter it holds the tree borrowed, so no write moves entries under it.
+// Expired entries are stepped over.
+pub struct Cursor<'a, K, V> {
+    bptree: &'a BPTree<K, V>,
+    leaf: Option<(LeafNode<K, V>, usize)>,
+}
+
+impl<'a, K, V> Cursor<'a, K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    pub(crate) fn new(bptree: &'a BPTree<K, V>) -> Result<Self> {
+        if !bptree.quarantine().is_empty() {
+            return Err(Error::RangeUnavailable);
+        }
+        Ok(Self{ bptree, leaf: None })
+    }
+
+    // Moves to the first entry whose key is `key` or after it. Returns whether there is one.
+    pub fn seek<Q>(&mut self, key: &Q) -> Result<bool>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.leaf = None;
+        let pager = self.bptree.pager();
+        pager.order().check::<K, Q>()?;
+        let leaf = match self.bptree.root_ptr() {
+            Some(root) => Node::<K, V>::load_node(root, pager)?.find_leaf(key, pager)?,
+            None => return Ok(false),
+        };
+        let (Ok(i) | Err(i)) = pager.order().search(leaf.keys(), key);
+        self.forward(leaf, i)
+    }
+
+    pub fn seek_first(&mut self) -> Result<bool> {
+        self.leaf = None;
+        match first_leaf(self.bptree)? {
+            Some(ptr) => self.forward(LeafNode::load(ptr, self.bptree.pager())?, 0),
+            None => Ok(false),
+        }
+    }
+
+    pub fn seek_last(&mut self) -> Result<bool> {
+        self.leaf = None;
+        match last_leaf(self.bptree)? {
+            Some(ptr) => {
+                let leaf = LeafNode::load(ptr, self.bptree.pager())?;
+                let len = leaf.len();
+                self.backward(leaf, len)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Moves to the next entry; returns false when that leaves the tree.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool> {
+        match self.leaf.take() {
+            Some((leaf, i)) => self.forward(leaf, i + 1),
+            None => self.seek_first(),
+        }
+    }
+
+    pub fn prev(&mut self) -> Result<bool> {
+        match self.leaf.take() {
+            Some((leaf, i)) => self.backward(leaf, i),
+            None => self.seek_last(),
+        }
+    }
+
+    // The key of the entry the cursor is on, without reading its value.
+    pub fn key(&self) -> Option<&K> {
+        self.leaf.as_ref().map(|(leaf, i)| &leaf.keys()[*i])
+    }
+
+    pub fn current(&self) -> Result<Option<(K, V)>> {
+        match &self.leaf {
+            Some((leaf, i)) => {
+                let value = leaf.entries()[*i].slot.with_value(self.bptree.pager(), V::clone)?;
+                Ok(Some((leaf.keys()[*i].clone(), value)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Settles on the first live entry at `i` in `leaf` or after it, along the leaf chain.
+    fn forward(&mut self, mut leaf: LeafNode<K, V>, mut i: usize) -> Result<bool> {
+        loop {
+            while i < leaf.len() {
+                if !self.bptree.is_expired(&leaf.entries()[i]) {
+                    self.leaf = Some((leaf, i));
+                    return Ok(true);
+                }
+                i += 1;
+            }
+            match leaf.next() {
+                Some(ptr) => (leaf, i) = (LeafNode::load(ptr, self.bptree.pager())?, 0),
+                None => return Ok(false),
+            }
+        }
+    }
+
+    // Settles on the last live entry before `i` in `leaf`, or in the leaves before it.
+    fn backward(&mut self, mut leaf: LeafNode<K, V>, mut i: usize) -> Result<bool> {
+        loop {
+            while i > 0 {
+                i -= 1;
+                if !self.bptree.is_expired(&leaf.entries()[i]) {
+                    self.leaf = Some((leaf, i));
+                    return Ok(true);
+                }
+            }
+            match leaf.prev() {
+                Some(ptr) => {
+                    leaf = LeafNode::load(ptr, self.bptree.pager())?;
+                    i = leaf.len();
+                }
+                None => return Ok(false),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_cursor_merge_join() -> Result<()> {
+        let mut customers: BPTree<u64, String> = BPTree::new(Path::new("data").join("test_cursor_customers.db"), Some(4))?;
+        let mut orders: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_cursor_orders.db"), Some(4))?;
+        for id in (0..300u64).step_by(3) {
+            customers.set(id, format!("customer-{}", id))?;
+        }
+        for id in (0..300u64).step_by(5) {
+            orders.set(id, id * 100)?;
+        }
+
+        // Customers with an order: every id divisible by 15.
+        let (mut left, mut right) = (customers.cursor()?, orders.cursor()?);
+        let (mut more_left, mut more_right) = (left.next()?, right.next()?);
+        let mut joined = Vec::new();
+        while more_left && more_right {
+            match left.key().cmp(&right.key()) {
+                Ordering::Less => more_left = left.next()?,
+                Ordering::Greater => more_right = right.next()?,
+                Ordering::Equal => {
+                    let ((id, name), (_, total)) = (left.current()?.unwrap(), right.current()?.unwrap());
+                    joined.push((id, name, total));
+                    more_left = left.next()?;
+                    more_right = right.next()?;
+                }
+            }
+        }
+        assert_eq!(joined.len(), 20);
+        assert_eq!(joined[3], (45, "customer-45".to_string(), 4500));
+
+        let mut cursor = customers.cursor()?;
+        assert_eq!(cursor.key(), None);
+        assert!(cursor.seek(&100)?);
+        assert_eq!(cursor.key(), Some(&102));
+        assert!(cursor.prev()? && cursor.prev()?);
+        assert_eq!(cursor.key(), Some(&96));
+        assert!(!cursor.seek(&298)?);
+        assert!(cursor.prev()?);
+        assert_eq!(cursor.current()?, Some((297, "customer-297".to_string())));
+        assert!(!cursor.next()?);
+        assert!(cursor.next()?);
+        assert_eq!(cursor.key(), Some(&0));
+        assert!(!cursor.prev()?);
+        Ok(())
+    }
+}
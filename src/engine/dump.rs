@@ -0,0 +1,154 @@
+use std::fmt::Debug;
+use std::io::{BufWriter, Write};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::Node;
+use crate::engine::page::PagePtr;
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    // One line per node, level by level from the root.
+    Text,
+    // A Graphviz digraph: parent to child edges, and dashed edges along the leaf chain.
+    Dot,
+}
+
+// A node and the keys its parent's separators give it.
+type Placed<K> = (PagePtr, Option<K>, Option<K>);
+
+// Writes the nodes of the tree's current family, level by level from the root, each with its
+// page, the range of keys it covers, its keys, and its children or leaf links. Meant for
+// looking at small trees while debugging: every key is written.
+pub(crate) fn dump<K, V, W>(bptree: &BPTree<K, V>, writer: W, format: DumpFormat) -> Result<()>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          W: Write
+{
+    let mut writer = BufWriter::new(writer);
+    if format == DumpFormat::Dot {
+        writeln!(writer, "digraph bptree {{")?;
+        writeln!(writer, "  node [shape=record];")?;
+    }
+    let mut level: Vec<Placed<K>> = bptree.root_ptr().map(|root| (root, None, None)).into_iter().collect();
+    let mut depth = 0;
+    while !level.is_empty() {
+        let mut below = Vec::new();
+        match format {
+            DumpFormat::Text => writeln!(writer, "level {}", depth)?,
+            DumpFormat::Dot => write!(writer, "  {{ rank=same;")?,
+        }
+        let mut lines = Vec::with_capacity(level.len());
+        for (ptr, low, high) in level {
+            let range = format!("[{}, {})", bound(low.as_ref(), "-inf"), bound(high.as_ref(), "+inf"));
+            let line = match Node::<K, V>::load_node(ptr, bptree.pager())? {
+                Node::Inner(inner) => {
+                    let children = inner.childptrs();
+                    for (i, &child) in children.iter().enumerate() {
+                        let (low, high) = inner.child_bounds(i);
+                        below.push((child, low.cloned(), high.cloned()));
+                    }
+                    match format {
+                        DumpFormat::Text => format!("  inner {} {} keys {:?} children {:?}", ptr, range, inner.keys(), children),
+                        DumpFormat::Dot => {
+                            let edges: Vec<String> = children.iter().map(|child| format!("  p{} -> p{};", ptr, child)).collect();
+                            format!("  p{} [label=\"{}\"];\n{}", ptr, label(ptr, &range, inner.keys()), edges.join("\n"))
+                        }
+                    }
+                }
+                Node::Leaf(leaf) => match format {
+                    DumpFormat::Text => format!("  leaf {} {} keys {:?} prev {} next {}", ptr, range, leaf.keys(), link(leaf.prev()), link(leaf.next())),
+                    DumpFormat::Dot => {
+                        let next = leaf.next().map(|next| format!("\n  p{} -> p{} [style=dashed, constraint=false];", ptr, next));
+                        format!("  p{} [label=\"{}\"];{}", ptr, label(ptr, &range, leaf.keys()), next.unwrap_or_default())
+                    }
+                },
+            };
+            if format == DumpFormat::Dot {
+                write!(writer, " p{};", ptr)?;
+            }
+            lines.push(line);
+        }
+        if format == DumpFormat::Dot {
+            writeln!(writer, " }}")?;
+        }
+        for line in lines {
+            writeln!(writer, "{}", line)?;
+        }
+        level = below;
+        depth += 1;
+    }
+    if format == DumpFormat::Dot {
+        writeln!(writer, "}}")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn bound<K: Debug>(key: Option<&K>, unbounded: &str) -> String {
+    match key {
+        Some(key) => format!("{:?}", key),
+        None => unbounded.to_string(),
+    }
+}
+
+fn link(ptr: Option<PagePtr>) -> String {
+    match ptr {
+        Some(ptr) => ptr.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+// A record label: the page and its range, then one field per key. Characters the record
+// syntax gives a meaning to are escaped.
+fn label<K: Debug>(ptr: PagePtr, range: &str, keys: &[K]) -> String {
+    let mut fields = vec![format!("{} {}", ptr, range)];
+    fields.extend(keys.iter().map(|key| format!("{:?}", key)));
+    let escaped: Vec<String> = fields.iter().map(|field| {
+        field.chars().fold(String::new(), |mut escaped, c| {
+            if "\"\\|{}<>".contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+            escaped
+        })
+    }).collect();
+    escaped.join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_dump() -> Result<()> {
+        let path = Path::new("data").join("test_dump.db");
+        let mut bptree: BPTree<u64, String> = BPTree::new(&path, Some(4))?;
+        let mut text = Vec::new();
+        bptree.dump(&mut text, DumpFormat::Text)?;
+        assert!(text.is_empty());
+
+        for i in 0..40u64 {
+            bptree.set(i, format!("value-{}", i))?;
+        }
+        let mut text = Vec::new();
+        bptree.dump(&mut text, DumpFormat::Text)?;
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "level 0");
+        assert!(lines[1].starts_with(&format!("  inner {} [-inf, +inf) keys", bptree.root_ptr().unwrap())));
+        let leaves: Vec<&str> = lines.iter().copied().filter(|line| line.starts_with("  leaf")).collect();
+        assert_eq!(bptree.metrics()?.leaf_pages, leaves.len() as u64);
+        assert!(leaves[0].contains("[-inf, ") && leaves[0].contains("keys [0, 1") && leaves[0].contains("prev -"));
+        assert!(leaves.last().unwrap().contains(", +inf)") && leaves.last().unwrap().ends_with("next -"));
+
+        let mut dot = Vec::new();
+        bptree.dump(&mut dot, DumpFormat::Dot)?;
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph bptree {\n") && dot.ends_with("}\n"));
+        assert_eq!(dot.matches("style=dashed").count(), leaves.len() - 1);
+        assert_eq!(dot.matches(" -> ").count(), 2 * leaves.len() - 1 + text.matches("  inner").count() - 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::{BufWriter, Read, Write};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use crate::engine::batch::WriteBatch;
+use crate::engine::bptree::BPTree;
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // A JSON array of [key, value] pairs, one pair to a line.
+    Json,
+    // A "key,value" header, then one row per entry. Strings are written as they are, any
+    // other key or value as its JSON text.
+    Csv,
+}
+
+// Writes every entry in key order, read along the leaf chain. Returns how many there were.
+pub(crate) fn export<K, V, W>(bptree: &BPTree<K, V>, writer: W, format: Format) -> Result<u64>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          W: Write
+{
+    let mut writer = BufWriter::new(writer);
+    let mut count = 0;
+    match format {
+        Format::Json => writer.write_all(b"[")?,
+        Format::Csv => writer.write_all(b"key,value\n")?,
+    }
+    for entry in bptree.range(..)? {
+        let (key, value) = entry?;
+        match format {
+            Format::Json => {
+                writer.write_all(if count == 0 { b"\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut writer, &(key, value))?;
+            }
+            Format::Csv => {
+                write_field(&mut writer, &csv_text(&key)?)?;
+                writer.write_all(b",")?;
+                write_field(&mut writer, &csv_text(&value)?)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        count += 1;
+    }
+    if format == Format::Json {
+        writer.write_all(if count == 0 { b"]\n" } else { b"\n]\n" })?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// Reads entries written by export(), or by hand in the same format, and writes them to the
+// tree; a key given twice gets the later value. The whole input is read first. An empty
+// tree is bulk-loaded, any other takes the entries as one write batch. Returns how many
+// distinct keys were read.
+pub(crate) fn import<K, V, R>(bptree: &mut BPTree<K, V>, mut reader: R, format: Format) -> Result<u64>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + 'static,
+          R: Read
+{
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    let pairs: Vec<(K, V)> = match format {
+        Format::Json => serde_json::from_str(&text)?,
+        Format::Csv => {
+            let mut records = csv_records(&text)?.into_iter();
+            match records.next() {
+                Some((_, header)) if header == ["key", "value"] => (),
+                _ => return Err(Error::Csv("missing key,value header".to_string())),
+            }
+            records.map(|(line, record)| match record.as_slice() {
+                [key, value] => Ok((from_csv_text(key)?, from_csv_text(value)?)),
+                _ => Err(Error::Csv(format!("line {} has {} fields", line, record.len()))),
+            }).collect::<Result<_>>()?
+        }
+    };
+    let entries: BTreeMap<K, V> = pairs.into_iter().collect();
+    let count = entries.len() as u64;
+    if bptree.is_empty() {
+        bptree.bulk_load(bptree.pager().order().sorted(entries.into_iter().collect()))?;
+    } else {
+        let mut batch = WriteBatch::with_capacity(entries.len());
+        for (key, value) in entries {
+            batch.put(key, value);
+        }
+        bptree.write_batch(batch)?;
+    }
+    Ok(count)
+}
+
+fn csv_text<T: Serialize>(item: &T) -> Result<String> {
+    match serde_json::to_value(item)? {
+        Value::String(text) => Ok(text),
+        value => Ok(value.to_string()),
+    }
+}
+
+// Tried as a string first, so a string that happens to look like JSON stays a string.
+fn from_csv_text<T: DeserializeOwned>(text: &str) -> Result<T> {
+    match serde_json::from_value(Value::String(text.to_string())) {
+        Ok(item) => Ok(item),
+        Err(_) => Ok(serde_json::from_str(text)?),
+    }
+}
+
+fn write_field<W: Write>(writer: &mut W, field: &str) -> Result<()> {
+    if field.contains([',', '"', '\n', '\r']) {
+        write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+    } else {
+        writer.write_all(field.as_bytes())?;
+    }
+    Ok(())
+}
+
+// The records of RFC 4180 text, each with the line it starts on. Quoted fields may hold
+// commas, line breaks and doubled quotes.
+fn csv_records(text: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut record = Vec::new();
+        loop {
+            let mut field = String::new();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            line += (c == '\n') as usize;
+                            field.push(c);
+                        }
+                        None => return Err(Error::Csv(format!("unterminated quote on line {}", start))),
+                    }
+                }
+            }
+            while let Some(&c) = chars.peek() {
+                if matches!(c, ',' | '\n' | '\r') {
+                    break;
+                }
+                field.push(c);
+                chars.next();
+            }
+            record.push(field);
+            match chars.next() {
+                Some(',') => continue,
+                Some('\r') if chars.peek() == Some(&'\n') => {
+                    chars.next();
+                }
+                _ => (),
+            }
+            line += 1;
+            break;
+        }
+        records.push((start, record));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_export_import() -> Result<()> {
+        let mut source: BPTree<String, Vec<u8>> = BPTree::new(Path::new("data").join("test_export.db"), Some(4))?;
+        for i in 0..100u8 {
+            source.set(format!("key {:03}", i), vec![i; i as usize % 3])?;
+        }
+        source.set("quoted \"key\", with\nbreak".to_string(), vec![])?;
+        source.set("[1]".to_string(), vec![1])?;
+
+        for (format, name) in [(Format::Json, "test_import_json.db"), (Format::Csv, "test_import_csv.db")] {
+            let mut text = Vec::new();
+            assert_eq!(export(&source, &mut text, format)?, 102);
+            let mut target: BPTree<String, Vec<u8>> = BPTree::new(Path::new("data").join(name), Some(4))?;
+            assert_eq!(import(&mut target, text.as_slice(), format)?, 102);
+            let entries = target.range(..)?.collect::<Result<Vec<_>>>()?;
+            assert_eq!(entries, source.range(..)?.collect::<Result<Vec<_>>>()?);
+
+            // Into a tree that isn't empty, overwriting what is there.
+            target.set("key 005".to_string(), vec![9])?;
+            target.set("extra".to_string(), vec![9])?;
+            assert_eq!(import(&mut target, text.as_slice(), format)?, 102);
+            assert_eq!(target.get("key 005")?, vec![5, 5]);
+            assert_eq!(target.key_count(), 103);
+        }
+
+        let mut target: BPTree<u64, String> = BPTree::new(Path::new("data").join("test_import_typed.db"), Some(4))?;
+        let csv = "key,value\r\n7,\"a,b\"\r\n3,42\r\n7,c\r\n";
+        assert_eq!(import(&mut target, csv.as_bytes(), Format::Csv)?, 2);
+        assert_eq!(target.range(..)?.collect::<Result<Vec<_>>>()?, vec![(3, "42".to_string()), (7, "c".to_string())]);
+        assert!(matches!(import(&mut target, "key,value\n1\n".as_bytes(), Format::Csv), Err(Error::Csv(_))));
+        assert!(matches!(import(&mut target, "[[1, 2]]".as_bytes(), Format::Json), Err(Error::Json(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use std::ops::{Deref, DerefMut};
+use crate::engine::bptree::BPTree;
+use crate::engine::page::PagePtr;
+
+// The family a tree file starts out with, and the one open() hands out.
+pub const DEFAULT_FAMILY: &str = "";
+
+// A family while another one is the tree's: what the tree keeps for its own keys.
+#[derive(Debug, Clone)]
+pub(crate) struct Family<K> {
+    pub(crate) root_ptr: Option<PagePtr>,
+    pub(crate) key_count: u64,
+    pub(crate) key_bounds: Option<(K, K)>,
+}
+
+// The tree with one of its column families in place of the one it had: everything done
+// through it reads and writes that family. The family it had comes back when this is dropped.
+pub struct ColumnFamily<'a, K, V> {
+    bptree: &'a mut BPTree<K, V>,
+    previous: String,
+}
+
+impl<'a, K, V> ColumnFamily<'a, K, V> {
+    pub(crate) fn new(bptree: &'a mut BPTree<K, V>, previous: String) -> Self {
+        Self{ bptree, previous }
+    }
+}
+
+impl<K, V> Deref for ColumnFamily<'_, K, V> {
+    type Target = BPTree<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        self.bptree
+    }
+}
+
+impl<K, V> DerefMut for ColumnFamily<'_, K, V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.bptree
+    }
+}
+
+impl<K, V> Drop for ColumnFamily<'_, K, V> {
+    fn drop(&mut self) {
+        self.bptree.switch_family(&self.previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::Options;
+    use crate::error::{Error, Result};
+    use super::*;
+
+    #[test]
+    fn test_families_share_a_file() -> Result<()> {
+        let path = Path::new("data").join("test_family.db");
+        let mut bptree: BPTree<u64, String> = BPTree::with_options(&path, Options{ max_key_count: Some(4), ..Options::default() })?;
+        bptree.create_cf("users")?;
+        bptree.create_cf("orders")?;
+        assert!(matches!(bptree.create_cf("users"), Err(Error::TreeExists(_))));
+        for i in 0..200u64 {
+            bptree.set(i, format!("default-{}", i))?;
+            bptree.open_cf("users")?.set(i * 2, format!("user-{}", i))?;
+        }
+        {
+            let mut orders = bptree.open_cf("orders")?;
+            for i in 0..50u64 {
+                orders.set(i, format!("order-{}", i))?;
+            }
+            assert_eq!(orders.key_count(), 50);
+            assert!(matches!(orders.get(&100), Err(Error::KeyNotFound)));
+            assert!(matches!(orders.drop_cf("orders"), Err(Error::ColumnFamilyInUse(_))));
+        }
+        assert_eq!(bptree.key_count(), 200);
+        assert_eq!(bptree.get(&100)?, "default-100");
+        assert_eq!(bptree.open_cf("users")?.get(&100)?, "user-50");
+        assert!(matches!(bptree.open_cf("items"), Err(Error::TreeNotFound(_))));
+        bptree.vacuum()?;
+        assert!(bptree.verify(|_| ())?.is_ok());
+        bptree.close()?;
+
+        let mut bptree: BPTree<u64, String> = BPTree::open(&path)?;
+        assert_eq!(bptree.cf_names().collect::<Vec<_>>(), ["orders", "users"]);
+        assert_eq!(bptree.get(&199)?, "default-199");
+        assert_eq!(bptree.open_cf("users")?.range(..10)?.count(), 5);
+        assert_eq!(bptree.open_cf("orders")?.get(&49)?, "order-49");
+        let pages = bptree.page_count();
+        bptree.drop_cf("users")?;
+        assert!(matches!(bptree.open_cf("users"), Err(Error::TreeNotFound(_))));
+        bptree.create_cf("users")?;
+        assert!(bptree.open_cf("users")?.is_empty());
+        bptree.open_cf("users")?.set(1, "again".to_string())?;
+        assert_eq!(bptree.page_count(), pages);
+        Ok(())
+    }
+}
@@ -0,0 +1,414 @@
+use std::borrow::Borrow;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem;
+use std::path::{Path, PathBuf};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::btnode::{NODE_TYPE_OFFSET, PAGE_PTR_OFFSET};
+use crate::engine::codec::Codec;
+use crate::engine::overflow::{self, OverflowRef};
+use crate::engine::page::{self, PagePtr, Pager, DEFAULT_PAGE_SIZE};
+use crate::engine::{ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+
+pub(crate) const HASH_META_NODE_TYPE: u8 = 4;
+pub(crate) const BUCKET_NODE_TYPE: u8 = 5;
+const FORMAT_VERSION: u32 = 2;
+
+const MAGIC: &[u8; 8] = b"KVHASH\0\0";
+const MAGIC_OFFSET: usize = NODE_TYPE_OFFSET + 1;
+const META_LEN_OFFSET: usize = MAGIC_OFFSET + MAGIC.len();
+const META_OFFSET: usize = META_LEN_OFFSET + 8;
+
+const LOCAL_DEPTH_OFFSET: usize = NODE_TYPE_OFFSET + 1;
+const ENTRIES_LEN_OFFSET: usize = LOCAL_DEPTH_OFFSET + 1;
+const BUCKET_DATA_OFFSET: usize = ENTRIES_LEN_OFFSET + 8;
+// Hash files keep the default page size.
+const PAGE_DATA_SIZE: usize = page::data_size(DEFAULT_PAGE_SIZE);
+const BUCKET_CAPACITY: usize = PAGE_DATA_SIZE - BUCKET_DATA_OFFSET;
+// Keys whose hashes agree on this many low bits can't be told apart by the directory.
+const MAX_DEPTH: u8 = 24;
+
+// FNV-1a, finished with splitmix64 so every bit depends on the whole input. Hashes are
+// stored, so unlike std's default hasher this must hash the same way in every build.
+struct StableHasher(u64);
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut hash = self.0;
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^ (hash >> 31)
+    }
+}
+
+pub(crate) fn stable_hash<T: Hash + ?Sized>(item: &T) -> u64 {
+    let mut hasher = StableHasher(0xcbf29ce484222325);
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct HashMeta {
+    version: u32,
+    codec: Codec,
+    key_size: u64,
+    value_size: u64,
+    global_depth: u8,
+    page_count: u64,
+    key_count: u64,
+    free_pages: Vec<PagePtr>,
+    directory: OverflowRef,
+}
+
+// A bucket holds the entries whose hashes agree with its own on the low `depth` bits,
+// sorted by key, on one page.
+struct Bucket<K, V> {
+    ptr: PagePtr,
+    depth: u8,
+    entries: Vec<(K, V)>,
+}
+
+// Point lookups in O(1) page reads: an extendible hash table over the same Pager as the
+// tree. A directory of 2^global_depth bucket pointers, picked by the low bits of the key's
+// hash, is kept in memory; a full bucket splits in two and, when it was the only one for
+// its bits, doubles the directory. Buckets are not merged back when they empty out.
+// Like the tree, buckets are written in place and the directory and counters only on
+// flush(), so open() sees the table as of the last flush.
+pub struct HashIndex<K, V> {
+    path: PathBuf,
+    pager: Pager,
+    global_depth: u8,
+    directory: Vec<PagePtr>,
+    directory_chain: Vec<PagePtr>,
+    page_count: u64,
+    key_count: u64,
+    free_pages: Vec<PagePtr>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> HashIndex<K, V>
+    where K: Ord + Hash + Serialize + DeserializeOwned,
+          V: Serialize + DeserializeOwned
+{
+    // Creates an empty table at `path`, replacing whatever file is there.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let pager = Pager::open(&path, Codec::default())?;
+        let mut index = Self{
+            path: path.as_ref().to_path_buf(),
+            pager,
+            global_depth: 0,
+            directory: vec![1],
+            directory_chain: Vec::new(),
+            // Page 0 is the meta page, page 1 the first bucket.
+            page_count: 2,
+            key_count: 0,
+            free_pages: Vec::new(),
+            _marker: PhantomData,
+        };
+        index.store_bucket(Bucket{ ptr: 1, depth: 0, entries: Vec::new() })?;
+        index.flush()?;
+        Ok(index)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let pager = Pager::open_existing(&path, Codec::default())?;
+        let page = match pager.load_page(0) {
+            Err(Error::PageNotFound) => return Err(Error::NotADatabase),
+            page => page?,
+        };
+        if page.get_page_byte(NODE_TYPE_OFFSET) != HASH_META_NODE_TYPE || page.get_bytes_from_offset(MAGIC_OFFSET, MAGIC.len())? != MAGIC {
+            return Err(Error::NotADatabase);
+        }
+        let codec = Codec::default().with_limit(u64::MAX);
+        let len: u64 = codec.deserialize(page.get_bytes_from_offset(META_LEN_OFFSET, 8)?)?;
+        if META_OFFSET as u64 + len > PAGE_DATA_SIZE as u64 {
+            return Err(Error::CorruptedPage);
+        }
+        let meta: HashMeta = codec.deserialize(page.get_bytes_from_offset(META_OFFSET, len as usize)?)?;
+        if meta.version != FORMAT_VERSION || meta.key_size != mem::size_of::<K>() as u64 || meta.value_size != mem::size_of::<V>() as u64 {
+            return Err(Error::IncompatibleFormat);
+        }
+        let directory: Vec<PagePtr> = codec.deserialize(&overflow::read_chain(&meta.directory, &pager)?)?;
+        if directory.len() != 1 << meta.global_depth {
+            return Err(Error::CorruptedPage);
+        }
+        let directory_chain = overflow::chain_pages(&meta.directory, &pager)?;
+        let pager = Pager::open_existing(&path, meta.codec)?;
+        Ok(Self{
+            path: path.as_ref().to_path_buf(),
+            pager,
+            global_depth: meta.global_depth,
+            directory,
+            directory_chain,
+            page_count: meta.page_count,
+            key_count: meta.key_count,
+            free_pages: meta.free_pages,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized, F: FnOnce(&V) -> R
+    {
+        let bucket = self.load_bucket(self.bucket_for(key))?;
+        match bucket.entries.binary_search_by(|(k, _)| k.borrow().cmp(key)) {
+            Ok(i) => Ok(f(&bucket.entries[i].1)),
+            Err(_) => Err(Error::KeyNotFound),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Result<V>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized, V: Clone
+    {
+        self.get_with(key, V::clone)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> Result<bool>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized
+    {
+        match self.get_with(key, |_| ()) {
+            Ok(()) => Ok(true),
+            Err(Error::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Returns the value the key had before, if any.
+    pub fn set(&mut self, key: K, value: V) -> Result<Option<V>> {
+        // An entry that can't have a bucket to itself would split buckets without end.
+        if 8 + self.pager.codec().with_limit(u64::MAX).serialized_size(&(&key, &value))? > BUCKET_CAPACITY as u64 {
+            return Err(Error::PageSizeNotEnough);
+        }
+        let mut bucket = self.load_bucket(self.bucket_for(&key))?;
+        let replaced = match bucket.entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+            Ok(i) => Some(mem::replace(&mut bucket.entries[i].1, value)),
+            Err(i) => {
+                bucket.entries.insert(i, (key, value));
+                None
+            }
+        };
+        self.store_bucket(bucket)?;
+        if replaced.is_none() {
+            self.key_count += 1;
+        }
+        Ok(replaced)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<V>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized
+    {
+        let mut bucket = self.load_bucket(self.bucket_for(key))?;
+        let (_, value) = match bucket.entries.binary_search_by(|(k, _)| k.borrow().cmp(key)) {
+            Ok(i) => bucket.entries.remove(i),
+            Err(_) => return Err(Error::KeyNotFound),
+        };
+        self.store_bucket(bucket)?;
+        self.key_count -= 1;
+        Ok(value)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.key_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key_count == 0
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn global_depth(&self) -> u8 {
+        self.global_depth
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    // Writes the directory and counters, so open() finds the table as it is now. The old
+    // directory pages are only freed once the meta page points at the new ones.
+    pub fn flush(&mut self) -> Result<()> {
+        let codec = Codec::default().with_limit(u64::MAX);
+        let bytes = codec.serialize(&self.directory)?;
+        let chain: Vec<PagePtr> = (0..overflow::pages_needed(bytes.len(), &self.pager)).map(|_| self.next_page_ptr()).collect();
+        let directory = overflow::write_chain(&bytes, &chain, &self.pager)?;
+        let replaced = mem::replace(&mut self.directory_chain, chain);
+        let meta = HashMeta{
+            version: FORMAT_VERSION,
+            codec: *self.pager.codec(),
+            key_size: mem::size_of::<K>() as u64,
+            value_size: mem::size_of::<V>() as u64,
+            global_depth: self.global_depth,
+            page_count: self.page_count,
+            key_count: self.key_count,
+            free_pages: self.free_pages.iter().chain(&replaced).copied().collect(),
+            directory,
+        };
+        let body = codec.serialize(&meta)?;
+        if META_OFFSET + body.len() > PAGE_DATA_SIZE {
+            return Err(Error::PageSizeNotEnough);
+        }
+        let mut page = self.pager.new_page();
+        page.write_bytes_at_offset(PAGE_PTR_OFFSET, &0u64.to_be_bytes())?;
+        page.write_bytes_at_offset(NODE_TYPE_OFFSET, &[HASH_META_NODE_TYPE])?;
+        page.write_bytes_at_offset(MAGIC_OFFSET, MAGIC)?;
+        page.write_bytes_at_offset(META_LEN_OFFSET, &(body.len() as u64).to_be_bytes())?;
+        page.write_bytes_at_offset(META_OFFSET, &body)?;
+        self.pager.write_page(0, &page)?;
+        self.pager.sync()?;
+        self.free_pages.extend(replaced);
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    fn bucket_for<Q: Hash + ?Sized>(&self, key: &Q) -> PagePtr {
+        let mask = (1u64 << self.global_depth) - 1;
+        self.directory[(stable_hash(key) & mask) as usize]
+    }
+
+    fn next_page_ptr(&mut self) -> PagePtr {
+        if let Some(ptr) = self.free_pages.pop() {
+            return ptr;
+        }
+        self.page_count += 1;
+        self.page_count - 1
+    }
+
+    fn load_bucket(&self, ptr: PagePtr) -> Result<Bucket<K, V>> {
+        let page = self.pager.load_page(ptr)?;
+        if page.get_page_byte(NODE_TYPE_OFFSET) != BUCKET_NODE_TYPE {
+            return Err(Error::UnkonwNodeType);
+        }
+        let depth = page.get_page_byte(LOCAL_DEPTH_OFFSET);
+        let len = u64::from_be_bytes(page.get_bytes_from_offset(ENTRIES_LEN_OFFSET, 8)?.try_into().unwrap());
+        if len > BUCKET_CAPACITY as u64 {
+            return Err(Error::CorruptedPage);
+        }
+        let entries = self.pager.codec().deserialize(page.get_bytes_from_offset(BUCKET_DATA_OFFSET, len as usize)?)?;
+        Ok(Bucket{ ptr, depth, entries })
+    }
+
+    // Writes the bucket back, splitting it for as long as it doesn't fit its page.
+    fn store_bucket(&mut self, bucket: Bucket<K, V>) -> Result<()> {
+        let bytes = self.pager.codec().with_limit(u64::MAX).serialize(&bucket.entries)?;
+        if bytes.len() <= BUCKET_CAPACITY {
+            let mut page = self.pager.new_page();
+            page.write_bytes_at_offset(PAGE_PTR_OFFSET, &bucket.ptr.to_be_bytes())?;
+            page.write_bytes_at_offset(NODE_TYPE_OFFSET, &[BUCKET_NODE_TYPE])?;
+            page.write_bytes_at_offset(LOCAL_DEPTH_OFFSET, &[bucket.depth])?;
+            page.write_bytes_at_offset(ENTRIES_LEN_OFFSET, &(bytes.len() as u64).to_be_bytes())?;
+            page.write_bytes_at_offset(BUCKET_DATA_OFFSET, &bytes)?;
+            return self.pager.write_page(bucket.ptr, &page);
+        }
+        if bucket.entries.len() < 2 || bucket.depth == MAX_DEPTH {
+            return Err(Error::PageSizeNotEnough);
+        }
+        if bucket.depth == self.global_depth {
+            self.directory.extend_from_within(..);
+            self.global_depth += 1;
+        }
+        // The bucket keeps the entries with a 0 at bit `depth`, the new one takes the rest.
+        let bit = 1u64 << bucket.depth;
+        let sibling = self.next_page_ptr();
+        let (high, low): (Vec<_>, Vec<_>) = bucket.entries.into_iter().partition(|(key, _)| stable_hash(key) & bit != 0);
+        for (i, ptr) in self.directory.iter_mut().enumerate() {
+            if *ptr == bucket.ptr && i as u64 & bit != 0 {
+                *ptr = sibling;
+            }
+        }
+        self.store_bucket(Bucket{ ptr: bucket.ptr, depth: bucket.depth + 1, entries: low })?;
+        self.store_bucket(Bucket{ ptr: sibling, depth: bucket.depth + 1, entries: high })
+    }
+}
+
+impl<K, V> ReadEngine<K, V> for HashIndex<K, V>
+    where K: Ord + Hash + Serialize + DeserializeOwned,
+          V: Serialize + DeserializeOwned
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        HashIndex::get_with(self, key, f)
+    }
+}
+
+impl<K, V> WriteEngine<K, V> for HashIndex<K, V>
+    where K: Ord + Hash + Serialize + DeserializeOwned,
+          V: Serialize + DeserializeOwned
+{
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        HashIndex::set(self, key, value).map(|_| ())
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static
+    {
+        HashIndex::remove(self, key).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use crate::engine::replay;
+    use super::*;
+
+    #[test]
+    fn test_hash_index() -> Result<()> {
+        let path = Path::new("data").join("test_hash.db");
+        let mut index: HashIndex<String, Vec<u8>> = HashIndex::new(&path)?;
+        for i in 0..5000u32 {
+            assert_eq!(index.set(format!("key {}", i), vec![i as u8; 20])?, None);
+        }
+        assert_eq!(index.set("key 7".to_string(), vec![1])?, Some(vec![7; 20]));
+        for i in (0..5000u32).filter(|i| i % 4 == 0) {
+            index.remove(format!("key {}", i).as_str())?;
+        }
+        assert!(matches!(index.remove("key 0"), Err(Error::KeyNotFound)));
+        assert_eq!(index.len(), 3750);
+        assert!(index.global_depth() >= 6);
+        assert_eq!(index.get("key 7")?, vec![1]);
+        assert!(!index.contains_key("key 8")?);
+        index.close()?;
+
+        let mut index: HashIndex<String, Vec<u8>> = HashIndex::open(&path)?;
+        assert_eq!(index.len(), 3750);
+        for i in 0..5000u32 {
+            let expected = match i {
+                7 => Some(vec![1]),
+                i if i % 4 == 0 => None,
+                i => Some(vec![i as u8; 20]),
+            };
+            assert_eq!(index.get(format!("key {}", i).as_str()).ok(), expected);
+        }
+        // Keeps working after the directory moved to new pages.
+        let pages = index.page_count();
+        index.flush()?;
+        index.flush()?;
+        assert_eq!(index.page_count(), pages);
+        assert!(matches!(index.set("big".to_string(), vec![0; 5000]), Err(Error::PageSizeNotEnough)));
+        assert!(matches!(BPTree::<String, Vec<u8>>::open(&path), Err(Error::NotADatabase)));
+        assert!(matches!(HashIndex::<u32, Vec<u8>>::open(&path), Err(Error::IncompatibleFormat)));
+
+        // Behaves like the tree as far as KVStoreEngine goes.
+        let ops = replay::mixed_ops(3000);
+        let mut index: HashIndex<u64, u64> = HashIndex::new(Path::new("data").join("test_hash_replay.db"))?;
+        let mut bptree: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_hash_replay_tree.db"), None)?;
+        assert!(replay::replay(&ops, &mut index, &mut bptree).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use crate::engine::page::PagePtr;
+
+// Every this many recorded accesses all counters are halved, so the map follows the
+// current workload instead of the tree's whole history.
+const DECAY_EVERY: u64 = 8192;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+// One leaf's share of the recent accesses, with the keys it held when the map was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeafHeat<K> {
+    pub leaf: PagePtr,
+    pub first_key: K,
+    pub last_key: K,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+// Approximate per-leaf access frequencies, decayed by periodic halving.
+#[derive(Debug, Default)]
+pub(crate) struct Heat {
+    counts: HashMap<PagePtr, Counts>,
+    accesses: u64,
+}
+
+impl Heat {
+    pub(crate) fn record_read(&mut self, leaf: PagePtr) {
+        self.counts.entry(leaf).or_default().reads += 1;
+        self.tick();
+    }
+
+    pub(crate) fn record_write(&mut self, leaf: PagePtr) {
+        self.counts.entry(leaf).or_default().writes += 1;
+        self.tick();
+    }
+
+    pub(crate) fn forget(&mut self, leaf: PagePtr) {
+        self.counts.remove(&leaf);
+    }
+
+    pub(crate) fn get(&self, leaf: PagePtr) -> Counts {
+        self.counts.get(&leaf).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn leaves(&self) -> Vec<(PagePtr, Counts)> {
+        self.counts.iter().map(|(ptr, counts)| (*ptr, *counts)).collect()
+    }
+
+    fn tick(&mut self) {
+        self.accesses += 1;
+        if self.accesses.is_multiple_of(DECAY_EVERY) {
+            self.counts.retain(|_, counts| {
+                counts.reads /= 2;
+                counts.writes /= 2;
+                counts.reads + counts.writes > 0
+            });
+        }
+    }
+}
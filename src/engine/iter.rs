@@ -0,0 +1,426 @@
+use std::fmt::Debug;
+use std::ops::Bound;
+use std::vec;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::{Entry, LeafNode, Node};
+use crate::engine::page::PagePtr;
+use crate::error::{Error, Result};
+
+// Leftmost leaf, where every scan of the whole tree starts.
+pub(crate) fn first_leaf<K, V>(bptree: &BPTree<K, V>) -> Result<Option<PagePtr>>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let mut next_leaf = bptree.root_ptr();
+    while let Some(ptr) = next_leaf {
+        match Node::<K, V>::load_node(ptr, bptree.pager())? {
+            Node::Leaf(_) => break,
+            Node::Inner(inner) => next_leaf = Some(inner.childptrs()[0]),
+        }
+    }
+    Ok(next_leaf)
+}
+
+// Rightmost leaf, where a reverse scan of the whole tree starts.
+pub(crate) fn last_leaf<K, V>(bptree: &BPTree<K, V>) -> Result<Option<PagePtr>>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let mut prev_leaf = bptree.root_ptr();
+    while let Some(ptr) = prev_leaf {
+        match Node::<K, V>::load_node(ptr, bptree.pager())? {
+            Node::Leaf(_) => break,
+            Node::Inner(inner) => prev_leaf = inner.childptrs().last().copied(),
+        }
+    }
+    Ok(prev_leaf)
+}
+
+type Entries<K, V> = std::iter::Zip<vec::IntoIter<K>, vec::IntoIter<Entry<V>>>;
+
+fn no_entries<K, V>() -> Entries<K, V> {
+    Vec::new().into_iter().zip(Vec::new())
+}
+
+// Walks the leaf chain from the leftmost leaf under a pinned root. The iterator holds the
+// tree borrowed for its whole lifetime and writers need `&mut`, so no write can interleave with the
+// scan: every leaf it visits belongs to the tree as it was when the root was pinned.
+pub struct Iter<'a, K, V> {
+    bptree: &'a BPTree<K, V>,
+    next_leaf: Option<PagePtr>,
+    entries: std::iter::Zip<vec::IntoIter<K>, vec::IntoIter<Entry<V>>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    pub(crate) fn new(bptree: &'a BPTree<K, V>) -> Result<Self> {
+        if !bptree.quarantine().is_empty() {
+            return Err(Error::RangeUnavailable);
+        }
+        Ok(Self{
+            bptree,
+            next_leaf: first_leaf(bptree)?,
+            entries: Vec::new().into_iter().zip(Vec::new()),
+        })
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, entry)) = self.entries.next() {
+                if self.bptree.is_expired(&entry) {
+                    continue;
+                }
+                return Some(entry.slot.into_value(self.bptree.pager()).map(|value| (key, value)));
+            }
+            let ptr = self.next_leaf.take()?;
+            match LeafNode::<K, V>::load(ptr, self.bptree.pager()) {
+                Ok(leaf) => {
+                    self.next_leaf = leaf.next();
+                    let (keys, values) = leaf.into_parts();
+                    self.entries = keys.into_iter().zip(values);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Cursor over the keys between two bounds. It is positioned by a point descent to the
+// start key and then follows the leaf chain until it passes the end bound. Read from the
+// back, it descends to the end key on the first call and follows the prev pointers down.
+// Each end knows which leaf the other loads next; the end that reaches that leaf first takes
+// it, and from then on each end goes on into the other's entries once its own run out.
+pub struct RangeIter<'a, K, V> {
+    bptree: &'a BPTree<K, V>,
+    next_leaf: Option<PagePtr>,
+    front_leaf: Option<PagePtr>,
+    entries: Entries<K, V>,
+    prev_leaf: Option<PagePtr>,
+    back_entries: Entries<K, V>,
+    back_positioned: bool,
+    met: bool,
+    done: bool,
+    start: Bound<K>,
+    end: Bound<K>,
+}
+
+impl<'a, K, V> RangeIter<'a, K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    pub(crate) fn new(bptree: &'a BPTree<K, V>, start: Bound<K>, end: Bound<K>) -> Result<Self> {
+        if bptree.quarantine().overlaps(&start, &end) {
+            return Err(Error::RangeUnavailable);
+        }
+        let next_leaf = match (&start, bptree.root_ptr()) {
+            (_, None) => None,
+            (Bound::Unbounded, Some(_)) => first_leaf(bptree)?,
+            (Bound::Included(key) | Bound::Excluded(key), Some(root)) => {
+                Some(Node::<K, V>::load_node(root, bptree.pager())?.find_leaf(key, bptree.pager())?.ptr())
+            }
+        };
+        Ok(Self{
+            bptree,
+            next_leaf,
+            front_leaf: None,
+            entries: no_entries(),
+            prev_leaf: None,
+            back_entries: no_entries(),
+            back_positioned: false,
+            met: false,
+            done: false,
+            start,
+            end,
+        })
+    }
+
+    fn end_leaf(&self) -> Result<Option<PagePtr>> {
+        match (&self.end, self.bptree.root_ptr()) {
+            (_, None) => Ok(None),
+            (Bound::Unbounded, Some(_)) => last_leaf(self.bptree),
+            (Bound::Included(key) | Bound::Excluded(key), Some(root)) => {
+                Ok(Some(Node::<K, V>::load_node(root, self.bptree.pager())?.find_leaf(key, self.bptree.pager())?.ptr()))
+            }
+        }
+    }
+
+    pub fn start_bound(&self) -> Bound<&K> {
+        self.start.as_ref()
+    }
+
+    pub fn end_bound(&self) -> Bound<&K> {
+        self.end.as_ref()
+    }
+
+    fn after_start(&self, key: &K) -> bool {
+        let order = self.bptree.pager().order();
+        match &self.start {
+            Bound::Included(start) => order.cmp::<K, K>(key, start).is_ge(),
+            Bound::Excluded(start) => order.cmp::<K, K>(key, start).is_gt(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn before_end(&self, key: &K) -> bool {
+        let order = self.bptree.pager().order();
+        match &self.end {
+            Bound::Included(end) => order.cmp::<K, K>(key, end).is_le(),
+            Bound::Excluded(end) => order.cmp::<K, K>(key, end).is_lt(),
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for RangeIter<'a, K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_live()?.and_then(|(key, entry)| {
+            entry.slot.into_value(self.bptree.pager()).map(|value| (key, value))
+        }))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for RangeIter<'a, K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        Some(self.next_back_live()?.and_then(|(key, entry)| {
+            entry.slot.into_value(self.bptree.pager()).map(|value| (key, value))
+        }))
+    }
+}
+
+impl<'a, K, V> RangeIter<'a, K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    // Keys only; overflowed values in the range are never read.
+    pub fn keys(mut self) -> impl Iterator<Item = Result<K>> + 'a {
+        std::iter::from_fn(move || Some(self.next_live()?.map(|(key, _)| key)))
+    }
+
+    // Every stored entry, expired ones included.
+    pub(crate) fn entries(mut self) -> impl Iterator<Item = Result<(K, Entry<V>)>> + 'a {
+        std::iter::from_fn(move || self.next_entry())
+    }
+
+    fn next_live(&mut self) -> Option<Result<(K, Entry<V>)>> {
+        loop {
+            match self.next_entry()? {
+                Ok((_, entry)) if self.bptree.is_expired(&entry) => continue,
+                next => return Some(next),
+            }
+        }
+    }
+
+    fn next_back_live(&mut self) -> Option<Result<(K, Entry<V>)>> {
+        loop {
+            match self.next_back_entry()? {
+                Ok((_, entry)) if self.bptree.is_expired(&entry) => continue,
+                next => return Some(next),
+            }
+        }
+    }
+
+    fn next_entry(&mut self) -> Option<Result<(K, Entry<V>)>> {
+        while !self.done {
+            let next = match self.entries.next() {
+                None if self.met => self.back_entries.next(),
+                next => next,
+            };
+            if let Some((key, entry)) = next {
+                if !self.after_start(&key) {
+                    continue;
+                }
+                if !self.before_end(&key) {
+                    self.done = true;
+                    return None;
+                }
+                return Some(Ok((key, entry)));
+            }
+            let ptr = match self.next_leaf.take() {
+                Some(ptr) if !self.met => ptr,
+                _ => {
+                    self.done = true;
+                    return None;
+                }
+            };
+            if self.prev_leaf == Some(ptr) {
+                self.prev_leaf = None;
+                self.met = true;
+            }
+            match LeafNode::<K, V>::load(ptr, self.bptree.pager()) {
+                Ok(leaf) => {
+                    if !self.met {
+                        self.next_leaf = leaf.next();
+                    }
+                    self.front_leaf = Some(ptr);
+                    let (keys, values) = leaf.into_parts();
+                    self.entries = keys.into_iter().zip(values);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    fn next_back_entry(&mut self) -> Option<Result<(K, Entry<V>)>> {
+        if !self.back_positioned && !self.done {
+            self.back_positioned = true;
+            match self.end_leaf() {
+                Ok(ptr) => self.prev_leaf = ptr,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        while !self.done {
+            let next = match self.back_entries.next_back() {
+                None if self.met => self.entries.next_back(),
+                next => next,
+            };
+            if let Some((key, entry)) = next {
+                if !self.before_end(&key) {
+                    continue;
+                }
+                if !self.after_start(&key) {
+                    self.done = true;
+                    return None;
+                }
+                return Some(Ok((key, entry)));
+            }
+            let ptr = match self.prev_leaf.take() {
+                Some(ptr) if !self.met => ptr,
+                _ => {
+                    self.done = true;
+                    return None;
+                }
+            };
+            // The front end has this leaf loaded already.
+            if self.front_leaf == Some(ptr) {
+                self.met = true;
+                continue;
+            }
+            if self.next_leaf == Some(ptr) {
+                self.next_leaf = None;
+                self.met = true;
+            }
+            match LeafNode::<K, V>::load(ptr, self.bptree.pager()) {
+                Ok(leaf) => {
+                    if !self.met {
+                        self.prev_leaf = leaf.prev();
+                    }
+                    let (keys, values) = leaf.into_parts();
+                    self.back_entries = keys.into_iter().zip(values);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_iter_snapshot_in_order() -> Result<()> {
+        let path = Path::new("data").join("test_iter.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        assert_eq!(bptree.iter_snapshot()?.count(), 0);
+        for i in 0..200 {
+            bptree.set((i * 37) % 200, i)?;
+        }
+        for i in 0..50 {
+            bptree.remove(&(i * 2))?;
+        }
+        let keys = bptree.iter_snapshot()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        let expected: Vec<u64> = (0..200).filter(|k| k % 2 == 1 || *k >= 100).collect();
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_follows_leaf_chain() -> Result<()> {
+        let path = Path::new("data").join("test_range.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        assert_eq!(bptree.range(..)?.count(), 0);
+        for i in (0..300).rev() {
+            bptree.set(i * 2, i)?;
+        }
+        let range = bptree.range(101..=121)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(range, (51..=60).map(|i| (i * 2, i)).collect::<Vec<_>>());
+        assert_eq!(bptree.range(590..)?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?, vec![590, 592, 594, 596, 598]);
+        assert_eq!(bptree.range(10..10)?.count(), 0);
+        assert_eq!(bptree.range(1000..)?.count(), 0);
+        let all = bptree.scan_all()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(all.len(), 300);
+        assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_range() -> Result<()> {
+        let path = Path::new("data").join("test_reverse_range.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(&path, Some(4))?;
+        assert_eq!(bptree.iter_rev()?.count(), 0);
+        for i in 0..300 {
+            bptree.set((i * 37) % 300, i)?;
+        }
+        // Merges relink the leaves on both sides.
+        for i in (0..300).filter(|i| i % 3 != 0 && (*i < 100 || *i > 200)) {
+            bptree.remove(&i)?;
+        }
+        let forward = bptree.range(..)?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        let mut backward = bptree.iter_rev()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        backward.reverse();
+        assert_eq!(backward, forward);
+        let latest = bptree.range(..=250)?.rev().take(3).map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(latest, vec![249, 246, 243]);
+        assert_eq!(bptree.range(150..151)?.rev().count(), 1);
+        assert_eq!(bptree.range(160..160)?.rev().count(), 0);
+
+        // Both ends of one cursor meet without handing out an entry twice.
+        for (start, end) in [(0, 300), (120, 180), (101, 103), (90, 210)] {
+            let expected: Vec<u64> = forward.iter().copied().filter(|k| *k >= start && *k < end).collect();
+            for fronts in 0..expected.len() + 1 {
+                let mut range = bptree.range(start..end)?;
+                let mut keys: Vec<u64> = range.by_ref().take(fronts).map(|entry| entry.map(|(k, _)| k)).collect::<Result<_>>()?;
+                let mut back = Vec::new();
+                while let Some(entry) = range.next_back() {
+                    back.push(entry?.0);
+                    if let Some(entry) = range.next() {
+                        keys.push(entry?.0);
+                    }
+                }
+                assert!(range.next().is_none());
+                keys.extend(back.into_iter().rev());
+                assert_eq!(keys, expected);
+            }
+        }
+        bptree.close()?;
+
+        let mut bptree: BPTree<u64, u64> = BPTree::open(&path)?;
+        assert_eq!(bptree.iter_rev()?.next().transpose()?, Some((297, 81)));
+        bptree.remove_range(..)?;
+        bptree.bulk_load((0..500).map(|i| (i, i)).collect())?;
+        let keys = bptree.iter_rev()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (0..500).rev().collect::<Vec<_>>());
+        Ok(())
+    }
+}
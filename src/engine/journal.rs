@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+use std::fmt;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use crate::engine::codec::Codec;
+use crate::engine::wal::{Change, ChangeStream};
+use crate::error::Result;
+
+// Bytes of the key's SHA-256 shown in place of the key.
+const KEY_HASH_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalOp {
+    Set,
+    Remove,
+}
+
+// One change of the log as it goes into a bug report: what was done to which key when,
+// and how big it was, but not the data itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalRecord {
+    pub seq: u64,
+    // Milliseconds since the Unix epoch.
+    pub time: u64,
+    pub op: JournalOp,
+    pub key_hash: String,
+    pub key_bytes: u64,
+    pub value_bytes: Option<u64>,
+    // Offset of the record in the change log.
+    pub offset: u64,
+}
+
+impl fmt::Display for JournalRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            JournalOp::Set => "set",
+            JournalOp::Remove => "remove",
+        };
+        write!(f, "seq={} time={} op={} key={} key_bytes={}", self.seq, self.time, op, self.key_hash, self.key_bytes)?;
+        if let Some(value_bytes) = self.value_bytes {
+            write!(f, " value_bytes={}", value_bytes)?;
+        }
+        write!(f, " offset={}", self.offset)
+    }
+}
+
+fn key_hash(encoded: &[u8]) -> String {
+    Sha256::digest(encoded)[..KEY_HASH_LEN].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Reads the stream to its end and keeps the last `records` changes.
+pub(crate) fn tail<K, V>(mut changes: ChangeStream<K, V>, records: usize, codec: &Codec) -> Result<Vec<JournalRecord>>
+    where K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned
+{
+    let codec = codec.with_limit(u64::MAX);
+    let mut last = VecDeque::with_capacity(records);
+    let mut offset = changes.offset();
+    for event in changes.by_ref() {
+        let event = event?;
+        let (op, key, value_bytes) = match &event.change {
+            Change::Set(key, value) | Change::SetExpiring(key, value, _) => (JournalOp::Set, key, Some(codec.serialized_size(value)?)),
+            Change::Remove(key) => (JournalOp::Remove, key, None),
+        };
+        let key = codec.serialize(key)?;
+        if last.len() == records {
+            last.pop_front();
+        }
+        if records > 0 {
+            last.push_back(JournalRecord{
+                seq: event.seq,
+                time: event.time,
+                op,
+                key_hash: key_hash(&key),
+                key_bytes: key.len() as u64,
+                value_bytes,
+                offset,
+            });
+        }
+        offset = event.next;
+    }
+    Ok(last.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use super::*;
+
+    #[test]
+    fn test_journal_tail() -> Result<()> {
+        let path = Path::new("data").join("test_journal.db");
+        let options = Options{ max_key_count: Some(4), change_log: true, ..Options::default() };
+        let mut bptree: BPTree<String, Vec<u8>> = BPTree::with_options(path, options)?;
+        for i in 0..20 {
+            bptree.set(format!("user/{}", i), vec![0; i])?;
+        }
+        bptree.remove("user/3")?;
+        let journal = bptree.journal(3)?;
+        assert_eq!(journal.iter().map(|record| record.seq).collect::<Vec<_>>(), vec![19, 20, 21]);
+        assert_eq!((journal[1].op, journal[1].key_bytes, journal[1].value_bytes), (JournalOp::Set, 15, Some(27)));
+        assert_eq!(journal[2].value_bytes, None);
+        assert!(journal[2].time >= journal[0].time && journal[0].time > 0);
+        assert_eq!(journal[2].offset, bptree.changes(journal[1].offset)?.next().unwrap()?.next);
+        let line = journal[2].to_string();
+        assert!(line.starts_with("seq=21 time=") && line.contains(" op=remove key=") && !line.contains("user"));
+        assert_eq!(bptree.journal(100)?.len(), 21);
+        Ok(())
+    }
+}
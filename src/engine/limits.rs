@@ -0,0 +1,159 @@
+use std::fmt::Debug;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::Node;
+use crate::engine::page::PagePtr;
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    FileBytes,
+    Keys,
+    Depth,
+    // Writes not yet flushed to disk.
+    WalLag,
+}
+
+// Thresholds that only warn. `None` leaves that one unwatched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SoftLimits {
+    pub file_bytes: Option<u64>,
+    pub keys: Option<u64>,
+    pub depth: Option<u64>,
+    pub wal_lag: Option<u64>,
+}
+
+impl SoftLimits {
+    fn threshold(&self, limit: Limit) -> Option<u64> {
+        match limit {
+            Limit::FileBytes => self.file_bytes,
+            Limit::Keys => self.keys,
+            Limit::Depth => self.depth,
+            Limit::WalLag => self.wal_lag,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Warning {
+    pub limit: Limit,
+    pub value: u64,
+    pub threshold: u64,
+}
+
+pub type WarningCallback = Box<dyn Fn(&Warning) + Send + Sync>;
+
+const LIMITS: [Limit; 4] = [Limit::FileBytes, Limit::Keys, Limit::Depth, Limit::WalLag];
+
+// Checks the soft limits after every write. A warning goes to every callback when a value
+// goes over its threshold, and only again once it has been back under it in between.
+#[derive(Default)]
+pub(crate) struct LimitWatch {
+    limits: SoftLimits,
+    callbacks: Vec<WarningCallback>,
+    exceeded: Vec<Limit>,
+    // Depth only changes with the root, so it is only walked again when the root moved.
+    depth: Option<(Option<PagePtr>, u64)>,
+}
+
+impl LimitWatch {
+    pub(crate) fn limits(&self) -> SoftLimits {
+        self.limits
+    }
+
+    pub(crate) fn set_limits(&mut self, limits: SoftLimits) {
+        self.limits = limits;
+        self.exceeded.clear();
+    }
+
+    pub(crate) fn add_callback(&mut self, callback: WarningCallback) {
+        self.callbacks.push(callback);
+    }
+
+    pub(crate) fn check<K, V>(&mut self, bptree: &BPTree<K, V>) -> Result<()>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        if self.callbacks.is_empty() {
+            return Ok(());
+        }
+        for limit in LIMITS {
+            let threshold = match self.limits.threshold(limit) {
+                Some(threshold) => threshold,
+                None => continue,
+            };
+            let value = match limit {
+                Limit::FileBytes => bptree.page_count() * bptree.page_size() as u64,
+                Limit::Keys => bptree.key_count(),
+                Limit::Depth => self.depth(bptree)?,
+                Limit::WalLag => bptree.last_seq() - bptree.durable_seq(),
+            };
+            let was_exceeded = self.exceeded.contains(&limit);
+            if value > threshold && !was_exceeded {
+                self.exceeded.push(limit);
+                let warning = Warning{ limit, value, threshold };
+                self.callbacks.iter().for_each(|callback| callback(&warning));
+            } else if value <= threshold && was_exceeded {
+                self.exceeded.retain(|exceeded| *exceeded != limit);
+            }
+        }
+        Ok(())
+    }
+
+    fn depth<K, V>(&mut self, bptree: &BPTree<K, V>) -> Result<u64>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        let root = bptree.root_ptr();
+        match self.depth {
+            Some((ptr, depth)) if ptr == root => return Ok(depth),
+            _ => (),
+        }
+        let (mut next, mut depth) = (root, 0);
+        while let Some(ptr) = next {
+            depth += 1;
+            next = match Node::<K, V>::load_node(ptr, bptree.pager())? {
+                Node::Leaf(_) => None,
+                Node::Inner(inner) => Some(inner.childptrs()[0]),
+            };
+        }
+        self.depth = Some((root, depth));
+        Ok(depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    #[test]
+    fn test_soft_limit_warnings() -> Result<()> {
+        let mut bptree: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_soft_limits.db"), Some(4))?;
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let seen = warnings.clone();
+        bptree.on_soft_limit(move |warning| seen.lock().unwrap().push(*warning));
+        bptree.set_soft_limits(SoftLimits{ keys: Some(10), depth: Some(2), wal_lag: Some(50), ..SoftLimits::default() });
+        for i in 0..40 {
+            bptree.set(i, i)?;
+        }
+        bptree.set(0, 1)?;
+        assert_eq!(bptree.key_count(), 40);
+        let limits: Vec<Limit> = warnings.lock().unwrap().iter().map(|warning| warning.limit).collect();
+        assert_eq!(limits, vec![Limit::Keys, Limit::Depth]);
+        assert_eq!(warnings.lock().unwrap()[0], Warning{ limit: Limit::Keys, value: 11, threshold: 10 });
+
+        // Shrinking back under the key and depth limits re-arms them; the lag limit goes off
+        // on the way.
+        for i in 0..35 {
+            bptree.remove(&i)?;
+        }
+        for i in 100..110 {
+            bptree.set(i, i)?;
+        }
+        let limits: Vec<Limit> = warnings.lock().unwrap().iter().map(|warning| warning.limit).collect();
+        assert_eq!(limits, vec![Limit::Keys, Limit::Depth, Limit::WalLag, Limit::Keys, Limit::Depth]);
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::mem;
+use serde::Serialize;
+use crate::engine::codec::Codec;
+use crate::engine::lsm::sstable::Record;
+use crate::error::Result;
+
+// The writes not yet in a table, newest value per key, with removes kept as None. Its size
+// is the encoded size of every write since it was last drained, so overwrites make it
+// flush a little early.
+pub(crate) struct Memtable<K, V> {
+    entries: BTreeMap<K, Option<V>>,
+    bytes: u64,
+    codec: Codec,
+}
+
+impl<K, V> Memtable<K, V>
+    where K: Ord + Serialize, V: Serialize
+{
+    pub(crate) fn new(codec: Codec) -> Self {
+        Self{ entries: BTreeMap::new(), bytes: 0, codec: codec.with_limit(u64::MAX) }
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: Option<V>) -> Result<()> {
+        self.bytes += self.codec.serialized_size(&(&key, &value))?;
+        self.entries.insert(key, value);
+        Ok(())
+    }
+
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<(&K, &Option<V>)>
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        self.entries.get_key_value(key)
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = Record<K, V>> {
+        self.bytes = 0;
+        mem::take(&mut self.entries).into_iter()
+    }
+}
@@ -0,0 +1,489 @@
+mod memtable;
+mod sstable;
+
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{ErrorKind, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::clock::{Clock, SystemClock};
+use crate::engine::codec::Codec;
+use crate::engine::wal::{Change, ChangeLog, ChangeStream};
+use crate::engine::{ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+use self::memtable::Memtable;
+use self::sstable::{Record, SsTable};
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+const MEMTABLE_LOG: &str = "memtable.log";
+const TABLE_EXTENSION: &str = "sst";
+
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    // The memtable is written out as a table once its writes take this many bytes.
+    pub memtable_bytes: u64,
+    // Tables kept before the background thread merges them all into one.
+    pub compact_at: usize,
+    // Sync the memtable log after every write instead of leaving it to the OS.
+    pub sync_writes: bool,
+    // Only used when the directory is new; an existing one keeps its own.
+    pub codec: Codec,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self{ memtable_bytes: 4 << 20, compact_at: 4, sync_writes: false, codec: Codec::default() }
+    }
+}
+
+// Which tables make up the store, newest first. Replaced atomically, like the Db catalog.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+    codec: Codec,
+    key_size: u64,
+    value_size: u64,
+    next_id: u64,
+    seq: u64,
+    tables: Vec<u64>,
+}
+
+struct Tables<K, V> {
+    manifest: Manifest,
+    list: Vec<Arc<SsTable<K, V>>>,
+    // Why the last background compaction failed, handed to the next flush.
+    failure: Option<String>,
+}
+
+// What the writer and the compaction thread share.
+struct Inner<K, V> {
+    dir: PathBuf,
+    tables: Mutex<Tables<K, V>>,
+    // Held for the length of a compaction, so two never merge the same tables.
+    compacting: Mutex<()>,
+}
+
+// A write-optimized store in a directory: writes go to a log and an in-memory memtable,
+// which is written out as an immutable sorted table (SSTable) once it is big enough. Reads
+// look at the memtable, then at the tables from newest to oldest. When compact_at tables
+// have piled up, a background thread merges them all into one, dropping overwritten values
+// and removed keys. A set costs one log append instead of a page rewrite.
+// After a crash the memtable is rebuilt from its log; tables and the manifest are only
+// ever replaced whole.
+pub struct Lsm<K, V> {
+    inner: Arc<Inner<K, V>>,
+    options: LsmOptions,
+    codec: Codec,
+    memtable: Memtable<K, V>,
+    log: ChangeLog,
+    seq: u64,
+    compactor: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl<K, V> Lsm<K, V>
+    where K: Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+          V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    // Opens the store in `dir`, creating the directory if there is none.
+    pub fn open<P: AsRef<Path>>(dir: P, options: LsmOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        remove_if_exists(&dir.join(MANIFEST_TMP))?;
+        let manifest: Manifest = match fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => Codec::default().with_limit(u64::MAX).deserialize(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Manifest{
+                codec: options.codec,
+                key_size: mem::size_of::<K>() as u64,
+                value_size: mem::size_of::<V>() as u64,
+                next_id: 0,
+                seq: 0,
+                tables: Vec::new(),
+            },
+            Err(e) => return Err(e.into()),
+        };
+        if manifest.key_size != mem::size_of::<K>() as u64 || manifest.value_size != mem::size_of::<V>() as u64 {
+            return Err(Error::IncompatibleFormat);
+        }
+        let codec = manifest.codec;
+        let list = manifest.tables.iter()
+            .map(|id| SsTable::open(*id, &table_path(&dir, *id), codec).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        // Tables a compaction or flush wrote but never got into the manifest.
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let listed = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()).is_some_and(|id| manifest.tables.contains(&id));
+            if path.extension().is_some_and(|extension| extension == TABLE_EXTENSION) && !listed {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let log_path = dir.join(MEMTABLE_LOG);
+        let mut memtable = Memtable::new(codec);
+        let mut seq = manifest.seq;
+        if log_path.exists() {
+            for event in ChangeStream::<K, V>::open(&log_path, 0, codec)? {
+                let event = event?;
+                seq = seq.max(event.seq);
+                match event.change {
+                    Change::Set(key, value) | Change::SetExpiring(key, value, _) => memtable.insert(key, Some(value))?,
+                    Change::Remove(key) => memtable.insert(key, None)?,
+                }
+            }
+        }
+        let log = ChangeLog::open(&log_path, codec)?;
+
+        let inner = Arc::new(Inner{
+            dir,
+            tables: Mutex::new(Tables{ manifest, list, failure: None }),
+            compacting: Mutex::new(()),
+        });
+        let (sender, receiver) = mpsc::channel::<()>();
+        let worker = inner.clone();
+        let handle = thread::spawn(move || {
+            for () in receiver {
+                if let Err(e) = compact(&worker) {
+                    worker.lock().failure = Some(e.to_string());
+                }
+            }
+        });
+        Ok(Self{ inner, options, codec, memtable, log, seq, compactor: Some((sender, handle)) })
+    }
+
+    pub fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized, F: FnOnce(&V) -> R
+    {
+        self.find(key, |_, value| f(value))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Result<V>
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        self.get_with(key, V::clone)
+    }
+
+    pub fn set(&mut self, key: K, value: V) -> Result<()> {
+        self.write(Change::Set(key, value))
+    }
+
+    // Fails with KeyNotFound when the key isn't there, like the tree does; otherwise the
+    // key is marked removed until compaction drops it.
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        let key = self.find(key, |key, _| key.clone())?;
+        self.write(Change::Remove(key))
+    }
+
+    // Writes the memtable out as a new table and empties its log.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(failure) = self.inner.lock().failure.take() {
+            return Err(Error::CompactionFailed(failure));
+        }
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let id = {
+            let mut tables = self.inner.lock();
+            tables.manifest.next_id += 1;
+            tables.manifest.next_id - 1
+        };
+        let table = SsTable::write(id, &table_path(&self.inner.dir, id), self.codec, self.memtable.drain().map(Ok))?;
+        let count = {
+            let mut tables = self.inner.lock();
+            tables.list.insert(0, Arc::new(table));
+            tables.manifest.seq = self.seq;
+            store_manifest(&self.inner.dir, &mut tables)?;
+            tables.list.len()
+        };
+        self.log = ChangeLog::create(self.inner.dir.join(MEMTABLE_LOG), self.codec)?;
+        if count >= self.options.compact_at {
+            if let Some((sender, _)) = &self.compactor {
+                let _ = sender.send(());
+            }
+        }
+        Ok(())
+    }
+
+    // Merges every table into one on this thread, after any background compaction under way.
+    pub fn compact(&mut self) -> Result<()> {
+        compact(&self.inner)
+    }
+
+    pub fn table_count(&self) -> usize {
+        self.inner.lock().list.len()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.inner.dir
+    }
+
+    fn write(&mut self, change: Change<K, V>) -> Result<()> {
+        self.seq += 1;
+        self.log.append(self.seq, SystemClock.now(), &change)?;
+        if self.options.sync_writes {
+            self.log.sync()?;
+        }
+        match change {
+            Change::Set(key, value) | Change::SetExpiring(key, value, _) => self.memtable.insert(key, Some(value))?,
+            Change::Remove(key) => self.memtable.insert(key, None)?,
+        }
+        if self.memtable.bytes() >= self.options.memtable_bytes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // The newest record of the key, which must not be a remove.
+    fn find<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized, F: FnOnce(&K, &V) -> R
+    {
+        match self.memtable.get(key) {
+            Some((key, Some(value))) => return Ok(f(key, value)),
+            Some((_, None)) => return Err(Error::KeyNotFound),
+            None => (),
+        }
+        let tables = self.inner.lock().list.clone();
+        for table in tables {
+            match table.get(key)? {
+                Some((key, Some(value))) => return Ok(f(&key, &value)),
+                Some((_, None)) => return Err(Error::KeyNotFound),
+                None => (),
+            }
+        }
+        Err(Error::KeyNotFound)
+    }
+}
+
+impl<K, V> Inner<K, V> {
+    fn lock(&self) -> MutexGuard<'_, Tables<K, V>> {
+        self.tables.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Waits for the background thread to finish what it is doing; the memtable stays in its log.
+impl<K, V> Drop for Lsm<K, V> {
+    fn drop(&mut self) {
+        if let Some((sender, handle)) = self.compactor.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+// Merges all tables there are when it starts into one. Tables flushed meanwhile are newer
+// and stay in front of it. As the oldest table is part of the merge, nothing older can be
+// hiding behind a remove, so removes are dropped with the values they hide.
+fn compact<K, V>(inner: &Inner<K, V>) -> Result<()>
+    where K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned
+{
+    let _compacting = inner.compacting.lock().unwrap_or_else(|e| e.into_inner());
+    let (merging, id, codec) = {
+        let mut tables = inner.lock();
+        if tables.list.len() < 2 {
+            return Ok(());
+        }
+        tables.manifest.next_id += 1;
+        (tables.list.clone(), tables.manifest.next_id - 1, tables.manifest.codec)
+    };
+    let merged = SsTable::write(id, &table_path(&inner.dir, id), codec, Merge::new(&merging)?)?;
+    {
+        let mut tables = inner.lock();
+        let ids: HashSet<u64> = merging.iter().map(|table| table.id()).collect();
+        tables.list.retain(|table| !ids.contains(&table.id()));
+        tables.list.push(Arc::new(merged));
+        store_manifest(&inner.dir, &mut tables)?;
+    }
+    // Readers still holding a merged table keep reading it through their open handle.
+    for table in merging {
+        remove_if_exists(table.path())?;
+    }
+    Ok(())
+}
+
+type Records<'a, K, V> = Box<dyn Iterator<Item = Result<Record<K, V>>> + 'a>;
+
+// The newest record of every key across tables given newest first, skipping removes.
+struct Merge<'a, K, V> {
+    sources: Vec<Records<'a, K, V>>,
+    heads: Vec<Option<Record<K, V>>>,
+}
+
+impl<'a, K, V> Merge<'a, K, V>
+    where K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned
+{
+    fn new(tables: &'a [Arc<SsTable<K, V>>]) -> Result<Self> {
+        let mut sources: Vec<Records<'a, K, V>> = Vec::new();
+        let mut heads = Vec::new();
+        for table in tables {
+            let mut records = Box::new(table.records());
+            heads.push(records.next().transpose()?);
+            sources.push(records);
+        }
+        Ok(Self{ sources, heads })
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl<'a, K, V> Iterator for Merge<'a, K, V>
+    where K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned
+{
+    type Item = Result<Record<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // The first of equal keys is the newest.
+            let newest = (0..self.heads.len())
+                .filter(|i| self.heads[*i].is_some())
+                .min_by(|a, b| self.heads[*a].as_ref().unwrap().0.cmp(&self.heads[*b].as_ref().unwrap().0))?;
+            let record = self.heads[newest].take().unwrap();
+            for i in 0..self.heads.len() {
+                if i == newest || self.heads[i].as_ref().is_some_and(|(key, _)| *key == record.0) {
+                    if let Err(e) = self.advance(i) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+            if record.1.is_some() {
+                return Some(Ok(record));
+            }
+        }
+    }
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:08}.{}", id, TABLE_EXTENSION))
+}
+
+fn store_manifest<K, V>(dir: &Path, tables: &mut Tables<K, V>) -> Result<()> {
+    tables.manifest.tables = tables.list.iter().map(|table| table.id()).collect();
+    let tmp = dir.join(MANIFEST_TMP);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&Codec::default().with_limit(u64::MAX).serialize(&tables.manifest)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(MANIFEST))?;
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+impl<K, V> ReadEngine<K, V> for Lsm<K, V>
+    where K: Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+          V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        Lsm::get_with(self, key, f)
+    }
+}
+
+impl<K, V> WriteEngine<K, V> for Lsm<K, V>
+    where K: Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+          V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        Lsm::set(self, key, value)
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static
+    {
+        Lsm::remove(self, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use crate::engine::replay::{self, Op};
+    use super::*;
+
+    #[test]
+    fn test_lsm() -> Result<()> {
+        let dir = Path::new("data").join("test_lsm");
+        let _ = fs::remove_dir_all(&dir);
+        let options = LsmOptions{ memtable_bytes: 16 << 10, compact_at: 3, ..LsmOptions::default() };
+        let mut lsm: Lsm<u64, String> = Lsm::open(&dir, options.clone())?;
+        for i in 0..5000u64 {
+            lsm.set(i, format!("value {}", i))?;
+        }
+        for i in (0..5000u64).filter(|i| i % 3 == 0) {
+            lsm.remove(&i)?;
+        }
+        assert!(matches!(lsm.remove(&3), Err(Error::KeyNotFound)));
+        lsm.set(3, "back".to_string())?;
+        assert!(lsm.table_count() >= 1);
+        assert_eq!(lsm.get(&3)?, "back");
+        assert_eq!(lsm.get(&4)?, "value 4");
+        assert!(matches!(lsm.get(&6), Err(Error::KeyNotFound)));
+        // The unflushed part of the memtable comes back from its log.
+        lsm.set(9, "again".to_string())?;
+        drop(lsm);
+
+        let mut lsm: Lsm<u64, String> = Lsm::open(&dir, options.clone())?;
+        lsm.compact()?;
+        assert_eq!(lsm.table_count(), 1);
+        for i in 0..5000u64 {
+            let expected = match i {
+                3 => Some("back".to_string()),
+                9 => Some("again".to_string()),
+                i if i % 3 == 0 => None,
+                i => Some(format!("value {}", i)),
+            };
+            assert_eq!(lsm.get(&i).ok(), expected);
+        }
+        let files = fs::read_dir(&dir)?.filter(|entry| entry.as_ref().is_ok_and(|entry| entry.path().extension().is_some_and(|e| e == TABLE_EXTENSION))).count();
+        assert_eq!(files, 1);
+        drop(lsm);
+        assert!(matches!(Lsm::<u32, String>::open(&dir, options), Err(Error::IncompatibleFormat)));
+        Ok(())
+    }
+
+    // Overwrites and removes land in newer tables than the values they shadow, and reads
+    // have to find the newest of them, before and after compaction.
+    #[test]
+    fn test_lsm_replays_like_the_tree() -> Result<()> {
+        let dir = Path::new("data").join("test_lsm_replay");
+        let _ = fs::remove_dir_all(&dir);
+        let options = LsmOptions{ memtable_bytes: 1 << 10, compact_at: usize::MAX, ..LsmOptions::default() };
+        let mut lsm: Lsm<u64, u64> = Lsm::open(&dir, options)?;
+        let mut bptree: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_lsm_replay_tree.db"), None)?;
+
+        let mut ops: Vec<Op<u64, u64>> = (0..600).map(|i| Op::Set(i, i)).collect();
+        assert!(replay::replay(&ops, &mut lsm, &mut bptree).is_empty());
+        let tables = lsm.table_count();
+        assert!(tables >= 3);
+        ops = (0..600).step_by(2).map(|i| Op::Set(i, i + 1000)).collect();
+        ops.extend((0..600).step_by(3).map(Op::Remove));
+        // Removing those again has to find their tombstones, not the values they shadow.
+        ops.extend((0..600).step_by(9).map(Op::Remove));
+        assert!(replay::replay(&ops, &mut lsm, &mut bptree).is_empty());
+        assert!(lsm.table_count() > tables);
+
+        let gets: Vec<Op<u64, u64>> = (0..600).map(Op::Get).collect();
+        assert!(replay::replay(&gets, &mut lsm, &mut bptree).is_empty());
+        lsm.compact()?;
+        assert_eq!(lsm.table_count(), 1);
+        assert!(replay::replay(&gets, &mut lsm, &mut bptree).is_empty());
+        assert!(replay::replay(&replay::mixed_ops(3000), &mut lsm, &mut bptree).is_empty());
+        Ok(())
+    }
+}
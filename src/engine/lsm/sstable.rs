@@ -0,0 +1,133 @@
+use std::borrow::Borrow;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::path::{Path, PathBuf};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::codec::Codec;
+use crate::engine::page::{read_exact_at, DEFAULT_PAGE_SIZE};
+use crate::error::{Error, Result};
+
+// Where the index starts and how long it is, at the very end of the file.
+const FOOTER_LEN: u64 = 16;
+// Entries are cut into blocks of about this many bytes; a lookup reads one of them.
+const BLOCK_BYTES: usize = DEFAULT_PAGE_SIZE;
+
+// An entry as the memtable and the tables keep it: None marks a removed key, which has to
+// hide older values of the key until compaction drops both.
+pub(crate) type Record<K, V> = (K, Option<V>);
+
+// An immutable file of records sorted by key: the records in blocks, then an index of the
+// first key, offset and length of every block, then the footer. The index stays in memory.
+pub(crate) struct SsTable<K, V> {
+    id: u64,
+    path: PathBuf,
+    fd: File,
+    codec: Codec,
+    index: Vec<(K, u64, u64)>,
+    _marker: PhantomData<V>,
+}
+
+impl<K, V> SsTable<K, V> {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<K, V> SsTable<K, V>
+    where K: Ord + Serialize + DeserializeOwned,
+          V: Serialize + DeserializeOwned
+{
+    // Writes the records, which must be sorted by key without duplicates, to a new table.
+    pub(crate) fn write<I>(id: u64, path: &Path, codec: Codec, records: I) -> Result<Self>
+        where I: IntoIterator<Item = Result<Record<K, V>>>
+    {
+        let codec = codec.with_limit(u64::MAX);
+        let mut writer = BufWriter::new(File::create(path)?);
+        let (mut index, mut block, mut block_bytes, mut offset) = (Vec::new(), Vec::new(), 0, 0);
+        for record in records {
+            let record = record?;
+            block_bytes += codec.serialized_size(&record)? as usize;
+            block.push(record);
+            if block_bytes >= BLOCK_BYTES {
+                write_block(&mut writer, &codec, mem::take(&mut block), &mut index, &mut offset)?;
+                block_bytes = 0;
+            }
+        }
+        write_block(&mut writer, &codec, block, &mut index, &mut offset)?;
+        let index_bytes = codec.serialize(&index)?;
+        writer.write_all(&index_bytes)?;
+        writer.write_all(&offset.to_be_bytes())?;
+        writer.write_all(&(index_bytes.len() as u64).to_be_bytes())?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(Self{ id, path: path.to_path_buf(), fd: File::open(path)?, codec, index, _marker: PhantomData })
+    }
+
+    pub(crate) fn open(id: u64, path: &Path, codec: Codec) -> Result<Self> {
+        let codec = codec.with_limit(u64::MAX);
+        let fd = OpenOptions::new().read(true).open(path)?;
+        let len = fd.metadata()?.len();
+        if len < FOOTER_LEN {
+            return Err(Error::CorruptedPage);
+        }
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        read_exact_at(&fd, &mut footer, len - FOOTER_LEN)?;
+        let (start, index_len) = (u64::from_be_bytes(footer[..8].try_into().unwrap()), u64::from_be_bytes(footer[8..].try_into().unwrap()));
+        if start.checked_add(index_len) != Some(len - FOOTER_LEN) {
+            return Err(Error::CorruptedPage);
+        }
+        let mut index_bytes = vec![0u8; index_len as usize];
+        read_exact_at(&fd, &mut index_bytes, start)?;
+        let index = codec.deserialize(&index_bytes)?;
+        Ok(Self{ id, path: path.to_path_buf(), fd, codec, index, _marker: PhantomData })
+    }
+
+    // Some(None) when the table has the key as removed, None when it doesn't have it.
+    pub(crate) fn get<Q>(&self, key: &Q) -> Result<Option<Record<K, V>>>
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        let block = match self.index.partition_point(|(first, _, _)| first.borrow() <= key) {
+            0 => return Ok(None),
+            i => self.read_block(i - 1)?,
+        };
+        match block.binary_search_by(|(k, _)| k.borrow().cmp(key)) {
+            Ok(i) => Ok(block.into_iter().nth(i)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Every record in key order, a block at a time.
+    pub(crate) fn records(&self) -> impl Iterator<Item = Result<Record<K, V>>> + '_ {
+        (0..self.index.len()).flat_map(move |i| match self.read_block(i) {
+            Ok(block) => block.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        })
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<Record<K, V>>> {
+        let (_, offset, len) = &self.index[i];
+        let mut bytes = vec![0u8; *len as usize];
+        read_exact_at(&self.fd, &mut bytes, *offset)?;
+        self.codec.deserialize(&bytes)
+    }
+}
+
+fn write_block<K, V, W>(writer: &mut W, codec: &Codec, block: Vec<Record<K, V>>, index: &mut Vec<(K, u64, u64)>, offset: &mut u64) -> Result<()>
+    where K: Serialize, V: Serialize, W: Write
+{
+    if block.is_empty() {
+        return Ok(());
+    }
+    let bytes = codec.serialize(&block)?;
+    writer.write_all(&bytes)?;
+    let first = block.into_iter().next().unwrap().0;
+    index.push((first, *offset, bytes.len() as u64));
+    *offset += bytes.len() as u64;
+    Ok(())
+}
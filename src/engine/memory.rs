@@ -0,0 +1,102 @@
+use std::borrow::Borrow;
+use std::collections::btree_map::{self, BTreeMap};
+use std::hash::Hash;
+use std::iter::FromIterator;
+use std::ops::RangeBounds;
+use crate::engine::{ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+
+// An engine that keeps everything in a BTreeMap and nothing on disk: fast and gone with the
+// process. With the same ordering as the tree it also serves as the model the tree is
+// checked against in differential tests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryKVStore<K, V> {
+    inner: BTreeMap<K, V>,
+}
+
+impl<K: Ord, V> MemoryKVStore<K, V> {
+    pub fn new() -> Self {
+        Self{ inner: BTreeMap::new() }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inner.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    // Entries with keys in `range`, in key order; like the tree's range(), from either end.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> btree_map::Range<'_, K, V> {
+        self.inner.range(range)
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, K, V> {
+        self.inner.iter()
+    }
+
+    pub fn into_inner(self) -> BTreeMap<K, V> {
+        self.inner
+    }
+}
+
+impl<K: Ord, V> From<BTreeMap<K, V>> for MemoryKVStore<K, V> {
+    fn from(inner: BTreeMap<K, V>) -> Self {
+        Self{ inner }
+    }
+}
+
+impl<K: Ord, V> FromIterator<(K, V)> for MemoryKVStore<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(items: I) -> Self {
+        Self{ inner: items.into_iter().collect() }
+    }
+}
+
+impl<K: Ord, V> ReadEngine<K, V> for MemoryKVStore<K, V> {
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        self.inner.get(key).map(f).ok_or(Error::KeyNotFound)
+    }
+}
+
+impl<K: Ord, V> WriteEngine<K, V> for MemoryKVStore<K, V> {
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        self.inner.insert(key, value);
+        Ok(())
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static
+    {
+        self.inner.remove(key).map(|_| ()).ok_or(Error::KeyNotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use crate::engine::replay::{self, Op};
+    use super::*;
+
+    #[test]
+    fn test_matches_bptree() -> Result<()> {
+        let ops: Vec<Op<u64, u64>> = (0..20000u64).map(|i| match i % 7 {
+            0..=3 => Op::Set(i * 7919 % 5000, i),
+            4 | 5 => Op::Remove(i * 104729 % 5000),
+            _ => Op::Get(i * 31 % 5000),
+        }).collect();
+        let mut memory: MemoryKVStore<u64, u64> = MemoryKVStore::new();
+        let mut bptree: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_memory.db"), Some(8))?;
+        assert!(replay::replay(&ops, &mut memory, &mut bptree).is_empty());
+
+        assert_eq!(memory.len(), bptree.key_count());
+        let expected: Vec<(u64, u64)> = memory.range(1000..2000).map(|(key, value)| (*key, *value)).collect();
+        assert_eq!(bptree.range(1000..2000)?.collect::<Result<Vec<_>>>()?, expected);
+        let expected: Vec<(u64, u64)> = memory.iter().rev().map(|(key, value)| (*key, *value)).collect();
+        assert_eq!(bptree.iter_rev()?.collect::<Result<Vec<_>>>()?, expected);
+        Ok(())
+    }
+}
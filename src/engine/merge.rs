@@ -0,0 +1,123 @@
+use std::fmt::Debug;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::error::{Error, Result};
+
+// Folds an operand into the value a key has, None if it has none, and returns the value the
+// key gets: e.g. adds to a counter or appends to a list.
+pub type MergeOperator<K, V> = Box<dyn Fn(&K, Option<&V>, V) -> V + Send + Sync>;
+
+// Two descents: one finds the stored value and hands it to the operator in its decoded
+// leaf, without a copy, and the other writes the merged value back like set(), which
+// versions, quotas and the change log follow from. Writes need `&mut self`, so nothing
+// comes in between. The entry keeps the expiry it had.
+pub(crate) fn merge<K, V>(bptree: &mut BPTree<K, V>, key: K, operand: V) -> Result<u64>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let operator = bptree.merge_operator().ok_or(Error::NoMergeOperator)?;
+    let (merged, expires) = fold(bptree, &key, |old| Ok(operator(&key, old, operand)))?;
+    write_back(bptree, key, merged, expires)
+}
+
+// merge() with `f` for the operator, which may fail or leave the key as it is by returning
+// None. Returns the version written, if any.
+pub(crate) fn update<K, V, F>(bptree: &mut BPTree<K, V>, key: K, f: F) -> Result<Option<u64>>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          F: FnOnce(Option<&V>) -> Result<Option<V>>
+{
+    match fold(bptree, &key, f)? {
+        (Some(value), expires) => write_back(bptree, key, value, expires).map(Some),
+        (None, _) => Ok(None),
+    }
+}
+
+// Runs `f` on the live value of `key`, None if it has none, and returns what it made of it
+// with the expiry of the entry, 0 for none.
+fn fold<K, V, R, F>(bptree: &BPTree<K, V>, key: &K, f: F) -> Result<(R, u64)>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          F: FnOnce(Option<&V>) -> Result<R>
+{
+    let mut f = Some(f);
+    match bptree.leaf_for(key) {
+        Ok(leaf) => match leaf.entry(key, bptree.pager()) {
+            Some(entry) if !bptree.is_expired(entry) => {
+                let folded = entry.slot.with_value(bptree.pager(), |old| f.take().unwrap()(Some(old)))??;
+                return Ok((folded, entry.expires));
+            }
+            _ => (),
+        },
+        Err(Error::KeyNotFound) => (),
+        Err(e) => return Err(e),
+    }
+    Ok((f.take().unwrap()(None)?, 0))
+}
+
+fn write_back<K, V>(bptree: &mut BPTree<K, V>, key: K, value: V, expires: u64) -> Result<u64>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    match expires {
+        0 => bptree.set(key, value),
+        expires => bptree.set_expiring_at(key, value, expires),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::engine::bptree::Options;
+    use crate::engine::clock::ManualClock;
+    use super::*;
+
+    #[test]
+    fn test_merge_operator() -> Result<()> {
+        let path = Path::new("data").join("test_merge.db");
+        let mut counters: BPTree<String, u64> = BPTree::new(&path, Some(4))?;
+        assert!(matches!(counters.merge("hits".to_string(), 1), Err(Error::NoMergeOperator)));
+        counters.set_merge_operator(|_, old, operand| old.copied().unwrap_or(0) + operand);
+        for i in 0..100u64 {
+            counters.merge(format!("page-{}", i % 7), i)?;
+        }
+        assert_eq!(counters.get("page-0")?, (0..100).step_by(7).sum::<u64>());
+        assert_eq!(counters.key_count(), 7);
+
+        let path = Path::new("data").join("test_merge_lists.db");
+        let mut lists: BPTree<u64, Vec<String>> = BPTree::new(&path, Some(4))?;
+        lists.set_merge_operator(|_, old, mut operand| {
+            let mut list = old.cloned().unwrap_or_default();
+            list.append(&mut operand);
+            list
+        });
+        lists.merge(1, vec!["a".to_string()])?;
+        lists.merge(1, vec!["b".to_string(), "c".to_string()])?;
+        assert_eq!(lists.get(&1)?, ["a", "b", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_keeps_ttl() -> Result<()> {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let path = Path::new("data").join("test_merge_ttl.db");
+        let options = Options{ max_key_count: Some(4), clock: Some(clock.clone()), ..Options::default() };
+        let mut limits: BPTree<String, u64> = BPTree::with_options(&path, options)?;
+        limits.set_merge_operator(|_, old, operand| old.copied().unwrap_or(0) + operand);
+        limits.set_with_ttl("client-1".to_string(), 1, Duration::from_secs(60))?;
+        limits.merge("client-1".to_string(), 1)?;
+        clock.advance(Duration::from_secs(30));
+        limits.merge("client-1".to_string(), 1)?;
+        assert_eq!(limits.get("client-1")?, 3);
+
+        clock.advance(Duration::from_secs(30));
+        assert!(matches!(limits.get("client-1"), Err(Error::KeyNotFound)));
+        // An expired counter starts over, without a TTL.
+        limits.merge("client-1".to_string(), 1)?;
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(limits.get("client-1")?, 1);
+        Ok(())
+    }
+}
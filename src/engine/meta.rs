@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use crate::engine::btnode::{NODE_TYPE_OFFSET, PAGE_PTR_OFFSET};
+use crate::engine::cipher::SALT_LEN;
+use crate::engine::codec::Codec;
+use crate::engine::compression::Compression;
+use crate::engine::overflow::{self, OverflowRef};
+use crate::engine::page::{PagePtr, Pager};
+use crate::error::{Error, Result};
+
+// Page 0 of every tree file describes the rest of it. Its payload is always encoded with the
+// default codec, so it can be read before knowing which codec the tree itself was made with.
+pub const META_PAGE: PagePtr = 0;
+pub const META_NODE_TYPE: u8 = 3;
+pub const FORMAT_VERSION: u32 = 9;
+
+const MAGIC: &[u8; 8] = b"KVSTORE\0";
+const MAGIC_OFFSET: usize = NODE_TYPE_OFFSET + 1;
+const META_LEN_OFFSET: usize = MAGIC_OFFSET + MAGIC.len();
+const META_OFFSET: usize = META_LEN_OFFSET + 8;
+// Free pages listed on the meta page itself; a longer list goes to an overflow chain.
+const INLINE_FREE_PAGES: usize = 256;
+
+// What open() needs to pick a tree up where the last flush left it: the settings that
+// shape the file and the tree's state at the time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Meta {
+    pub(crate) version: u32,
+    pub(crate) page_size: u64,
+    pub(crate) codec: Codec,
+    pub(crate) compression: Compression,
+    pub(crate) comparator: Option<String>,
+    pub(crate) key_size: u64,
+    pub(crate) value_size: u64,
+    pub(crate) max_key_count: u64,
+    pub(crate) inline_threshold: u64,
+    pub(crate) retention_ms: Option<u64>,
+    pub(crate) keep_empty_root: bool,
+    pub(crate) dedup_values: bool,
+    pub(crate) write_once: bool,
+    pub(crate) root_ptr: Option<PagePtr>,
+    pub(crate) page_count: u64,
+    pub(crate) seq: u64,
+    pub(crate) key_count: u64,
+    // The root and key count of every column family but the default one, by name.
+    pub(crate) families: Vec<(String, Option<PagePtr>, u64)>,
+    // How far a master key rotation of the values got, encoded by envelope::Encrypted.
+    pub(crate) rotation: Option<Vec<u8>>,
+    pub(crate) free_pages: Vec<PagePtr>,
+    pub(crate) free_chain: Option<OverflowRef>,
+}
+
+// Writes the meta page with `free` as the free list. When the list is too long for the
+// page, its tail is written to a chain on pages taken off the list itself; those pages are
+// returned and must not be handed out until the next store() has replaced the chain.
+pub(crate) fn store(meta: &mut Meta, free: &mut Vec<PagePtr>, pager: &Pager) -> Result<Vec<PagePtr>> {
+    let codec = Codec::default().with_limit(u64::MAX);
+    let mut chain_pages = Vec::new();
+    meta.free_chain = None;
+    if free.len() > INLINE_FREE_PAGES {
+        let mut needed = 1;
+        while overflow::pages_needed(8 + 8 * (free.len() - INLINE_FREE_PAGES - needed), pager) > needed {
+            needed += 1;
+        }
+        chain_pages = free.split_off(free.len() - needed);
+        let spilled = codec.serialize(&free[INLINE_FREE_PAGES..])?;
+        meta.free_chain = Some(overflow::write_chain(&spilled, &chain_pages, pager)?);
+    }
+    meta.free_pages = free[..free.len().min(INLINE_FREE_PAGES)].to_vec();
+
+    let body = codec.serialize(&*meta)?;
+    let mut page = pager.new_page();
+    if META_OFFSET + body.len() > meta_end(page.data_size()) {
+        return Err(Error::PageSizeNotEnough);
+    }
+    page.write_bytes_at_offset(PAGE_PTR_OFFSET, &META_PAGE.to_be_bytes())?;
+    page.write_bytes_at_offset(NODE_TYPE_OFFSET, &[META_NODE_TYPE])?;
+    page.write_bytes_at_offset(MAGIC_OFFSET, MAGIC)?;
+    page.write_bytes_at_offset(META_LEN_OFFSET, &(body.len() as u64).to_be_bytes())?;
+    page.write_bytes_at_offset(META_OFFSET, &body)?;
+    pager.write_page(META_PAGE, &page)?;
+    meta.free_pages.clear();
+    Ok(chain_pages)
+}
+
+// Reads the meta page back, with the whole free list in `free_pages`.
+pub(crate) fn load(pager: &Pager) -> Result<Meta> {
+    let page = match pager.load_page(META_PAGE) {
+        Err(Error::PageNotFound) => return Err(Error::NotADatabase),
+        page => page?,
+    };
+    if page.get_page_byte(NODE_TYPE_OFFSET) != META_NODE_TYPE || page.get_bytes_from_offset(MAGIC_OFFSET, MAGIC.len())? != MAGIC {
+        return Err(Error::NotADatabase);
+    }
+    let codec = Codec::default().with_limit(u64::MAX);
+    let len: u64 = codec.deserialize(page.get_bytes_from_offset(META_LEN_OFFSET, 8)?)?;
+    if META_OFFSET as u64 + len > meta_end(page.data_size()) as u64 {
+        return Err(Error::CorruptedPage);
+    }
+    let mut meta: Meta = codec.deserialize(page.get_bytes_from_offset(META_OFFSET, len as usize)?)?;
+    if meta.version != FORMAT_VERSION {
+        return Err(Error::IncompatibleFormat);
+    }
+    if meta.page_size != page.size() as u64 {
+        return Err(Error::CorruptedPage);
+    }
+    if let Some(chain) = &meta.free_chain {
+        let spilled: Vec<PagePtr> = codec.deserialize(&overflow::read_chain(chain, pager)?)?;
+        meta.free_pages.extend(spilled);
+    }
+    Ok(meta)
+}
+
+// The room the meta takes up, short of the salt an encrypted tree keeps on page 0.
+fn meta_end(data_size: usize) -> usize {
+    data_size - SALT_LEN
+}
+
+// The pages of the chain a loaded meta keeps its free list on.
+pub(crate) fn chain_pages(meta: &Meta, pager: &Pager) -> Result<Vec<PagePtr>> {
+    match &meta.free_chain {
+        Some(chain) => overflow::chain_pages(chain, pager),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::allocation::PageKind;
+    use crate::engine::bptree::{BPTree, Options};
+    use super::*;
+
+    #[test]
+    fn test_open_restores_last_flush() -> Result<()> {
+        let path = Path::new("data").join("test_open.db");
+        let options = Options{ max_key_count: Some(4), inline_threshold: Some(64), ..Options::default() };
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(&path, options)?;
+        for i in 0..2000u64 {
+            bptree.set(i, vec![i as u8; if i % 100 == 0 { 5000 } else { 8 }])?;
+        }
+        // Enough removes to spill the free list off the meta page.
+        for i in (0..2000u64).filter(|i| i % 3 != 0) {
+            bptree.remove(&i)?;
+        }
+        let (root, pages, seq) = (bptree.root_ptr(), bptree.page_count(), bptree.last_seq());
+        let map = bptree.allocation_map()?;
+        assert!(map.count(PageKind::Free) > INLINE_FREE_PAGES as u64);
+        bptree.close()?;
+
+        let bptree: BPTree<u64, Vec<u8>> = BPTree::open(&path)?;
+        assert_eq!((bptree.root_ptr(), bptree.page_count(), bptree.last_seq()), (root, pages, seq));
+        assert_eq!(bptree.max_key_count(), 4);
+        assert_eq!(bptree.inline_threshold(), 64);
+        assert_eq!(bptree.key_count(), 667);
+        assert_eq!(bptree.get(&300)?, vec![44u8; 5000]);
+        assert!(matches!(bptree.get(&100), Err(Error::KeyNotFound)));
+        assert_eq!(bptree.iter_snapshot()?.count(), 667);
+        assert!(bptree.verify(|_| ())?.corrupt.is_empty());
+        // The pages holding the spilled part of the free list are off it while they do.
+        let reopened = bptree.allocation_map()?;
+        assert_eq!(reopened.count(PageKind::Meta), 1);
+        assert_eq!(reopened.count(PageKind::Free) + reopened.count(PageKind::Overflow), map.count(PageKind::Free) + map.count(PageKind::Overflow));
+        drop(bptree);
+
+        assert!(matches!(BPTree::<u32, Vec<u8>>::open(&path), Err(Error::IncompatibleFormat)));
+        assert!(matches!(BPTree::<u64, u64>::open(Path::new("data").join("test_open_missing.db")), Err(Error::IOError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_size_is_kept() -> Result<()> {
+        let path = Path::new("data").join("test_page_size.db");
+        let options = |page_size| Options{ page_size: Some(page_size), ..Options::default() };
+        assert!(matches!(BPTree::<u64, u64>::with_options(&path, options(5000)), Err(Error::UnsupportedPageSize(5000))));
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(&path, options(16384))?;
+        assert_eq!(bptree.inline_threshold(), 4096);
+        // Values a 4 KiB page would send to overflow pages stay inline.
+        for i in 0..300u64 {
+            bptree.set(i, vec![i as u8; if i == 7 { 40_000 } else { 3000 }])?;
+        }
+        let map = bptree.allocation_map()?;
+        assert_eq!(map.count(PageKind::Overflow), 3);
+        assert_eq!(map.pages.len() as u64 * 16384, std::fs::metadata(&path)?.len());
+        bptree.close()?;
+
+        let bptree: BPTree<u64, Vec<u8>> = BPTree::open(&path)?;
+        assert_eq!(bptree.page_size(), 16384);
+        assert_eq!(bptree.get(&7)?, vec![7u8; 40_000]);
+        assert_eq!(bptree.get(&299)?, vec![43u8; 3000]);
+        assert!(bptree.verify(|_| ())?.is_ok());
+        drop(bptree);
+        assert!(matches!(BPTree::<u64, Vec<u8>>::open_with_options(&path, options(4096)), Err(Error::PageSizeMismatch(16384))));
+
+        // Page 0 of an encrypted tree is sealed, so its size is found from the checksum.
+        let encrypted = Options{ passphrase: Some("page size".into()), write_ahead_log: true, ..options(65536) };
+        let mut bptree: BPTree<u64, String> = BPTree::with_options(&path, encrypted.clone())?;
+        for i in 0..100u64 {
+            bptree.set(i, format!("value-{}", i))?;
+        }
+        drop(bptree);
+        let bptree: BPTree<u64, String> = BPTree::open_with_options(&path, Options{ page_size: None, ..encrypted })?;
+        assert_eq!(bptree.page_size(), 65536);
+        assert_eq!(bptree.get(&99)?, "value-99");
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt::Debug;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::allocation::PageKind;
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::{self, LeafNode, Node};
+use crate::error::Result;
+
+// What a tree looks like on disk, and what it has done since it was opened, for monitoring.
+// Unlike the Stats analyze() keeps for range estimates, nothing here is sampled: the page
+// counts come from the allocation map and the fill factor from every leaf.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    // Levels from the root down to the leaves, 0 for an empty tree.
+    pub height: u64,
+    pub inner_pages: u64,
+    pub leaf_pages: u64,
+    pub overflow_pages: u64,
+    pub free_pages: u64,
+    pub file_bytes: u64,
+    // Bytes the entries of the leaves take up, over the room the leaves have for them.
+    pub fill_factor: f64,
+    pub page_reads: u64,
+    pub page_writes: u64,
+    pub splits: u64,
+    pub merges: u64,
+}
+
+// The counters are read first, so the pages read here don't show up in them.
+pub(crate) fn collect<K, V>(bptree: &BPTree<K, V>) -> Result<Metrics>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let pager = bptree.pager();
+    let (page_reads, page_writes) = (pager.page_reads(), pager.page_writes());
+    let (splits, merges) = bptree.splits_and_merges();
+    let map = bptree.allocation_map()?;
+
+    let mut height = 0;
+    let mut next = bptree.root_ptr();
+    while let Some(ptr) = next {
+        height += 1;
+        next = match Node::<K, V>::load_node(ptr, pager)? {
+            Node::Inner(inner) => Some(inner.childptrs()[0]),
+            Node::Leaf(_) => None,
+        };
+    }
+
+    // Every family's leaves, along their leaf chains.
+    let codec = pager.codec().with_limit(u64::MAX);
+    let (mut leaves, mut used) = (0u64, 0u64);
+    for root in bptree.family_roots() {
+        let mut next = Some(root);
+        while let Some(Node::Inner(inner)) = next.map(|ptr| Node::<K, V>::load_node(ptr, pager)).transpose()? {
+            next = Some(inner.childptrs()[0]);
+        }
+        while let Some(ptr) = next {
+            let leaf = LeafNode::<K, V>::load(ptr, pager)?;
+            for (key, entry) in leaf.keys().iter().zip(leaf.entries()) {
+                used += btnode::entry_size(key, &entry.slot, &codec)?;
+            }
+            leaves += 1;
+            next = leaf.next();
+        }
+    }
+
+    Ok(Metrics{
+        height,
+        inner_pages: map.count(PageKind::Inner),
+        leaf_pages: map.count(PageKind::Leaf),
+        overflow_pages: map.count(PageKind::Overflow),
+        free_pages: bptree.free_page_count(),
+        file_bytes: pager.file_len()?,
+        fill_factor: used as f64 / (leaves.max(1) * btnode::node_capacity(pager.page_size())) as f64,
+        page_reads,
+        page_writes,
+        splits,
+        merges,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_metrics() -> Result<()> {
+        let path = Path::new("data").join("test_metrics.db");
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(&path, Some(4))?;
+        let empty = bptree.metrics()?;
+        assert_eq!((empty.height, empty.leaf_pages, empty.splits), (0, 0, 0));
+
+        for i in 0..200u64 {
+            let len = if i == 7 { 10_000 } else { 16 };
+            bptree.set(i, vec![i as u8; len])?;
+        }
+        let grown = bptree.metrics()?;
+        assert!(grown.height >= 4);
+        assert!(grown.splits >= grown.leaf_pages - 1);
+        assert_eq!(grown.overflow_pages, 3);
+        assert!(grown.fill_factor > 0.0 && grown.fill_factor < 1.0);
+        assert!(grown.page_writes > grown.leaf_pages + grown.inner_pages);
+        assert_eq!(grown.file_bytes, bptree.page_count() * bptree.page_size() as u64);
+
+        for i in 0..150u64 {
+            bptree.remove(&i)?;
+        }
+        let shrunk = bptree.metrics()?;
+        assert!(shrunk.merges > 0);
+        assert!(shrunk.free_pages > 0);
+        assert!(shrunk.leaf_pages < grown.leaf_pages);
+        assert!(shrunk.page_reads >= grown.page_reads);
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io;
+use std::sync::RwLock;
+use memmap2::Mmap;
+
+// A read-only map of a tree file. Writes go through the file and show up in the map, since
+// both are backed by the same OS pages; pages written past its end are picked up by mapping
+// the file again the first time one of them is read. The file must not shrink while it is
+// mapped: the tree never truncates a file it has open, but another process doing so would
+// make reads fault.
+#[derive(Debug, Default)]
+pub(crate) struct MappedFile {
+    map: RwLock<Option<Mmap>>,
+}
+
+impl MappedFile {
+    // Copies the bytes at `offset` into `out`, or returns false if the file ends before them.
+    pub(crate) fn read(&self, fd: &File, offset: u64, out: &mut [u8]) -> io::Result<bool> {
+        let end = offset + out.len() as u64;
+        {
+            let map = self.map.read().unwrap();
+            if let Some(map) = map.as_ref().filter(|map| map.len() as u64 >= end) {
+                out.copy_from_slice(&map[offset as usize..end as usize]);
+                return Ok(true);
+            }
+        }
+        let mut map = self.map.write().unwrap();
+        if map.as_ref().is_none_or(|map| (map.len() as u64) < end) {
+            if fd.metadata()?.len() < end {
+                return Ok(false);
+            }
+            // Safe as long as the file isn't truncated under the map, see above.
+            *map = Some(unsafe { Mmap::map(fd)? });
+        }
+        out.copy_from_slice(&map.as_ref().unwrap()[offset as usize..end as usize]);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use crate::error::{Error, Result};
+
+    #[test]
+    fn test_mmap_reads_follow_writes() -> Result<()> {
+        let path = Path::new("data").join("test_mmap.db");
+        let options = Options{ max_key_count: Some(4), mmap: true, ..Options::default() };
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(&path, options.clone())?;
+        for i in 0..200u64 {
+            bptree.set(i, vec![i as u8; if i % 50 == 0 { 6000 } else { 16 }])?;
+            assert_eq!(bptree.get(&i)?.len(), if i % 50 == 0 { 6000 } else { 16 });
+        }
+        // Overwrites land in pages that are already mapped.
+        for i in 0..100u64 {
+            bptree.set(i, vec![1; 16])?;
+        }
+        bptree.remove(&150)?;
+        assert_eq!(bptree.get(&10)?, vec![1; 16]);
+        assert_eq!(bptree.get(&199)?, vec![199; 16]);
+        assert!(bptree.verify(|_| ())?.corrupt.is_empty());
+        bptree.close()?;
+
+        let bptree: BPTree<u64, Vec<u8>> = BPTree::open_with_options(&path, options)?;
+        assert_eq!(bptree.scan_all()?.count(), 199);
+        assert_eq!(bptree.get(&100)?, vec![100; 6000]);
+        assert!(matches!(bptree.get(&150), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+}
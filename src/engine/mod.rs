@@ -1,13 +1,89 @@
-mod array;
-mod bptree;
-mod btnode;
-mod page;
+pub mod allocation;
+pub mod array;
+pub mod batch;
+pub mod bptree;
+pub mod btnode;
+pub mod cache;
+pub mod cipher;
+pub mod clock;
+pub mod codec;
+pub mod compaction;
+pub mod comparator;
+pub mod compression;
+pub mod cursor;
+pub mod dump;
+pub mod export;
+pub mod family;
+pub mod hash;
+pub mod heat;
+pub mod iter;
+pub mod journal;
+pub mod limits;
+pub mod lsm;
+pub mod memory;
+pub mod merge;
+pub mod meta;
+pub mod metrics;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod overflow;
+pub mod page;
+pub mod prefix;
+pub mod pressure;
+pub mod quarantine;
+pub mod quota;
+pub mod replay;
+pub mod search;
+pub mod stats;
+pub mod transform;
+pub mod vacuum;
+pub mod verify;
+pub mod wal;
+pub mod zone;
 
+use std::borrow::Borrow;
+use std::hash::Hash;
 use crate::error::Result;
 
-pub trait KVStoreEngine<K,V> {
-    fn get(&self, key: &K) -> Result<V>;
+pub trait ReadEngine<K,V> {
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static, F: FnOnce(&V) -> R;
+
+    fn get<Q>(&self, key: &Q) -> Result<V>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static, V: Clone
+    {
+        self.get_with(key, V::clone)
+    }
+}
+
+pub trait WriteEngine<K,V> {
     fn set(&mut self, key: K, value: V) -> Result<()>;
-    fn remove(&mut self, key: &K) -> Result<()>;
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static;
+}
+
+pub trait KVStoreEngine<K,V>: ReadEngine<K,V> + WriteEngine<K,V> {}
+
+impl<K, V, E: ReadEngine<K,V> + WriteEngine<K,V>> KVStoreEngine<K,V> for E {}
+
+// Exposes only the read half of an engine, e.g. to hand a store to code that must not write.
+pub struct ReadOnly<E>(E);
+
+impl<E> ReadOnly<E> {
+    pub fn new(engine: E) -> Self {
+        Self(engine)
+    }
+
+    pub fn into_inner(self) -> E {
+        self.0
+    }
+}
+
+impl<K, V, E: ReadEngine<K,V>> ReadEngine<K,V> for ReadOnly<E> {
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        self.0.get_with(key, f)
+    }
 }
 
@@ -0,0 +1,393 @@
+use std::convert::TryInto;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::mem;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::Slot;
+use crate::engine::btnode::{HAS_NEXT_OFFSET, NEXT_PAGE_PTR_OFFSET, NODE_TYPE_OFFSET, PAGE_PTR_LEN, PAGE_PTR_OFFSET};
+use crate::engine::page::{self, Page, Pager, PagePtr};
+use crate::error::{Error, Result};
+
+pub const OVERFLOW_NODE_TYPE: u8 = 2;
+
+const CHUNK_LEN: usize = 8;
+const CHUNK_LEN_OFFSET: usize = NEXT_PAGE_PTR_OFFSET + PAGE_PTR_LEN;//18
+const CHUNK_DATA_OFFSET: usize = CHUNK_LEN_OFFSET + CHUNK_LEN;//26
+
+pub fn chunk_capacity(pager: &Pager) -> usize {
+    page::data_size(pager.page_size()) - CHUNK_DATA_OFFSET
+}
+
+// By default values larger than this are written to a chain of overflow pages and the leaf
+// only keeps a reference to the head of the chain. Options::inline_threshold changes it, up
+// to inline_threshold_limit() so that a leaf still holds at least two values.
+pub fn max_inline_value(page_size: usize) -> usize {
+    page_size / 4
+}
+
+pub fn inline_threshold_limit(page_size: usize) -> usize {
+    page_size / 2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverflowRef {
+    head: PagePtr,
+    len: u64,
+}
+
+impl OverflowRef {
+    pub fn head(&self) -> PagePtr {
+        self.head
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn moved_to(&self, head: PagePtr) -> Self {
+        Self{ head, len: self.len }
+    }
+}
+
+pub fn pages_needed(len: usize, pager: &Pager) -> usize {
+    len.div_ceil(chunk_capacity(pager)).max(1)
+}
+
+// Writes `bytes` across `ptrs`, one chunk per page, each page linking to the next.
+pub fn write_chain(bytes: &[u8], ptrs: &[PagePtr], pager: &Pager) -> Result<OverflowRef> {
+    assert_eq!(ptrs.len(), pages_needed(bytes.len(), pager));
+    let mut chunks = bytes.chunks(chunk_capacity(pager));
+    for (i, ptr) in ptrs.iter().enumerate() {
+        write_chunk(*ptr, ptrs.get(i + 1).copied(), chunks.next().unwrap_or(&[]), pager)?;
+    }
+    Ok(OverflowRef{
+        head: ptrs[0],
+        len: bytes.len() as u64,
+    })
+}
+
+fn write_chunk(ptr: PagePtr, next: Option<PagePtr>, chunk: &[u8], pager: &Pager) -> Result<()> {
+    let mut page = pager.new_page();
+    let bytes = page.bytes_mut();
+    bytes[PAGE_PTR_OFFSET..PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&ptr.to_be_bytes());
+    bytes[NODE_TYPE_OFFSET] = OVERFLOW_NODE_TYPE;
+    if let Some(next) = next {
+        bytes[HAS_NEXT_OFFSET] = 1;
+        bytes[NEXT_PAGE_PTR_OFFSET..NEXT_PAGE_PTR_OFFSET + PAGE_PTR_LEN].clone_from_slice(&next.to_be_bytes());
+    }
+    bytes[CHUNK_LEN_OFFSET..CHUNK_LEN_OFFSET + CHUNK_LEN].clone_from_slice(&(chunk.len() as u64).to_be_bytes());
+    bytes[CHUNK_DATA_OFFSET..CHUNK_DATA_OFFSET + chunk.len()].clone_from_slice(chunk);
+    pager.write_page(ptr, &page)
+}
+
+pub fn read_chain(overflow: &OverflowRef, pager: &Pager) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(overflow.len as usize);
+    OverflowReader::new(overflow, pager).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != overflow.len {
+        return Err(Error::CorruptedPage);
+    }
+    Ok(bytes)
+}
+
+pub type ValueDigest = [u8; 32];
+
+pub fn digest_chain(overflow: &OverflowRef, pager: &Pager) -> Result<ValueDigest> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut OverflowReader::new(overflow, pager), &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+// Reference counts for overflow chains shared by identical values. A chain is never
+// rewritten in place: changing a value always writes a new chain (or takes a reference to
+// an existing one) and releases the old one, so sharing is copy-on-write.
+#[derive(Debug, Default)]
+pub(crate) struct SharedValues {
+    by_digest: HashMap<ValueDigest, OverflowRef>,
+    refs: HashMap<PagePtr, (ValueDigest, u64)>,
+}
+
+impl SharedValues {
+    pub fn acquire(&mut self, digest: &ValueDigest) -> Option<OverflowRef> {
+        let overflow = *self.by_digest.get(digest)?;
+        self.refs.get_mut(&overflow.head).unwrap().1 += 1;
+        Some(overflow)
+    }
+
+    pub fn insert(&mut self, digest: ValueDigest, overflow: OverflowRef) {
+        self.by_digest.insert(digest, overflow);
+        self.refs.insert(overflow.head, (digest, 1));
+    }
+
+    // Counts one more key referring to the chain, e.g. when the counts are rebuilt on open.
+    pub(crate) fn add_ref(&mut self, overflow: OverflowRef, pager: &Pager) -> Result<()> {
+        match self.refs.get_mut(&overflow.head) {
+            Some((_, refs)) => *refs += 1,
+            None => self.insert(digest_chain(&overflow, pager)?, overflow),
+        }
+        Ok(())
+    }
+
+    // Drops one reference and returns whether the chain's pages can be freed.
+    pub fn release(&mut self, head: PagePtr) -> bool {
+        match self.refs.get_mut(&head) {
+            None => true,
+            Some((_, refs)) if *refs > 1 => {
+                *refs -= 1;
+                false
+            }
+            Some((digest, _)) => {
+                self.by_digest.remove(digest);
+                self.refs.remove(&head);
+                true
+            }
+        }
+    }
+}
+
+// Page numbers of every page in the chain, so they can be handed back to the free list.
+pub fn chain_pages(overflow: &OverflowRef, pager: &Pager) -> Result<Vec<PagePtr>> {
+    let mut pages = Vec::new();
+    let mut next = Some(overflow.head);
+    while let Some(ptr) = next {
+        let (_, link) = load_chunk(ptr, pager)?;
+        pages.push(ptr);
+        next = link;
+    }
+    Ok(pages)
+}
+
+fn load_chunk(ptr: PagePtr, pager: &Pager) -> Result<(Page, Option<PagePtr>)> {
+    let page = pager.load_page(ptr)?;
+    let next = next_chunk(&page)?;
+    Ok((page, next))
+}
+
+// Rewrites the page numbers a chunk carries: its own and the next page's.
+pub(crate) fn renumber_chunk(page: &mut Page, ptr: PagePtr, next: Option<PagePtr>) -> Result<()> {
+    page.write_bytes_at_offset(PAGE_PTR_OFFSET, &ptr.to_be_bytes())?;
+    if let Some(next) = next {
+        page.write_bytes_at_offset(NEXT_PAGE_PTR_OFFSET, &next.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+// The page after this one in its chain.
+pub(crate) fn next_chunk(page: &Page) -> Result<Option<PagePtr>> {
+    if page.get_page_byte(NODE_TYPE_OFFSET) != OVERFLOW_NODE_TYPE {
+        return Err(Error::CorruptedPage);
+    }
+    match page.get_page_byte(HAS_NEXT_OFFSET) {
+        0 => Ok(None),
+        _ => Ok(Some(u64::from_be_bytes(page.get_bytes_from_offset(NEXT_PAGE_PTR_OFFSET, PAGE_PTR_LEN)?.try_into().unwrap()))),
+    }
+}
+
+// Streams an overflow chain one page at a time, so at most a single page of the value
+// is held in memory regardless of how large the value is.
+pub struct OverflowReader<'a> {
+    pager: &'a Pager,
+    next: Option<PagePtr>,
+    page: Option<Page>,
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> OverflowReader<'a> {
+    pub fn new(overflow: &OverflowRef, pager: &'a Pager) -> Self {
+        Self{
+            pager,
+            next: Some(overflow.head),
+            page: None,
+            pos: 0,
+            end: 0,
+        }
+    }
+
+    fn load_next(&mut self) -> Result<bool> {
+        let ptr = match self.next {
+            Some(ptr) => ptr,
+            None => return Ok(false),
+        };
+        let (page, next) = load_chunk(ptr, self.pager)?;
+        let chunk_len = u64::from_be_bytes(page.get_bytes_from_offset(CHUNK_LEN_OFFSET, CHUNK_LEN)?.try_into().unwrap()) as usize;
+        if chunk_len > chunk_capacity(self.pager) {
+            return Err(Error::CorruptedPage);
+        }
+        self.next = next;
+        self.page = Some(page);
+        self.pos = CHUNK_DATA_OFFSET;
+        self.end = CHUNK_DATA_OFFSET + chunk_len;
+        Ok(true)
+    }
+}
+
+impl<'a> Read for OverflowReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.end {
+            match self.load_next() {
+                Ok(true) => {},
+                Ok(false) => return Ok(0),
+                Err(Error::IOError(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+        let page = self.page.as_ref().unwrap();
+        let n = buf.len().min(self.end - self.pos);
+        buf[..n].clone_from_slice(page.get_bytes_from_offset(self.pos, n).unwrap());
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+pub enum ValueReader<'a> {
+    Inline(io::Cursor<Vec<u8>>),
+    Overflow(OverflowReader<'a>),
+}
+
+impl<'a> Read for ValueReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::Inline(cursor) => cursor.read(buf),
+            ValueReader::Overflow(reader) => reader.read(buf),
+        }
+    }
+}
+
+// Byte values are encoded as a u64 length followed by the bytes.
+const LEN_PREFIX: usize = 8;
+
+// Streams a byte value into overflow pages as it is written. Nothing is visible in the
+// tree until `commit`; dropping the writer instead gives its pages back.
+pub struct ValueWriter<'a, K>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    bptree: &'a mut BPTree<K, Vec<u8>>,
+    key: Option<K>,
+    // Bytes not yet written to a page. Until the value spills, this is the whole value.
+    buf: Vec<u8>,
+    // Pages of the chain so far; the last one is the page `buf` will be written to.
+    pages: Vec<PagePtr>,
+    len: u64,
+}
+
+impl<'a, K> ValueWriter<'a, K>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    pub(crate) fn new(bptree: &'a mut BPTree<K, Vec<u8>>, key: K, len_hint: u64) -> Result<Self> {
+        let inline_threshold = bptree.inline_threshold();
+        let mut writer = Self{
+            bptree,
+            key: Some(key),
+            buf: Vec::with_capacity((len_hint as usize).min(inline_threshold)),
+            pages: Vec::new(),
+            len: 0,
+        };
+        if len_hint > inline_threshold as u64 {
+            writer.spill()?;
+        }
+        Ok(writer)
+    }
+
+    fn spilled(&self) -> bool {
+        !self.pages.is_empty()
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let ptr = self.bptree.next_page_ptr();
+        self.pages.push(ptr);
+        // The real length is patched in on commit.
+        let mut buf = Vec::with_capacity(chunk_capacity(self.bptree.pager()));
+        buf.extend_from_slice(&[0u8; LEN_PREFIX]);
+        buf.extend_from_slice(&self.buf);
+        self.buf = buf;
+        self.flush_full_chunks()
+    }
+
+    fn flush_full_chunks(&mut self) -> Result<()> {
+        let capacity = chunk_capacity(self.bptree.pager());
+        while self.buf.len() > capacity {
+            let ptr = *self.pages.last().unwrap();
+            let next = self.bptree.next_page_ptr();
+            write_chunk(ptr, Some(next), &self.buf[..capacity], self.bptree.get_pager())?;
+            self.buf.drain(..capacity);
+            self.pages.push(next);
+        }
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<u64> {
+        let key = self.key.take().unwrap();
+        if !self.spilled() {
+            let value = mem::take(&mut self.buf);
+            return self.bptree.set(key, value);
+        }
+        let prefix = self.bptree.pager().codec().serialize(&self.len)?;
+        let head = self.pages[0];
+        if self.pages.len() == 1 {
+            self.buf[..LEN_PREFIX].clone_from_slice(&prefix);
+        }
+        write_chunk(*self.pages.last().unwrap(), None, &self.buf, self.bptree.get_pager())?;
+        if self.pages.len() > 1 {
+            let mut page = self.bptree.pager().load_page(head)?;
+            page.write_bytes_at_offset(CHUNK_DATA_OFFSET, &prefix)?;
+            self.bptree.get_pager().write_page(head, &page)?;
+        }
+        let mut overflow = OverflowRef{
+            head,
+            len: LEN_PREFIX as u64 + self.len,
+        };
+        if self.bptree.shared_values().is_some() {
+            let digest = digest_chain(&overflow, self.bptree.pager())?;
+            let shared = self.bptree.shared_values().unwrap();
+            match shared.acquire(&digest) {
+                // Identical value already stored; our own chain is freed on drop.
+                Some(existing) => overflow = existing,
+                None => shared.insert(digest, overflow),
+            }
+        }
+        if overflow.head == head {
+            self.pages.clear();
+        }
+        self.bptree.set_slot(key, Slot::Overflow(overflow))
+    }
+}
+
+impl<'a, K> Write for ValueWriter<'a, K>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+        self.len += bytes.len() as u64;
+        let result = match self.spilled() {
+            true => self.flush_full_chunks(),
+            false if self.buf.len() > self.bptree.inline_threshold() => self.spill(),
+            false => Ok(()),
+        };
+        match result {
+            Ok(()) => Ok(bytes.len()),
+            Err(Error::IOError(e)) => Err(e),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, K> Drop for ValueWriter<'a, K>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn drop(&mut self) {
+        for ptr in self.pages.drain(..) {
+            self.bptree.delete_page(ptr);
+        }
+    }
+}
@@ -1,126 +1,563 @@
-#[allow(dead_code)]
-
-use std::path::{PathBuf, Path};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::engine::cache::{Admission, CacheStats, PageCache};
+use crate::engine::cipher::{PageCipher, CIPHER_HEADER_LEN};
+use crate::engine::pressure::MemoryPressure;
+use crate::engine::codec::Codec;
+use crate::engine::comparator::{Comparator, KeyOrder};
+use crate::engine::compression::Compression;
+#[cfg(feature = "mmap")]
+use crate::engine::mmap::MappedFile;
 use crate::error::{Result, Error};
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Read, Write};
+use std::io::{self, Seek, SeekFrom};
 
 pub type PagePtr = u64;
-pub const PAGE_SIZE: usize = 4096;
+// The page size of a tree unless its Options give another; every page of a file has the
+// size the file was created with.
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+pub const MIN_PAGE_SIZE: usize = 4096;
+pub const MAX_PAGE_SIZE: usize = 65536;
+// The last bytes of every page hold a CRC32 of the rest, stamped by the pager when the page
+// is written and checked whenever it is read back from the file.
+pub const CHECKSUM_LEN: usize = 4;
 
-pub fn max_key_count(size_key: u64, size_value: u64) -> u64 {
-    (PAGE_SIZE as u64 - size_value - 34) / (size_key + size_value)
+// Page sizes are powers of two from MIN_PAGE_SIZE to MAX_PAGE_SIZE.
+pub fn check_page_size(page_size: usize) -> Result<()> {
+    match page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        true => Ok(()),
+        false => Err(Error::UnsupportedPageSize(page_size)),
+    }
+}
+
+// The bytes of a page nodes can use: short of the checksum, and of the header an encrypted
+// tree seals pages with.
+pub const fn data_size(page_size: usize) -> usize {
+    page_size - CHECKSUM_LEN - CIPHER_HEADER_LEN
+}
+
+// Entries that fit a page next to the node header, the length prefixes and an inner node's
+// fences. Every leaf entry also carries its 8-byte version; callers count a write time, if
+// any, as part of the value. Entries set with a TTL may still split leaves early.
+pub fn max_key_count(page_size: usize, size_key: u64, size_value: u64) -> u64 {
+    (data_size(page_size) as u64 - 108 - 2 * size_key) / (size_key + size_value + 8)
 }
 
 pub fn split_at(max_key_count: u64) -> usize {
     ((max_key_count / 2) + (max_key_count % 2)) as usize
 }
 
+// Page buffers go back to a small per-thread pool when a page is dropped, so loading and
+// storing nodes doesn't allocate a page every time.
+const POOLED_BUFFERS: usize = 64;
+
+thread_local! {
+    static BUFFERS: RefCell<Vec<Box<[u8]>>> = const { RefCell::new(Vec::new()) };
+}
+
 pub struct Page{
-    data: Box<[u8; PAGE_SIZE]>
+    // Only None while the page is being dropped.
+    data: Option<Box<[u8]>>,
 }
 
-impl Page{
-    pub fn new() -> Self{
-        Self{
-            data: Box::new([0u8; PAGE_SIZE])
+impl Clone for Page {
+    fn clone(&self) -> Self {
+        let mut page = Self::recycled(self.size());
+        page.bytes_mut().copy_from_slice(self.bytes());
+        page
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            let _ = BUFFERS.try_with(|buffers| {
+                let mut buffers = buffers.borrow_mut();
+                if buffers.len() < POOLED_BUFFERS {
+                    buffers.push(data);
+                }
+            });
         }
     }
+}
+
+impl Page{
+    pub fn new(size: usize) -> Self{
+        let mut page = Self::recycled(size);
+        page.bytes_mut().fill(0);
+        page
+    }
 
-    pub fn from_bytes(bytes: [u8; PAGE_SIZE]) -> Self {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut page = Self::recycled(bytes.len());
+        page.bytes_mut().copy_from_slice(bytes);
+        page
+    }
+
+    // A page with whatever the recycled buffer held, for callers that overwrite all of it.
+    // A pooled buffer of another size is dropped.
+    fn recycled(size: usize) -> Self {
+        let data = BUFFERS.try_with(|buffers| buffers.borrow_mut().pop()).ok().flatten();
         Self{
-            data: Box::new(bytes),
+            data: Some(data.filter(|data| data.len() == size).unwrap_or_else(|| vec![0u8; size].into_boxed_slice())),
         }
     }
 
+    pub fn size(&self) -> usize {
+        self.bytes().len()
+    }
+
+    pub fn data_size(&self) -> usize {
+        data_size(self.size())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.data.as_ref().unwrap()
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.data.as_mut().unwrap()
+    }
+
     pub fn write_bytes_at_offset(&mut self, offset: usize, value: &[u8]) -> Result<()>{
         let end = offset+value.len();
-        if end > PAGE_SIZE {
+        if end > self.size() {
             Err(Error::PageSizeNotEnough)
         }
         else{
-            for i in offset..end {
-                self.data[offset..end].clone_from_slice(value);
-            }
+            self.bytes_mut()[offset..end].clone_from_slice(value);
             Ok(())
         }
     }
 
     pub fn get_bytes_from_offset(&self, offset: usize, size: usize) -> Result<&[u8]> {
         let end = offset + size;
-        if end > PAGE_SIZE {
+        if end > self.size() {
             Err(Error::PageSizeNotEnough)
         }
         else{
-            let bytes = &self.data[offset..end];
+            let bytes = &self.bytes()[offset..end];
             Ok(bytes)
         }
     }
 
-    pub fn get_page_data(&self) -> [u8; PAGE_SIZE] {
-        *self.data
+    fn checksum_offset(&self) -> usize {
+        self.size() - CHECKSUM_LEN
+    }
+
+    fn checksum(&self) -> u32 {
+        crc32fast::hash(&self.bytes()[..self.checksum_offset()])
+    }
+
+    pub(crate) fn stamp(&mut self) {
+        let checksum = self.checksum();
+        let offset = self.checksum_offset();
+        self.bytes_mut()[offset..].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    pub fn checksum_ok(&self) -> bool {
+        self.bytes()[self.checksum_offset()..] == self.checksum().to_be_bytes()
+    }
+
+    pub fn get_page_data(&self) -> Vec<u8> {
+        self.bytes().to_vec()
     }
 
     pub fn get_page_byte(&self, pos: usize) -> u8 {
-        self.data[pos]
+        self.bytes()[pos]
     }
 }
 
 
 pub struct Pager {
     fd: File,
+    page_size: usize,
+    codec: Codec,
+    compression: Compression,
+    order: KeyOrder,
+    cipher: Option<PageCipher>,
+    cache: Option<Mutex<PageCache>>,
+    // Page writes held back until the redo log has them; see with_staging().
+    staged: Option<Mutex<BTreeMap<PagePtr, Page>>>,
+    write_back: bool,
+    // Pages read from the file and written to it, cache hits and staged writes not counted.
+    reads: AtomicU64,
+    writes: AtomicU64,
+    #[cfg(feature = "mmap")]
+    mapped: Option<MappedFile>,
 }
 
 impl Pager{
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self>{
+    pub fn open<P: AsRef<Path>>(path: P, codec: Codec) -> Result<Self>{
         let fd = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
             .truncate(true)
             .open(path)?;
-        Ok(Self{fd})
+        Ok(Self{
+            fd,
+            page_size: DEFAULT_PAGE_SIZE,
+            codec,
+            compression: Compression::None,
+            order: KeyOrder::new(None, codec),
+            cipher: None,
+            cache: None,
+            staged: None,
+            write_back: false,
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            #[cfg(feature = "mmap")]
+            mapped: None,
+        })
     }
 
-    pub fn load_page(&mut self, page_ptr: PagePtr) -> Result<Page> {
-        let offset = page_ptr * PAGE_SIZE as u64;
-        let file_len = self.fd.seek(SeekFrom::End(0))?;
-        if file_len < offset as u64 {
-            Err(Error::PageNotFound)
+    // Opens a file that must already exist, keeping what it holds.
+    pub fn open_existing<P: AsRef<Path>>(path: P, codec: Codec) -> Result<Self>{
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(Self{
+            fd,
+            page_size: DEFAULT_PAGE_SIZE,
+            codec,
+            compression: Compression::None,
+            order: KeyOrder::new(None, codec),
+            cipher: None,
+            cache: None,
+            staged: None,
+            write_back: false,
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            #[cfg(feature = "mmap")]
+            mapped: None,
+        })
+    }
+
+    // Reads and writes pages of `page_size` bytes. Nothing encoded for a page is longer than
+    // the page, so the codec's limit grows with it.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        if self.codec.limit() < page_size as u64 {
+            self.codec = self.codec.with_limit(page_size as u64);
         }
-        else{
-            let mut bytes = [0u8; PAGE_SIZE];
-            self.fd.seek(SeekFrom::Start(offset as u64))?;
-            self.fd.read_exact(&mut bytes)?;
-            let page = Page::from_bytes(bytes);
-            Ok(page)
+        self
+    }
+
+    // Takes the page size page 0 of the file was written with: the one its checksum holds
+    // for, as the size has to be known before the page can be read. If none does, the page
+    // is left for meta::load() to find what is wrong with it.
+    pub(crate) fn with_stored_page_size(self) -> Result<Self> {
+        let file_len = self.file_len()?;
+        let mut page_size = MIN_PAGE_SIZE;
+        while page_size <= MAX_PAGE_SIZE && page_size as u64 <= file_len {
+            let mut page = Page::recycled(page_size);
+            read_exact_at(&self.fd, page.bytes_mut(), 0)?;
+            if page.checksum_ok() {
+                return Ok(self.with_page_size(page_size));
+            }
+            page_size *= 2;
+        }
+        Ok(self)
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // A zeroed page of the pager's size.
+    pub fn new_page(&self) -> Page {
+        Page::new(self.page_size)
+    }
+
+    // Compresses the entries of leaves written from now on.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // Nodes on these pages keep their keys in the order of `comparator` instead of their Ord.
+    pub fn with_comparator(mut self, comparator: Option<Arc<dyn Comparator>>) -> Self {
+        self.order = KeyOrder::new(comparator, self.codec);
+        self
+    }
+
+    pub(crate) fn order(&self) -> &KeyOrder {
+        &self.order
+    }
+
+    // Encrypts pages on their way to the file and decrypts them when they are read back.
+    // Pages in memory, cached or staged, stay as they are.
+    pub(crate) fn with_cipher(mut self, cipher: Option<PageCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    pub(crate) fn cipher(&self) -> Option<&PageCipher> {
+        self.cipher.as_ref()
+    }
+
+    // Keeps up to `pages` pages in memory; 0 turns the cache off.
+    pub fn with_cache(mut self, pages: usize, admission: Admission) -> Self {
+        self.cache = match pages {
+            0 => None,
+            pages => Some(Mutex::new(PageCache::new(pages, admission))),
+        };
+        self
+    }
+
+    // Leaves written pages in the cache and only writes them to the file when they are
+    // evicted or on flush(). Needs a cache; until then a crash loses them, and with them
+    // possibly half of a split.
+    pub fn with_write_back(mut self, write_back: bool) -> Self {
+        self.write_back = write_back;
+        self
+    }
+
+    // Reads pages out of a memory map of the file instead of with a read per page, so the
+    // OS page cache serves them without a syscall. Writes still go through the file.
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap(mut self, mmap: bool) -> Self {
+        self.mapped = if mmap { Some(MappedFile::default()) } else { None };
+        self
+    }
+
+    // Shrinks the cache while `source` reports memory pressure and lets it grow back after.
+    pub fn with_memory_pressure(self, source: Option<MemoryPressure>) -> Self {
+        if let (Some(cache), Some(source)) = (&self.cache, source) {
+            cache.lock().unwrap().watch(source);
+        }
+        self
+    }
+
+    // Keeps written pages in memory, where reads still find them, instead of writing them to
+    // the file, until take_staged() hands them over to be logged and written.
+    pub fn with_staging(mut self, staging: bool) -> Self {
+        self.staged = if staging { Some(Mutex::default()) } else { None };
+        self
+    }
+
+    // The pages written since the last call, in page order.
+    pub(crate) fn take_staged(&self) -> Vec<(PagePtr, Page)> {
+        match &self.staged {
+            Some(staged) => std::mem::take(&mut *staged.lock().unwrap()).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub(crate) fn has_staged(&self) -> bool {
+        self.staged.as_ref().is_some_and(|staged| !staged.lock().unwrap().is_empty())
+    }
+
+    // Writes a page to the file whether or not writes are staged.
+    pub(crate) fn write_through(&self, page_ptr: PagePtr, page: &Page) -> Result<()> {
+        let page = self.stamped(page);
+        self.write_file(page_ptr, &page)?;
+        self.cache_page(page_ptr, page, false)
+    }
+
+    // The page as write_through() puts it in the file.
+    pub(crate) fn image(&self, page_ptr: PagePtr, page: &Page) -> Result<Page> {
+        let page = self.stamped(page);
+        match &self.cipher {
+            Some(cipher) => cipher.seal(page_ptr, &page),
+            None => Ok(page),
+        }
+    }
+
+    // Writes what image() returned back to the file, as it is. Every page of the file is as
+    // long as the image, so this works before the pager knows the page size.
+    pub(crate) fn write_image(&self, page_ptr: PagePtr, image: &Page) -> Result<()> {
+        write_all_at(&self.fd, image.bytes(), page_ptr * image.size() as u64)?;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Writes a stamped page to the file, sealed if the tree is encrypted.
+    fn write_file(&self, page_ptr: PagePtr, page: &Page) -> Result<()> {
+        match &self.cipher {
+            Some(cipher) => self.write_image(page_ptr, &cipher.seal(page_ptr, page)?),
+            None => self.write_image(page_ptr, page),
+        }
+    }
+
+    // The page read from the file, decrypted if the tree is encrypted.
+    fn opened(&self, page_ptr: PagePtr, page: Page) -> Result<Page> {
+        match &self.cipher {
+            Some(cipher) => cipher.open_page(page_ptr, page),
+            None => Ok(page),
+        }
+    }
+
+    // Writes every dirty page of a write-back cache to the file.
+    pub fn flush(&self) -> Result<()> {
+        let dirty = match &self.cache {
+            Some(cache) => cache.lock().unwrap().take_dirty(),
+            None => return Ok(()),
+        };
+        dirty.iter().try_for_each(|(ptr, page)| self.write_file(*ptr, page))
+    }
+
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.lock().unwrap().stats())
+    }
+
+    fn cache_page(&self, page_ptr: PagePtr, page: Page, dirty: bool) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            let evicted = cache.lock().unwrap().insert(page_ptr, page, dirty);
+            evicted.iter().try_for_each(|(ptr, page)| self.write_file(*ptr, page))?;
         }
+        Ok(())
+    }
 
+    fn stamped(&self, page: &Page) -> Page {
+        let mut page = page.clone();
+        page.stamp();
+        page
+    }
+
+    pub fn codec(&self) -> &Codec {
+        &self.codec
+    }
+
+    pub fn page_reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn page_writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    pub fn file_len(&self) -> Result<u64> {
+        Ok(self.fd.metadata()?.len())
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    // Reads go through positional I/O so they only need a shared reference to the pager.
+    pub fn load_page(&self, page_ptr: PagePtr) -> Result<Page> {
+        if let Some(page) = self.cache.as_ref().and_then(|cache| cache.lock().unwrap().get(page_ptr)) {
+            return Ok(page);
+        }
+        let page = self.read_page(page_ptr)?;
+        if !page.checksum_ok() {
+            return Err(Error::CorruptedPage);
+        }
+        if self.cache.is_some() {
+            self.cache_page(page_ptr, page.clone(), false)?;
+        }
+        Ok(page)
+    }
+
+    // The page as it is in the file, or staged for it, bypassing the cache and without
+    // checking it.
+    pub fn read_page(&self, page_ptr: PagePtr) -> Result<Page> {
+        if let Some(page) = self.staged.as_ref().and_then(|staged| staged.lock().unwrap().get(&page_ptr).cloned()) {
+            return Ok(page);
+        }
+        if let Some(page) = self.cache.as_ref().and_then(|cache| cache.lock().unwrap().dirty_page(page_ptr)) {
+            return Ok(page);
+        }
+        let offset = page_ptr * self.page_size as u64;
+        let mut page = Page::recycled(self.page_size);
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &self.mapped {
+            return match mapped.read(&self.fd, offset, page.bytes_mut())? {
+                true => self.opened(page_ptr, page),
+                false => Err(Error::PageNotFound),
+            };
+        }
+        let file_len = self.fd.metadata()?.len();
+        if file_len < offset + self.page_size as u64 {
+            return Err(Error::PageNotFound);
+        }
+        read_exact_at(&self.fd, page.bytes_mut(), offset)?;
+        self.opened(page_ptr, page)
     }
 
     pub fn insert_page(&mut self, page_ptr: PagePtr, page: &Page) -> Result<()>{
-        let offset = page_ptr * PAGE_SIZE as u64;
+        let offset = page_ptr * self.page_size as u64;
         let file_len = self.fd.seek(SeekFrom::End(0))?;
-        if file_len < offset as u64 {
+        if file_len < offset {
             Err(Error::PageNotFound)
         }
         else{
-            self.fd.seek(SeekFrom::Start(offset as u64))?;
-            let bytes = page.get_page_data();
-            self.fd.write_all(&bytes)?;
-            Ok(())
+            self.write_through(page_ptr, page)
         }
     }
 
+    // Writes the page at its own offset, growing the file when the page lies past the end.
+    // Like reads this is positional, so distinct pages can be written from several threads.
+    pub fn write_page(&self, page_ptr: PagePtr, page: &Page) -> Result<()> {
+        match &self.staged {
+            Some(staged) => {
+                let page = self.stamped(page);
+                staged.lock().unwrap().insert(page_ptr, page.clone());
+                self.cache_page(page_ptr, page, false)
+            }
+            None if self.write_back && self.cache.is_some() => self.cache_page(page_ptr, self.stamped(page), true),
+            None => self.write_through(page_ptr, page),
+        }
+    }
+
+    // Forces every page written so far onto the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.flush()?;
+        self.fd.sync_data()?;
+        Ok(())
+    }
+
     pub fn append_page(&mut self, page: &Page) -> Result<()> {
         let offset = self.fd.seek(SeekFrom::End(0))?;
-        self.fd.seek(SeekFrom::Start(offset as u64))?;
-        let bytes = page.get_page_data();
-        self.fd.write_all(&bytes)?;
-        Ok(())
+        self.write_through(offset / self.page_size as u64, page)
     }
 }
 
+#[cfg(unix)]
+pub(crate) fn read_exact_at(fd: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    fd.read_exact_at(buf, offset)
+}
 
+#[cfg(windows)]
+pub(crate) fn read_exact_at(fd: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match fd.seek_read(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
 
+#[cfg(unix)]
+fn write_all_at(fd: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    fd.write_all_at(buf, offset)
+}
 
+#[cfg(windows)]
+fn write_all_at(fd: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match fd.seek_write(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::ops::Bound;
+
+// Keys that can be grouped by a prefix. All keys starting with a prefix form one contiguous
+// range in key order, from the prefix itself up to (excluding) its successor.
+pub trait KeyPrefix: Sized {
+    fn has_prefix(&self, prefix: &Self) -> bool;
+
+    // Smallest key greater than every key starting with `self`, or None when no such key
+    // exists (e.g. a prefix made only of 0xff bytes).
+    fn prefix_successor(&self) -> Option<Self>;
+
+    fn prefix_range(&self) -> (Bound<Self>, Bound<Self>)
+        where Self: Clone
+    {
+        let end = match self.prefix_successor() {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        (Bound::Included(self.clone()), end)
+    }
+}
+
+impl KeyPrefix for Vec<u8> {
+    fn has_prefix(&self, prefix: &Self) -> bool {
+        self.as_slice().starts_with(prefix)
+    }
+
+    fn prefix_successor(&self) -> Option<Self> {
+        let mut end = self.clone();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                return Some(end);
+            }
+        }
+        None
+    }
+}
+
+impl KeyPrefix for String {
+    fn has_prefix(&self, prefix: &Self) -> bool {
+        self.as_str().starts_with(prefix.as_str())
+    }
+
+    fn prefix_successor(&self) -> Option<Self> {
+        let mut end = self.clone();
+        while let Some(last) = end.pop() {
+            let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+            if let Some(next) = next {
+                end.push(next);
+                return Some(end);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_successor() {
+        assert_eq!(vec![1u8, 2].prefix_successor(), Some(vec![1, 3]));
+        assert_eq!(vec![1u8, 0xff].prefix_successor(), Some(vec![2]));
+        assert_eq!(vec![0xffu8].prefix_successor(), None);
+        assert_eq!(String::from("ab").prefix_successor(), Some(String::from("ac")));
+        assert_eq!(String::from("a\u{d7ff}").prefix_successor(), Some(String::from("a\u{e000}")));
+        assert_eq!(String::from("a\u{10ffff}").prefix_successor(), Some(String::from("b")));
+        assert!(vec![1u8, 2, 3].has_prefix(&vec![1, 2]));
+        assert!(!String::from("ab").has_prefix(&String::from("b")));
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const PRESSURE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Stall share, in percent, above which the cache starts to give pages back, and at which
+// it is down to MIN_CACHE_PERCENT of its configured size.
+const LOW_PRESSURE: f64 = 1.0;
+const HIGH_PRESSURE: f64 = 25.0;
+const MIN_CACHE_PERCENT: usize = 10;
+
+// Where the page cache learns how short on memory the process is: the share of the last
+// ten seconds, in percent, during which some task was stalled waiting for memory.
+#[derive(Clone)]
+pub enum MemoryPressure {
+    // A Linux PSI file. Reads that fail, e.g. on other systems, leave the cache as it is.
+    Psi(PathBuf),
+    Callback(Arc<dyn Fn() -> Option<f64> + Send + Sync>),
+}
+
+impl fmt::Debug for MemoryPressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryPressure::Psi(path) => f.debug_tuple("Psi").field(path).finish(),
+            MemoryPressure::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+impl MemoryPressure {
+    // Pressure on the whole machine.
+    pub fn system() -> Self {
+        MemoryPressure::Psi(PathBuf::from("/proc/pressure/memory"))
+    }
+
+    // Pressure inside the container, with cgroup v2 mounted at the usual place.
+    pub fn cgroup() -> Self {
+        MemoryPressure::Psi(PathBuf::from("/sys/fs/cgroup/memory.pressure"))
+    }
+
+    pub fn callback<F: Fn() -> Option<f64> + Send + Sync + 'static>(f: F) -> Self {
+        MemoryPressure::Callback(Arc::new(f))
+    }
+
+    pub fn sample(&self) -> Option<f64> {
+        match self {
+            MemoryPressure::Psi(path) => parse_psi(&fs::read_to_string(path).ok()?),
+            MemoryPressure::Callback(f) => f(),
+        }
+    }
+}
+
+// The avg10 of the `some` line, e.g. "some avg10=1.50 avg60=0.80 avg300=0.20 total=123".
+fn parse_psi(text: &str) -> Option<f64> {
+    let line = text.lines().find(|line| line.starts_with("some "))?;
+    line.split_whitespace().find_map(|field| field.strip_prefix("avg10="))?.parse().ok()
+}
+
+// How many of `max` pages the cache may hold under `pressure`, shrinking linearly from the
+// full size at LOW_PRESSURE down to the floor at HIGH_PRESSURE.
+pub(crate) fn target_capacity(max: usize, pressure: f64) -> usize {
+    let floor = (max * MIN_CACHE_PERCENT / 100).max(1).min(max);
+    let shrink = ((pressure - LOW_PRESSURE) / (HIGH_PRESSURE - LOW_PRESSURE)).clamp(0.0, 1.0);
+    max - ((max - floor) as f64 * shrink).round() as usize
+}
+
+// Samples a pressure source at most once per interval.
+pub(crate) struct PressureWatch {
+    source: MemoryPressure,
+    next_check: Instant,
+}
+
+impl PressureWatch {
+    pub(crate) fn new(source: MemoryPressure) -> Self {
+        Self{ source, next_check: Instant::now() }
+    }
+
+    // The current pressure, if it is time to look again and the source could tell.
+    pub(crate) fn poll(&mut self) -> Option<f64> {
+        let now = Instant::now();
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + PRESSURE_CHECK_INTERVAL;
+        self.source.sample()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use crate::error::Result;
+    use super::*;
+
+    #[test]
+    fn test_cache_shrinks_under_pressure() -> Result<()> {
+        assert_eq!(parse_psi("some avg10=12.50 avg60=3.00 avg300=1.00 total=1\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"), Some(12.5));
+        assert_eq!(target_capacity(1000, 0.5), 1000);
+        assert_eq!(target_capacity(1000, 13.0), 550);
+        assert_eq!(target_capacity(1000, 90.0), 100);
+
+        let psi = Path::new("data").join("test_pressure.psi");
+        fs::write(&psi, "some avg10=40.00 avg60=10.00 avg300=2.00 total=99\n")?;
+        let options = Options{
+            max_key_count: Some(8),
+            cache_pages: 200,
+            memory_pressure: Some(MemoryPressure::Psi(psi)),
+            ..Options::default()
+        };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(Path::new("data").join("test_pressure.db"), options)?;
+        for i in 0..2000 {
+            bptree.set(i, i)?;
+        }
+        let stats = bptree.cache_stats().unwrap();
+        assert_eq!(stats.capacity, 20);
+        assert!(stats.evictions > 0);
+
+        let options = Options{
+            max_key_count: Some(8),
+            cache_pages: 200,
+            memory_pressure: Some(MemoryPressure::callback(|| None)),
+            ..Options::default()
+        };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(Path::new("data").join("test_pressure_none.db"), options)?;
+        for i in 0..2000 {
+            bptree.set(i, i)?;
+        }
+        assert_eq!(bptree.cache_stats().unwrap().capacity, 200);
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use std::ops::Bound;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::{Node, Slot};
+use crate::engine::overflow;
+use crate::engine::page::PagePtr;
+use crate::error::{Error, Result};
+
+pub type KeyRange<K> = (Bound<K>, Bound<K>);
+
+fn contains<K, Q>(range: &KeyRange<K>, key: &Q) -> bool
+    where K: Borrow<Q>, Q: Ord + ?Sized
+{
+    let after_start = match &range.0 {
+        Bound::Included(start) => key >= start.borrow(),
+        Bound::Excluded(start) => key > start.borrow(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match &range.1 {
+        Bound::Included(end) => key <= end.borrow(),
+        Bound::Excluded(end) => key < end.borrow(),
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+// Whether no key can be both at or below `end` and at or above `start`.
+fn ends_before<K: Ord>(end: &Bound<K>, start: &Bound<K>) -> bool {
+    match (end, start) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(end), Bound::Included(start)) => end < start,
+        (Bound::Included(end) | Bound::Excluded(end), Bound::Included(start) | Bound::Excluded(start)) => end <= start,
+    }
+}
+
+// Key ranges that can't be served because pages holding them are corrupt. Lookups, writes
+// and scans that touch one fail with RangeUnavailable instead of reading the bad pages.
+#[derive(Debug)]
+pub(crate) struct Quarantine<K> {
+    ranges: Vec<KeyRange<K>>,
+}
+
+impl<K> Default for Quarantine<K> {
+    fn default() -> Self {
+        Self{ ranges: Vec::new() }
+    }
+}
+
+impl<K: Ord> Quarantine<K> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub(crate) fn ranges(&self) -> &[KeyRange<K>] {
+        &self.ranges
+    }
+
+    pub(crate) fn add(&mut self, range: KeyRange<K>) {
+        if !self.ranges.contains(&range) {
+            self.ranges.push(range);
+        }
+    }
+
+    pub(crate) fn covers<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        self.ranges.iter().any(|range| contains(range, key))
+    }
+
+    pub(crate) fn overlaps(&self, start: &Bound<K>, end: &Bound<K>) -> bool {
+        self.ranges.iter().any(|range| !ends_before(end, &range.0) && !ends_before(&range.1, start))
+    }
+
+    // Fails with RangeUnavailable if the key is quarantined.
+    pub(crate) fn check<Q>(&self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        match !self.ranges.is_empty() && self.covers(key) {
+            true => Err(Error::RangeUnavailable),
+            false => Ok(()),
+        }
+    }
+}
+
+// Errors that mean a page can't be used, as opposed to the file not being readable at all.
+fn unreadable(e: &Error) -> bool {
+    matches!(e, Error::CorruptedPage | Error::PageNotFound | Error::UnkonwNodeType | Error::SerdeError(_))
+}
+
+// What a walk from the root can still make of the tree: the live entries of every leaf it
+// could read, in key order, and the key ranges it couldn't. A bad node loses the range its
+// parent gives it, a bad overflow chain only its own key. Values are only decoded when
+// `values` is set; otherwise overflow chains are just checked page by page.
+pub(crate) struct Salvaged<K, V> {
+    pub(crate) entries: Vec<(K, V)>,
+    pub(crate) lost: Vec<KeyRange<K>>,
+}
+
+pub(crate) fn salvage<K, V>(bptree: &BPTree<K, V>, values: bool) -> Result<Salvaged<K, V>>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let (mut entries, mut lost) = (Vec::new(), Vec::new());
+    let mut pending: Vec<(PagePtr, Option<K>, Option<K>)> = bptree.root_ptr().map(|root| (root, None, None)).into_iter().collect();
+    while let Some((ptr, low, high)) = pending.pop() {
+        let node = match Node::<K, V>::load_node(ptr, bptree.pager()) {
+            Ok(node) => node,
+            Err(e) if unreadable(&e) => {
+                lost.push((low.map_or(Bound::Unbounded, Bound::Included), high.map_or(Bound::Unbounded, Bound::Excluded)));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let leaf = match node {
+            Node::Inner(inner) => {
+                for i in (0..inner.childptrs().len()).rev() {
+                    let (low, high) = inner.child_bounds(i);
+                    pending.push((inner.childptrs()[i], low.cloned(), high.cloned()));
+                }
+                continue;
+            }
+            Node::Leaf(leaf) => leaf,
+        };
+        let (keys, slots) = leaf.into_parts();
+        for (key, entry) in keys.into_iter().zip(slots) {
+            if bptree.is_expired(&entry) {
+                continue;
+            }
+            let read = match (&entry.slot, values) {
+                (_, true) => entry.slot.into_value(bptree.pager()).map(Some),
+                (Slot::Overflow(value), false) => overflow::chain_pages(value, bptree.pager()).map(|_| None),
+                (Slot::Inline(_), false) => Ok(None),
+            };
+            match read {
+                Ok(Some(value)) => entries.push((key, value)),
+                Ok(None) => (),
+                Err(e) if unreadable(&e) => lost.push((Bound::Included(key.clone()), Bound::Included(key))),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(Salvaged{ entries, lost })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+    use crate::engine::bptree::Options;
+    use crate::engine::page::DEFAULT_PAGE_SIZE;
+    use super::*;
+
+    fn corrupt(path: &Path, ptr: PagePtr) -> Result<()> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(ptr * DEFAULT_PAGE_SIZE as u64 + 100))?;
+        file.write_all(&[0xff; 4])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_and_repair() -> Result<()> {
+        let path = Path::new("data").join("test_quarantine.db");
+        let options = Options{ max_key_count: Some(8), ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(&path, options)?;
+        bptree.bulk_load((0..1000).map(|i| (i, i * 2)).collect())?;
+        let replica: Vec<(u64, u64)> = (0..1000).map(|i| (i, i * 2)).collect();
+        let leaf = bptree.leaf_for(&500)?.ptr();
+        corrupt(&path, leaf)?;
+
+        let lost = bptree.quarantine_corrupt()?;
+        assert_eq!(lost.len(), 1);
+        assert!(matches!(bptree.get(&500), Err(Error::RangeUnavailable)));
+        assert!(matches!(bptree.set(500, 0), Err(Error::RangeUnavailable)));
+        assert!(matches!(bptree.iter_snapshot(), Err(Error::RangeUnavailable)));
+        assert!(bptree.par_range(400..600, 1).is_err());
+        assert_eq!(bptree.get(&10)?, 20);
+        assert_eq!(bptree.get(&900)?, 1800);
+        bptree.set(10, 11)?;
+        let below = bptree.par_range(..490, 1)?.remove(0).count();
+        assert_eq!(below, 490);
+
+        // The replica is older for key 10; only the quarantined range is taken from it.
+        assert_eq!(bptree.repair(replica)?, 8);
+        assert!(bptree.quarantined().is_empty());
+        assert_eq!(bptree.get(&500)?, 1000);
+        assert_eq!(bptree.get(&10)?, 11);
+        assert_eq!(bptree.iter_snapshot()?.count(), 1000);
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::borrow::Borrow;
+use std::ops::Bound;
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+
+// Limits for the keys under one prefix. `None` leaves that dimension unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+// Live keys under a prefix and their encoded key plus value bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+struct Tenant<K> {
+    prefix: K,
+    start: Bound<K>,
+    end: Bound<K>,
+    quota: Quota,
+    usage: Usage,
+}
+
+impl<K: Ord> Tenant<K> {
+    fn contains<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        let after_start = match &self.start {
+            Bound::Included(start) => key >= start.borrow(),
+            Bound::Excluded(start) => key > start.borrow(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) => key <= end.borrow(),
+            Bound::Excluded(end) => key < end.borrow(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+// Tenants are kept as the key range of their prefix, so matching a key only needs Ord and
+// works for any key type. Prefixes may nest; a key is charged to every tenant containing it.
+pub(crate) struct Quotas<K> {
+    tenants: Vec<Tenant<K>>,
+}
+
+impl<K> Default for Quotas<K> {
+    fn default() -> Self {
+        Self{ tenants: Vec::new() }
+    }
+}
+
+impl<K: Ord> Quotas<K> {
+    pub(crate) fn insert(&mut self, prefix: K, range: (Bound<K>, Bound<K>), quota: Quota, usage: Usage) {
+        self.tenants.retain(|tenant| tenant.prefix != prefix);
+        self.tenants.push(Tenant{ prefix, start: range.0, end: range.1, quota, usage });
+    }
+
+    pub(crate) fn remove(&mut self, prefix: &K) -> Option<Quota> {
+        let i = self.tenants.iter().position(|tenant| &tenant.prefix == prefix)?;
+        Some(self.tenants.remove(i).quota)
+    }
+
+    pub(crate) fn get(&self, prefix: &K) -> Option<(Quota, Usage)> {
+        self.tenants.iter().find(|tenant| &tenant.prefix == prefix).map(|tenant| (tenant.quota, tenant.usage))
+    }
+
+    pub(crate) fn covers<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        self.tenants.iter().any(|tenant| tenant.contains(key))
+    }
+
+    // Accounts for storing `new` bytes under `key` in place of `old` (None for a new key).
+    // Nothing is charged unless every tenant containing the key stays within its quota; a
+    // write that doesn't grow a dimension is allowed even if the tenant is already over it.
+    pub(crate) fn charge(&mut self, key: &K, old: Option<u64>, new: u64) -> Result<()> {
+        let added_keys = u64::from(old.is_none());
+        let old = old.unwrap_or(0);
+        for tenant in self.tenants.iter().filter(|tenant| tenant.contains(key)) {
+            let keys = tenant.usage.keys + added_keys;
+            let bytes = tenant.usage.bytes - old.min(tenant.usage.bytes) + new;
+            let over_keys = tenant.quota.max_keys.is_some_and(|max| keys > max && added_keys > 0);
+            let over_bytes = tenant.quota.max_bytes.is_some_and(|max| bytes > max && new > old);
+            if over_keys || over_bytes {
+                return Err(Error::QuotaExceeded);
+            }
+        }
+        for tenant in self.tenants.iter_mut().filter(|tenant| tenant.contains(key)) {
+            tenant.usage.keys += added_keys;
+            tenant.usage.bytes = tenant.usage.bytes - old.min(tenant.usage.bytes) + new;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn release<Q>(&mut self, key: &Q, bytes: u64)
+        where K: Borrow<Q>, Q: Ord + ?Sized
+    {
+        for tenant in self.tenants.iter_mut().filter(|tenant| tenant.contains(key)) {
+            tenant.usage.keys = tenant.usage.keys.saturating_sub(1);
+            tenant.usage.bytes = tenant.usage.bytes.saturating_sub(bytes);
+        }
+    }
+
+    // Forgets what was counted, for a tree that is about to be loaded again.
+    pub(crate) fn reset_usage(&mut self) {
+        self.tenants.iter_mut().for_each(|tenant| tenant.usage = Usage::default());
+    }
+}
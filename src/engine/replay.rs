@@ -0,0 +1,105 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use serde::{Deserialize, Serialize};
+use crate::engine::KVStoreEngine;
+use crate::error::Error;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Op<K, V> {
+    Set(K, V),
+    Remove(K),
+    Get(K),
+}
+
+// What an engine returned for one operation. Errors are compared by their message, so two
+// engines agree on a failure only if they fail the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome<V> {
+    Done,
+    Value(V),
+    NotFound,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence<K, V> {
+    pub index: usize,
+    pub op: Op<K, V>,
+    pub left: Outcome<V>,
+    pub right: Outcome<V>,
+}
+
+pub fn apply<K, V, E>(engine: &mut E, op: &Op<K, V>) -> Outcome<V>
+    where K: Clone + Ord + Hash + 'static, V: Clone, E: KVStoreEngine<K, V>
+{
+    let result = match op {
+        Op::Set(key, value) => engine.set(key.clone(), value.clone()).map(|_| Outcome::Done),
+        Op::Remove(key) => engine.remove(key).map(|_| Outcome::Done),
+        Op::Get(key) => engine.get(key).map(Outcome::Value),
+    };
+    match result {
+        Ok(outcome) => outcome,
+        Err(Error::KeyNotFound) => Outcome::NotFound,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+// A reproducible log of `count` sets, removes and gets on 300 keys, half of them sets, for
+// comparing engines that should behave alike.
+pub fn mixed_ops(count: usize) -> Vec<Op<u64, u64>> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..count).map(|i| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let key = (state >> 33) % 300;
+        match (state >> 20) % 4 {
+            0 | 1 => Op::Set(key, i as u64),
+            2 => Op::Remove(key),
+            _ => Op::Get(key),
+        }
+    }).collect()
+}
+
+// Applies the same operation log to both engines and returns every operation whose
+// outcomes differ, in log order.
+pub fn replay<'a, K, V, A, B, I>(ops: I, left: &mut A, right: &mut B) -> Vec<Divergence<K, V>>
+    where K: Debug + Clone + Ord + Hash + 'static, V: Debug + Clone + PartialEq + 'a,
+          A: KVStoreEngine<K, V>, B: KVStoreEngine<K, V>,
+          I: IntoIterator<Item = &'a Op<K, V>>, K: 'a
+{
+    ops.into_iter().enumerate().filter_map(|(index, op)| {
+        let (l, r) = (apply(left, op), apply(right, op));
+        match l == r {
+            true => None,
+            false => Some(Divergence{ index, op: op.clone(), left: l, right: r }),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::array::ArrayKVStore;
+    use crate::engine::bptree::BPTree;
+    use crate::engine::WriteEngine;
+    use crate::error::Result;
+    use super::*;
+
+    #[test]
+    fn test_replay_bptree_against_array() -> Result<()> {
+        let path = Path::new("data").join("test_replay.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(path, Some(4))?;
+        let mut array = ArrayKVStore::new();
+        let log = mixed_ops(5000);
+        assert_eq!(replay(&log, &mut bptree, &mut array), vec![]);
+
+        array.set(1000, 1)?;
+        let divergences = replay(&[Op::Get(999), Op::Get(1000)], &mut bptree, &mut array);
+        assert_eq!(divergences, vec![Divergence{
+            index: 1,
+            op: Op::Get(1000),
+            left: Outcome::NotFound,
+            right: Outcome::Value(1),
+        }]);
+        Ok(())
+    }
+}
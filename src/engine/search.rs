@@ -0,0 +1,75 @@
+use std::borrow::Borrow;
+#[cfg(feature = "simd")]
+use std::any::TypeId;
+
+// Below this many candidates the remaining keys are compared in one straight pass,
+// which the compiler turns into packed compares instead of a chain of branches.
+#[cfg(feature = "simd")]
+const LINEAR_SCAN: usize = 32;
+
+pub fn search<K, Q>(keys: &[K], key: &Q) -> Result<usize, usize>
+    where K: Borrow<Q> + 'static,
+          Q: Ord + ?Sized + 'static
+{
+    #[cfg(feature = "simd")]
+    {
+        if let Some(found) = search_fixed::<K, Q, u32>(keys, key)
+            .or_else(|| search_fixed::<K, Q, u64>(keys, key))
+            .or_else(|| search_fixed::<K, Q, u128>(keys, key)) {
+            return found;
+        }
+    }
+    keys.binary_search_by(|k| k.borrow().cmp(key))
+}
+
+#[cfg(feature = "simd")]
+fn search_fixed<K, Q, T>(keys: &[K], key: &Q) -> Option<Result<usize, usize>>
+    where K: 'static,
+          Q: ?Sized + 'static,
+          T: Copy + Ord + 'static
+{
+    if TypeId::of::<K>() != TypeId::of::<T>() || TypeId::of::<Q>() != TypeId::of::<T>() {
+        return None;
+    }
+    // SAFETY: K, Q and T are the same type, checked above.
+    let keys = unsafe { std::slice::from_raw_parts(keys.as_ptr() as *const T, keys.len()) };
+    let key = unsafe { *(key as *const Q as *const T) };
+    let i = lower_bound(keys, key);
+    match keys.get(i) {
+        Some(k) if *k == key => Some(Ok(i)),
+        _ => Some(Err(i)),
+    }
+}
+
+#[cfg(feature = "simd")]
+fn lower_bound<T: Copy + Ord>(keys: &[T], key: T) -> usize {
+    let mut base = 0;
+    let mut len = keys.len();
+    while len > LINEAR_SCAN {
+        let half = len / 2;
+        base = if keys[base + half - 1] < key { base + half } else { base };
+        len -= half;
+    }
+    base + keys[base..base + len].iter().map(|k| (*k < key) as usize).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_matches_binary_search() {
+        let keys: Vec<u64> = (0..500).map(|i| i * 3).collect();
+        for key in 0..1600 {
+            assert_eq!(search(&keys, &key), keys.binary_search(&key));
+        }
+        let wide: Vec<u128> = (0..77).map(|i| (i as u128) << 70).collect();
+        for key in [0u128, 1, 5 << 70, u128::MAX] {
+            assert_eq!(search(&wide, &key), wide.binary_search(&key));
+        }
+        let words = vec![String::from("a"), String::from("c"), String::from("e")];
+        assert_eq!(search(&words, "d"), Err(2));
+        assert_eq!(search(&words, "e"), Ok(2));
+        assert_eq!(search::<u32, u32>(&[], &7), Err(0));
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::Debug;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::{LeafNode, Node};
+use crate::engine::codec::Codec;
+use crate::engine::page::PagePtr;
+use crate::error::Result;
+
+const SAMPLE_LEAVES: usize = 64;
+const HISTOGRAM_BUCKETS: usize = 32;
+
+// Upper key of an equi-depth histogram bucket and the estimated number of rows in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket<K> {
+    pub upper: K,
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stats<K> {
+    pub row_count: u64,
+    pub leaf_count: u64,
+    pub sampled_leaves: u64,
+    pub avg_key_size: f64,
+    pub avg_value_size: f64,
+    pub histogram: Vec<Bucket<K>>,
+}
+
+impl<K: Ord> Stats<K> {
+    // Rows in the buckets the range covers; buckets only partly covered count for half.
+    pub fn estimate_range<R: RangeBounds<K>>(&self, range: &R) -> u64 {
+        let mut estimate = 0;
+        let mut lower: Option<&K> = None;
+        for bucket in &self.histogram {
+            let covers_start = match (range.start_bound(), lower) {
+                (Bound::Unbounded, _) => true,
+                (Bound::Included(start) | Bound::Excluded(start), Some(lower)) => start <= lower,
+                (_, None) => false,
+            };
+            let covers_end = match range.end_bound() {
+                Bound::Unbounded => true,
+                Bound::Included(end) => &bucket.upper <= end,
+                Bound::Excluded(end) => &bucket.upper < end,
+            };
+            let overlaps = match range.start_bound() {
+                Bound::Unbounded => true,
+                Bound::Included(start) => start <= &bucket.upper,
+                Bound::Excluded(start) => start < &bucket.upper,
+            } && match (range.end_bound(), lower) {
+                (Bound::Included(end) | Bound::Excluded(end), Some(lower)) => end > lower,
+                _ => true,
+            };
+            estimate += match (covers_start && covers_end, overlaps) {
+                (true, _) => bucket.rows,
+                (false, true) => bucket.rows.div_ceil(2),
+                (false, false) => 0,
+            };
+            lower = Some(&bucket.upper);
+        }
+        estimate
+    }
+}
+
+// Reads the leaf level through the inner nodes and decodes an evenly spaced sample of at
+// most SAMPLE_LEAVES leaves; everything else is extrapolated from the sample.
+pub(crate) fn analyze<K, V>(bptree: &BPTree<K, V>) -> Result<Stats<K>>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let mut leaves: Vec<PagePtr> = bptree.root_ptr().into_iter().collect();
+    loop {
+        let mut children = Vec::new();
+        for ptr in &leaves {
+            match Node::<K, V>::load_node(*ptr, bptree.pager())? {
+                Node::Inner(inner) => children.extend_from_slice(inner.childptrs()),
+                Node::Leaf(_) => break,
+            }
+        }
+        if children.is_empty() {
+            break;
+        }
+        leaves = children;
+    }
+
+    let sample_count = leaves.len().min(SAMPLE_LEAVES);
+    let codec = bptree.pager().codec().with_limit(u64::MAX);
+    let (mut rows, mut key_bytes, mut value_bytes) = (0u64, 0u64, 0u64);
+    let mut sampled_keys = Vec::new();
+    for i in 0..sample_count {
+        let leaf = LeafNode::<K, V>::load(leaves[i * leaves.len() / sample_count], bptree.pager())?;
+        let (keys, entries) = leaf.into_parts();
+        rows += keys.len() as u64;
+        for (key, entry) in keys.into_iter().zip(entries) {
+            key_bytes += codec.serialized_size(&key)?;
+            value_bytes += entry.slot.value_len(&codec)?;
+            sampled_keys.push(key);
+        }
+    }
+
+    let row_count = match sample_count {
+        0 => 0,
+        _ => rows * leaves.len() as u64 / sample_count as u64,
+    };
+    let buckets = sampled_keys.len().min(HISTOGRAM_BUCKETS);
+    let histogram = (1..=buckets).map(|i| Bucket{
+        upper: sampled_keys[i * sampled_keys.len() / buckets - 1].clone(),
+        rows: row_count / buckets as u64 + u64::from(((i - 1) as u64) < row_count % buckets as u64),
+    }).collect();
+    Ok(Stats{
+        row_count,
+        leaf_count: leaves.len() as u64,
+        sampled_leaves: sample_count as u64,
+        avg_key_size: key_bytes as f64 / rows.max(1) as f64,
+        avg_value_size: value_bytes as f64 / rows.max(1) as f64,
+        histogram,
+    })
+}
+
+pub(crate) fn load<K: DeserializeOwned>(path: &Path, codec: &Codec) -> Result<Option<Stats<K>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(codec.with_limit(u64::MAX).deserialize(&bytes)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn store<K: Serialize>(stats: &Stats<K>, path: &Path, codec: &Codec) -> Result<()> {
+    let tmp = path.with_extension("stats.tmp");
+    fs::write(&tmp, codec.with_limit(u64::MAX).serialize(stats)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::hash::Hash;
+use sha2::{Digest, Sha256};
+use crate::engine::KVStoreEngine;
+use crate::error::Result;
+
+const HMAC_BLOCK: usize = 64;
+
+// Rewrites keys on their way into the engine. The same key must always map to the same
+// stored key, and distinct keys should not collide.
+pub trait KeyTransform<K> {
+    fn apply(&self, key: &K) -> K;
+}
+
+impl<K, F: Fn(&K) -> K> KeyTransform<K> for F {
+    fn apply(&self, key: &K) -> K {
+        self(key)
+    }
+}
+
+// Prepends the first `width` bytes of the key's SHA-256, so sequential keys spread over
+// the whole key space. The original key stays intact after the prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashPrefix {
+    width: usize,
+}
+
+impl HashPrefix {
+    pub fn new(width: usize) -> Self {
+        assert!(width > 0 && width <= 32);
+        Self{ width }
+    }
+
+    pub fn strip<'a>(&self, stored: &'a [u8]) -> &'a [u8] {
+        &stored[self.width.min(stored.len())..]
+    }
+}
+
+impl KeyTransform<Vec<u8>> for HashPrefix {
+    fn apply(&self, key: &Vec<u8>) -> Vec<u8> {
+        let mut stored = Sha256::digest(key)[..self.width].to_vec();
+        stored.extend_from_slice(key);
+        stored
+    }
+}
+
+// Stores HMAC-SHA256(secret, key) instead of the key, so the file never contains the
+// keys themselves. Lookups still work for whoever holds the secret.
+#[derive(Clone)]
+pub struct HmacKeys {
+    inner_pad: [u8; HMAC_BLOCK],
+    outer_pad: [u8; HMAC_BLOCK],
+}
+
+impl HmacKeys {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut block = [0u8; HMAC_BLOCK];
+        match secret.len() > HMAC_BLOCK {
+            true => block[..32].copy_from_slice(&Sha256::digest(secret)),
+            false => block[..secret.len()].copy_from_slice(secret),
+        }
+        Self{
+            inner_pad: block.map(|byte| byte ^ 0x36),
+            outer_pad: block.map(|byte| byte ^ 0x5c),
+        }
+    }
+}
+
+impl KeyTransform<Vec<u8>> for HmacKeys {
+    fn apply(&self, key: &Vec<u8>) -> Vec<u8> {
+        let inner = Sha256::new().chain_update(self.inner_pad).chain_update(key).finalize();
+        Sha256::new().chain_update(self.outer_pad).chain_update(inner).finalize().to_vec()
+    }
+}
+
+// An engine whose keys pass through a transform on every write and lookup. Stored key
+// order follows the transformed keys, so range scans on the inner engine see them in that
+// order, not the callers'.
+pub struct KeyTransformed<E, T> {
+    engine: E,
+    transform: T,
+}
+
+impl<E, T> KeyTransformed<E, T> {
+    pub fn new(engine: E, transform: T) -> Self {
+        Self{ engine, transform }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    pub fn get_with<K, V, R, F>(&self, key: &K, f: F) -> Result<R>
+        where E: KVStoreEngine<K, V>, T: KeyTransform<K>, K: Ord + Hash + 'static, F: FnOnce(&V) -> R
+    {
+        self.engine.get_with(&self.transform.apply(key), f)
+    }
+
+    pub fn get<K, V>(&self, key: &K) -> Result<V>
+        where E: KVStoreEngine<K, V>, T: KeyTransform<K>, K: Ord + Hash + 'static, V: Clone
+    {
+        self.get_with(key, V::clone)
+    }
+
+    pub fn set<K, V>(&mut self, key: K, value: V) -> Result<()>
+        where E: KVStoreEngine<K, V>, T: KeyTransform<K>
+    {
+        self.engine.set(self.transform.apply(&key), value)
+    }
+
+    pub fn remove<K, V>(&mut self, key: &K) -> Result<()>
+        where E: KVStoreEngine<K, V>, T: KeyTransform<K>, K: Ord + Hash + 'static
+    {
+        self.engine.remove(&self.transform.apply(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use crate::error::Error;
+    use super::*;
+
+    #[test]
+    fn test_key_transforms() -> Result<()> {
+        let path = Path::new("data").join("test_key_transform.db");
+        let bptree: BPTree<Vec<u8>, u64> = BPTree::new(path, Some(4))?;
+        let prefix = HashPrefix::new(1);
+        let mut tree = KeyTransformed::new(bptree, prefix);
+        for i in 0..50u64 {
+            tree.set(i.to_be_bytes().to_vec(), i)?;
+        }
+        assert_eq!(tree.get(&7u64.to_be_bytes().to_vec())?, 7);
+        tree.remove(&7u64.to_be_bytes().to_vec())?;
+        assert!(matches!(tree.get(&7u64.to_be_bytes().to_vec()), Err(Error::KeyNotFound)));
+        let stored = tree.inner().iter_snapshot()?.map(|entry| entry.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        let originals: Vec<&[u8]> = stored.iter().map(|key| prefix.strip(key)).collect();
+        assert!(originals.windows(2).any(|pair| pair[0] > pair[1]));
+
+        // RFC 4231, test case 2.
+        let hmac = HmacKeys::new(b"Jefe");
+        assert_eq!(hmac.apply(&b"what do ya want for nothing?".to_vec())[..4], [0x5b, 0xdc, 0xc1, 0x46]);
+        let mut tree = KeyTransformed::new(tree.into_inner(), hmac);
+        tree.set(b"secret".to_vec(), 1)?;
+        assert_eq!(tree.get(&b"secret".to_vec())?, 1);
+        assert!(matches!(tree.inner().get(b"secret".as_slice()), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::{Node, Slot, NODE_TYPE_OFFSET};
+use crate::engine::overflow::{self, OVERFLOW_NODE_TYPE};
+use crate::engine::page::{PagePtr, Pager};
+use crate::error::Result;
+
+// Copies the pages reachable from `roots` to `target`, numbered from 1 up so the copy has
+// no gaps: the inner nodes level by level, then the leaves in key order, then the overflow
+// chains of their values in the same order, shared chains once. Page 0 is left for the meta
+// page. Returns the new roots and the number of pages the copy holds.
+pub(crate) fn rewrite<K, V>(bptree: &BPTree<K, V>, roots: &[Option<PagePtr>], target: &Pager) -> Result<(Vec<Option<PagePtr>>, u64)>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    // Numbers are handed out first, as a node can only be written once its children have one.
+    let mut order = Vec::new();
+    let mut chains = Vec::new();
+    let mut level: Vec<PagePtr> = roots.iter().flatten().copied().collect();
+    while !level.is_empty() {
+        let mut children = Vec::new();
+        for ptr in level {
+            order.push(ptr);
+            match Node::<K, V>::load_node(ptr, bptree.pager())? {
+                Node::Inner(inner) => children.extend_from_slice(inner.childptrs()),
+                Node::Leaf(leaf) => chains.extend(leaf.into_parts().1.into_iter().filter_map(|entry| match entry.slot {
+                    Slot::Overflow(value) => Some(value),
+                    Slot::Inline(_) => None,
+                })),
+            }
+        }
+        level = children;
+    }
+    let mut new: HashMap<PagePtr, PagePtr> = order.iter().enumerate().map(|(i, ptr)| (*ptr, i as PagePtr + 1)).collect();
+    for chain in chains {
+        if new.contains_key(&chain.head()) {
+            continue;
+        }
+        for ptr in overflow::chain_pages(&chain, bptree.pager())? {
+            new.insert(ptr, order.len() as PagePtr + 1);
+            order.push(ptr);
+        }
+    }
+
+    let renumbered = |ptr: PagePtr| new[&ptr];
+    for ptr in &order {
+        let mut page = bptree.pager().load_page(*ptr)?;
+        if page.get_page_byte(NODE_TYPE_OFFSET) == OVERFLOW_NODE_TYPE {
+            let next = overflow::next_chunk(&page)?;
+            overflow::renumber_chunk(&mut page, new[ptr], next.map(renumbered))?;
+            target.write_page(new[ptr], &page)?;
+            continue;
+        }
+        match Node::<K, V>::from_page(*ptr, page, bptree.pager().codec())? {
+            Node::Inner(mut inner) => {
+                inner.renumber(renumbered);
+                inner.store_node_to_page(target)?;
+            }
+            Node::Leaf(mut leaf) => {
+                leaf.renumber(renumbered);
+                leaf.store_node_to_page(target)?;
+            }
+        }
+    }
+    Ok((roots.iter().map(|root| root.map(renumbered)).collect(), order.len() as u64 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use crate::engine::allocation::PageKind;
+    use crate::engine::bptree::Options;
+    use crate::engine::page::DEFAULT_PAGE_SIZE;
+    use crate::error::Error;
+    use super::*;
+
+    #[test]
+    fn test_vacuum_shrinks_file() -> Result<()> {
+        let path = Path::new("data").join("test_vacuum.db");
+        let options = Options{ max_key_count: Some(4), dedup_values: true, cache_pages: 32, ..Options::default() };
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::with_options(&path, options.clone())?;
+        for i in 0..3000u64 {
+            let value = match i % 50 {
+                0 => vec![(i % 3) as u8; 6000],
+                _ => vec![i as u8; 8],
+            };
+            bptree.set(i, value)?;
+        }
+        for i in (0..3000u64).filter(|i| i % 10 != 0) {
+            bptree.remove(&i)?;
+        }
+        let before = bptree.page_count();
+        let reclaimed = bptree.vacuum()?;
+        assert_eq!(bptree.page_count(), before - reclaimed);
+        assert_eq!(fs::metadata(&path)?.len(), bptree.page_count() * DEFAULT_PAGE_SIZE as u64);
+        assert_eq!(bptree.free_page_count(), 0);
+        let map = bptree.allocation_map()?;
+        assert_eq!(map.count(PageKind::Free) + map.count(PageKind::Unreadable), 0);
+        // Identical values still share three chains of two pages.
+        assert_eq!(map.count(PageKind::Overflow), 6);
+
+        assert_eq!(bptree.key_count(), 300);
+        assert_eq!(bptree.get(&100)?, vec![1; 6000]);
+        assert!(matches!(bptree.get(&101), Err(Error::KeyNotFound)));
+        let keys = bptree.iter_rev()?.map(|entry| entry.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (0..300).rev().map(|i| i * 10).collect::<Vec<_>>());
+        // The tree carries on in the new file.
+        bptree.set(5, vec![5; 6000])?;
+        bptree.remove(&0)?;
+        assert!(bptree.verify(|_| ())?.corrupt.is_empty());
+        bptree.close()?;
+
+        let bptree: BPTree<u64, Vec<u8>> = BPTree::open_with_options(&path, options)?;
+        assert_eq!(bptree.iter_snapshot()?.count(), 300);
+        assert_eq!(bptree.get(&5)?, vec![5; 6000]);
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashSet;
+use std::fmt::{self, Debug};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::{Node, Slot};
+use crate::engine::codec::Codec;
+use crate::engine::overflow;
+use crate::engine::page::{Page, PagePtr};
+use crate::error::{Error, Result};
+
+// Pages checked between two progress reports, and per read lock in the background.
+pub const VERIFY_BATCH: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyProgress {
+    pub checked: u64,
+    pub corrupt: u64,
+    // Pages in the file. Only reachable ones are checked, so the pass may end short of it.
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub checked: u64,
+    // Pages that failed their checksum or couldn't be read or decoded. Nothing they point to
+    // was checked.
+    pub corrupt: Vec<PagePtr>,
+    // Pages that read fine but don't fit together into a tree.
+    pub violations: Vec<Violation>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.violations.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    // The keys of the node are out of order, or one repeats.
+    Unsorted(PagePtr),
+    // A key of the node lies outside the range its parent's separators give it.
+    OutOfBounds(PagePtr),
+    // The fence keys of the inner node aren't the range its parent gives it.
+    FenceMismatch(PagePtr),
+    // The prev or next pointer of the leaf isn't the leaf beside it in key order.
+    BrokenChain(PagePtr),
+    // A node reached a second time, from another parent or from below itself.
+    Revisited(PagePtr),
+    // A page the tree references, which is on the free list or holds part of it.
+    Free(PagePtr),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Unsorted(ptr) => write!(f, "page {}: keys out of order", ptr),
+            Violation::OutOfBounds(ptr) => write!(f, "page {}: key outside its parent's separators", ptr),
+            Violation::FenceMismatch(ptr) => write!(f, "page {}: fences differ from its parent's separators", ptr),
+            Violation::BrokenChain(ptr) => write!(f, "page {}: leaf links skip or miss a leaf", ptr),
+            Violation::Revisited(ptr) => write!(f, "page {}: referenced more than once", ptr),
+            Violation::Free(ptr) => write!(f, "page {}: in use and on the free list", ptr),
+        }
+    }
+}
+
+// Key bounds travel encoded, so a Verifier isn't tied to a key type.
+type Bound = Option<Vec<u8>>;
+
+#[derive(Debug, Clone)]
+enum Pending {
+    // A node and the range [low, high) of keys its parent's separators give it.
+    Node(PagePtr, Bound, Bound),
+    Chunk(PagePtr),
+    // Where the leaves of one column family end.
+    FamilyEnd,
+}
+
+impl Pending {
+    fn ptr(&self) -> Option<PagePtr> {
+        match self {
+            Pending::Node(ptr, ..) | Pending::Chunk(ptr) => Some(*ptr),
+            Pending::FamilyEnd => None,
+        }
+    }
+}
+
+// Where the walk is along the leaf chain of a family. Leaves are reached in key order,
+// since children are walked from the left.
+#[derive(Debug, Clone, Copy, Default)]
+enum Chain {
+    #[default]
+    Start,
+    // The last leaf reached and its next pointer.
+    After(PagePtr, Option<PagePtr>),
+    // A corrupt page hid how many leaves came last.
+    Lost,
+}
+
+// Walks every page reachable from the roots of the column families, inner nodes, leaves
+// and the overflow chains of their values, reading each straight from the file so the
+// cache can't hide a bad page. The walk can be split into steps; pages written between
+// steps were checksummed on their way to the file, so a step only needs the tree for as
+// long as it runs. A structural walk also checks that the pages make up one tree: keys in
+// order and within their parent's separators, fences, the leaf chain and the free list.
+// That only holds if no write comes between its steps.
+#[derive(Debug, Default)]
+pub struct Verifier {
+    pending: Vec<Pending>,
+    seen: HashSet<PagePtr>,
+    report: VerifyReport,
+    started: bool,
+    structural: bool,
+    chain: Chain,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn structural() -> Self {
+        Self{ structural: true, ..Self::default() }
+    }
+
+    // Checks up to `pages` more pages and returns whether the walk is done.
+    pub fn step<K, V>(&mut self, bptree: &BPTree<K, V>, pages: usize) -> Result<bool>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        if !self.started {
+            self.started = true;
+            for root in bptree.family_roots().into_iter().rev() {
+                self.pending.push(Pending::FamilyEnd);
+                self.pending.push(Pending::Node(root, None, None));
+            }
+        }
+        for _ in 0..pages {
+            let next = match self.pending.pop() {
+                Some(next) => next,
+                None => break,
+            };
+            let ptr = match next.ptr() {
+                Some(ptr) => ptr,
+                None => {
+                    self.end_family();
+                    continue;
+                }
+            };
+            if !self.seen.insert(ptr) {
+                // Overflow chains of deduplicated values are shared between leaves.
+                if let (true, Pending::Node(..)) = (self.structural, &next) {
+                    self.report.violations.push(Violation::Revisited(ptr));
+                }
+                continue;
+            }
+            self.report.checked += 1;
+            match self.check(bptree, next) {
+                Ok(()) => (),
+                Err(Error::IOError(e)) => return Err(Error::IOError(e)),
+                Err(_) => {
+                    self.report.corrupt.push(ptr);
+                    self.chain = Chain::Lost;
+                }
+            }
+        }
+        if !self.pending.is_empty() {
+            return Ok(false);
+        }
+        if self.structural {
+            let free: Vec<PagePtr> = bptree.free_pages().filter(|ptr| self.seen.contains(ptr)).collect();
+            self.report.violations.extend(free.into_iter().map(Violation::Free));
+            self.structural = false;
+        }
+        Ok(true)
+    }
+
+    fn check<K, V>(&mut self, bptree: &BPTree<K, V>, next: Pending) -> Result<()>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        let (ptr, low, high) = match next {
+            Pending::Node(ptr, low, high) => (ptr, low, high),
+            Pending::Chunk(ptr) => {
+                let page = checked_page(bptree, ptr)?;
+                self.pending.extend(overflow::next_chunk(&page)?.map(Pending::Chunk));
+                return Ok(());
+            }
+            Pending::FamilyEnd => return Ok(()),
+        };
+        let page = checked_page(bptree, ptr)?;
+        let codec = bptree.pager().codec().with_limit(u64::MAX);
+        match Node::<K, V>::from_page(ptr, page, bptree.pager().codec())? {
+            Node::Inner(inner) => {
+                if self.structural {
+                    self.check_keys(bptree, ptr, inner.keys(), &low, &high)?;
+                    if encode(&codec, inner.low_fence())? != low || encode(&codec, inner.high_fence())? != high {
+                        self.report.violations.push(Violation::FenceMismatch(ptr));
+                    }
+                }
+                for i in (0..inner.childptrs().len()).rev() {
+                    let (low, high) = match self.structural {
+                        true => (encode(&codec, inner.child_bounds(i).0)?, encode(&codec, inner.child_bounds(i).1)?),
+                        false => (None, None),
+                    };
+                    self.pending.push(Pending::Node(inner.childptrs()[i], low, high));
+                }
+            }
+            Node::Leaf(leaf) => {
+                if self.structural {
+                    self.check_keys(bptree, ptr, leaf.keys(), &low, &high)?;
+                    self.check_chain(ptr, leaf.prev(), leaf.next());
+                }
+                let (_, entries) = leaf.into_parts();
+                for entry in entries {
+                    if let Slot::Overflow(value) = entry.slot {
+                        self.pending.push(Pending::Chunk(value.head()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_keys<K, V>(&mut self, bptree: &BPTree<K, V>, ptr: PagePtr, keys: &[K], low: &Bound, high: &Bound) -> Result<()>
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        let order = bptree.pager().order();
+        if keys.windows(2).any(|pair| !order.cmp::<K, K>(&pair[0], &pair[1]).is_lt()) {
+            self.report.violations.push(Violation::Unsorted(ptr));
+        }
+        let codec = bptree.pager().codec().with_limit(u64::MAX);
+        let low: Option<K> = low.as_ref().map(|low| codec.deserialize(low)).transpose()?;
+        let high: Option<K> = high.as_ref().map(|high| codec.deserialize(high)).transpose()?;
+        let outside = keys.iter().any(|key| {
+            low.as_ref().is_some_and(|low| order.cmp::<K, K>(key, low).is_lt())
+                || high.as_ref().is_some_and(|high| order.cmp::<K, K>(key, high).is_ge())
+        });
+        if outside {
+            self.report.violations.push(Violation::OutOfBounds(ptr));
+        }
+        Ok(())
+    }
+
+    fn check_chain(&mut self, ptr: PagePtr, prev: Option<PagePtr>, next: Option<PagePtr>) {
+        match self.chain {
+            Chain::Start if prev.is_some() => self.report.violations.push(Violation::BrokenChain(ptr)),
+            Chain::After(last, last_next) => {
+                if last_next != Some(ptr) {
+                    self.report.violations.push(Violation::BrokenChain(last));
+                }
+                if prev != Some(last) {
+                    self.report.violations.push(Violation::BrokenChain(ptr));
+                }
+            }
+            _ => (),
+        }
+        self.chain = Chain::After(ptr, next);
+    }
+
+    fn end_family(&mut self) {
+        if let (true, Chain::After(last, Some(_))) = (self.structural, self.chain) {
+            self.report.violations.push(Violation::BrokenChain(last));
+        }
+        self.chain = Chain::Start;
+    }
+
+    pub fn progress<K, V>(&self, bptree: &BPTree<K, V>) -> VerifyProgress
+        where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+              V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+    {
+        VerifyProgress{
+            checked: self.report.checked,
+            corrupt: self.report.corrupt.len() as u64,
+            total: bptree.page_count(),
+        }
+    }
+
+    pub fn into_report(self) -> VerifyReport {
+        self.report
+    }
+}
+
+fn checked_page<K, V>(bptree: &BPTree<K, V>, ptr: PagePtr) -> Result<Page>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let page = bptree.pager().read_page(ptr)?;
+    match page.checksum_ok() {
+        true => Ok(page),
+        false => Err(Error::CorruptedPage),
+    }
+}
+
+fn encode<K: Serialize>(codec: &Codec, key: Option<&K>) -> Result<Bound> {
+    key.map(|key| codec.serialize(key)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+    use crate::engine::btnode::LeafNode;
+    use crate::engine::iter::first_leaf;
+    use crate::engine::page::DEFAULT_PAGE_SIZE;
+    use super::*;
+
+    #[test]
+    fn test_verify_finds_flipped_byte() -> Result<()> {
+        let path = Path::new("data").join("test_verify.db");
+        let mut bptree: BPTree<u64, Vec<u8>> = BPTree::new(&path, Some(8))?;
+        for i in 0..2000u64 {
+            let len = if i % 50 == 0 { 9000 } else { 16 };
+            bptree.set(i, vec![i as u8; len])?;
+        }
+        let mut reports = Vec::new();
+        let report = bptree.verify(|progress| reports.push(*progress))?;
+        assert!(report.is_ok());
+        assert!(reports.len() > 1);
+        assert!(report.checked > 250 + 40 * 3);
+        assert_eq!(reports.last().unwrap().checked, report.checked);
+
+        // A byte in the unused part of the first leaf, which no decoding would notice.
+        let leaf = first_leaf(&bptree)?.unwrap();
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(leaf * DEFAULT_PAGE_SIZE as u64 + 2000))?;
+        file.write_all(&[0xa5])?;
+        let report = bptree.verify(|_| ())?;
+        assert_eq!(report.corrupt, vec![leaf]);
+        assert!(matches!(bptree.get(&0), Err(Error::CorruptedPage)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_finds_broken_structure() -> Result<()> {
+        let path = Path::new("data").join("test_verify_structure.db");
+        let mut bptree: BPTree<u64, u64> = BPTree::new(&path, Some(4))?;
+        for i in 0..100u64 {
+            bptree.set(i, i)?;
+        }
+        assert_eq!(bptree.verify(|_| ())?.violations, vec![]);
+
+        let pager = bptree.pager();
+        let first = LeafNode::<u64, u64>::load(first_leaf(&bptree)?.unwrap(), pager)?;
+        let second = LeafNode::<u64, u64>::load(first.next().unwrap(), pager)?;
+        let third = second.next().unwrap();
+        let (first_ptr, second_ptr) = (first.ptr(), second.ptr());
+        // The first leaf loses its next pointer and the second gets its keys swapped.
+        let (keys, entries) = first.into_parts();
+        LeafNode::from_parts(first_ptr, keys, entries, None, None).store_node_to_page(pager)?;
+        let (prev, next) = (second.prev(), second.next());
+        let (mut keys, entries) = second.into_parts();
+        keys.swap(0, 1);
+        LeafNode::from_parts(second_ptr, keys, entries, prev, next).store_node_to_page(pager)?;
+        bptree.delete_page(third);
+        bptree.flush()?;
+
+        let report = bptree.verify(|_| ())?;
+        assert!(report.corrupt.is_empty());
+        assert_eq!(report.violations, vec![
+            Violation::Unsorted(second_ptr),
+            Violation::BrokenChain(first_ptr),
+            Violation::Free(third),
+        ]);
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::codec::Codec;
+use crate::engine::page::{self, read_exact_at, Page, PagePtr, Pager};
+use crate::error::{Error, Result};
+
+const LEN_PREFIX: u64 = 8;
+const CRC_LEN: u64 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Change<K, V> {
+    Set(K, V),
+    Remove(K),
+    // A set with a TTL, and when it expires (unix millis).
+    SetExpiring(K, V, u64),
+}
+
+// A change read back from the log. `next` is the offset right after it: a consumer that
+// stores it once the event is handled resumes from there with ChangeStream::open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent<K, V> {
+    pub seq: u64,
+    // When the change was logged, in milliseconds since the Unix epoch.
+    pub time: u64,
+    pub change: Change<K, V>,
+    pub next: u64,
+}
+
+// Append-only log of every write to the tree, in sequence order. Each record is its
+// encoded length followed by the encoded (seq, time, change), so a byte offset into the file is
+// a stable position that stays valid for as long as the file exists.
+pub(crate) struct ChangeLog {
+    fd: File,
+    len: u64,
+    codec: Codec,
+}
+
+impl ChangeLog {
+    pub(crate) fn create<P: AsRef<Path>>(path: P, codec: Codec) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self{
+            fd,
+            len: 0,
+            codec: codec.with_limit(u64::MAX),
+        })
+    }
+
+    // Appends to the log already at `path`, or starts one if there is none.
+    pub(crate) fn open<P: AsRef<Path>>(path: P, codec: Codec) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self{
+            len: fd.metadata()?.len(),
+            fd,
+            codec: codec.with_limit(u64::MAX),
+        })
+    }
+
+    // Returns the offset of the appended record.
+    pub(crate) fn append<K: Serialize, V: Serialize>(&mut self, seq: u64, time: u64, change: &Change<K, V>) -> Result<u64> {
+        let body = self.codec.serialize(&(seq, time, change))?;
+        let mut record = self.codec.serialize(&(body.len() as u64))?;
+        record.extend_from_slice(&body);
+        self.fd.write_all(&record)?;
+        let offset = self.len;
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.fd.sync_data()?;
+        Ok(())
+    }
+}
+
+// Reads the log from a given offset with its own file handle, so it doesn't borrow the
+// tree and can tail the log from another thread while writes go on. Reaching the end (or a
+// record that is still being appended) ends the iteration for now; calling next() again
+// later picks up whatever has been written since.
+pub struct ChangeStream<K, V> {
+    fd: File,
+    offset: u64,
+    codec: Codec,
+    change_type: PhantomData<(K, V)>,
+}
+
+impl<K, V> ChangeStream<K, V>
+    where K: DeserializeOwned, V: DeserializeOwned
+{
+    pub fn open<P: AsRef<Path>>(path: P, offset: u64, codec: Codec) -> Result<Self> {
+        Ok(Self{
+            fd: File::open(path)?,
+            offset,
+            codec: codec.with_limit(u64::MAX),
+            change_type: PhantomData,
+        })
+    }
+
+    // Offset of the next event to be read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn read_next(&mut self) -> Result<Option<ChangeEvent<K, V>>> {
+        let file_len = self.fd.metadata()?.len();
+        if file_len < self.offset + LEN_PREFIX {
+            return Ok(None);
+        }
+        let mut prefix = [0u8; LEN_PREFIX as usize];
+        read_exact_at(&self.fd, &mut prefix, self.offset)?;
+        let body_len: u64 = self.codec.deserialize(&prefix)?;
+        if file_len - self.offset - LEN_PREFIX < body_len {
+            return Ok(None);
+        }
+        let mut body = vec![0u8; body_len as usize];
+        read_exact_at(&self.fd, &mut body, self.offset + LEN_PREFIX)?;
+        let (seq, time, change) = self.codec.deserialize(&body)?;
+        self.offset += LEN_PREFIX + body_len;
+        Ok(Some(ChangeEvent{ seq, time, change, next: self.offset }))
+    }
+}
+
+impl<K, V> Iterator for ChangeStream<K, V>
+    where K: DeserializeOwned, V: DeserializeOwned
+{
+    type Item = Result<ChangeEvent<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next().transpose()
+    }
+}
+
+// Redo log of page images, so a crash while a write is rewriting pages in place can't leave
+// the tree half split or half merged. Every write's pages, the meta page among them, are
+// appended as one record of (seq, pages) and synced before any of them is written to the
+// tree file; open() writes the records it finds back before reading the meta page. Pages
+// are logged as they go into the tree file, so an encrypted tree's are sealed. Once the
+// tree file is synced the log is cut back to nothing. Records end in a CRC32 of their
+// body, so a torn last record is told apart and dropped.
+pub(crate) struct RedoLog {
+    fd: File,
+    len: u64,
+    codec: Codec,
+}
+
+impl RedoLog {
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self{ fd, len: 0, codec: Codec::default().with_limit(u64::MAX) })
+    }
+
+    pub(crate) fn append(&mut self, seq: u64, pages: &[(PagePtr, Page)]) -> Result<()> {
+        let images: Vec<(PagePtr, &[u8])> = pages.iter().map(|(ptr, page)| (*ptr, page.bytes())).collect();
+        let body = self.codec.serialize(&(seq, images))?;
+        let mut record = self.codec.serialize(&(body.len() as u64))?;
+        record.extend_from_slice(&body);
+        record.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        self.fd.write_all(&record)?;
+        self.fd.sync_data()?;
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Drops every record; only once the pages they hold are synced to the tree file.
+    pub(crate) fn truncate(&mut self) -> Result<()> {
+        self.fd.set_len(0)?;
+        self.fd.sync_data()?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+// Writes the pages of every whole record in the redo log at `path` to the tree file,
+// syncs it and removes the log. Returns the sequence number of the last record replayed.
+pub(crate) fn recover<P: AsRef<Path>>(path: P, pager: &Pager) -> Result<Option<u64>> {
+    let fd = match File::open(&path) {
+        Ok(fd) => fd,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let codec = Codec::default().with_limit(u64::MAX);
+    let file_len = fd.metadata()?.len();
+    let (mut offset, mut last_seq) = (0, None);
+    while file_len >= offset + LEN_PREFIX {
+        let mut prefix = [0u8; LEN_PREFIX as usize];
+        read_exact_at(&fd, &mut prefix, offset)?;
+        let body_len: u64 = codec.deserialize(&prefix)?;
+        if file_len - offset - LEN_PREFIX < body_len.saturating_add(CRC_LEN) {
+            break;
+        }
+        let mut body = vec![0u8; body_len as usize];
+        read_exact_at(&fd, &mut body, offset + LEN_PREFIX)?;
+        let mut crc = [0u8; CRC_LEN as usize];
+        read_exact_at(&fd, &mut crc, offset + LEN_PREFIX + body_len)?;
+        if crc32fast::hash(&body).to_be_bytes() != crc {
+            break;
+        }
+        let (seq, images): (u64, Vec<(PagePtr, Vec<u8>)>) = codec.deserialize(&body)?;
+        for (ptr, image) in images {
+            page::check_page_size(image.len()).map_err(|_| Error::CorruptedPage)?;
+            pager.write_image(ptr, &Page::from_bytes(&image))?;
+        }
+        last_seq = Some(seq);
+        offset += LEN_PREFIX + body_len + CRC_LEN;
+    }
+    pager.sync()?;
+    fs::remove_file(path)?;
+    Ok(last_seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom};
+    use std::path::Path;
+    use crate::engine::bptree::{BPTree, Options};
+    use super::*;
+
+    #[test]
+    fn test_change_stream_resumes() -> Result<()> {
+        let path = Path::new("data").join("test_change_stream.db");
+        let options = Options{ max_key_count: Some(4), change_log: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(path, options)?;
+        for i in 0..10 {
+            bptree.set(i, i * 10)?;
+        }
+        bptree.remove(&3)?;
+
+        let mut stream = bptree.changes(0)?;
+        let events = stream.by_ref().collect::<Result<Vec<_>>>()?;
+        assert_eq!(events.len(), 11);
+        assert_eq!(events[4].change, Change::Set(4, 40));
+        assert_eq!(events[10].change, Change::Remove(3));
+        assert!(events.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+        assert_eq!(Some(stream.offset()), bptree.change_log_end());
+
+        // The stream picks up later writes, and a new one resumes after a handled event.
+        bptree.set(20, 200)?;
+        assert_eq!(stream.next().transpose()?.map(|event| event.change), Some(Change::Set(20, 200)));
+        assert!(stream.next().is_none());
+        let resumed = bptree.changes(events[8].next)?.map(|event| event.map(|event| event.change)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(resumed, vec![Change::Set(9, 90), Change::Remove(3), Change::Set(20, 200)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_redo_log_recovers_torn_pages() -> Result<()> {
+        let path = Path::new("data").join("test_redo_log.db");
+        let options = Options{ max_key_count: Some(4), write_ahead_log: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(&path, options.clone())?;
+        for i in 0..10 {
+            bptree.set(i, i)?;
+        }
+        bptree.flush()?;
+        for i in 10..100 {
+            bptree.set(i, i)?;
+        }
+        for i in 0..20 {
+            bptree.remove(&i)?;
+        }
+        assert_eq!(bptree.durable_seq(), bptree.last_seq());
+        let pages = bptree.page_count();
+        // Play a crash that tore every page written since the flush, plus a half-appended
+        // record at the end of the log.
+        drop(bptree);
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        for ptr in 0..pages {
+            file.seek(SeekFrom::Start(ptr * page::DEFAULT_PAGE_SIZE as u64 + 64))?;
+            file.write_all(&[0xab; 64])?;
+        }
+        let mut redo = OpenOptions::new().append(true).open(path.with_extension("redo"))?;
+        redo.write_all(&[0, 0, 0, 0, 0, 0, 0x10, 0, 1, 2, 3])?;
+
+        let bptree: BPTree<u64, u64> = BPTree::open_with_options(&path, options)?;
+        assert!(bptree.verify(|_| ())?.corrupt.is_empty());
+        assert_eq!(bptree.iter_snapshot()?.collect::<Result<Vec<_>>>()?, (20..100).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(bptree.key_count(), 80);
+        bptree.close()?;
+        assert_eq!(fs::metadata(path.with_extension("redo"))?.len(), 0);
+        Ok(())
+    }
+}
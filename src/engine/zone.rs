@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use std::vec;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::btnode::LeafNode;
+use crate::engine::iter;
+use crate::engine::page::PagePtr;
+use crate::error::Result;
+
+// Smallest and largest value stored in a leaf, expired entries included, and the leaf
+// after it, so a scan can pass over a leaf that can't match without even reading it.
+#[derive(Debug, Clone)]
+pub(crate) struct Zone<V> {
+    bounds: Option<(V, V)>,
+    next: Option<PagePtr>,
+}
+
+impl<V: Ord> Zone<V> {
+    fn overlaps<R: RangeBounds<V>>(&self, range: &R) -> bool {
+        let (min, max) = match &self.bounds {
+            Some(bounds) => bounds,
+            None => return false,
+        };
+        let after_start = match range.start_bound() {
+            Bound::Included(start) => max >= start,
+            Bound::Excluded(start) => max > start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.end_bound() {
+            Bound::Included(end) => min <= end,
+            Bound::Excluded(end) => min < end,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+// Zones of the leaves scanned since they were last written. Built lazily by value scans;
+// every write to a leaf drops its zone.
+pub(crate) struct ZoneMap<V> {
+    zones: HashMap<PagePtr, Zone<V>>,
+}
+
+impl<V> Default for ZoneMap<V> {
+    fn default() -> Self {
+        Self{ zones: HashMap::new() }
+    }
+}
+
+impl<V: Clone> ZoneMap<V> {
+    fn get(&self, leaf: PagePtr) -> Option<Zone<V>> {
+        self.zones.get(&leaf).cloned()
+    }
+
+    fn insert(&mut self, leaf: PagePtr, zone: Zone<V>) {
+        self.zones.insert(leaf, zone);
+    }
+
+    pub(crate) fn forget(&mut self, leaf: PagePtr) {
+        self.zones.remove(&leaf);
+    }
+}
+
+// Entries whose value lies in a range, in key order. The whole tree is walked, but leaves
+// whose zone doesn't overlap the range are skipped.
+pub struct ValueScan<'a, K, V, R> {
+    bptree: &'a BPTree<K, V>,
+    range: R,
+    next_leaf: Option<PagePtr>,
+    entries: vec::IntoIter<(K, V)>,
+    skipped: u64,
+}
+
+impl<'a, K, V, R> ValueScan<'a, K, V, R>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          R: RangeBounds<V>
+{
+    pub(crate) fn new(bptree: &'a BPTree<K, V>, range: R) -> Result<Self> {
+        Ok(Self{
+            bptree,
+            range,
+            next_leaf: iter::first_leaf(bptree)?,
+            entries: Vec::new().into_iter(),
+            skipped: 0,
+        })
+    }
+
+    // Leaves passed over so far because of their zone.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    fn scan_leaf(&mut self, ptr: PagePtr) -> Result<()> {
+        let leaf = LeafNode::<K, V>::load(ptr, self.bptree.pager())?;
+        let next = leaf.next();
+        let (keys, entries) = leaf.into_parts();
+        let mut bounds: Option<(V, V)> = None;
+        let mut matches = Vec::new();
+        for (key, entry) in keys.into_iter().zip(entries) {
+            let expired = self.bptree.is_expired(&entry);
+            let value = entry.slot.into_value(self.bptree.pager())?;
+            bounds = match bounds {
+                None => Some((value.clone(), value.clone())),
+                Some((min, max)) if value < min => Some((value.clone(), max)),
+                Some((min, max)) if value > max => Some((min, value.clone())),
+                bounds => bounds,
+            };
+            if !expired && self.range.contains(&value) {
+                matches.push((key, value));
+            }
+        }
+        if let Some(zones) = self.bptree.zones() {
+            zones.lock().unwrap().insert(ptr, Zone{ bounds, next });
+        }
+        self.next_leaf = next;
+        self.entries = matches.into_iter();
+        Ok(())
+    }
+}
+
+impl<'a, K, V, R> Iterator for ValueScan<'a, K, V, R>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          R: RangeBounds<V>
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            let ptr = self.next_leaf.take()?;
+            let zone = self.bptree.zones().and_then(|zones| zones.lock().unwrap().get(ptr));
+            match zone {
+                Some(zone) if !zone.overlaps(&self.range) => {
+                    self.skipped += 1;
+                    self.next_leaf = zone.next;
+                }
+                _ => if let Err(e) = self.scan_leaf(ptr) {
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::Options;
+    use super::*;
+
+    #[test]
+    fn test_scan_skips_leaves_by_zone() -> Result<()> {
+        let path = Path::new("data").join("test_zone_map.db");
+        let options = Options{ max_key_count: Some(8), zone_maps: true, ..Options::default() };
+        let mut bptree: BPTree<u64, u64> = BPTree::with_options(path, options)?;
+        // Values grow with the keys, so only a couple of leaves hold values in 500..=520.
+        bptree.bulk_load((0..400u64).map(|i| (i, i * 2)).collect())?;
+        let scan = |bptree: &BPTree<u64, u64>| -> Result<(Vec<u64>, u64)> {
+            let mut scan = bptree.values_between(500..=520)?;
+            let keys = scan.by_ref().map(|entry| entry.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+            Ok((keys, scan.skipped()))
+        };
+        let (keys, skipped) = scan(&bptree)?;
+        assert_eq!(keys, (250..=260).collect::<Vec<_>>());
+        assert_eq!(skipped, 0);
+        let (keys, skipped) = scan(&bptree)?;
+        assert_eq!(keys, (250..=260).collect::<Vec<_>>());
+        assert_eq!(skipped, 48);
+
+        // A write drops the zone of the leaf it lands in.
+        bptree.set(3, 510)?;
+        bptree.remove(&255)?;
+        let (keys, skipped) = scan(&bptree)?;
+        assert_eq!(keys, vec![3, 250, 251, 252, 253, 254, 256, 257, 258, 259, 260]);
+        assert_eq!(skipped, 47);
+        Ok(())
+    }
+}
@@ -0,0 +1,370 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::Bound;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{AeadCore, ChaCha20Poly1305, Key, Nonce};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::codec::Codec;
+use crate::engine::iter::RangeIter;
+use crate::engine::{KVStoreEngine, ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+
+pub type MasterKey = [u8; 32];
+
+pub const ROTATION_BATCH: usize = 128;
+
+// Master keys by id. New values are always sealed with the current one; any key still in
+// the ring can open older values.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: BTreeMap<u32, MasterKey>,
+    current: u32,
+}
+
+impl Keyring {
+    pub fn new(id: u32, key: MasterKey) -> Self {
+        Self{
+            keys: BTreeMap::from([(id, key)]),
+            current: id,
+        }
+    }
+
+    pub fn add(&mut self, id: u32, key: MasterKey) {
+        self.keys.insert(id, key);
+    }
+
+    pub fn set_current(&mut self, id: u32) -> Result<()> {
+        if !self.keys.contains_key(&id) {
+            return Err(Error::UnknownMasterKey(id));
+        }
+        self.current = id;
+        Ok(())
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    // Values sealed with a removed key can no longer be read.
+    pub fn remove(&mut self, id: u32) -> Option<MasterKey> {
+        match id == self.current {
+            true => None,
+            false => self.keys.remove(&id),
+        }
+    }
+
+    fn cipher(&self, id: u32) -> Result<ChaCha20Poly1305> {
+        match self.keys.get(&id) {
+            Some(key) => Ok(ChaCha20Poly1305::new(Key::from_slice(key))),
+            None => Err(Error::UnknownMasterKey(id)),
+        }
+    }
+}
+
+// What is stored in place of a value: the value sealed with its own random data key, and
+// that data key sealed with a master key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub(crate) master: u32,
+    wrapped_key: Vec<u8>,
+    key_nonce: [u8; 12],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    fn seal(plaintext: &[u8], keyring: &Keyring) -> Result<Self> {
+        let data_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&data_key).encrypt(&nonce, plaintext).map_err(|_| Error::DecryptionFailed)?;
+        let mut envelope = Self{
+            master: keyring.current,
+            wrapped_key: Vec::new(),
+            key_nonce: [0; 12],
+            nonce: nonce.into(),
+            ciphertext,
+        };
+        envelope.wrap(data_key.as_slice(), keyring)?;
+        Ok(envelope)
+    }
+
+    fn open(&self, keyring: &Keyring) -> Result<Vec<u8>> {
+        let data_key = self.unwrap_key(keyring)?;
+        ChaCha20Poly1305::new(Key::from_slice(&data_key))
+            .decrypt(Nonce::from_slice(&self.nonce), self.ciphertext.as_slice())
+            .map_err(|_| Error::DecryptionFailed)
+    }
+
+    fn wrap(&mut self, data_key: &[u8], keyring: &Keyring) -> Result<()> {
+        let key_nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        self.wrapped_key = keyring.cipher(keyring.current)?.encrypt(&key_nonce, data_key).map_err(|_| Error::DecryptionFailed)?;
+        self.key_nonce = key_nonce.into();
+        self.master = keyring.current;
+        Ok(())
+    }
+
+    fn unwrap_key(&self, keyring: &Keyring) -> Result<Vec<u8>> {
+        keyring.cipher(self.master)?
+            .decrypt(Nonce::from_slice(&self.key_nonce), self.wrapped_key.as_slice())
+            .map_err(|_| Error::DecryptionFailed)
+    }
+
+    // Seals the same data key with the current master key; the value itself is untouched.
+    fn rewrap(&mut self, keyring: &Keyring) -> Result<()> {
+        let data_key = self.unwrap_key(keyring)?;
+        self.wrap(&data_key, keyring)
+    }
+}
+
+// A master key rotation in progress: every record up to and including `after` has been
+// re-wrapped from `old` to `new`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rotation<K> {
+    pub old: u32,
+    pub new: u32,
+    pub after: Option<K>,
+}
+
+// Envelope encryption at the engine boundary: every value is encrypted before it reaches
+// the inner engine and decrypted on the way out. Keys are stored in the clear. Because each
+// write seals under the current master key, switching the keyring to a new key rotates
+// records lazily as they are rewritten.
+pub struct Encrypted<E> {
+    engine: E,
+    keyring: Keyring,
+    codec: Codec,
+}
+
+impl<E> Encrypted<E> {
+    pub fn new(engine: E, keyring: Keyring) -> Self {
+        Self{
+            engine,
+            keyring,
+            codec: Codec::default().with_limit(u64::MAX),
+        }
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    pub fn keyring_mut(&mut self) -> &mut Keyring {
+        &mut self.keyring
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    // Id of the master key that currently wraps the value's data key.
+    pub fn master_key_of<K, Q>(&self, key: &Q) -> Result<u32>
+        where E: ReadEngine<K, Vec<u8>>, K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static
+    {
+        Ok(self.envelope(key)?.master)
+    }
+
+    pub(crate) fn envelope<K, Q>(&self, key: &Q) -> Result<Envelope>
+        where E: ReadEngine<K, Vec<u8>>, K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static
+    {
+        self.engine.get_with(key, |bytes: &Vec<u8>| self.codec.deserialize(bytes))?
+    }
+}
+
+// Rotation re-wraps data keys in key order, a batch at a time, and records how far it got
+// on the tree's meta page, flushed with the batch it covers. Reads and writes can go on
+// between batches, and an interrupted rotation is picked up again by resume_rotation().
+impl<K> Encrypted<BPTree<K, Vec<u8>>>
+    where K: Debug + Clone + Ord + Hash + Serialize + DeserializeOwned + 'static
+{
+    // Makes `new` the current master key and re-wraps every data key sealed with `old`.
+    // Returns how many records were re-wrapped.
+    pub fn rotate_master_key(&mut self, old: u32, new: u32) -> Result<u64> {
+        self.start_rotation(old, new)?;
+        self.finish_rotation()
+    }
+
+    pub fn start_rotation(&mut self, old: u32, new: u32) -> Result<()> {
+        self.keyring.cipher(old)?;
+        self.keyring.set_current(new)?;
+        if old == new {
+            return Ok(());
+        }
+        self.store_rotation(&Rotation{ old, new, after: None })
+    }
+
+    pub fn pending_rotation(&self) -> Result<Option<Rotation<K>>> {
+        self.engine.rotation().map(|bytes| self.codec.deserialize(bytes)).transpose()
+    }
+
+    pub fn resume_rotation(&mut self) -> Result<u64> {
+        if let Some(rotation) = self.pending_rotation()? {
+            self.keyring.set_current(rotation.new)?;
+        }
+        self.finish_rotation()
+    }
+
+    // Re-wraps up to `batch` more records. Returns how many were re-wrapped and whether
+    // the rotation is complete.
+    pub fn rotation_step(&mut self, batch: usize) -> Result<(u64, bool)> {
+        let mut rotation = match self.pending_rotation()? {
+            Some(rotation) => rotation,
+            None => return Ok((0, true)),
+        };
+        let start = match rotation.after.take() {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        let keys = RangeIter::new(&self.engine, start, Bound::Unbounded)?
+            .keys()
+            .take(batch)
+            .collect::<Result<Vec<K>>>()?;
+        let mut rewrapped = 0;
+        for key in &keys {
+            let mut envelope = self.envelope(key)?;
+            if envelope.master == rotation.old {
+                envelope.rewrap(&self.keyring)?;
+                self.engine.set(key.clone(), self.codec.serialize(&envelope)?)?;
+                rewrapped += 1;
+            }
+        }
+        if keys.len() < batch {
+            self.engine.store_rotation(None)?;
+            return Ok((rewrapped, true));
+        }
+        rotation.after = keys.into_iter().last();
+        self.store_rotation(&rotation)?;
+        Ok((rewrapped, false))
+    }
+
+    fn finish_rotation(&mut self) -> Result<u64> {
+        let mut total = 0;
+        loop {
+            let (rewrapped, done) = self.rotation_step(ROTATION_BATCH)?;
+            total += rewrapped;
+            if done {
+                return Ok(total);
+            }
+        }
+    }
+
+    fn store_rotation(&mut self, rotation: &Rotation<K>) -> Result<()> {
+        let bytes = self.codec.serialize(rotation)?;
+        self.engine.store_rotation(Some(bytes))
+    }
+}
+
+impl<K, V, E> ReadEngine<K, V> for Encrypted<E>
+    where E: KVStoreEngine<K, Vec<u8>>, V: DeserializeOwned
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        let plaintext = self.envelope(key)?.open(&self.keyring)?;
+        Ok(f(&self.codec.deserialize(&plaintext)?))
+    }
+}
+
+impl<K, V, E> WriteEngine<K, V> for Encrypted<E>
+    where E: KVStoreEngine<K, Vec<u8>>, V: Serialize
+{
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        let envelope = Envelope::seal(&self.codec.serialize(&value)?, &self.keyring)?;
+        self.engine.set(key, self.codec.serialize(&envelope)?)
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + Hash + ?Sized + 'static
+    {
+        self.engine.remove(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use super::*;
+
+    #[test]
+    fn test_envelope_lazy_rotation() -> Result<()> {
+        let path = Path::new("data").join("test_envelope.db");
+        let bptree: BPTree<u64, Vec<u8>> = BPTree::new(path, Some(8))?;
+        let mut store = Encrypted::new(bptree, Keyring::new(1, [1; 32]));
+        for i in 0..10u64 {
+            store.set(i, format!("value {}", i))?;
+        }
+        let raw = store.engine.get(&3)?;
+        assert!(!raw.windows(7).any(|window| window == b"value 3"));
+
+        store.keyring_mut().add(2, [2; 32]);
+        store.keyring_mut().set_current(2)?;
+        store.set(3, String::from("rewritten"))?;
+        assert_eq!(store.master_key_of(&3)?, 2);
+        assert_eq!(store.master_key_of(&4)?, 1);
+        assert_eq!(ReadEngine::<u64, String>::get(&store, &4)?, "value 4");
+        assert_eq!(ReadEngine::<u64, String>::get(&store, &3)?, "rewritten");
+
+        store.keyring_mut().remove(1);
+        assert!(matches!(ReadEngine::<u64, String>::get(&store, &4), Err(Error::UnknownMasterKey(1))));
+        assert_eq!(ReadEngine::<u64, String>::get(&store, &3)?, "rewritten");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_master_key() -> Result<()> {
+        let path = Path::new("data").join("test_rotation.db");
+        let bptree: BPTree<u64, Vec<u8>> = BPTree::new(path, Some(8))?;
+        let mut store = Encrypted::new(bptree, Keyring::new(1, [1; 32]));
+        for i in 0..100u64 {
+            store.set(i, i)?;
+        }
+        store.keyring_mut().add(2, [2; 32]);
+        store.start_rotation(1, 2)?;
+        assert_eq!(store.rotation_step(30)?, (30, false));
+        assert_eq!(store.pending_rotation()?, Some(Rotation{ old: 1, new: 2, after: Some(29) }));
+        store.set(50, 500u64)?;
+        assert_eq!(store.master_key_of(&50)?, 2);
+
+        // Records written meanwhile are already on the new key and are skipped.
+        assert_eq!(store.resume_rotation()?, 69);
+        assert_eq!(store.pending_rotation()?, None);
+        store.keyring_mut().remove(1);
+        assert_eq!(ReadEngine::<u64, u64>::get(&store, &99)?, 99);
+        assert_eq!(ReadEngine::<u64, u64>::get(&store, &50)?, 500);
+        assert_eq!(store.rotate_master_key(2, 2)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_rotation_after_reopen() -> Result<()> {
+        let path = Path::new("data").join("test_rotation_reopen.db");
+        let bptree: BPTree<u64, Vec<u8>> = BPTree::new(&path, Some(8))?;
+        let mut store = Encrypted::new(bptree, Keyring::new(1, [1; 32]));
+        for i in 0..100u64 {
+            store.set(i, i)?;
+        }
+        store.keyring_mut().add(2, [2; 32]);
+        store.start_rotation(1, 2)?;
+        assert_eq!(store.rotation_step(40)?, (40, false));
+        // Dropped without a close: only what the last batch flushed is in the file.
+        drop(store);
+
+        let mut keyring = Keyring::new(1, [1; 32]);
+        keyring.add(2, [2; 32]);
+        let mut store = Encrypted::new(BPTree::<u64, Vec<u8>>::open(&path)?, keyring);
+        assert_eq!(store.pending_rotation()?, Some(Rotation{ old: 1, new: 2, after: Some(39) }));
+        assert_eq!(store.master_key_of(&39)?, 2);
+        assert_eq!(store.resume_rotation()?, 60);
+        assert_eq!(store.keyring().current(), 2);
+        store.keyring_mut().remove(1);
+        for i in 0..100u64 {
+            assert_eq!(ReadEngine::<u64, u64>::get(&store, &i)?, i);
+        }
+        store.into_inner().close()?;
+        assert_eq!(Encrypted::new(BPTree::<u64, Vec<u8>>::open(&path)?, Keyring::new(2, [2; 32])).pending_rotation()?, None);
+        Ok(())
+    }
+}
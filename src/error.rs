@@ -2,6 +2,7 @@ use thiserror::Error;
 use bincode;
 use std::io;
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error{
     #[error("Key not found")]
     KeyNotFound,
@@ -16,7 +17,71 @@ pub enum Error{
     #[error("Unexpected node type")]
     UnkonwNodeType,
     #[error("roo page ptr is null ")]
-    RootPageIsNull
+    RootPageIsNull,
+    #[error("Page data is corrupted")]
+    CorruptedPage,
+    #[error("Bulk load requires an empty tree")]
+    TreeNotEmpty,
+    #[error("Bulk load input must be sorted by key without duplicates")]
+    UnsortedInput,
+    #[error("Version mismatch: expected {expected}, found {actual}")]
+    VersionMismatch { expected: u64, actual: u64 },
+    #[error("Write would exceed the quota of a key prefix")]
+    QuotaExceeded,
+    #[error("The tree was created without a change log")]
+    ChangeLogDisabled,
+    #[error("The change log does not reach back to the tree's creation")]
+    ChangeLogIncomplete,
+    #[error("Sink error: {0}")]
+    SinkError(String),
+    #[error("Value could not be decrypted")]
+    DecryptionFailed,
+    #[error("Unknown master key {0}")]
+    UnknownMasterKey(u32),
+    #[error("A write panicked and the tree could not be rebuilt from its change log")]
+    Poisoned,
+    #[error("No tree named {0}")]
+    TreeNotFound(String),
+    #[error("A tree named {0} already exists")]
+    TreeExists(String),
+    #[error("Sketches built with different parameters can't be merged")]
+    IncompatibleSketches,
+    #[error("The key range is quarantined because pages holding it are corrupt")]
+    RangeUnavailable,
+    #[error("The key already exists and the tree is write-once")]
+    AlreadyExists,
+    #[error("Counter would overflow")]
+    CounterOverflow,
+    #[error("Not a KVStore file")]
+    NotADatabase,
+    #[error("The file was written with another format version or key/value types")]
+    IncompatibleFormat,
+    #[error("Encoded keys are limited to {0} bytes")]
+    KeyTooLarge(u64),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Malformed CSV: {0}")]
+    Csv(String),
+    #[error("Background compaction failed: {0}")]
+    CompactionFailed(String),
+    #[error("Page compressed with codec {0}, which this build was compiled without")]
+    UnsupportedCompression(u8),
+    #[error("The tree was created with comparator {0:?}")]
+    ComparatorMismatch(Option<String>),
+    #[error("A tree with a comparator is looked up by its key type, a string or bytes")]
+    UnsupportedLookupType,
+    #[error("Page size {0} is not a power of two from 4096 to 65536")]
+    UnsupportedPageSize(usize),
+    #[error("The tree was created with {0}-byte pages")]
+    PageSizeMismatch(usize),
+    #[error("Not possible in a file with column families")]
+    HasColumnFamilies,
+    #[error("Column family {0} is the one in use")]
+    ColumnFamilyInUse(String),
+    #[error("No merge operator is set")]
+    NoMergeOperator,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
\ No newline at end of file
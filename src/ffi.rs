@@ -0,0 +1,208 @@
+// C ABI over a tree of byte keys and byte values, built with the `ffi` feature.
+//
+// Handles are opaque pointers from kvstore_open() that must be passed to kvstore_close()
+// exactly once; closing flushes the tree, so the next kvstore_open() finds it as it was. Buffers filled in by the library must be released with
+// kvstore_buffer_free(). Key and value pointers may be null only when their length is 0.
+// Every call returns KVSTORE_OK, KVSTORE_NOT_FOUND or KVSTORE_ERROR; panics are caught and
+// reported as errors rather than unwinding into the caller.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use crate::engine::bptree::{BPTree, Options};
+use crate::engine::iter::RangeIter;
+use crate::error::{Error, Result};
+
+pub const KVSTORE_OK: c_int = 0;
+pub const KVSTORE_NOT_FOUND: c_int = 1;
+pub const KVSTORE_ERROR: c_int = -1;
+
+pub struct KvStore {
+    tree: BPTree<Vec<u8>, Vec<u8>>,
+}
+
+#[repr(C)]
+pub struct KvBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl KvBuffer {
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        let mut bytes = std::mem::ManuallyDrop::new(bytes);
+        Self{ data: bytes.as_mut_ptr(), len: bytes.len(), capacity: bytes.capacity() }
+    }
+}
+
+// Called with each entry of a scan; returning anything but 0 stops the scan.
+pub type KvScanCallback = extern "C" fn(context: *mut c_void, key: *const u8, key_len: usize, value: *const u8, value_len: usize) -> c_int;
+
+fn status(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => KVSTORE_OK,
+        Err(Error::KeyNotFound) => KVSTORE_NOT_FOUND,
+        Err(_) => KVSTORE_ERROR,
+    }
+}
+
+fn guarded<F: FnOnce() -> Result<()>>(f: F) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_or(KVSTORE_ERROR, status)
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    match len {
+        0 => &[],
+        _ => slice::from_raw_parts(data, len),
+    }
+}
+
+// Opens the tree at `path` (a NUL-terminated UTF-8 string), creating it when there is no
+// file there yet, or returns null.
+#[no_mangle]
+pub unsafe extern "C" fn kvstore_open(path: *const c_char) -> *mut KvStore {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+    let opened = panic::catch_unwind(|| {
+        let path = CStr::from_ptr(path).to_str().ok()?;
+        BPTree::open_or_create(path, Options::default()).ok()
+    });
+    match opened {
+        Ok(Some(tree)) => Box::into_raw(Box::new(KvStore{ tree })),
+        _ => ptr::null_mut(),
+    }
+}
+
+// Flushes and frees the store. The handle is gone even when the flush fails.
+#[no_mangle]
+pub unsafe extern "C" fn kvstore_close(store: *mut KvStore) -> c_int {
+    if store.is_null() {
+        return KVSTORE_ERROR;
+    }
+    let store = Box::from_raw(store);
+    guarded(move || store.tree.close())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn kvstore_get(store: *const KvStore, key: *const u8, key_len: usize, value: *mut KvBuffer) -> c_int {
+    if store.is_null() || value.is_null() {
+        return KVSTORE_ERROR;
+    }
+    guarded(|| {
+        let found = (*store).tree.get(bytes(key, key_len))?;
+        *value = KvBuffer::from_vec(found);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn kvstore_set(store: *mut KvStore, key: *const u8, key_len: usize, value: *const u8, value_len: usize) -> c_int {
+    if store.is_null() {
+        return KVSTORE_ERROR;
+    }
+    guarded(|| (*store).tree.set(bytes(key, key_len).to_vec(), bytes(value, value_len).to_vec()).map(|_| ()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn kvstore_remove(store: *mut KvStore, key: *const u8, key_len: usize) -> c_int {
+    if store.is_null() {
+        return KVSTORE_ERROR;
+    }
+    guarded(|| (*store).tree.remove(bytes(key, key_len)).map(|_| ()))
+}
+
+// Calls `callback` for every entry with start <= key < end in key order. A null `end`
+// scans to the last key.
+#[no_mangle]
+pub unsafe extern "C" fn kvstore_scan(
+    store: *const KvStore,
+    start: *const u8,
+    start_len: usize,
+    end: *const u8,
+    end_len: usize,
+    callback: KvScanCallback,
+    context: *mut c_void,
+) -> c_int {
+    if store.is_null() {
+        return KVSTORE_ERROR;
+    }
+    guarded(|| {
+        let start = std::ops::Bound::Included(bytes(start, start_len).to_vec());
+        let end = match end.is_null() {
+            true => std::ops::Bound::Unbounded,
+            false => std::ops::Bound::Excluded(bytes(end, end_len).to_vec()),
+        };
+        for entry in RangeIter::new(&(*store).tree, start, end)? {
+            let (key, value) = entry?;
+            if callback(context, key.as_ptr(), key.len(), value.as_ptr(), value.len()) != 0 {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn kvstore_buffer_free(buffer: *mut KvBuffer) {
+    if buffer.is_null() || (*buffer).data.is_null() {
+        return;
+    }
+    let buffer = &mut *buffer;
+    drop(Vec::from_raw_parts(buffer.data, buffer.len, buffer.capacity));
+    buffer.data = ptr::null_mut();
+    buffer.len = 0;
+    buffer.capacity = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use super::*;
+
+    extern "C" fn collect(context: *mut c_void, key: *const u8, key_len: usize, _: *const u8, _: usize) -> c_int {
+        let keys = unsafe { &mut *(context as *mut Vec<Vec<u8>>) };
+        keys.push(unsafe { bytes(key, key_len) }.to_vec());
+        (keys.len() == 3) as c_int
+    }
+
+    #[test]
+    fn test_c_abi_round_trip() {
+        unsafe {
+            let _ = std::fs::remove_file("data/test_ffi.db");
+            let path = CString::new("data/test_ffi.db").unwrap();
+            let store = kvstore_open(path.as_ptr());
+            assert!(!store.is_null());
+            for i in 0..10u8 {
+                let key = [b'k', i];
+                assert_eq!(kvstore_set(store, key.as_ptr(), key.len(), [i; 3].as_ptr(), 3), KVSTORE_OK);
+            }
+            let mut value = KvBuffer{ data: ptr::null_mut(), len: 0, capacity: 0 };
+            assert_eq!(kvstore_get(store, [b'k', 4].as_ptr(), 2, &mut value), KVSTORE_OK);
+            assert_eq!(slice::from_raw_parts(value.data, value.len), &[4, 4, 4]);
+            kvstore_buffer_free(&mut value);
+            assert!(value.data.is_null());
+
+            assert_eq!(kvstore_remove(store, [b'k', 4].as_ptr(), 2), KVSTORE_OK);
+            assert_eq!(kvstore_remove(store, [b'k', 4].as_ptr(), 2), KVSTORE_NOT_FOUND);
+            assert_eq!(kvstore_get(store, [b'k', 4].as_ptr(), 2, &mut value), KVSTORE_NOT_FOUND);
+
+            let mut keys: Vec<Vec<u8>> = Vec::new();
+            let context = &mut keys as *mut Vec<Vec<u8>> as *mut c_void;
+            assert_eq!(kvstore_scan(store, [b'k', 3].as_ptr(), 2, ptr::null(), 0, collect, context), KVSTORE_OK);
+            assert_eq!(keys, vec![vec![b'k', 3], vec![b'k', 5], vec![b'k', 6]]);
+            assert_eq!(kvstore_close(store), KVSTORE_OK);
+
+            let store = kvstore_open(path.as_ptr());
+            assert!(!store.is_null());
+            assert_eq!(kvstore_get(store, [b'k', 9].as_ptr(), 2, &mut value), KVSTORE_OK);
+            assert_eq!(slice::from_raw_parts(value.data, value.len), &[9, 9, 9]);
+            kvstore_buffer_free(&mut value);
+            assert_eq!(kvstore_get(store, [b'k', 4].as_ptr(), 2, &mut value), KVSTORE_NOT_FOUND);
+            assert_eq!(kvstore_close(store), KVSTORE_OK);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::borrow::Borrow;
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::{ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Term(String),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+impl Query {
+    pub fn term(term: &str) -> Self {
+        Query::Term(term.to_string())
+    }
+}
+
+// Distinct lowercased runs of alphanumeric characters.
+pub fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// A tree of text values with an inverted index in a companion tree, which maps every term
+// to the sorted keys whose value contains it. Both trees are only kept in step when written
+// through this wrapper.
+pub struct FullText<K> {
+    tree: BPTree<K, String>,
+    index: BPTree<String, Vec<K>>,
+}
+
+impl<K> FullText<K>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    // Indexes whatever `tree` already holds; the index tree must start out empty.
+    pub fn new(tree: BPTree<K, String>, index: BPTree<String, Vec<K>>) -> Result<Self> {
+        if !index.is_empty() {
+            return Err(Error::TreeNotEmpty);
+        }
+        let entries = tree.iter_snapshot()?.collect::<Result<Vec<_>>>()?;
+        let mut text = Self{ tree, index };
+        for (key, value) in entries {
+            for term in tokenize(&value) {
+                text.add_posting(term, &key)?;
+            }
+        }
+        Ok(text)
+    }
+
+    pub fn tree(&self) -> &BPTree<K, String> {
+        &self.tree
+    }
+
+    pub fn index(&self) -> &BPTree<String, Vec<K>> {
+        &self.index
+    }
+
+    pub fn into_inner(self) -> (BPTree<K, String>, BPTree<String, Vec<K>>) {
+        (self.tree, self.index)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Result<String>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.tree.get(key)
+    }
+
+    pub fn set(&mut self, key: K, value: String) -> Result<u64> {
+        let old = match self.tree.get(&key) {
+            Ok(old) => tokenize(&old),
+            Err(Error::KeyNotFound) => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+        let new = tokenize(&value);
+        let version = self.tree.set(key.clone(), value)?;
+        for term in old.difference(&new) {
+            self.remove_posting(term, &key)?;
+        }
+        for term in new.difference(&old) {
+            self.add_posting(term.clone(), &key)?;
+        }
+        Ok(version)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        let old = self.tree.get(key)?;
+        let version = self.tree.remove(key)?;
+        for term in tokenize(&old) {
+            self.remove_posting(&term, key)?;
+        }
+        Ok(version)
+    }
+
+    // Keys whose value contains the term, in key order.
+    pub fn search(&self, term: &str) -> Result<Vec<K>> {
+        match self.index.get(term.to_lowercase().as_str()) {
+            Ok(keys) => Ok(keys),
+            Err(Error::KeyNotFound) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn query(&self, query: &Query) -> Result<Vec<K>> {
+        match query {
+            Query::Term(term) => self.search(term),
+            Query::And(queries) => {
+                let mut queries = queries.iter();
+                let mut keys = match queries.next() {
+                    Some(query) => self.query(query)?,
+                    None => return Ok(Vec::new()),
+                };
+                for query in queries {
+                    if keys.is_empty() {
+                        break;
+                    }
+                    let other = self.query(query)?;
+                    keys.retain(|key| other.binary_search(key).is_ok());
+                }
+                Ok(keys)
+            }
+            Query::Or(queries) => {
+                let mut keys = BTreeSet::new();
+                for query in queries {
+                    keys.extend(self.query(query)?);
+                }
+                Ok(keys.into_iter().collect())
+            }
+        }
+    }
+
+    fn add_posting(&mut self, term: String, key: &K) -> Result<()> {
+        let mut keys = self.search(&term)?;
+        if let Err(i) = keys.binary_search(key) {
+            keys.insert(i, key.clone());
+            self.index.set(term, keys)?;
+        }
+        Ok(())
+    }
+
+    fn remove_posting<Q>(&mut self, term: &str, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        let mut keys = self.search(term)?;
+        if let Ok(i) = keys.binary_search_by(|other| other.borrow().cmp(key)) {
+            keys.remove(i);
+            match keys.is_empty() {
+                true => self.index.remove(term)?,
+                false => self.index.set(term.to_string(), keys)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<K> ReadEngine<K, String> for FullText<K>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&String) -> R
+    {
+        self.tree.get_with(key, f)
+    }
+}
+
+impl<K> WriteEngine<K, String> for FullText<K>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn set(&mut self, key: K, value: String) -> Result<()> {
+        FullText::set(self, key, value).map(|_| ())
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        FullText::remove(self, key).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_search_and_boolean_queries() -> Result<()> {
+        let mut tree: BPTree<u64, String> = BPTree::new(Path::new("data").join("test_fulltext.db"), Some(4))?;
+        tree.set(1, "The quick brown fox".to_string())?;
+        let index = BPTree::new(Path::new("data").join("test_fulltext_index.db"), Some(4))?;
+        let mut text = FullText::new(tree, index)?;
+        text.set(2, "A quick brown dog, a lazy dog".to_string())?;
+        text.set(3, "Lazy foxes sleep".to_string())?;
+
+        assert_eq!(text.search("QUICK")?, vec![1, 2]);
+        assert_eq!(text.search("dog")?, vec![2]);
+        assert_eq!(text.search("cat")?, Vec::<u64>::new());
+        assert_eq!(text.query(&Query::And(vec![Query::term("brown"), Query::term("lazy")]))?, vec![2]);
+        assert_eq!(text.query(&Query::Or(vec![Query::term("fox"), Query::term("foxes")]))?, vec![1, 3]);
+        assert_eq!(text.query(&Query::And(vec![
+            Query::term("lazy"),
+            Query::Or(vec![Query::term("dog"), Query::term("sleep")]),
+        ]))?, vec![2, 3]);
+
+        // Rewriting a value moves its key between terms; removing it leaves no trace.
+        text.set(2, "A slow brown dog".to_string())?;
+        assert_eq!(text.search("quick")?, vec![1]);
+        assert_eq!(text.search("slow")?, vec![2]);
+        text.remove(&2)?;
+        assert_eq!(text.search("brown")?, vec![1]);
+        assert!(matches!(text.index().get("dog"), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+}
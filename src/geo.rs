@@ -0,0 +1,214 @@
+use std::fmt::Debug;
+use std::ops::RangeInclusive;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::error::Result;
+
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+// Ranges a bounding box is decomposed into by query_bbox().
+pub const MAX_BBOX_RANGES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl BBox {
+    // A box with min_lon > max_lon crosses the antimeridian.
+    pub fn new(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Self {
+        Self{ min_lat, min_lon, max_lat, max_lon }
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        let lon_inside = match self.min_lon <= self.max_lon {
+            true => self.min_lon <= lon && lon <= self.max_lon,
+            false => self.min_lon <= lon || lon <= self.max_lon,
+        };
+        self.min_lat <= lat && lat <= self.max_lat && lon_inside
+    }
+}
+
+fn quantize(value: f64, min: f64, max: f64) -> u32 {
+    let scaled = (value.clamp(min, max) - min) / (max - min) * 4294967296.0;
+    scaled.min(u32::MAX as f64) as u32
+}
+
+fn dequantize(value: u32, min: f64, max: f64) -> f64 {
+    // Middle of the cell.
+    min + (value as f64 + 0.5) / 4294967296.0 * (max - min)
+}
+
+// Longitude bits go first, as in a geohash.
+fn interleave(lon: u32, lat: u32) -> u64 {
+    (spread(lon) << 1) | spread(lat)
+}
+
+fn spread(value: u32) -> u64 {
+    let mut x = value as u64;
+    x = (x | (x << 16)) & 0x0000ffff0000ffff;
+    x = (x | (x << 8)) & 0x00ff00ff00ff00ff;
+    x = (x | (x << 4)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x << 2)) & 0x3333333333333333;
+    (x | (x << 1)) & 0x5555555555555555
+}
+
+fn compact(value: u64) -> u32 {
+    let mut x = value & 0x5555555555555555;
+    x = (x | (x >> 1)) & 0x3333333333333333;
+    x = (x | (x >> 2)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x >> 4)) & 0x00ff00ff00ff00ff;
+    x = (x | (x >> 8)) & 0x0000ffff0000ffff;
+    (x | (x >> 16)) as u32
+}
+
+// Z-order key of a point: nearby points mostly get nearby keys, and every quadtree cell is
+// one contiguous key range. The resolution is about a centimeter.
+pub fn encode(lat: f64, lon: f64) -> u64 {
+    interleave(quantize(lon, -180.0, 180.0), quantize(lat, -90.0, 90.0))
+}
+
+// Center of the cell the key stands for, as (lat, lon).
+pub fn decode(key: u64) -> (f64, f64) {
+    (dequantize(compact(key), -90.0, 90.0), dequantize(compact(key >> 1), -180.0, 180.0))
+}
+
+// The standard base32 geohash of a point, which is a prefix of its Z-order key.
+pub fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    assert!(precision <= 12);
+    let key = encode(lat, lon);
+    (0..precision)
+        .map(|i| GEOHASH_ALPHABET[(key >> (59 - 5 * i) & 0x1f) as usize] as char)
+        .collect()
+}
+
+// Key ranges that together hold every point in the box, at most `max_ranges` of them (but
+// at least one per side of the antimeridian). The quadtree is refined level by level while
+// the budget allows; the cells on the edge of the box at the last level also hold points
+// outside of it.
+pub fn bbox_ranges(bbox: &BBox, max_ranges: usize) -> Vec<RangeInclusive<u64>> {
+    let lat = (quantize(bbox.min_lat, -90.0, 90.0), quantize(bbox.max_lat, -90.0, 90.0));
+    let mut ranges = match bbox.min_lon <= bbox.max_lon {
+        true => cover((quantize(bbox.min_lon, -180.0, 180.0), quantize(bbox.max_lon, -180.0, 180.0)), lat, max_ranges),
+        false => {
+            let budget = (max_ranges / 2).max(1);
+            let mut ranges = cover((quantize(bbox.min_lon, -180.0, 180.0), u32::MAX), lat, budget);
+            ranges.extend(cover((0, quantize(bbox.max_lon, -180.0, 180.0)), lat, budget));
+            ranges
+        }
+    };
+    ranges.sort_by_key(|range| *range.start());
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.end().checked_add(1) == Some(*range.start()) => *last = *last.start()..=*range.end(),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// Key ranges of the quadtree cells covering the quantized box.
+fn cover(lon: (u32, u32), lat: (u32, u32), max_ranges: usize) -> Vec<RangeInclusive<u64>> {
+    let span = |cell: u32, level: u32| -> (u32, u32) {
+        let shift = 32 - level;
+        let start = ((cell as u64) << shift) as u32;
+        (start, (((cell as u64 + 1) << shift) - 1) as u32)
+    };
+    let mut full = Vec::new();
+    let mut partial = vec![(0u32, 0u32)];
+    let mut level = 0;
+    while level < 32 && !partial.is_empty() {
+        let mut new_full = Vec::new();
+        let mut next = Vec::new();
+        for (cx, cy) in &partial {
+            for (dx, dy) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                let cell = (cx * 2 + dx, cy * 2 + dy);
+                let (x, y) = (span(cell.0, level + 1), span(cell.1, level + 1));
+                if x.1 < lon.0 || x.0 > lon.1 || y.1 < lat.0 || y.0 > lat.1 {
+                    continue;
+                }
+                match lon.0 <= x.0 && x.1 <= lon.1 && lat.0 <= y.0 && y.1 <= lat.1 {
+                    true => new_full.push(cell),
+                    false => next.push(cell),
+                }
+            }
+        }
+        if full.len() + new_full.len() + next.len() > max_ranges {
+            break;
+        }
+        full.extend(new_full.into_iter().map(|cell| (cell, level + 1)));
+        partial = next;
+        level += 1;
+    }
+    full.into_iter()
+        .chain(partial.into_iter().map(|cell| (cell, level)))
+        .map(|((cx, cy), level)| {
+            let shift = 64 - 2 * level;
+            let prefix = interleave(cx, cy);
+            let start = prefix.checked_shl(shift).unwrap_or(0);
+            let len = 1u64.checked_shl(shift).map_or(u64::MAX, |len| len - 1);
+            start..=start + len
+        })
+        .collect()
+}
+
+// Every entry whose point lies inside the box, in key order. Points are compared by the
+// center of their key's cell, so one within a centimeter of an edge may land either way.
+pub fn query_bbox<V>(bptree: &BPTree<u64, V>, bbox: &BBox) -> Result<Vec<(u64, V)>>
+    where V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    let mut found = Vec::new();
+    for range in bbox_ranges(bbox, MAX_BBOX_RANGES) {
+        for cursor in bptree.par_range(range, 1)? {
+            for entry in cursor {
+                let (key, value) = entry?;
+                let (lat, lon) = decode(key);
+                if bbox.contains(lat, lon) {
+                    found.push((key, value));
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        let (lat, lon) = decode(encode(-33.8688, 151.2093));
+        assert!((lat + 33.8688).abs() < 1e-6 && (lon - 151.2093).abs() < 1e-6);
+        assert!(encode(10.0, 10.0) < encode(10.0, 100.0));
+    }
+
+    #[test]
+    fn test_query_bbox() -> Result<()> {
+        let mut bptree: BPTree<u64, u32> = BPTree::new(Path::new("data").join("test_geo.db"), None)?;
+        let mut points = Vec::new();
+        for i in 0..60 {
+            for j in 0..60 {
+                let (lat, lon) = (-89.0 + i as f64 * 3.0, -179.0 + j as f64 * 6.0);
+                bptree.set(encode(lat, lon), i * 100 + j)?;
+                points.push((lat, lon, i * 100 + j));
+            }
+        }
+        for bbox in [BBox::new(10.5, -20.0, 40.5, 35.5), BBox::new(-50.5, 150.0, -20.5, -160.0)] {
+            let ranges = bbox_ranges(&bbox, MAX_BBOX_RANGES);
+            assert!(ranges.len() <= MAX_BBOX_RANGES);
+            let mut expected: Vec<u32> = points.iter().filter(|(lat, lon, _)| bbox.contains(*lat, *lon)).map(|point| point.2).collect();
+            let mut found: Vec<u32> = query_bbox(&bptree, &bbox)?.into_iter().map(|(_, value)| value).collect();
+            expected.sort_unstable();
+            found.sort_unstable();
+            assert!(!expected.is_empty());
+            assert_eq!(found, expected);
+        }
+        Ok(())
+    }
+}
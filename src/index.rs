@@ -0,0 +1,238 @@
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::{ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+
+// The index tree: one entry per indexed key, `(field, Some(key))`, so a write touches one
+// entry however many keys share its field. None sorts before every key, which makes
+// `(field, None)` where the entries of `field` start.
+pub type Postings<I, K> = BPTree<(I, Option<K>), ()>;
+
+// A tree with a secondary index in a companion tree, which lists every key under the field
+// extracted from its value. Writes go to the index before the tree and leave it after, so
+// whichever write fails, the index still lists every key under its field and at worst some
+// key under a field it no longer has. Lookups skip those, and repair() drops them.
+pub struct SecondaryIndex<K, V, I> {
+    tree: BPTree<K, V>,
+    index: Postings<I, K>,
+    extract: Box<dyn Fn(&V) -> I + Send + Sync>,
+}
+
+impl<K, V, I> SecondaryIndex<K, V, I>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          I: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    // Indexes whatever `tree` holds when `index` is empty; otherwise `index` is taken to be
+    // the one an earlier SecondaryIndex kept for `tree` with the same `extract`.
+    pub fn new<F>(tree: BPTree<K, V>, index: Postings<I, K>, extract: F) -> Result<Self>
+        where F: Fn(&V) -> I + Send + Sync + 'static
+    {
+        let rebuild = index.is_empty();
+        let mut indexed = Self{ tree, index, extract: Box::new(extract) };
+        if rebuild {
+            let entries = indexed.tree.iter_snapshot()?.collect::<Result<Vec<_>>>()?;
+            for (key, value) in entries {
+                let field = (indexed.extract)(&value);
+                indexed.index.set((field, Some(key)), ())?;
+            }
+        }
+        Ok(indexed)
+    }
+
+    pub fn tree(&self) -> &BPTree<K, V> {
+        &self.tree
+    }
+
+    pub fn index(&self) -> &Postings<I, K> {
+        &self.index
+    }
+
+    pub fn into_inner(self) -> (BPTree<K, V>, Postings<I, K>) {
+        (self.tree, self.index)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Result<V>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.tree.get(key)
+    }
+
+    pub fn set(&mut self, key: K, value: V) -> Result<u64> {
+        let old = match self.tree.get(&key) {
+            Ok(old) => Some((self.extract)(&old)),
+            Err(Error::KeyNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let new = (self.extract)(&value);
+        if old.as_ref() != Some(&new) {
+            self.index.set((new.clone(), Some(key.clone())), ())?;
+        }
+        let version = self.tree.set(key.clone(), value)?;
+        match old {
+            Some(old) if old != new => self.remove_posting(old, key)?,
+            _ => (),
+        }
+        Ok(version)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        // The index entry is made of the key as stored, not `key`.
+        let (stored, old) = {
+            let mut cursor = self.tree.cursor()?;
+            cursor.seek(key)?;
+            match cursor.current()? {
+                Some((stored, old)) if stored.borrow() == key => (stored, old),
+                _ => return Err(Error::KeyNotFound),
+            }
+        };
+        let version = self.tree.remove(key)?;
+        self.remove_posting((self.extract)(&old), stored)?;
+        Ok(version)
+    }
+
+    // Entries whose value has `field`, in key order.
+    pub fn get_by_index(&self, field: &I) -> Result<Vec<(K, V)>> {
+        self.scan_by_index((Bound::Included(field.clone()), Bound::Included(field.clone())))
+    }
+
+    // Entries whose field is in `range`, by field and then by key.
+    pub fn scan_by_index<R: RangeBounds<I>>(&self, range: R) -> Result<Vec<(K, V)>> {
+        let start = match range.start_bound() {
+            Bound::Included(field) | Bound::Excluded(field) => Bound::Included((field.clone(), None)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut entries = Vec::new();
+        for posting in self.index.range((start, Bound::Unbounded))?.keys() {
+            let (field, key) = posting?;
+            let past_end = match range.end_bound() {
+                Bound::Included(end) => field > *end,
+                Bound::Excluded(end) => field >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                break;
+            }
+            if let (true, Some(key)) = (range.contains(&field), key) {
+                entries.extend(self.entry(&field, key)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    // Drops the keys the index lists under a field their value no longer has, which a
+    // failed write can leave behind. Returns how many there were.
+    pub fn repair(&mut self) -> Result<u64> {
+        let postings = self.index.iter_snapshot()?.map(|posting| posting.map(|(posting, _)| posting)).collect::<Result<Vec<_>>>()?;
+        let mut dropped = 0;
+        for (field, key) in postings {
+            let live = match &key {
+                Some(key) => self.entry(&field, key.clone())?.is_some(),
+                None => false,
+            };
+            if !live {
+                self.index.remove(&(field, key))?;
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+
+    // The entry of `key` if it is still there and still has `field`.
+    fn entry(&self, field: &I, key: K) -> Result<Option<(K, V)>> {
+        match self.tree.get(&key) {
+            Ok(value) if (self.extract)(&value) == *field => Ok(Some((key, value))),
+            Ok(_) | Err(Error::KeyNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove_posting(&mut self, field: I, key: K) -> Result<()> {
+        match self.index.remove(&(field, Some(key))) {
+            Ok(_) | Err(Error::KeyNotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<K, V, I> ReadEngine<K, V> for SecondaryIndex<K, V, I>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          I: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        self.tree.get_with(key, f)
+    }
+}
+
+impl<K, V, I> WriteEngine<K, V> for SecondaryIndex<K, V, I>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          I: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        SecondaryIndex::set(self, key, value).map(|_| ())
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        SecondaryIndex::remove(self, key).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    type User = (String, String);
+
+    fn user(name: &str, city: &str) -> User {
+        (name.to_string(), city.to_string())
+    }
+
+    #[test]
+    fn test_index_follows_writes() -> Result<()> {
+        let mut tree: BPTree<u64, User> = BPTree::new(Path::new("data").join("test_index.db"), Some(4))?;
+        tree.set(1, user("ada", "london"))?;
+        tree.set(2, user("alan", "london"))?;
+        let index = BPTree::new(Path::new("data").join("test_index_by_city.db"), Some(4))?;
+        let mut users = SecondaryIndex::new(tree, index, |user: &User| user.1.clone())?;
+        users.set(3, user("grace", "new york"))?;
+        users.set(4, user("edsger", "amsterdam"))?;
+
+        assert_eq!(users.get_by_index(&"london".to_string())?, vec![(1, user("ada", "london")), (2, user("alan", "london"))]);
+        users.set(2, user("alan", "manchester"))?;
+        users.remove(&1)?;
+        assert!(users.get_by_index(&"london".to_string())?.is_empty());
+        let postings = users.index().iter_snapshot()?.map(|posting| posting.map(|(posting, _)| posting)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(postings, vec![
+            ("amsterdam".to_string(), Some(4)),
+            ("manchester".to_string(), Some(2)),
+            ("new york".to_string(), Some(3)),
+        ]);
+        let keys: Vec<u64> = users.scan_by_index("b".to_string().."n".to_string())?.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![2]);
+        let keys: Vec<u64> = users.scan_by_index((Bound::Excluded("manchester".to_string()), Bound::Unbounded))?.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![3]);
+
+        // A write that failed between the index and the tree leaves a stale posting behind.
+        let (tree, mut index) = users.into_inner();
+        index.set(("paris".to_string(), Some(2)), ())?;
+        index.set(("paris".to_string(), Some(3)), ())?;
+        let mut users = SecondaryIndex::new(tree, index, |user: &User| user.1.clone())?;
+        assert!(users.get_by_index(&"paris".to_string())?.is_empty());
+        assert_eq!(users.scan_by_index(..)?.len(), 3);
+        assert_eq!(users.repair()?, 2);
+        assert_eq!(users.index().iter_snapshot()?.count(), 3);
+        Ok(())
+    }
+}
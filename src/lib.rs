@@ -1,5 +1,31 @@
-mod engine;
-mod error;
+#[cfg(feature = "tokio")]
+pub mod async_store;
+pub mod bitmap;
+pub mod blobs;
+pub mod db;
+pub mod engine;
+pub mod envelope;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fulltext;
+pub mod geo;
+pub mod index;
+#[cfg(feature = "node")]
+pub mod node;
+pub mod partitioned;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod resp;
+#[cfg(feature = "sessions")]
+pub mod session;
+pub mod shadow;
+pub mod shared;
+pub mod sink;
+pub mod sketch;
+pub mod standby;
+#[cfg(feature = "streams")]
+pub mod stream;
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,210 @@
+// Node.js bindings, built with the `node` feature. Every call returns a Promise; the work
+// runs on the libuv thread pool against a Shared tree, so reads from several pending calls
+// proceed in parallel and writes are applied one at a time.
+use std::ops::Bound;
+use napi::bindgen_prelude::{AsyncTask, Buffer};
+use napi::{Env, Task};
+use napi_derive::napi;
+use crate::engine::bptree::Options;
+use crate::engine::iter::RangeIter;
+use crate::error::{Error, Result};
+use crate::shared::Shared;
+
+type Store = Shared<Vec<u8>, Vec<u8>>;
+
+fn js_error(e: Error) -> napi::Error {
+    napi::Error::from_reason(e.to_string())
+}
+
+#[napi(object)]
+pub struct Entry {
+    pub key: Buffer,
+    pub value: Buffer,
+}
+
+#[napi(js_name = "KVStore")]
+pub struct JsKVStore {
+    store: Store,
+}
+
+#[napi]
+impl JsKVStore {
+    // `new KVStore(path)` in JavaScript. Throws if the file is there but isn't a tree.
+    #[napi(constructor)]
+    pub fn new(path: String) -> napi::Result<Self> {
+        Ok(Self{ store: Shared::open_or_create(path, Options::default()).map_err(js_error)? })
+    }
+
+    // Resolves to the value, or null when the key is missing.
+    #[napi]
+    pub fn get(&self, key: Buffer) -> AsyncTask<GetTask> {
+        AsyncTask::new(GetTask{ store: self.store.clone(), key: key.into() })
+    }
+
+    #[napi]
+    pub fn set(&self, key: Buffer, value: Buffer) -> AsyncTask<SetTask> {
+        AsyncTask::new(SetTask{ store: self.store.clone(), key: key.into(), value: value.into() })
+    }
+
+    // Resolves to whether the key was there.
+    #[napi]
+    pub fn remove(&self, key: Buffer) -> AsyncTask<RemoveTask> {
+        AsyncTask::new(RemoveTask{ store: self.store.clone(), key: key.into() })
+    }
+
+    // Resolves to the entries with start <= key < end in key order, at most `limit` of them.
+    #[napi]
+    pub fn scan(&self, start: Option<Buffer>, end: Option<Buffer>, limit: Option<u32>) -> AsyncTask<ScanTask> {
+        AsyncTask::new(ScanTask{
+            store: self.store.clone(),
+            start: start.map(Into::into),
+            end: end.map(Into::into),
+            limit: limit.map_or(usize::MAX, |limit| limit as usize),
+        })
+    }
+
+    // Resolves once the writes so far are in the file, so the next constructor call on the
+    // same path finds them.
+    #[napi]
+    pub fn flush(&self) -> AsyncTask<FlushTask> {
+        AsyncTask::new(FlushTask{ store: self.store.clone() })
+    }
+}
+
+pub struct GetTask {
+    store: Store,
+    key: Vec<u8>,
+}
+
+#[napi]
+impl Task for GetTask {
+    type Output = Option<Vec<u8>>;
+    type JsValue = Option<Buffer>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        match self.store.get(&self.key) {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::KeyNotFound) => Ok(None),
+            Err(e) => Err(js_error(e)),
+        }
+    }
+
+    fn resolve(&mut self, _: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output.map(Buffer::from))
+    }
+}
+
+pub struct SetTask {
+    store: Store,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+#[napi]
+impl Task for SetTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let (key, value) = (std::mem::take(&mut self.key), std::mem::take(&mut self.value));
+        self.store.set(key, value).map(|_| ()).map_err(js_error)
+    }
+
+    fn resolve(&mut self, _: Env, _: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(())
+    }
+}
+
+pub struct RemoveTask {
+    store: Store,
+    key: Vec<u8>,
+}
+
+#[napi]
+impl Task for RemoveTask {
+    type Output = bool;
+    type JsValue = bool;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        match self.store.remove(&self.key) {
+            Ok(_) => Ok(true),
+            Err(Error::KeyNotFound) => Ok(false),
+            Err(e) => Err(js_error(e)),
+        }
+    }
+
+    fn resolve(&mut self, _: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+pub struct ScanTask {
+    store: Store,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    limit: usize,
+}
+
+#[napi]
+impl Task for ScanTask {
+    type Output = Vec<(Vec<u8>, Vec<u8>)>;
+    type JsValue = Vec<Entry>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let start = self.start.take().map_or(Bound::Unbounded, Bound::Included);
+        let end = self.end.take().map_or(Bound::Unbounded, Bound::Excluded);
+        let limit = self.limit;
+        self.store.read(|tree| RangeIter::new(tree, start, end)?.take(limit).collect::<Result<Vec<_>>>())
+            .map_err(js_error)
+    }
+
+    fn resolve(&mut self, _: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output.into_iter().map(|(key, value)| Entry{ key: key.into(), value: value.into() }).collect())
+    }
+}
+
+pub struct FlushTask {
+    store: Store,
+}
+
+#[napi]
+impl Task for FlushTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        self.store.flush().map(|_| ()).map_err(js_error)
+    }
+
+    fn resolve(&mut self, _: Env, _: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    // Runs the work of each call the way the thread pool would, without a Node runtime.
+    #[test]
+    fn test_tasks() -> napi::Result<()> {
+        let path = Path::new("data").join("test_node_tasks.db");
+        let store: Store = Shared::create(&path, Options::default()).map_err(js_error)?;
+        for i in 0..20u8 {
+            SetTask{ store: store.clone(), key: vec![b'k', i], value: vec![i] }.compute()?;
+        }
+        assert_eq!(GetTask{ store: store.clone(), key: vec![b'k', 3] }.compute()?, Some(vec![3]));
+        assert!(RemoveTask{ store: store.clone(), key: vec![b'k', 3] }.compute()?);
+        assert!(!RemoveTask{ store: store.clone(), key: vec![b'k', 3] }.compute()?);
+        assert_eq!(GetTask{ store: store.clone(), key: vec![b'k', 3] }.compute()?, None);
+        let scan = ScanTask{ store: store.clone(), start: Some(vec![b'k', 2]), end: Some(vec![b'k', 6]), limit: 3 }.compute()?;
+        assert_eq!(scan.into_iter().map(|(key, _)| key[1]).collect::<Vec<_>>(), vec![2, 4, 5]);
+        FlushTask{ store }.compute()?;
+
+        let reopened = JsKVStore::new(path.to_string_lossy().into_owned())?;
+        assert_eq!(GetTask{ store: reopened.store.clone(), key: vec![b'k', 19] }.compute()?, Some(vec![19]));
+        assert_eq!(GetTask{ store: reopened.store, key: vec![b'k', 3] }.compute()?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::Duration;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::db::Db;
+use crate::engine::bptree::{BPTree, Options};
+use crate::engine::clock;
+use crate::error::{Error, Result};
+
+// Keys that carry the time they belong to, in unix millis.
+pub trait TimeKey {
+    fn millis(&self) -> u64;
+}
+
+impl TimeKey for u64 {
+    fn millis(&self) -> u64 {
+        *self
+    }
+}
+
+impl<T> TimeKey for (u64, T) {
+    fn millis(&self) -> u64 {
+        self.0
+    }
+}
+
+// Spreads a time series over one tree per period, named `<name>@<period index>` in the Db.
+// Retention then never touches individual keys: a partition that is entirely too old is
+// dropped from the catalog as a whole. Partitions are created on their first write.
+pub struct TimePartitioned<K, V> {
+    db: Db,
+    name: String,
+    period: u64,
+    options: Options,
+    partitions: BTreeMap<u64, BPTree<K, V>>,
+}
+
+impl<K, V> TimePartitioned<K, V>
+    where K: TimeKey + Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    pub fn create(db: Db, name: &str, period: Duration, options: Options) -> Result<Self> {
+        assert!(period.as_millis() > 0);
+        let prefix = format!("{}@", name);
+        if let Some(existing) = db.tree_names().find(|tree| tree.starts_with(&prefix)) {
+            return Err(Error::TreeExists(existing.to_string()));
+        }
+        Ok(Self{
+            db,
+            name: name.to_string(),
+            period: period.as_millis() as u64,
+            options,
+            partitions: BTreeMap::new(),
+        })
+    }
+
+    pub fn into_db(self) -> Db {
+        self.db
+    }
+
+    // Start of every partition, oldest first.
+    pub fn partitions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.partitions.keys().map(move |index| index * self.period)
+    }
+
+    pub fn partition(&self, start: u64) -> Option<&BPTree<K, V>> {
+        self.partitions.get(&(start / self.period))
+    }
+
+    pub fn get(&self, key: &K) -> Result<V> {
+        match self.partitions.get(&self.index(key)) {
+            Some(tree) => tree.get(key),
+            None => Err(Error::KeyNotFound),
+        }
+    }
+
+    pub fn set(&mut self, key: K, value: V) -> Result<u64> {
+        let index = self.index(&key);
+        if !self.partitions.contains_key(&index) {
+            let tree = self.db.create_tree(&self.tree_name(index), self.options.clone())?;
+            self.partitions.insert(index, tree);
+        }
+        self.partitions.get_mut(&index).unwrap().set(key, value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<u64> {
+        let index = self.index(key);
+        match self.partitions.get_mut(&index) {
+            Some(tree) => tree.remove(key),
+            None => Err(Error::KeyNotFound),
+        }
+    }
+
+    // Every record, partition by partition; in key order as long as keys sort by time.
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(K, V)>> + '_> {
+        let iters = self.partitions.values().map(|tree| tree.iter_snapshot()).collect::<Result<Vec<_>>>()?;
+        Ok(iters.into_iter().flatten())
+    }
+
+    // Drops every partition that ends at or before `cutoff` and returns how many there were.
+    pub fn drop_before(&mut self, cutoff: u64) -> Result<usize> {
+        let expired: Vec<u64> = self.partitions.keys()
+            .take_while(|index| (*index + 1) * self.period <= cutoff)
+            .copied()
+            .collect();
+        for index in &expired {
+            self.db.drop_tree(&self.tree_name(*index))?;
+            self.partitions.remove(index);
+        }
+        self.db.reclaim()?;
+        Ok(expired.len())
+    }
+
+    // Drops the partitions that hold nothing younger than `retention`, going by the clock of
+    // the options partitions are created with.
+    pub fn expire(&mut self, retention: Duration) -> Result<usize> {
+        let now = clock::or_system(self.options.clock.as_ref()).now();
+        self.drop_before(now.saturating_sub(retention.as_millis() as u64))
+    }
+
+    fn index(&self, key: &K) -> u64 {
+        key.millis() / self.period
+    }
+
+    fn tree_name(&self, index: u64) -> String {
+        format!("{}@{}", self.name, index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_drop_expired_partitions() -> Result<()> {
+        let dir = Path::new("data").join("test_time_partitioned");
+        let _ = fs::remove_dir_all(&dir);
+        let mut series = TimePartitioned::create(Db::open(&dir)?, "metrics", Duration::from_secs(1), Options::default())?;
+        for (i, at) in (0..5000u64).step_by(100).enumerate() {
+            series.set((at, i as u32), i as u64)?;
+        }
+        assert_eq!(series.partitions().collect::<Vec<_>>(), vec![0, 1000, 2000, 3000, 4000]);
+        let oldest = series.db.tree_path("metrics@0")?;
+
+        assert_eq!(series.drop_before(2500)?, 2);
+        assert_eq!(series.partitions().next(), Some(2000));
+        assert!(matches!(series.get(&(500, 5)), Err(Error::KeyNotFound)));
+        assert_eq!(series.get(&(2500, 25))?, 25);
+        assert!(!oldest.exists());
+        let keys = series.iter()?.map(|record| record.map(|(key, _)| key.0)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (2000..5000).step_by(100).collect::<Vec<_>>());
+
+        let db = series.into_db();
+        assert!(matches!(TimePartitioned::<(u64, u32), u64>::create(db, "metrics", Duration::from_secs(1), Options::default()),
+            Err(Error::TreeExists(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+// Python bindings, built with the `python` feature. Add pyo3's `extension-module` feature
+// when building a wheel with maturin.
+// The #[pymethods] expansion converts every PyResult error into PyErr again.
+#![allow(clippy::useless_conversion)]
+
+use std::ops;
+use std::vec;
+use pyo3::exceptions::{PyKeyError, PyOSError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use crate::engine::bptree::{BPTree, Options};
+use crate::engine::iter::RangeIter;
+use crate::error::Error;
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::KeyNotFound => PyKeyError::new_err("key not found"),
+            Error::IOError(e) => PyOSError::new_err(e.to_string()),
+            e => PyRuntimeError::new_err(e.to_string()),
+        }
+    }
+}
+
+// A dict of bytes to bytes backed by a tree file. Iteration walks a snapshot of the keys
+// taken when it starts.
+#[pyclass(name = "KVStore")]
+pub struct PyKVStore {
+    tree: Option<BPTree<Vec<u8>, Vec<u8>>>,
+}
+
+impl PyKVStore {
+    fn tree(&self) -> PyResult<&BPTree<Vec<u8>, Vec<u8>>> {
+        self.tree.as_ref().ok_or_else(|| PyValueError::new_err("store is closed"))
+    }
+
+    fn tree_mut(&mut self) -> PyResult<&mut BPTree<Vec<u8>, Vec<u8>>> {
+        self.tree.as_mut().ok_or_else(|| PyValueError::new_err("store is closed"))
+    }
+
+    fn entries(&self) -> PyResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.tree()?.iter_snapshot()?.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+#[pymethods]
+impl PyKVStore {
+    // Opens the tree file at `path`, creating it when there is none.
+    #[new]
+    fn new(path: &str) -> PyResult<Self> {
+        Ok(Self{ tree: Some(BPTree::open_or_create(path, Options::default())?) })
+    }
+
+    fn __getitem__<'py>(&self, py: Python<'py>, key: Vec<u8>) -> PyResult<Bound<'py, PyBytes>> {
+        let value = self.tree()?.get(&key)?;
+        Ok(PyBytes::new_bound(py, &value))
+    }
+
+    fn __setitem__(&mut self, key: Vec<u8>, value: Vec<u8>) -> PyResult<()> {
+        self.tree_mut()?.set(key, value)?;
+        Ok(())
+    }
+
+    fn __delitem__(&mut self, key: Vec<u8>) -> PyResult<()> {
+        self.tree_mut()?.remove(&key)?;
+        Ok(())
+    }
+
+    fn __contains__(&self, key: Vec<u8>) -> PyResult<bool> {
+        match self.tree()?.version(&key) {
+            Ok(_) => Ok(true),
+            Err(Error::KeyNotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Walks the whole tree.
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.tree()?.iter_snapshot()?.count())
+    }
+
+    fn __iter__(&self) -> PyResult<KeyIterator> {
+        let keys = RangeIter::new(self.tree()?, ops::Bound::Unbounded, ops::Bound::Unbounded)?.keys().collect::<Result<Vec<_>, _>>()?;
+        Ok(KeyIterator{ keys: keys.into_iter() })
+    }
+
+    #[pyo3(signature = (key, default=None))]
+    fn get(&self, py: Python<'_>, key: Vec<u8>, default: Option<PyObject>) -> PyResult<PyObject> {
+        match self.tree()?.get(&key) {
+            Ok(value) => Ok(PyBytes::new_bound(py, &value).into_any().unbind()),
+            Err(Error::KeyNotFound) => Ok(default.unwrap_or_else(|| py.None())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn keys<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+        Ok(self.entries()?.into_iter().map(|(key, _)| PyBytes::new_bound(py, &key)).collect())
+    }
+
+    fn values<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+        Ok(self.entries()?.into_iter().map(|(_, value)| PyBytes::new_bound(py, &value)).collect())
+    }
+
+    fn items<'py>(&self, py: Python<'py>) -> PyResult<Vec<(Bound<'py, PyBytes>, Bound<'py, PyBytes>)>> {
+        Ok(self.entries()?.into_iter().map(|(key, value)| (PyBytes::new_bound(py, &key), PyBytes::new_bound(py, &value))).collect())
+    }
+
+    // Flushes and closes the tree; any later access raises ValueError.
+    fn close(&mut self) -> PyResult<()> {
+        if let Some(tree) = self.tree.take() {
+            tree.close()?;
+        }
+        Ok(())
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.tree.is_none()
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&mut self, _args: &Bound<'_, pyo3::types::PyTuple>) -> PyResult<bool> {
+        self.close()?;
+        Ok(false)
+    }
+}
+
+#[pyclass]
+pub struct KeyIterator {
+    keys: vec::IntoIter<Vec<u8>>,
+}
+
+#[pymethods]
+impl KeyIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.keys.next().map(|key| PyBytes::new_bound(py, &key))
+    }
+}
+
+#[pymodule]
+fn kvstore(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyKVStore>()
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::types::PyDict;
+    use super::*;
+
+    #[test]
+    fn test_dict_semantics() {
+        let _ = std::fs::remove_file("data/test_python.db");
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let locals = PyDict::new_bound(py);
+            locals.set_item("KVStore", py.get_type_bound::<PyKVStore>()).unwrap();
+            py.run_bound(r#"
+with KVStore("data/test_python.db") as store:
+    for i in range(50):
+        store[b"key%02d" % i] = b"value%d" % i
+    del store[b"key07"]
+    assert store[b"key03"] == b"value3"
+    assert b"key07" not in store and b"key08" in store
+    assert store.get(b"key07") is None and store.get(b"key07", b"-") == b"-"
+    assert len(store) == 49
+    assert list(store)[:3] == [b"key00", b"key01", b"key02"]
+    assert dict(store.items())[b"key49"] == b"value49"
+    try:
+        store[b"key07"]
+        raise AssertionError("expected KeyError")
+    except KeyError:
+        pass
+assert store.closed
+try:
+    store[b"key03"]
+    raise AssertionError("expected ValueError")
+except ValueError:
+    pass
+with KVStore("data/test_python.db") as store:
+    assert len(store) == 49 and store[b"key49"] == b"value49"
+"#, None, Some(&locals)).unwrap();
+        });
+    }
+}
@@ -0,0 +1,405 @@
+// The Redis protocol (RESP2), as much of it as it takes to serve GET, SET, DEL, EXISTS and
+// SCAN from a tree of byte strings, so redis-cli and Redis client libraries can talk to the
+// store. The kvserver binary serves it over TCP.
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use crate::error::{Error, Result};
+use crate::shared::Shared;
+
+pub type ByteStore = Shared<Vec<u8>, Vec<u8>>;
+
+// Keys a SCAN looks at when the client doesn't give a COUNT.
+pub const SCAN_COUNT: usize = 10;
+const MAX_SCAN_COUNT: usize = 100_000;
+// Cursors a server keeps for all its connections; past that the oldest is forgotten.
+const MAX_CURSORS: usize = 1 << 16;
+// Limits on what a client may send: the length of a line, of one argument and the number
+// of arguments of a command.
+const MAX_LINE: u64 = 64 << 10;
+const MAX_BULK_LEN: usize = 512 << 20;
+const MAX_ARGS: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "+{}\r\n", status),
+            Reply::Error(message) => write!(out, "-{}\r\n", message),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(out))
+            }
+        }
+    }
+}
+
+fn protocol(message: &str) -> Error {
+    Error::Protocol(message.to_string())
+}
+
+// A line without its line break, or None at the end of the stream.
+fn read_line<R: BufRead>(input: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if input.take(MAX_LINE).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol("line too long or cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits).ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| protocol("invalid length"))
+}
+
+// Reads one command: an array of bulk strings, as clients send them, or an inline command
+// of words separated by spaces, as typed into telnet. None once the client has hung up.
+pub fn read_command<R: BufRead>(input: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(input)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(line.split(|byte| *byte == b' ').filter(|word| !word.is_empty()).map(<[u8]>::to_vec).collect()));
+    }
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let header = read_line(input)?.ok_or_else(|| protocol("unexpected end of stream"))?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol("expected a bulk string"));
+        }
+        let len = parse_len(&header[1..], MAX_BULK_LEN)?;
+        let mut arg = vec![0; len + 2];
+        input.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol("bulk string not terminated"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// The SCAN cursors handed out by a server, shared by all of its connections. A cursor
+// stands for the last key a scan returned, so keys that exist throughout a scan are
+// returned exactly once whatever is written meanwhile. Using a cursor doesn't use it up:
+// pooled clients continue a scan on whichever connection is free, and retry a call that
+// timed out with the same cursor.
+#[derive(Clone, Default)]
+pub struct ScanCursors {
+    cursors: Arc<Mutex<BTreeMap<u64, Vec<u8>>>>,
+}
+
+impl ScanCursors {
+    fn resume(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.cursors.lock().unwrap().get(&cursor).cloned()
+    }
+
+    fn issue(&self, last: Vec<u8>) -> u64 {
+        let mut cursors = self.cursors.lock().unwrap();
+        if cursors.len() >= MAX_CURSORS {
+            cursors.pop_first();
+        }
+        let cursor = cursors.last_key_value().map_or(1, |(cursor, _)| cursor + 1);
+        cursors.insert(cursor, last);
+        cursor
+    }
+}
+
+// One client's connection to the store.
+pub struct Session {
+    store: ByteStore,
+    cursors: ScanCursors,
+}
+
+impl Session {
+    pub fn new(store: ByteStore, cursors: ScanCursors) -> Self {
+        Self{ store, cursors }
+    }
+
+    pub fn execute(&mut self, command: &[Vec<u8>]) -> Reply {
+        let (name, args) = match command.split_first() {
+            Some(split) => split,
+            None => return Reply::Error("ERR empty command".to_string()),
+        };
+        let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+        let reply = match (name.as_str(), args) {
+            ("PING", []) => Ok(Reply::Status("PONG")),
+            ("PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+            ("GET", [key]) => self.get(key),
+            ("SET", [key, value]) => self.store.set(key.clone(), value.clone()).map(|_| Reply::Status("OK")),
+            ("DEL", keys) if !keys.is_empty() => self.del(keys),
+            ("EXISTS", keys) if !keys.is_empty() => self.exists(keys),
+            ("SCAN", [cursor, options @ ..]) => self.scan(cursor, options),
+            // Asked for by redis-cli on connect; an empty answer leaves it without hints.
+            ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+            ("PING" | "GET" | "SET" | "DEL" | "EXISTS" | "SCAN", _) => {
+                return Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()));
+            }
+            _ => return Reply::Error(format!("ERR unknown command '{}'", name)),
+        };
+        reply.unwrap_or_else(|e| Reply::Error(format!("ERR {}", e)))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Reply> {
+        match self.store.get(key) {
+            Ok(value) => Ok(Reply::Bulk(Some(value))),
+            Err(Error::KeyNotFound) => Ok(Reply::Bulk(None)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn del(&self, keys: &[Vec<u8>]) -> Result<Reply> {
+        self.store.write(|tree| {
+            let mut removed = 0;
+            for key in keys {
+                match tree.remove(key) {
+                    Ok(_) => removed += 1,
+                    Err(Error::KeyNotFound) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        })
+    }
+
+    fn exists(&self, keys: &[Vec<u8>]) -> Result<Reply> {
+        self.store.read(|tree| {
+            let mut found = 0;
+            for key in keys {
+                match tree.get_with(key, |_| ()) {
+                    Ok(()) => found += 1,
+                    Err(Error::KeyNotFound) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Reply::Integer(found))
+        })
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]. COUNT is how many keys to look at, so with
+    // MATCH fewer may come back; the scan is over once the cursor returned is 0.
+    fn scan(&self, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+        let start = match parse_len(cursor, usize::MAX).map(|cursor| cursor as u64) {
+            Ok(0) => Bound::Unbounded,
+            Ok(cursor) => match self.cursors.resume(cursor) {
+                Some(key) => Bound::Excluded(key),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_string())),
+            },
+            Err(_) => return Ok(Reply::Error("ERR invalid cursor".to_string())),
+        };
+        let (mut pattern, mut count) = (None, SCAN_COUNT);
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_uppercase().as_slice(), options.next()) {
+                (b"MATCH", Some(glob)) => pattern = Some(glob),
+                (b"COUNT", Some(n)) => match parse_len(n, MAX_SCAN_COUNT) {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return Ok(Reply::Error("ERR value is out of range".to_string())),
+                },
+                _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+            }
+        }
+        let keys: Vec<Vec<u8>> = self.store.read(|tree| tree.range((start, Bound::Unbounded))?.keys().take(count).collect())?;
+        let next = match keys.last() {
+            Some(last) if keys.len() == count => self.cursors.issue(last.clone()),
+            _ => 0,
+        };
+        let matched = keys.into_iter()
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Ok(Reply::Array(vec![Reply::Bulk(Some(next.to_string().into_bytes())), Reply::Array(matched)]))
+    }
+}
+
+// Redis glob patterns: `*`, `?`, `[abc]`, `[^a-z]` and `\` to match the next byte as is.
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'[', rest)) => {
+            let (first, key) = match key.split_first() {
+                Some(split) => split,
+                None => return false,
+            };
+            let (negated, set) = match rest.split_first() {
+                Some((b'^', set)) => (true, set),
+                _ => (false, rest),
+            };
+            let end = match set.iter().skip(1).position(|byte| *byte == b']') {
+                Some(i) => i + 1,
+                // An unclosed set matches its bytes up to the end of the pattern.
+                None => set.len(),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < end {
+                if i + 2 < end && set[i + 1] == b'-' {
+                    matched |= (set[i].min(set[i + 2])..=set[i].max(set[i + 2])).contains(first);
+                    i += 3;
+                } else {
+                    matched |= set[i] == *first;
+                    i += 1;
+                }
+            }
+            matched != negated && glob_match(&set[(end + 1).min(set.len())..], key)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => key.first() == Some(&rest[0]) && glob_match(&rest[1..], &key[1..]),
+        Some((byte, rest)) => key.first() == Some(byte) && glob_match(rest, &key[1..]),
+    }
+}
+
+// Serves one client until it hangs up or sends QUIT. Replies to pipelined commands go out
+// together, once every command that has arrived is answered.
+pub fn serve(store: ByteStore, cursors: ScanCursors, stream: TcpStream) -> Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::new(stream);
+    let mut session = Session::new(store, cursors);
+    loop {
+        let command = match read_command(&mut input) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(()),
+            Err(Error::Protocol(message)) => {
+                Reply::Error(format!("ERR Protocol error: {}", message)).write_to(&mut output)?;
+                output.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if command.is_empty() {
+            continue;
+        }
+        if command[0].eq_ignore_ascii_case(b"QUIT") {
+            Reply::Status("OK").write_to(&mut output)?;
+            output.flush()?;
+            return Ok(());
+        }
+        session.execute(&command).write_to(&mut output)?;
+        if input.buffer().is_empty() {
+            output.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+    use crate::engine::bptree::Options;
+    use super::*;
+
+    fn command(stream: &mut TcpStream, args: &[&str]) -> Result<()> {
+        write!(stream, "*{}\r\n", args.len())?;
+        for arg in args {
+            write!(stream, "${}\r\n{}\r\n", arg.len(), arg)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resp_session() -> Result<()> {
+        let store: ByteStore = Shared::create(Path::new("data").join("test_resp.db"), Options{ max_key_count: Some(4), ..Options::default() })?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || -> Result<()> { serve(store, ScanCursors::default(), listener.accept()?.0) });
+
+        let mut client = TcpStream::connect(addr)?;
+        for i in 0..25 {
+            command(&mut client, &["SET", &format!("user:{:02}", i), &format!("v{}", i)])?;
+        }
+        command(&mut client, &["GET", "user:07"])?;
+        command(&mut client, &["GET", "nope"])?;
+        command(&mut client, &["DEL", "user:00", "user:01", "nope"])?;
+        client.write_all(b"EXISTS user:00 user:02 user:03\r\n")?;
+        command(&mut client, &["SCAN", "0", "MATCH", "user:1?", "COUNT", "15"])?;
+        command(&mut client, &["SCAN", "1"])?;
+        command(&mut client, &["SCAN", "1"])?;
+        command(&mut client, &["SCAN", "99"])?;
+        command(&mut client, &["FLUSHALL"])?;
+        command(&mut client, &["QUIT"])?;
+        let mut replies = String::new();
+        client.read_to_string(&mut replies)?;
+        server.join().unwrap()?;
+
+        // The first 15 keys end at user:16, and COUNT stays with the call that gave it. A
+        // cursor used again gives the same keys again.
+        let rest = "*2\r\n$1\r\n0\r\n*8\r\n".to_string() + &(17..=24).map(|i| format!("$7\r\nuser:{}\r\n", i)).collect::<String>();
+        let expected = "+OK\r\n".repeat(25)
+            + "$2\r\nv7\r\n$-1\r\n:2\r\n:2\r\n"
+            + "*2\r\n$1\r\n1\r\n*7\r\n" + &(10..=16).map(|i| format!("$7\r\nuser:{}\r\n", i)).collect::<String>()
+            + &rest + &rest
+            + "-ERR invalid cursor\r\n-ERR unknown command 'FLUSHALL'\r\n+OK\r\n";
+        assert_eq!(replies, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_continues_on_another_connection() -> Result<()> {
+        let store: ByteStore = Shared::create(Path::new("data").join("test_resp_cursors.db"), Options::default())?;
+        let cursors = ScanCursors::default();
+        let (first, second) = (Session::new(store.clone(), cursors.clone()), Session::new(store, cursors));
+        for i in 0..25 {
+            first.store.set(format!("k{:02}", i).into_bytes(), Vec::new())?;
+        }
+        let mut keys = Vec::new();
+        let mut cursor = b"0".to_vec();
+        for session in [&first, &second].iter().cycle() {
+            let (next, page) = match session.scan(&cursor, &[])? {
+                Reply::Array(reply) => match &reply[..] {
+                    [Reply::Bulk(Some(next)), Reply::Array(page)] => (next.clone(), page.clone()),
+                    reply => panic!("unexpected reply {:?}", reply),
+                },
+                reply => panic!("unexpected reply {:?}", reply),
+            };
+            keys.extend(page);
+            if next == b"0" {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(keys, (0..25).map(|i| Reply::Bulk(Some(format!("k{:02}", i).into_bytes()))).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"k[0-9]", b"k7"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*", b""));
+    }
+}
@@ -0,0 +1,188 @@
+// A tower-sessions store, built with the `sessions` feature, so axum and other tower
+// services can keep sessions in a tree file.
+//
+// Sessions are keyed by id and stored with their expiry next to the JSON-encoded record.
+// An expired session reads as missing and is removed when it is loaded; delete_expired()
+// sweeps the rest, e.g. from ExpiredDeletion::continuously_delete_expired.
+use std::path::Path;
+use std::sync::Arc;
+use async_trait::async_trait;
+use time::OffsetDateTime;
+use tower_sessions_core::session::{Id, Record};
+use tower_sessions_core::session_store::{self, ExpiredDeletion};
+use crate::engine::bptree::Options;
+use crate::engine::clock::IdSource;
+use crate::engine::iter::RangeIter;
+use crate::error::{Error, Result};
+use crate::shared::Shared;
+
+type Entry = (i64, Vec<u8>);
+
+fn backend(e: Error) -> session_store::Error {
+    session_store::Error::Backend(e.to_string())
+}
+
+fn expired(expiry: i64) -> bool {
+    expiry <= OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[derive(Clone)]
+pub struct SessionStore {
+    store: Shared<i128, Entry>,
+    // Random ids unless set.
+    ids: Option<Arc<dyn IdSource>>,
+}
+
+impl std::fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionStore").finish_non_exhaustive()
+    }
+}
+
+impl SessionStore {
+    pub fn create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        Ok(Self{ store: Shared::create(path, options)?, ids: None })
+    }
+
+    pub fn from_shared(store: Shared<i128, Entry>) -> Self {
+        Self{ store, ids: None }
+    }
+
+    pub fn with_ids(mut self, ids: Arc<dyn IdSource>) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    fn new_id(&self) -> Id {
+        match &self.ids {
+            Some(ids) => Id(ids.next_id() as i128),
+            None => Id::default(),
+        }
+    }
+
+    fn entry(record: &Record) -> session_store::Result<Entry> {
+        let data = serde_json::to_vec(record).map_err(|e| session_store::Error::Encode(e.to_string()))?;
+        Ok((record.expiry_date.unix_timestamp(), data))
+    }
+}
+
+#[async_trait]
+impl session_store::SessionStore for SessionStore {
+    // Picks a fresh id under the write lock, so two sessions can't be given the same one.
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        let entry = Self::entry(record)?;
+        self.store.write(|tree| {
+            while tree.version(&record.id.0).is_ok() {
+                record.id = self.new_id();
+            }
+            tree.set(record.id.0, entry).map(|_| ())
+        }).map_err(backend)
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        self.store.set(record.id.0, Self::entry(record)?).map(|_| ()).map_err(backend)
+    }
+
+    async fn load(&self, id: &Id) -> session_store::Result<Option<Record>> {
+        let (expiry, data) = match self.store.get(&id.0) {
+            Ok(entry) => entry,
+            Err(Error::KeyNotFound) => return Ok(None),
+            Err(e) => return Err(backend(e)),
+        };
+        if expired(expiry) {
+            self.delete(id).await?;
+            return Ok(None);
+        }
+        serde_json::from_slice(&data).map(Some).map_err(|e| session_store::Error::Decode(e.to_string()))
+    }
+
+    async fn delete(&self, id: &Id) -> session_store::Result<()> {
+        match self.store.remove(&id.0) {
+            Ok(_) | Err(Error::KeyNotFound) => Ok(()),
+            Err(e) => Err(backend(e)),
+        }
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for SessionStore {
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        self.store.write(|tree| {
+            let ids = RangeIter::new(tree, std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)?
+                .filter_map(|entry| match entry {
+                    Ok((id, (expiry, _))) if expired(expiry) => Some(Ok(id)),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for id in ids {
+                tree.remove(&id)?;
+            }
+            Ok(())
+        }).map_err(backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::task::{Context, Poll, Wake, Waker};
+    use time::Duration;
+    use crate::engine::clock::SequentialIds;
+    use tower_sessions_core::SessionStore as _;
+    use super::*;
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // The store never waits on anything, so every future is ready on the first poll.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Noop));
+        match Box::pin(future).as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => unreachable!(),
+        }
+    }
+
+    fn record(expires_in: Duration) -> Record {
+        Record{ id: Id::default(), data: Default::default(), expiry_date: OffsetDateTime::now_utc() + expires_in }
+    }
+
+    #[test]
+    fn test_session_lifecycle() -> std::result::Result<(), session_store::Error> {
+        let store = SessionStore::create(Path::new("data").join("test_sessions.db"), Options::default()).map_err(backend)?;
+        let mut live = record(Duration::hours(1));
+        live.data.insert("user".to_string(), serde_json::json!({"id": 7, "name": "ada"}));
+        block_on(store.create(&mut live))?;
+        assert_eq!(block_on(store.load(&live.id))?, Some(live.clone()));
+
+        let mut stale = record(Duration::seconds(-1));
+        block_on(store.create(&mut stale))?;
+        assert_eq!(block_on(store.load(&stale.id))?, None);
+        assert!(matches!(store.store.get(&stale.id.0), Err(Error::KeyNotFound)));
+
+        block_on(store.save(&record(Duration::seconds(-1))))?;
+        block_on(store.delete_expired())?;
+        assert_eq!(store.store.read(|tree| Ok(tree.iter_snapshot()?.count())).map_err(backend)?, 1);
+
+        block_on(store.delete(&live.id))?;
+        block_on(store.delete(&live.id))?;
+        assert_eq!(block_on(store.load(&live.id))?, None);
+
+        // A taken id is skipped.
+        let store = store.with_ids(Arc::new(SequentialIds::starting_at(5)));
+        let mut first = record(Duration::hours(1));
+        first.id = Id(6);
+        block_on(store.save(&first))?;
+        let (mut second, mut third) = (record(Duration::hours(1)), record(Duration::hours(1)));
+        second.id = Id(6);
+        third.id = Id(6);
+        block_on(store.create(&mut second))?;
+        block_on(store.create(&mut third))?;
+        assert_eq!((second.id, third.id), (Id(5), Id(7)));
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::hash::Hash;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::engine::KVStoreEngine;
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShadowReport {
+    pub reads: u64,
+    // Sampled reads where the secondary found a different value, or none where the primary
+    // found one or the other way round.
+    pub mismatches: u64,
+    pub writes: u64,
+    // Operations that failed on the secondary but not on the primary.
+    pub errors: u64,
+    // Time the sampled reads took on either side.
+    pub primary_time: Duration,
+    pub secondary_time: Duration,
+}
+
+enum Mirrored<K, V> {
+    Read(K, Option<V>, Duration),
+    Set(K, V),
+    Remove(K),
+}
+
+fn found<V>(result: Result<V>) -> Result<Option<V>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::KeyNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Serves everything from the primary engine and replays it on a secondary one on a thread
+// of its own, e.g. to try a new version or other settings on production traffic. Every
+// successful write is mirrored, so the secondary holds the same data, but only
+// `read_percent` of the reads are; their results and latencies are compared in the
+// background and summed up in a ShadowReport. Nothing the secondary does reaches callers.
+pub struct Shadow<E, S, K, V> {
+    primary: E,
+    sender: Option<Sender<Mirrored<K, V>>>,
+    worker: Option<JoinHandle<S>>,
+    report: Arc<Mutex<ShadowReport>>,
+    read_percent: f64,
+    // Reads are picked evenly: each one adds read_percent and one is mirrored per 100.
+    credit: f64,
+}
+
+impl<E, S, K, V> Shadow<E, S, K, V>
+    where E: KVStoreEngine<K, V>,
+          S: KVStoreEngine<K, V> + Send + 'static,
+          K: Clone + Ord + Hash + Send + 'static,
+          V: Clone + PartialEq + Send + 'static
+{
+    pub fn new(primary: E, secondary: S, read_percent: f64) -> Self {
+        assert!((0.0..=100.0).contains(&read_percent));
+        let report = Arc::new(Mutex::new(ShadowReport::default()));
+        let (sender, receiver) = mpsc::channel();
+        let worker_report = report.clone();
+        let worker = thread::spawn(move || {
+            let mut secondary = secondary;
+            for op in receiver {
+                mirror(&mut secondary, op, &worker_report);
+            }
+            secondary
+        });
+        Self{ primary, sender: Some(sender), worker: Some(worker), report, read_percent, credit: 0.0 }
+    }
+
+    pub fn primary(&self) -> &E {
+        &self.primary
+    }
+
+    // What has been compared so far; mirrored operations may still be queued.
+    pub fn report(&self) -> ShadowReport {
+        self.report.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<V> {
+        self.credit += self.read_percent;
+        if self.credit < 100.0 {
+            return self.primary.get(key);
+        }
+        self.credit -= 100.0;
+        let start = Instant::now();
+        let result = self.primary.get(key);
+        let elapsed = start.elapsed();
+        match &result {
+            Ok(value) => self.send(Mirrored::Read(key.clone(), Some(value.clone()), elapsed)),
+            Err(Error::KeyNotFound) => self.send(Mirrored::Read(key.clone(), None, elapsed)),
+            Err(_) => (),
+        }
+        result
+    }
+
+    pub fn set(&mut self, key: K, value: V) -> Result<()> {
+        self.primary.set(key.clone(), value.clone())?;
+        self.send(Mirrored::Set(key, value));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        self.primary.remove(key)?;
+        self.send(Mirrored::Remove(key.clone()));
+        Ok(())
+    }
+
+    // Waits for the secondary to catch up and hands both engines back with the final report.
+    pub fn finish(mut self) -> Result<(E, S, ShadowReport)> {
+        self.sender = None;
+        let secondary = self.worker.take().unwrap().join().map_err(|_| Error::Poisoned)?;
+        let report = self.report();
+        Ok((self.primary, secondary, report))
+    }
+
+    // The worker only goes away if mirroring panicked, in which case there is nothing left
+    // to mirror to.
+    fn send(&mut self, op: Mirrored<K, V>) {
+        if let Some(sender) = &self.sender {
+            if sender.send(op).is_err() {
+                self.sender = None;
+            }
+        }
+    }
+}
+
+fn mirror<S, K, V>(secondary: &mut S, op: Mirrored<K, V>, report: &Mutex<ShadowReport>)
+    where S: KVStoreEngine<K, V>, K: Ord + Hash + 'static, V: Clone + PartialEq
+{
+    let written = match op {
+        Mirrored::Read(key, expected, primary_time) => {
+            let start = Instant::now();
+            let actual = found(secondary.get(&key));
+            let secondary_time = start.elapsed();
+            let mut report = report.lock().unwrap_or_else(|e| e.into_inner());
+            report.reads += 1;
+            report.primary_time += primary_time;
+            report.secondary_time += secondary_time;
+            match actual {
+                Ok(actual) if actual == expected => (),
+                Ok(_) => report.mismatches += 1,
+                Err(_) => report.errors += 1,
+            }
+            return;
+        }
+        Mirrored::Set(key, value) => secondary.set(key, value),
+        // The secondary may not have had the key to begin with; that shows up as a mismatch
+        // on reads, not as an error here.
+        Mirrored::Remove(key) => match secondary.remove(&key) {
+            Err(Error::KeyNotFound) => Ok(()),
+            result => result,
+        },
+    };
+    let mut report = report.lock().unwrap_or_else(|e| e.into_inner());
+    report.writes += 1;
+    if written.is_err() {
+        report.errors += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::BPTree;
+    use crate::engine::WriteEngine;
+    use super::*;
+
+    #[test]
+    fn test_shadow_compares_sampled_reads() -> Result<()> {
+        let primary: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_shadow_primary.db"), Some(4))?;
+        let mut secondary: BPTree<u64, u64> = BPTree::new(Path::new("data").join("test_shadow_secondary.db"), Some(16))?;
+        // A key only the secondary has, so reading it can't match.
+        WriteEngine::set(&mut secondary, 1000, 1)?;
+        let mut shadow = Shadow::new(primary, secondary, 25.0);
+        for i in 0..100 {
+            shadow.set(i, i * 2)?;
+        }
+        shadow.remove(&7)?;
+        for i in 0..100 {
+            let _ = shadow.get(&i);
+        }
+        // Every fourth read is mirrored, the last of these among them.
+        for _ in 0..4 {
+            assert!(matches!(shadow.get(&1000), Err(Error::KeyNotFound)));
+        }
+        for i in 0..3 {
+            assert_eq!(shadow.get(&i)?, i * 2);
+        }
+        let (primary, secondary, report) = shadow.finish()?;
+        assert_eq!(report.writes, 101);
+        assert_eq!(report.reads, 26);
+        assert!(report.secondary_time > Duration::ZERO);
+        assert_eq!(report.mismatches, 1);
+        assert_eq!(report.errors, 0);
+        assert_eq!(secondary.get(&50)?, primary.get(&50)?);
+        assert!(matches!(secondary.get(&7), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+}
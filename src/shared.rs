@@ -0,0 +1,391 @@
+use std::borrow::Borrow;
+use std::fs;
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::batch::WriteBatch;
+use crate::engine::bptree::{remove_if_exists, BPTree, Options, UpsertOutcome};
+use crate::engine::verify::{Verifier, VerifyProgress, VerifyReport, VERIFY_BATCH};
+use crate::engine::wal::Change;
+use crate::engine::{ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+
+struct State<K, V> {
+    tree: BPTree<K, V>,
+    poisoned: bool,
+}
+
+// A tree shared between threads, e.g. cloned into every request handler of a server. Reads
+// run concurrently, writes one at a time.
+//
+// A write that panics may leave the in-memory state (root, free pages, counters) half
+// updated, so the handle is poisoned and the panic carries on to the caller. The next
+// access on any clone rebuilds the tree from its change log, which only ever holds writes
+// that completed. That takes a log reaching back to the tree's creation, as it does when
+// the tree has had the log from the start. Without one, or if rebuilding fails, every
+// access returns Error::Poisoned from then on and the tree file is left as it was. State
+// that isn't logged, like quotas and the compaction filter, doesn't survive a rebuild.
+pub struct Shared<K, V> {
+    state: Arc<RwLock<State<K, V>>>,
+    path: Arc<PathBuf>,
+    options: Arc<Options>,
+}
+
+impl<K, V> Clone for Shared<K, V> {
+    fn clone(&self) -> Self {
+        Self{
+            state: self.state.clone(),
+            path: self.path.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+impl<K, V> Shared<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    pub fn create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        let tree = BPTree::with_options(&path, options.clone())?;
+        Ok(Self::from_tree(tree, path.as_ref(), options))
+    }
+
+    // Picks up a tree file where its last flush left it, see BPTree::open_with_options().
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        let tree = BPTree::open_with_options(&path, options.clone())?;
+        Ok(Self::from_tree(tree, path.as_ref(), options))
+    }
+
+    // Opens the tree file, or creates it when there is none, see BPTree::open_or_create().
+    pub fn open_or_create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        let tree = BPTree::open_or_create(&path, options.clone())?;
+        Ok(Self::from_tree(tree, path.as_ref(), options))
+    }
+
+    fn from_tree(tree: BPTree<K, V>, path: &Path, options: Options) -> Self {
+        Self{
+            state: Arc::new(RwLock::new(State{ tree, poisoned: false })),
+            path: Arc::new(path.to_path_buf()),
+            options: Arc::new(options),
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).poisoned
+    }
+
+    pub fn read<R, F>(&self, f: F) -> Result<R>
+        where F: FnOnce(&BPTree<K, V>) -> Result<R>
+    {
+        {
+            let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+            if !state.poisoned {
+                return f(&state.tree);
+            }
+        }
+        let state = self.recovered()?;
+        f(&state.tree)
+    }
+
+    pub fn write<R, F>(&self, f: F) -> Result<R>
+        where F: FnOnce(&mut BPTree<K, V>) -> Result<R>
+    {
+        let mut state = self.recovered()?;
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut state.tree))) {
+            Ok(result) => result,
+            Err(payload) => {
+                state.poisoned = true;
+                drop(state);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    pub fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        self.read(|tree| tree.get_with(key, f))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Result<V>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.get_with(key, V::clone)
+    }
+
+    pub fn set(&self, key: K, value: V) -> Result<u64> {
+        self.write(|tree| tree.set(key, value))
+    }
+
+    pub fn upsert(&self, key: K, value: V) -> Result<UpsertOutcome<V>> {
+        self.write(|tree| tree.upsert(key, value))
+    }
+
+    // Compare-and-swap under the write lock; see BPTree::set_if().
+    pub fn set_if(&self, key: K, expected: Option<&V>, value: V) -> Result<bool> {
+        self.write(|tree| tree.set_if(key, expected, value))
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Result<u64>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        self.write(|tree| tree.remove(key))
+    }
+
+    pub fn write_batch(&self, batch: WriteBatch<K, V>) -> Result<u64> {
+        self.write(|tree| tree.write_batch(batch))
+    }
+
+    // The entries in the range, collected under one read lock since iterators can't outlive
+    // it; at most `limit` of them.
+    pub fn range<R: RangeBounds<K>>(&self, range: R, limit: usize) -> Result<Vec<(K, V)>> {
+        self.read(|tree| tree.range(range)?.take(limit).collect())
+    }
+
+    pub fn flush(&self) -> Result<u64> {
+        self.write(|tree| tree.flush())
+    }
+
+    // Copies the tree under the read lock, so readers carry on and writers wait for it.
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        self.read(|tree| tree.backup_to(path))
+    }
+
+    // Runs under one read lock, so other readers carry on while the workers look keys up.
+    pub fn par_get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>>
+        where K: Sync, V: Send, BPTree<K, V>: Sync
+    {
+        self.read(|tree| tree.par_get_many(keys))
+    }
+
+    pub fn par_set_batch(&self, items: Vec<(K, V)>) -> Result<u64>
+        where K: Send, V: Send
+    {
+        self.write(|tree| tree.par_set_batch(items))
+    }
+
+    // Runs BPTree::verify() on a thread of its own, taking the read lock for one batch of
+    // pages at a time so writes carry on in between.
+    pub fn verify_in_background<F>(&self, mut progress: F) -> JoinHandle<Result<VerifyReport>>
+        where F: FnMut(&VerifyProgress) + Send + 'static, K: Send + Sync, V: Send + Sync
+    {
+        let store = self.clone();
+        thread::spawn(move || {
+            let mut verifier = Verifier::new();
+            loop {
+                let (done, status) = store.read(|tree| Ok((verifier.step(tree, VERIFY_BATCH)?, verifier.progress(tree))))?;
+                progress(&status);
+                if done {
+                    return Ok(verifier.into_report());
+                }
+            }
+        })
+    }
+
+    // Takes the write lock, rebuilding the tree first if it is poisoned.
+    fn recovered(&self) -> Result<RwLockWriteGuard<'_, State<K, V>>> {
+        // The lock itself is never held across a panic we didn't catch, but a caller's
+        // closure in read() may still have panicked while holding it.
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        if state.poisoned {
+            state.tree = self.rebuild(&state.tree).map_err(|_| Error::Poisoned)?;
+            state.poisoned = false;
+        }
+        Ok(state)
+    }
+
+    // Replays the change log into a scratch tree next to the tree file, with extension
+    // "rebuild", and only renames it over the tree file once it is complete. The log reaches
+    // back to the tree's creation if it starts at the tree's first write.
+    fn rebuild(&self, tree: &BPTree<K, V>) -> Result<BPTree<K, V>> {
+        if tree.change_log_end().is_none() {
+            return Err(Error::ChangeLogDisabled);
+        }
+        let changes = tree.changes(0)?.collect::<Result<Vec<_>>>()?;
+        let complete = match changes.first() {
+            Some(first) => first.seq == 1,
+            None => tree.last_seq() == 0,
+        };
+        if !complete {
+            return Err(Error::ChangeLogIncomplete);
+        }
+        let scratch = self.path.with_extension("rebuild");
+        let options = Options{ change_log: false, write_ahead_log: false, analyze_every: None, ..tree.options().clone() };
+        let replayed = BPTree::with_options(&scratch, options).and_then(|mut rebuilt| {
+            for event in changes {
+                match event.change {
+                    Change::Set(key, value) => rebuilt.set(key, value)?,
+                    Change::SetExpiring(key, value, expires) => rebuilt.set_expiring_at(key, value, expires)?,
+                    Change::Remove(key) => rebuilt.remove(&key)?,
+                };
+            }
+            rebuilt.close()
+        });
+        if let Err(e) = replayed {
+            remove_if_exists(&scratch)?;
+            return Err(e);
+        }
+        // The redo log holds pages of the file being replaced.
+        remove_if_exists(&self.path.with_extension("redo"))?;
+        fs::rename(&scratch, self.path.as_path())?;
+        BPTree::open_with_options(self.path.as_path(), (*self.options).clone())
+    }
+}
+
+impl<K, V> ReadEngine<K, V> for Shared<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        Shared::get_with(self, key, f)
+    }
+}
+
+impl<K, V> WriteEngine<K, V> for Shared<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        Shared::set(self, key, value).map(|_| ())
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        Shared::remove(self, key).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serializer};
+    use super::*;
+
+    // Panics when the tree encodes it, which is partway through the write that stores it.
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+    struct Fragile(u64);
+
+    impl Serialize for Fragile {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            assert_ne!(self.0, 13, "unlucky value");
+            serializer.serialize_u64(self.0)
+        }
+    }
+
+    fn torn_write(store: &Shared<u64, Fragile>) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| store.set(1000, Fragile(13))));
+        assert!(result.is_err());
+        assert!(store.is_poisoned());
+    }
+
+    #[test]
+    fn test_panicked_write_poisons_and_recovers() -> Result<()> {
+        let path = Path::new("data").join("test_shared_poison.db");
+        let options = Options{ max_key_count: Some(4), change_log: true, ..Options::default() };
+        let store = Shared::create(&path, options)?;
+        for i in 0..50u64 {
+            store.set(i, Fragile(i + 100))?;
+        }
+        store.remove(&10)?;
+        torn_write(&store);
+
+        let reader = store.clone();
+        assert_eq!(thread::spawn(move || reader.get(&49)).join().unwrap()?, Fragile(149));
+        assert!(!store.is_poisoned());
+        assert!(matches!(store.get(&1000), Err(Error::KeyNotFound)));
+        assert!(matches!(store.get(&10), Err(Error::KeyNotFound)));
+        store.set(50, Fragile(150))?;
+        assert_eq!(store.read(|tree| Ok(tree.iter_snapshot()?.count()))?, 50);
+        assert!(!path.with_extension("rebuild").exists());
+
+        let store = Shared::create(Path::new("data").join("test_shared_poison_nolog.db"), Options::default())?;
+        store.set(1, Fragile(1))?;
+        torn_write(&store);
+        assert!(matches!(store.get(&1), Err(Error::Poisoned)));
+        assert!(matches!(store.set(2, Fragile(2)), Err(Error::Poisoned)));
+
+        // A log turned on after the tree was written to can't rebuild it, and the file stays.
+        let path = Path::new("data").join("test_shared_poison_late_log.db");
+        remove_if_exists(&path.with_extension("wal"))?;
+        let mut tree: BPTree<u64, Fragile> = BPTree::new(&path, Some(4))?;
+        for i in 0..20u64 {
+            tree.set(i, Fragile(i + 100))?;
+        }
+        tree.close()?;
+        let store = Shared::open(&path, Options{ change_log: true, ..Options::default() })?;
+        torn_write(&store);
+        assert!(matches!(store.get(&1), Err(Error::Poisoned)));
+        drop(store);
+        let tree: BPTree<u64, Fragile> = BPTree::open(&path)?;
+        assert_eq!((tree.len(), tree.get(&19)?), (20, Fragile(119)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_readers_share_a_store_with_a_writer() -> Result<()> {
+        fn send_sync<T: Send + Sync>(_: &T) {}
+        let path = Path::new("data").join("test_shared_threads.db");
+        let options = Options{ max_key_count: Some(8), cache_pages: 64, ..Options::default() };
+        let store: Shared<u64, u64> = Shared::create(&path, options.clone())?;
+        send_sync(&store);
+        store.write_batch((0..1000).map(|i| (i, i)).collect())?;
+
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || (1000..2000).try_for_each(|i| store.set(i, i).map(|_| ())))
+        };
+        let readers: Vec<_> = (0..4u64).map(|n| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in (n..1000).step_by(4) {
+                    assert_eq!(store.get(&i)?, i);
+                    // Whatever the writer got to, the range sees a prefix of it.
+                    let seen = store.range(i.., 3000)?;
+                    assert!(seen.iter().enumerate().all(|(j, (key, _))| *key == i + j as u64));
+                }
+                Ok(())
+            })
+        }).collect();
+        writer.join().unwrap()?;
+        readers.into_iter().try_for_each(|reader| reader.join().unwrap())?;
+        store.flush()?;
+        drop(store);
+
+        let store: Shared<u64, u64> = Shared::open(&path, options)?;
+        assert_eq!(store.range(990..1010, 5)?, (990..995).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(store.read(|tree| Ok(tree.key_count()))?, 2000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_swap_counter() -> Result<()> {
+        let store: Shared<u64, u64> = Shared::create(Path::new("data").join("test_shared_cas.db"), Options::default())?;
+        let workers: Vec<_> = (0..4).map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    loop {
+                        let current = match store.get(&0) {
+                            Ok(current) => Some(current),
+                            Err(Error::KeyNotFound) => None,
+                            Err(e) => return Err(e),
+                        };
+                        if store.set_if(0, current.as_ref(), current.unwrap_or(0) + 1)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        }).collect();
+        workers.into_iter().try_for_each(|worker| worker.join().unwrap())?;
+        assert_eq!(store.get(&0)?, 400);
+        Ok(())
+    }
+}
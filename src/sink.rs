@@ -0,0 +1,202 @@
+use std::convert::TryInto;
+use crate::engine::bptree::BPTree;
+use crate::engine::codec::Codec;
+use crate::engine::wal::Change;
+use crate::error::{Error, Result};
+
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+
+// Reserved key space for connector checkpoints; the connector never publishes writes to it.
+const CHECKPOINT_PREFIX: &[u8] = b"\0sink/";
+
+// Where change events go. `flush` must only return once everything published before it
+// has been acknowledged by the broker.
+pub trait Publisher {
+    fn publish(&mut self, topic: &str, key: &[u8], payload: &[u8]) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Publishes the change log of a tree to a topic, one message per change keyed by the
+// changed key, with the encoded (seq, change) as payload. After every batch the publisher
+// is flushed and only then is the log offset checkpointed into the tree itself, so after a
+// crash the connector resends at most the last batch: delivery is at least once.
+pub struct Connector<P> {
+    publisher: P,
+    topic: String,
+    checkpoint_key: Vec<u8>,
+    batch_size: usize,
+    codec: Codec,
+}
+
+impl<P: Publisher> Connector<P> {
+    pub fn new(publisher: P, topic: &str) -> Self {
+        let mut checkpoint_key = CHECKPOINT_PREFIX.to_vec();
+        checkpoint_key.extend_from_slice(topic.as_bytes());
+        Self{
+            publisher,
+            topic: topic.to_string(),
+            checkpoint_key,
+            batch_size: DEFAULT_BATCH_SIZE,
+            codec: Codec::default().with_limit(u64::MAX),
+        }
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0);
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn into_inner(self) -> P {
+        self.publisher
+    }
+
+    // Log offset the next pump() starts from.
+    pub fn checkpoint(&self, bptree: &BPTree<Vec<u8>, Vec<u8>>) -> Result<u64> {
+        let offset = bptree.get_with(self.checkpoint_key.as_slice(), |bytes: &Vec<u8>| {
+            bytes.as_slice().try_into().map(u64::from_be_bytes)
+        });
+        match offset {
+            Ok(Ok(offset)) => Ok(offset),
+            Ok(Err(_)) => Err(Error::CorruptedPage),
+            Err(Error::KeyNotFound) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Publishes everything logged since the checkpoint and returns how many changes were sent.
+    pub fn pump(&mut self, bptree: &mut BPTree<Vec<u8>, Vec<u8>>) -> Result<u64> {
+        let mut stream = bptree.changes(self.checkpoint(bptree)?)?;
+        let mut published = 0;
+        loop {
+            let (mut read, mut sent) = (0, 0);
+            for event in stream.by_ref().take(self.batch_size) {
+                let event = event?;
+                read += 1;
+                let key = match &event.change {
+                    Change::Set(key, _) | Change::SetExpiring(key, _, _) | Change::Remove(key) => key,
+                };
+                if key.starts_with(CHECKPOINT_PREFIX) {
+                    continue;
+                }
+                let payload = self.codec.serialize(&(event.seq, &event.change))?;
+                self.publisher.publish(&self.topic, key, &payload)?;
+                sent += 1;
+            }
+            // Checkpoints are logged writes themselves; a batch of nothing but those isn't
+            // worth another one.
+            if sent > 0 {
+                self.publisher.flush()?;
+                bptree.set(self.checkpoint_key.clone(), stream.offset().to_be_bytes().to_vec())?;
+                published += sent;
+            }
+            if read < self.batch_size {
+                return Ok(published);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub struct KafkaPublisher {
+    producer: kafka::producer::Producer,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaPublisher {
+    // Waits for all in-sync replicas on every send, which the at-least-once guarantee needs.
+    pub fn connect(hosts: Vec<String>) -> Result<Self> {
+        let producer = kafka::producer::Producer::from_hosts(hosts)
+            .with_required_acks(kafka::producer::RequiredAcks::All)
+            .create()
+            .map_err(|e| Error::SinkError(e.to_string()))?;
+        Ok(Self{ producer })
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl Publisher for KafkaPublisher {
+    fn publish(&mut self, topic: &str, key: &[u8], payload: &[u8]) -> Result<()> {
+        self.producer.send(&kafka::producer::Record::from_key_value(topic, key, payload))
+            .map_err(|e| Error::SinkError(e.to_string()))
+    }
+}
+
+#[cfg(feature = "nats")]
+pub struct NatsPublisher {
+    connection: nats::Connection,
+}
+
+#[cfg(feature = "nats")]
+impl NatsPublisher {
+    pub fn connect(url: &str) -> Result<Self> {
+        Ok(Self{ connection: nats::connect(url)? })
+    }
+}
+
+// NATS subjects carry no message key, so the changed key only travels inside the payload.
+#[cfg(feature = "nats")]
+impl Publisher for NatsPublisher {
+    fn publish(&mut self, topic: &str, _key: &[u8], payload: &[u8]) -> Result<()> {
+        self.connection.publish(topic, payload)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.connection.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::engine::bptree::Options;
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        sent: Vec<(String, Vec<u8>)>,
+        fail_after: Option<usize>,
+    }
+
+    impl Publisher for Recorder {
+        fn publish(&mut self, topic: &str, key: &[u8], _payload: &[u8]) -> Result<()> {
+            if self.fail_after == Some(self.sent.len()) {
+                return Err(Error::SinkError(String::from("broker down")));
+            }
+            self.sent.push((topic.to_string(), key.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_connector_resends_unacknowledged_batch() -> Result<()> {
+        let path = Path::new("data").join("test_sink.db");
+        let options = Options{ max_key_count: Some(8), change_log: true, ..Options::default() };
+        let mut bptree: BPTree<Vec<u8>, Vec<u8>> = BPTree::with_options(path, options)?;
+        for i in 0..10u8 {
+            bptree.set(vec![i], vec![i])?;
+        }
+        let recorder = Recorder{ fail_after: Some(6), ..Recorder::default() };
+        let mut connector = Connector::new(recorder, "changes").with_batch_size(4);
+        assert!(matches!(connector.pump(&mut bptree), Err(Error::SinkError(_))));
+        assert!(connector.checkpoint(&bptree)? > 0);
+
+        // The second batch failed half way, so it is sent again from its start.
+        connector.publisher.fail_after = None;
+        assert_eq!(connector.pump(&mut bptree)?, 6);
+        bptree.remove(&vec![0])?;
+        assert_eq!(connector.pump(&mut bptree)?, 1);
+        assert_eq!(connector.pump(&mut bptree)?, 0);
+        let keys: Vec<Vec<u8>> = connector.into_inner().sent.into_iter().map(|(_, key)| key).collect();
+        let mut expected: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i]).collect();
+        expected.extend((4..10u8).map(|i| vec![i]));
+        expected.push(vec![0]);
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use std::hash::Hash;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::hash::stable_hash;
+use crate::engine::merge;
+use crate::error::{Error, Result};
+
+pub const DEFAULT_HLL_PRECISION: u8 = 12;
+pub const DEFAULT_CMS_WIDTH: usize = 2048;
+pub const DEFAULT_CMS_DEPTH: usize = 4;
+
+// Approximate summaries that absorb items one at a time and combine with others of the
+// same shape.
+pub trait Sketch: Debug + Clone + Default + Ord + Serialize + DeserializeOwned + 'static {
+    // Returns whether the sketch changed.
+    fn insert<T: Hash + ?Sized>(&mut self, item: &T) -> bool;
+
+    // Fails with IncompatibleSketches when the two were built with different parameters.
+    fn merge(&mut self, other: &Self) -> Result<()>;
+}
+
+// Distinct count estimate with a standard error of about 1.04 / sqrt(2^precision).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_HLL_PRECISION)
+    }
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> Self {
+        assert!((4..=16).contains(&precision));
+        Self{ precision, registers: vec![0; 1 << precision] }
+    }
+
+    pub fn count(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|rank| 2f64.powi(-(*rank as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|rank| **rank == 0).count();
+        // Small cardinalities are counted more precisely by the empty registers.
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+impl Sketch for HyperLogLog {
+    fn insert<T: Hash + ?Sized>(&mut self, item: &T) -> bool {
+        let hash = stable_hash(item);
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rank = ((hash << self.precision) | (1 << (self.precision - 1))).leading_zeros() as u8 + 1;
+        if rank <= self.registers[index] {
+            return false;
+        }
+        self.registers[index] = rank;
+        true
+    }
+
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        if self.precision != other.precision {
+            return Err(Error::IncompatibleSketches);
+        }
+        for (rank, other) in self.registers.iter_mut().zip(&other.registers) {
+            *rank = (*rank).max(*other);
+        }
+        Ok(())
+    }
+}
+
+// Frequency estimates that never undercount; each one overcounts by at most
+// e / width * total with probability 1 - e^-depth.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CountMin {
+    width: usize,
+    depth: usize,
+    counters: Vec<u64>,
+    total: u64,
+}
+
+impl Default for CountMin {
+    fn default() -> Self {
+        Self::new(DEFAULT_CMS_WIDTH, DEFAULT_CMS_DEPTH)
+    }
+}
+
+impl CountMin {
+    pub fn new(width: usize, depth: usize) -> Self {
+        assert!(width > 0 && depth > 0);
+        Self{ width, depth, counters: vec![0; width * depth], total: 0 }
+    }
+
+    pub fn add<T: Hash + ?Sized>(&mut self, item: &T, count: u64) {
+        for slot in self.slots(item) {
+            self.counters[slot] += count;
+        }
+        self.total += count;
+    }
+
+    pub fn estimate<T: Hash + ?Sized>(&self, item: &T) -> u64 {
+        self.slots(item).map(|slot| self.counters[slot]).min().unwrap_or(0)
+    }
+
+    // Sum of all counts added.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // One counter per row, picked by double hashing.
+    fn slots<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = usize> {
+        let hash = stable_hash(item);
+        let (h1, h2) = (hash & 0xffffffff, (hash >> 32) | 1);
+        let width = self.width as u64;
+        (0..self.depth).map(move |row| row * width as usize + (h1.wrapping_add(row as u64 * h2) % width) as usize)
+    }
+}
+
+impl Sketch for CountMin {
+    fn insert<T: Hash + ?Sized>(&mut self, item: &T) -> bool {
+        self.add(item, 1);
+        true
+    }
+
+    fn merge(&mut self, other: &Self) -> Result<()> {
+        if (self.width, self.depth) != (other.width, other.depth) {
+            return Err(Error::IncompatibleSketches);
+        }
+        for (counter, other) in self.counters.iter_mut().zip(&other.counters) {
+            *counter += other;
+        }
+        self.total += other.total;
+        Ok(())
+    }
+}
+
+// Sketches kept up to date inside the tree, through the read-modify-write path of
+// BPTree::merge(). A missing key starts out as S::default(); estimates are read in place
+// with get_with(), e.g. `tree.get_with(key, HyperLogLog::count)`.
+impl<K, S> BPTree<K, S>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          S: Sketch
+{
+    // Writes nothing when the item leaves the sketch as it was.
+    pub fn sketch_insert<T: Hash + ?Sized>(&mut self, key: K, item: &T) -> Result<()> {
+        merge::update(self, key, |old: Option<&S>| {
+            let mut sketch = old.cloned().unwrap_or_default();
+            Ok(sketch.insert(item).then_some(sketch))
+        }).map(|_| ())
+    }
+
+    // Folds a sketch built elsewhere, e.g. on another node, into the stored one.
+    pub fn sketch_merge(&mut self, key: K, other: &S) -> Result<()> {
+        merge::update(self, key, |old| sketch_operator(old, other).map(Some)).map(|_| ())
+    }
+
+    pub fn sketch_merge_into<Q>(&mut self, dest: K, src: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        let src = self.sketch(src)?;
+        self.sketch_merge(dest, &src)
+    }
+
+    fn sketch<Q>(&self, key: &Q) -> Result<S>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match self.get(key) {
+            Err(Error::KeyNotFound) => Ok(S::default()),
+            sketch => sketch,
+        }
+    }
+}
+
+// The merge operator of a sketch tree. Unlike the ones set_merge_operator() takes, it can
+// fail, when the two sketches don't have the same shape.
+fn sketch_operator<S: Sketch>(old: Option<&S>, other: &S) -> Result<S> {
+    let mut sketch = old.cloned().unwrap_or_default();
+    sketch.merge(other)?;
+    Ok(sketch)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_sketches_in_tree() -> Result<()> {
+        let mut visitors: BPTree<String, HyperLogLog> = BPTree::new(Path::new("data").join("test_hll.db"), None)?;
+        visitors.set("monday".to_string(), HyperLogLog::new(10))?;
+        visitors.set("tuesday".to_string(), HyperLogLog::new(10))?;
+        for i in 0..4_000u64 {
+            visitors.sketch_insert("monday".to_string(), &i)?;
+            visitors.sketch_insert("tuesday".to_string(), &(i + 2_000))?;
+        }
+        let count = |tree: &BPTree<String, HyperLogLog>, key: &str| tree.get_with(key, HyperLogLog::count);
+        let error = |count: u64, actual: f64| (count as f64 - actual).abs() / actual;
+        assert!(error(count(&visitors, "monday")?, 4_000.0) < 0.1);
+        visitors.set("week".to_string(), HyperLogLog::new(10))?;
+        visitors.sketch_merge_into("week".to_string(), "monday")?;
+        visitors.sketch_merge_into("week".to_string(), "tuesday")?;
+        assert!(error(count(&visitors, "week")?, 6_000.0) < 0.1);
+        assert!(matches!(visitors.sketch_merge("week".to_string(), &HyperLogLog::default()), Err(Error::IncompatibleSketches)));
+
+        let mut words: BPTree<u32, CountMin> = BPTree::new(Path::new("data").join("test_cms.db"), None)?;
+        words.set(1, CountMin::new(256, 4))?;
+        for i in 0..2_000u32 {
+            words.sketch_insert(1, &format!("word{}", i % 100))?;
+        }
+        let mut other = CountMin::new(256, 4);
+        other.add("word7", 1_000);
+        words.sketch_merge(1, &other)?;
+        let (seven, total) = words.get_with(&1, |sketch| (sketch.estimate("word7"), sketch.total()))?;
+        assert!((1_020..1_060).contains(&seven));
+        assert_eq!(total, 3_000);
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::time::Duration;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::bptree::BPTree;
+use crate::engine::wal::Change;
+use crate::engine::{ReadEngine, WriteEngine};
+use crate::error::{Error, Result};
+
+// Errors after which the primary's pages can't be trusted any more.
+fn is_corruption(e: &Error) -> bool {
+    matches!(e, Error::CorruptedPage | Error::PageNotFound | Error::UnkonwNodeType | Error::SerdeError(_))
+}
+
+// A primary tree paired with a warm standby in the same process. The standby replays the
+// primary's change log once a write is older than `delay`, so it trails by a bounded amount
+// and can take over without a rebuild. When a read or write on the primary fails with a
+// corruption error, the standby replays what is left of the log and the handles are swapped
+// before the operation is retried.
+pub struct Standby<K, V> {
+    primary: BPTree<K, V>,
+    standby: Option<BPTree<K, V>>,
+    failed: Option<BPTree<K, V>>,
+    // Change log offset the standby has applied up to.
+    offset: u64,
+    // Log end after each write through the wrapper that the standby hasn't applied yet, with
+    // the primary's monotonic clock at the time.
+    pending: VecDeque<(u64, Duration)>,
+    delay: Duration,
+}
+
+impl<K, V> Standby<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    // The standby must start out empty; it catches up on everything the primary logged.
+    pub fn new(primary: BPTree<K, V>, standby: BPTree<K, V>, delay: Duration) -> Result<Self> {
+        let end = primary.change_log_end().ok_or(Error::ChangeLogDisabled)?;
+        if !standby.is_empty() {
+            return Err(Error::TreeNotEmpty);
+        }
+        let mut pending = VecDeque::new();
+        if end > 0 {
+            pending.push_back((end, primary.clock().monotonic()));
+        }
+        Ok(Self{
+            primary,
+            standby: Some(standby),
+            failed: None,
+            offset: 0,
+            pending,
+            delay,
+        })
+    }
+
+    pub fn primary(&self) -> &BPTree<K, V> {
+        &self.primary
+    }
+
+    pub fn standby(&self) -> Option<&BPTree<K, V>> {
+        self.standby.as_ref()
+    }
+
+    // The tree that was replaced by the standby, kept for inspection.
+    pub fn failed(&self) -> Option<&BPTree<K, V>> {
+        self.failed.as_ref()
+    }
+
+    pub fn is_promoted(&self) -> bool {
+        self.failed.is_some()
+    }
+
+    // Log bytes written by the primary that the standby hasn't applied yet.
+    pub fn lag(&self) -> u64 {
+        match self.standby {
+            Some(_) => self.primary.change_log_end().unwrap_or(self.offset) - self.offset,
+            None => 0,
+        }
+    }
+
+    // Applies the writes that are older than the delay and returns how many changes that was.
+    pub fn catch_up(&mut self) -> Result<u64> {
+        let now = self.primary.clock().monotonic();
+        let mut until = self.offset;
+        while let Some(&(end, written)) = self.pending.front() {
+            if now.saturating_sub(written) < self.delay {
+                break;
+            }
+            until = end;
+            self.pending.pop_front();
+        }
+        self.apply(until)
+    }
+
+    // Applies everything logged so far, regardless of the delay.
+    pub fn sync(&mut self) -> Result<u64> {
+        self.pending.clear();
+        self.apply(self.primary.change_log_end().unwrap_or(self.offset))
+    }
+
+    // Makes the standby the primary. The change log is read from its own file, so this works
+    // however damaged the primary's pages are. Without a standby left this is a no-op.
+    pub fn promote(&mut self) -> Result<()> {
+        if self.standby.is_none() {
+            return Ok(());
+        }
+        self.sync()?;
+        if let Some(standby) = self.standby.take() {
+            self.failed = Some(std::mem::replace(&mut self.primary, standby));
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> BPTree<K, V> {
+        self.primary
+    }
+
+    fn apply(&mut self, until: u64) -> Result<u64> {
+        let standby = match &mut self.standby {
+            Some(standby) if until > self.offset => standby,
+            _ => return Ok(0),
+        };
+        let mut applied = 0;
+        for event in self.primary.changes(self.offset)? {
+            let event = event?;
+            if event.next > until {
+                break;
+            }
+            match event.change {
+                Change::Set(key, value) => { standby.set(key, value)?; },
+                Change::SetExpiring(key, value, expires) => { standby.set_expiring_at(key, value, expires)?; },
+                Change::Remove(key) => match standby.remove(&key) {
+                    Ok(_) | Err(Error::KeyNotFound) => {},
+                    Err(e) => return Err(e),
+                },
+            }
+            self.offset = event.next;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    fn written(&mut self) -> Result<()> {
+        if let Some(end) = self.primary.change_log_end() {
+            self.pending.push_back((end, self.primary.clock().monotonic()));
+        }
+        self.catch_up().map(|_| ())
+    }
+
+    fn failover(&mut self, e: Error) -> Result<()> {
+        match is_corruption(&e) && self.standby.is_some() {
+            true => self.promote(),
+            false => Err(e),
+        }
+    }
+}
+
+impl<K, V> ReadEngine<K, V> for Standby<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Result<R>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static, F: FnOnce(&V) -> R
+    {
+        // Reads take `&self`, so they can't swap handles; they fall back to the standby
+        // directly, which is complete once it has replayed the rest of the log.
+        let mut f = Some(f);
+        match self.primary.get_with(key, |value| f.take().map(|f| f(value))) {
+            Ok(Some(r)) => Ok(r),
+            Ok(None) => unreachable!(),
+            Err(e) if is_corruption(&e) && self.lag() == 0 => match (&self.standby, f) {
+                (Some(standby), Some(f)) => standby.get_with(key, f),
+                _ => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<K, V> WriteEngine<K, V> for Standby<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        // Only cloned while a failover is still possible.
+        let retry = self.standby.as_ref().map(|_| (key.clone(), value.clone()));
+        match self.primary.set(key, value) {
+            Ok(_) => self.written(),
+            Err(e) => {
+                self.failover(e)?;
+                let (key, value) = retry.ok_or(Error::CorruptedPage)?;
+                self.primary.set(key, value).map(|_| ())
+            }
+        }
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Result<()>
+        where K: Borrow<Q>, Q: Ord + ?Sized + 'static
+    {
+        match self.primary.remove(key) {
+            Ok(_) => self.written(),
+            Err(e) => {
+                self.failover(e)?;
+                self.primary.remove(key).map(|_| ())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::path::Path;
+    use crate::engine::bptree::Options;
+    use super::*;
+
+    #[test]
+    fn test_standby_takes_over_from_corrupted_primary() -> Result<()> {
+        let options = || Options{ max_key_count: Some(4), change_log: true, ..Options::default() };
+        let primary_path = Path::new("data").join("test_standby_primary.db");
+        let primary = BPTree::with_options(&primary_path, options())?;
+        let standby = BPTree::with_options(Path::new("data").join("test_standby_standby.db"), options())?;
+        let mut store = Standby::new(primary, standby, Duration::from_secs(3600))?;
+        for i in 0..100u64 {
+            store.set(i, i * 10)?;
+        }
+        store.remove(&7)?;
+        assert!(store.lag() > 0);
+        assert!(matches!(store.standby().unwrap().get(&1), Err(Error::KeyNotFound)));
+
+        OpenOptions::new().write(true).open(&primary_path)?.set_len(0)?;
+        assert!(store.get(&1).is_err());
+        store.set(100, 1000)?;
+        assert!(store.is_promoted());
+        assert_eq!(store.lag(), 0);
+        assert_eq!(store.get(&99)?, 990);
+        assert_eq!(store.get(&100)?, 1000);
+        assert!(matches!(store.get(&7), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_standby_follows_after_delay() -> Result<()> {
+        let options = || Options{ change_log: true, ..Options::default() };
+        let primary = BPTree::with_options(Path::new("data").join("test_standby_delay_primary.db"), options())?;
+        let standby = BPTree::with_options(Path::new("data").join("test_standby_delay_standby.db"), options())?;
+        let mut store = Standby::new(primary, standby, Duration::ZERO)?;
+        for i in 0..20u64 {
+            store.set(i, i)?;
+        }
+        store.remove(&3)?;
+        assert_eq!(store.lag(), 0);
+        assert_eq!(store.standby().unwrap().get(&19)?, 19);
+        assert!(matches!(store.standby().unwrap().get(&3), Err(Error::KeyNotFound)));
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+// futures adapters over a Shared tree, built with the `streams` feature. Neither holds a
+// lock while it is waiting to be polled: scans read a chunk of entries at a time, and the
+// sink hands each full batch to a writer thread.
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use futures::channel::oneshot;
+use futures::{Future, Sink, Stream};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::engine::batch::WriteBatch;
+use crate::engine::iter::RangeIter;
+use crate::error::{Error, Result};
+use crate::shared::Shared;
+
+pub const SCAN_CHUNK: usize = 256;
+pub const DEFAULT_SINK_BATCH: usize = 1024;
+
+// The entries of a range in key order. Each chunk is read under its own read lock, so
+// writes between chunks show up in the entries that follow.
+pub struct ScanStream<K, V> {
+    store: Shared<K, V>,
+    next: Bound<K>,
+    end: Bound<K>,
+    chunk: usize,
+    buffered: VecDeque<(K, V)>,
+    done: bool,
+}
+
+impl<K, V> ScanStream<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + 'static
+{
+    pub fn new<R: RangeBounds<K>>(store: Shared<K, V>, range: R) -> Self {
+        Self{
+            store,
+            next: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            chunk: SCAN_CHUNK,
+            buffered: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    pub fn with_chunk(mut self, chunk: usize) -> Self {
+        assert!(chunk > 0);
+        self.chunk = chunk;
+        self
+    }
+
+    fn refill(&mut self) -> Result<()> {
+        let (next, end, chunk) = (self.next.clone(), self.end.clone(), self.chunk);
+        let entries = self.store.read(|tree| RangeIter::new(tree, next, end)?.take(chunk).collect::<Result<Vec<_>>>())?;
+        self.done = entries.len() < chunk;
+        if let Some((key, _)) = entries.last() {
+            self.next = Bound::Excluded(key.clone());
+        }
+        self.buffered.extend(entries);
+        Ok(())
+    }
+}
+
+impl<K, V> Stream for ScanStream<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + Unpin + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + Unpin + 'static
+{
+    type Item = Result<(K, V)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.buffered.is_empty() && !self.done {
+            if let Err(e) = self.refill() {
+                self.done = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+        Poll::Ready(self.buffered.pop_front().map(Ok))
+    }
+}
+
+// Collects items into batches of `batch_size` and writes each one with write_batch() on a
+// thread of its own. Only one batch is written at a time: while it is, poll_ready() stays
+// pending once the next batch has filled up, which holds back the producer.
+pub struct BatchSink<K, V> {
+    store: Shared<K, V>,
+    batch: WriteBatch<K, V>,
+    batch_size: usize,
+    writing: Option<oneshot::Receiver<Result<u64>>>,
+}
+
+impl<K, V> BatchSink<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    pub fn new(store: Shared<K, V>) -> Self {
+        Self::with_batch_size(store, DEFAULT_SINK_BATCH)
+    }
+
+    pub fn with_batch_size(store: Shared<K, V>, batch_size: usize) -> Self {
+        assert!(batch_size > 0);
+        Self{ store, batch: WriteBatch::with_capacity(batch_size), batch_size, writing: None }
+    }
+
+    fn start_write(&mut self) {
+        let batch = mem::replace(&mut self.batch, WriteBatch::with_capacity(self.batch_size));
+        let store = self.store.clone();
+        let (done, writing) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = done.send(store.write(|tree| tree.write_batch(batch)));
+        });
+        self.writing = Some(writing);
+    }
+
+    fn poll_written(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let writing = match &mut self.writing {
+            Some(writing) => writing,
+            None => return Poll::Ready(Ok(())),
+        };
+        let written = match Pin::new(writing).poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            // The writer thread only goes away without answering if the write panicked.
+            Poll::Ready(written) => written.unwrap_or(Err(Error::Poisoned)),
+        };
+        self.writing = None;
+        Poll::Ready(written.map(|_| ()))
+    }
+}
+
+impl<K, V> Sink<(K, V)> for BatchSink<K, V>
+    where K: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + Unpin + 'static,
+          V: Debug + Clone + Ord + Serialize + DeserializeOwned + Send + Sync + Unpin + 'static
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.batch.len() < self.batch_size {
+            return Poll::Ready(Ok(()));
+        }
+        futures::ready!(self.poll_written(cx))?;
+        self.start_write();
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, (key, value): (K, V)) -> Result<()> {
+        self.batch.put(key, value);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        futures::ready!(self.poll_written(cx))?;
+        if self.batch.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        self.start_write();
+        self.poll_written(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use futures::executor::block_on;
+    use futures::{stream, SinkExt, StreamExt, TryStreamExt};
+    use crate::engine::bptree::Options;
+    use super::*;
+
+    #[test]
+    fn test_sink_then_scan() -> Result<()> {
+        let store: Shared<u32, String> = Shared::create(Path::new("data").join("test_stream.db"), Options::default())?;
+        let mut sink = BatchSink::with_batch_size(store.clone(), 64);
+        block_on(sink.send_all(&mut stream::iter((0..1_000).map(|i| Ok((i, format!("v{}", i)))))))?;
+        block_on(sink.close())?;
+        assert_eq!(store.get(&999)?, "v999");
+
+        let scan = ScanStream::new(store.clone(), 100..400).with_chunk(7);
+        let keys: Vec<u32> = block_on(scan.map_ok(|(key, _)| key).try_collect())?;
+        assert_eq!(keys, (100..400).collect::<Vec<_>>());
+        let tail: Vec<_> = block_on(ScanStream::new(store, 998..).collect::<Vec<_>>());
+        assert_eq!(tail.into_iter().collect::<Result<Vec<_>>>()?, vec![(998, "v998".to_string()), (999, "v999".to_string())]);
+        Ok(())
+    }
+}